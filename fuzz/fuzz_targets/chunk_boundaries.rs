@@ -0,0 +1,42 @@
+// Processes arbitrary html split at random boundaries, failing when they change the result : cargo fuzz run chunk_boundaries
+// Set SHADOW_FUZZ_DEFINITION to the path of a definition file to fuzz your own rules instead of the default definition
+#![no_main]
+
+use std::{cell::RefCell, rc::Rc, sync::OnceLock};
+
+use libfuzzer_sys::fuzz_target;
+use shadow_api::{testing, CompiledShadowJson};
+
+// Text buffers, attributes, edits and nested arrays : the handlers which keep state across chunks
+const DEFAULT_DEFINITION: &str = r##"
+{
+    "s": "body",
+    "sub": [
+        { "s": "h1", "edit": { "content": { "op": "match_replace", "match": "(\\S+) (\\S+)", "val": "$2 $1" } }, "data": { "values": { "title": { "source": "Contents" } } } },
+        { "s": "a[href]", "data": { "path": "links.", "values": { "href": { "source": "Attribute", "name": "href" }, "text": { "source": "Contents" } } } },
+        { "s": "p", "prepend": ["<b>"], "append": ["</b>"], "data": { "values": { "text": { "source": "Contents" } } } },
+        { "s": "script", "hide": true }
+    ]
+}
+"##;
+
+fn definition() -> &'static CompiledShadowJson {
+    static DEFINITION: OnceLock<CompiledShadowJson> = OnceLock::new();
+    DEFINITION.get_or_init(|| {
+        let json = match std::env::var("SHADOW_FUZZ_DEFINITION") {
+            Ok(path) => std::fs::read_to_string(path).expect("SHADOW_FUZZ_DEFINITION cannot be read"),
+            Err(_) => DEFAULT_DEFINITION.to_string(),
+        };
+        CompiledShadowJson::compile_str(&json, Rc::new(RefCell::new(Vec::new()))).expect("Invalid definition")
+    })
+}
+
+fuzz_target!(|input: &[u8]| {
+    // The first 8 bytes seed the chunk boundaries, the rest is the html
+    let Some((seed, html)) = input.split_first_chunk::<8>() else {
+        return;
+    };
+    if let Err(mismatch) = testing::check_random_boundaries(definition(), None, html, u64::from_le_bytes(*seed), 4) {
+        panic!("{}", mismatch);
+    }
+});
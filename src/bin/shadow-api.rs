@@ -0,0 +1,106 @@
+// Applies a ShadowJson definition to an html page, printing the rewritten html or the collected data (cli feature)
+use std::{cell::RefCell, io::{Read, Write}, process::ExitCode, rc::Rc};
+
+use shadow_api::{ShadowApi, ShadowApiOptions, ShadowJson};
+
+const USAGE: &str = "Usage: shadow-api --rules <rules.json> --input <page.html|url|-> [--as-json|--html] [--trace]
+
+  --rules <file>    ShadowJson definition, or array of definitions
+  --input <source>  Html file, http(s) URL, or - for the standard input
+  --as-json         Print the collected data instead of the html
+  --html            Print the rewritten html (default)
+  --trace           Print the matches, edits and data moves of each element to stderr, as json lines";
+
+struct Args {
+    rules: String,
+    input: String,
+    as_json: bool,
+    trace: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let (mut rules, mut input, mut as_json, mut trace) = (None, None, false, false);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--rules" => rules = Some(args.next().ok_or("--rules requires a file")?),
+                "--input" => input = Some(args.next().ok_or("--input requires a file, an URL or -")?),
+                "--as-json" => as_json = true,
+                "--html" => as_json = false,
+                "--trace" => trace = true,
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        Ok(Self {
+            rules: rules.ok_or("--rules is missing")?,
+            input: input.ok_or("--input is missing")?,
+            as_json,
+            trace,
+        })
+    }
+}
+
+// The definitions of the rules file, an object or an array of objects
+fn read_rules(path: &str) -> Result<Vec<ShadowJson>, String> {
+    let json = std::fs::read_to_string(path).map_err(|err| format!("Cannot read {} : {}", path, err))?;
+    ShadowJson::try_parse_list(&json).map_err(|err| format!("Invalid rules {} : {}", path, err))
+}
+
+fn open_input(input: &str) -> Result<Box<dyn Read>, String> {
+    if input == "-" {
+        Ok(Box::new(std::io::stdin().lock()))
+    } else if input.starts_with("http://") || input.starts_with("https://") {
+        let response = ureq::get(input).call().map_err(|err| format!("Cannot fetch {} : {}", input, err))?;
+        Ok(Box::new(response.into_body().into_reader()))
+    } else {
+        std::fs::File::open(input).map(|file| Box::new(file) as Box<dyn Read>).map_err(|err| format!("Cannot read {} : {}", input, err))
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let defs = read_rules(&args.rules)?;
+    let mut input = open_input(&args.input)?;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = std::io::BufWriter::new(std::io::stdout().lock());
+
+    let options = ShadowApiOptions { as_json: args.as_json, trace: args.trace, ..Default::default() };
+    let shadow_api_o = ShadowApi::new(Some(options));
+    shadow_api_o.parse(Rc::new(defs.into_iter().map(|def| Rc::new(RefCell::new(def))).collect()), Rc::clone(&errors));
+    let result = shadow_api_o.try_process_html(&mut output, &mut input, Rc::clone(&errors));
+    let trace = shadow_api_o.take_trace();
+    drop(shadow_api_o);
+    if args.as_json {
+        let _ = writeln!(output); // The data ends without a newline
+    }
+    output.flush().map_err(|err| format!("Cannot write the output : {}", err))?;
+
+    let mut stderr = std::io::stderr().lock();
+    for event in trace {
+        let _ = writeln!(stderr, "{}", serde_json::to_string(&event).unwrap_or_default());
+    }
+    for error in errors.borrow().iter() {
+        let _ = writeln!(stderr, "{}", error);
+    }
+    result.map(|_| ()).map_err(|err| err.to_string())
+}
+
+fn main() -> ExitCode {
+    if std::env::args().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        },
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -1,11 +1,48 @@
 mod shadow_api;
+pub mod testing;
 
 pub use crate::shadow_api::ShadowApi;
 pub use crate::shadow_api::ShadowApiInit;
-pub use crate::shadow_api::ShadowJson;
+pub use crate::shadow_api::{ShadowJson, ShadowJsonField};
 pub use crate::shadow_api::ShadowData;
+pub use crate::shadow_api::ShadowDataCollisionPolicy;
+pub use crate::shadow_api::ShadowDataUidMode;
 pub use crate::shadow_api::ShadowError;
+pub use crate::shadow_api::ShadowErrorPolicy;
+pub use crate::shadow_api::ShadowInjectionOverflow;
 pub use crate::shadow_api::ShadowDataCursor;
 pub use crate::shadow_api::ShadowApiOptions;
 pub use crate::shadow_api::ShadowApiRewriter;
-pub use crate::shadow_api::ShadowApiReplacer;
\ No newline at end of file
+pub use crate::shadow_api::ShadowApiReplacer;
+pub use crate::shadow_api::ReplacedChunk;
+pub use crate::shadow_api::ShadowApiSession;
+pub use crate::shadow_api::ShadowApiSessionEnd;
+pub use crate::shadow_api::ShadowApiStream;
+pub use crate::shadow_api::{ShadowApiStats, ShadowProcessReport};
+pub use crate::shadow_api::DocumentEndCallback;
+pub use crate::shadow_api::CompleteCallback;
+pub use crate::shadow_api::DataTransformer;
+pub use crate::shadow_api::ShadowUrlRewriter;
+pub use crate::shadow_api::InlineRewriter;
+pub use crate::shadow_api::CspNonceProvider;
+pub use crate::shadow_api::ShadowSanitizer;
+pub use crate::shadow_api::ShadowTextReplacer;
+pub use crate::shadow_api::{ShadowMasker, ShadowMaskDetector};
+pub use crate::shadow_api::{ShadowAggregate, ShadowAggregateFn};
+#[cfg(any(feature = "futures", feature = "tokio"))]
+pub use crate::shadow_api::ShadowApiRewriterAsync;
+#[cfg(feature = "http-body")]
+pub use crate::shadow_api::ShadowApiBody;
+#[cfg(feature = "wasm")]
+pub use crate::shadow_api::ShadowApiWasm;
+pub use crate::shadow_api::ShadowJsonOutput;
+pub use crate::shadow_api::ItemSink;
+pub use crate::shadow_api::ShadowOutputEncoding;
+pub use crate::shadow_api::CompiledShadowJson;
+pub use crate::shadow_api::ShadowApiCache;
+pub use crate::shadow_api::ShadowApiBuilder;
+pub use crate::shadow_api::DataCallback;
+pub use crate::shadow_api::StopCondition;
+pub use crate::shadow_api::MissingRequiredCallback;
+pub use crate::shadow_api::ShadowTraceEvent;
+pub use crate::shadow_api::{ShadowPlanEntry, ShadowPlanPurpose};
\ No newline at end of file
@@ -6,12 +6,11 @@
 //! 1. Construct a ShadowJson object which should contain the definition of DOM modifications and data collection.
 //! 2. Optionnally define how the data should be injected. By default, ShadowApi creates a JS variable `shadow_api_data` enclosed in <script> tags. This is always inserted right before the </body> closing tag
 //! 3. Call the ShadowApi::parse method on the constructed object. You will also need to provide `errors` object to store potential errors in your data definition for debugging.
-//! 4. Finally, call `ShadowApi::stream_response` to begin processing Fastly response chunk by chunk
+//! 4. Finally, call `ShadowApi::process_html` (or `ShadowApi::stream_fastly_response` with the `fastly` feature) to begin processing the response chunk by chunk
 //! 
 //! It is recommended that the steps 1,2 and 3 are done while waiting for the backend response (using `Fastly::Request::send_async` for example) - especially if ShadowJson is fetched through another API.
 
-use std::any::Any;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::io::{Write, Read};
 use std::rc::{Rc, Weak};
@@ -19,8 +18,9 @@ use std::borrow::Cow;
 use std::str::FromStr;
 use std::sync::atomic::AtomicUsize;
 use indexmap::IndexMap;
-use lol_html::html_content::{ContentType, Element, TextChunk};
-use lol_html::{ElementContentHandlers, Selector, HtmlRewriter, Settings, OutputSink};
+use lol_html::html_content::{Comment, ContentType, Doctype, DocumentEnd, Element, EndTag, TextChunk, TextType};
+use lol_html::{DocumentContentHandlers, ElementContentHandlers, Selector, HtmlRewriter, MemorySettings, Settings, OutputSink};
+use lol_html::errors::SelectorError;
 
 mod shadow_error;
 mod shadow_data;
@@ -28,34 +28,264 @@ mod shadow_data_cursor;
 mod shadow_json;
 mod shadow_api_rewriter;
 mod shadow_api_replacer;
+mod shadow_api_session;
+mod shadow_api_stream;
+mod shadow_api_stats;
+mod shadow_url_rewriter;
+mod shadow_sanitizer;
+mod shadow_fragment;
+mod shadow_attribute_injection;
+mod shadow_text_replacer;
+mod shadow_masker;
+mod shadow_aggregate;
+mod shadow_entities;
+mod shadow_json_output;
+mod shadow_io;
+mod shadow_json_compiled;
+mod shadow_api_cache;
+mod shadow_api_builder;
+mod shadow_api_sentinel;
+mod shadow_api_trace;
+mod shadow_api_plan;
+mod shadow_injection_budget;
+mod shadow_sibling;
+mod shadow_nth;
+mod shadow_match_state;
+mod shadow_required_state;
+mod shadow_select_state;
+mod shadow_form_state;
+mod shadow_region_state;
+mod shadow_label_state;
+mod shadow_text_buffer;
+mod shadow_edit;
 
-#[cfg(feature = "async")]
+#[cfg(any(feature = "futures", feature = "tokio"))]
 mod shadow_api_async;
+#[cfg(feature = "http-body")]
+mod shadow_api_body;
+#[cfg(feature = "fastly")]
+mod shadow_api_fastly;
+#[cfg(feature = "worker")]
+mod shadow_api_worker;
+#[cfg(feature = "wasm")]
+mod shadow_api_wasm;
+#[cfg(feature = "python")]
+mod shadow_api_python;
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-pub use shadow_error::ShadowError;
+pub use shadow_error::{ShadowError, ShadowErrorPolicy};
 pub use shadow_data::ShadowData;
-pub use shadow_json::ShadowJson;
+pub use shadow_data::ShadowDataCollisionPolicy;
+pub use shadow_data::ShadowOutputEncoding;
+pub use shadow_data::ShadowDataUidMode;
+pub use shadow_json::{ShadowJson, ShadowJsonField};
 pub use shadow_data_cursor::ShadowDataCursor;
 pub use shadow_api_rewriter::ShadowApiRewriter;
-pub use shadow_api_replacer::ShadowApiReplacer;
-use shadow_json::ShadowJsonValueSource;
+pub use shadow_api_replacer::{ShadowApiReplacer, ReplacedChunk};
+pub use shadow_api_session::{ShadowApiSession, ShadowApiSessionEnd};
+pub use shadow_api_stream::ShadowApiStream;
+pub use shadow_api_stats::{ShadowApiStats, ShadowRuleMatches, ShadowProcessReport};
+pub use shadow_url_rewriter::ShadowUrlRewriter;
+pub use shadow_sanitizer::ShadowSanitizer;
+pub use shadow_text_replacer::ShadowTextReplacer;
+pub use shadow_masker::{ShadowMasker, ShadowMaskDetector};
+pub use shadow_aggregate::{ShadowAggregate, ShadowAggregateFn};
+pub use shadow_json_output::ShadowJsonOutput;
+use shadow_io::{ChunkedWriter, read_chunk, write_fully};
+use shadow_api_sentinel::SentinelScanner;
+pub use shadow_api_trace::ShadowTraceEvent;
+pub use shadow_api_plan::{ShadowPlanEntry, ShadowPlanPurpose};
+use shadow_api_trace::{AppliedEdits, NodeTracer};
+use shadow_fragment::FragmentInjection;
+use shadow_attribute_injection::AttributeInjection;
+use shadow_data::ShadowDataUids;
+use shadow_injection_budget::InjectionBudget;
+use shadow_sibling::{SiblingCombinator, SiblingTracker};
+use shadow_nth::ShadowNth;
+use shadow_match_state::{MatchRestriction, MatchScope, ShadowMatchState};
+use shadow_required_state::RequiredState;
+use shadow_select_state::{SelectState, collect_value};
+use shadow_form_state::{FormSelect, FormState, FormText};
+use shadow_region_state::RegionState;
+use shadow_label_state::LabelState;
+use shadow_text_buffer::TextBuffer;
+use shadow_edit::{apply_class_op, apply_style};
+pub use shadow_json_compiled::CompiledShadowJson;
+pub use shadow_api_cache::ShadowApiCache;
+pub use shadow_api_builder::ShadowApiBuilder;
+#[cfg(any(feature = "futures", feature = "tokio"))]
+pub use shadow_api_async::ShadowApiRewriterAsync;
+#[cfg(feature = "http-body")]
+pub use shadow_api_body::ShadowApiBody;
+#[cfg(feature = "wasm")]
+pub use shadow_api_wasm::ShadowApiWasm;
+use shadow_json::{ShadowJsonCombinator, ShadowJsonEditOne, ShadowJsonInline, ShadowJsonKeyCase, ShadowJsonRegion, ShadowJsonRegionOp, ShadowJsonRewriteUrls, ShadowJsonValueSource};
 
 const MAX_CHUNK_BYTESIZE: usize = 8096;
 
+/// Callback invoked once the whole document has been processed, with the collected data
+pub type DocumentEndCallback = Rc<Box<dyn Fn(&mut DocumentEnd, &ShadowData)>>;
+/// Receives the final collected data once the document was processed, e.g. to serialize a subset of it into a response header or a log record
+pub type CompleteCallback = Rc<Box<dyn Fn(&ShadowData)>>;
+/// Post-processing applied to the collected data right before it is formatted for injection
+pub type DataTransformer = Rc<Box<dyn Fn(&mut ShadowData)>>;
+/// Rewrites the whole contents of a <style> or <script> element matched by a node defining `inline`. Receives the tag name and the contents
+pub type InlineRewriter = Rc<Box<dyn Fn(&str, &mut String)>>;
+/// Returns the CSP nonce of the current response
+pub type CspNonceProvider = Rc<Box<dyn Fn() -> String>>;
+/// Receives each array item streamed with the stream_items option, as a NDJSON line (serialized item followed by a newline)
+pub type ItemSink = Rc<Box<dyn Fn(&[u8])>>;
+/// Tells whether the data collected so far is enough, in which case the rest of the input is not read (as_json option only)
+pub type StopCondition = Rc<Box<dyn Fn(&ShadowData) -> bool>>;
+/// Receives the values collected at a data path matching the pattern it was registered with (see on_data), along with that path
+pub type DataCallback = Rc<Box<dyn Fn(&str, &ShadowData)>>;
+/// Receives the ShadowError::MissingRequired errors of the document, once it ends with required data missing
+pub type MissingRequiredCallback = Rc<Box<dyn Fn(&[ShadowError])>>;
+// Data listeners registered with on_data : path pattern and callback
+type DataListeners = Rc<Vec<(String, DataCallback)>>;
+type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
 pub struct ShadowApi<'a> {
     data_formatter: Rc<Box<dyn Fn(String) -> String>>,
     pub ech: RefCell<Vec<(Cow<'a, Selector>, ElementContentHandlers<'a>)>>,
+    pub dch: RefCell<Vec<DocumentContentHandlers<'a>>>,
+    on_document_end: Option<DocumentEndCallback>,
+    on_complete: Option<CompleteCallback>,
+    on_missing_required: Option<MissingRequiredCallback>,
+    data_transformer: Option<DataTransformer>,
+    inline_rewriter: Option<InlineRewriter>,
+    csp_nonce: Option<CspNonceProvider>,
+    sanitizer: Option<Rc<ShadowSanitizer>>,
+    text_replacer: Option<Rc<ShadowTextReplacer>>,
+    masker: Option<Rc<ShadowMasker>>,
+    item_sink: Option<ItemSink>,
+    data_listeners: Vec<(String, DataCallback)>,
+    stop_condition: Option<StopCondition>,
+    stop_requested: Rc<Cell<bool>>, // Set by the handlers of the nodes defining stop_after
+    failed: Rc<Cell<bool>>, // Set once a handler failed with the fail_open error policy
+    pass: Rc<Cell<ShadowPass>>, // Pass run by the handlers of ech/dch, see the two_pass option
+    pub(crate) second_pass: RefCell<Option<ShadowHandlers<'a>>>, // Rewriting handlers of the second pass, built by parse when the two_pass option is set
+    low_priority_paths: Vec<String>, // Dropped first from the injected data exceeding max_injected_bytes, see set_low_priority_paths
+    attribute_injection: Option<AttributeInjection>, // Injects the data as data-* attributes instead of a <script> tag, see set_attribute_injection
+    ld_json_key: Option<String>, // Path of the data receiving the ld+json blocks of the document, see set_ld_json_key
+    sentinel: Option<(Selector, usize)>, // Selector required within the first bytes of the input for it to be rewritten, and amount of bytes scanned
     max_chunk_bytesize: usize,
     options: Option<ShadowApiOptions>,
     pub shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+    uids: Option<ShadowDataUids>, // Uid counter of the collected data, with the debug_uids option
+    stats: Rc<RefCell<ShadowApiStats>>,
+    rules: Cell<usize>, // Rules registered by parse so far, see ShadowApiStats::matches
+    trace: Option<Rc<RefCell<Vec<ShadowTraceEvent>>>>, // Set with the trace option
+    flags: Rc<HashMap<String, String>>, // Runtime flags the enabled_if conditions of the nodes are evaluated against
+    plan: RefCell<Vec<ShadowPlanEntry>>, // Handlers registered by parse, see plan
+    parsed: RefCell<Vec<ParsedDefinitions>>, // Definitions given to parse, to build the handlers again in reset_for_next_document
 }
 
+// Definitions handled by a call to parse (or its variants), with the errors container and the handlers built for them
+type ParsedDefinitions = (Rc<Vec<Rc<RefCell<ShadowJson>>>>, Rc<RefCell<Vec<String>>>, ShadowParseMode);
+
 #[derive(Serialize, Deserialize, Debug, Default, Copy, Clone)]
 pub struct ShadowApiOptions {
     #[serde(default)]
     pub as_json: bool,
+    #[serde(default)]
+    pub collision_policy: ShadowDataCollisionPolicy, // How to resolve data keys written more than once (e.g. a data.values key colliding with a path). Defaults to merge
+    #[serde(default)]
+    pub fallback_injection: bool, // Append the data at the end of the document if </body> never came, even when no <body> was found at all
+    #[serde(default)]
+    pub fallback_close_body: bool, // When the data is appended at the end of the document, also append the missing "</body></html>"
+    #[serde(default)]
+    pub collect_meta: bool, // Collect the document title, canonical url and meta tags into a "head" object, without any definition
+    #[serde(default)]
+    pub stream_items: bool, // Pass each array item to the item sink as soon as its element closes, instead of keeping it in the data
+    #[serde(default)]
+    pub output_encoding: ShadowOutputEncoding, // Encoding of the collected data written in as_json mode (json, msg_pack or cbor)
+    #[serde(default)]
+    pub two_pass: bool, // Buffer the whole document to collect the data first, then rewrite it with the complete data. Ignored with as_json
+    #[serde(default)]
+    pub error_policy: ShadowErrorPolicy, // How the errors of the handlers affect the response : collect (default), fail_open or fail_closed
+    #[serde(default)]
+    pub debug_uids: bool, // Give the collected data elements reproducible uids (shown by ShadowData::visualize), numbered from 0 by each ShadowApi. They follow the mode of the thread otherwise (see ShadowData::set_uid_mode), which leaves them empty by default
+    #[serde(default)]
+    pub stats: bool, // Count the elements matched by each rule (see ShadowApi::take_stats). Every rule then registers an element handler
+    #[serde(default)]
+    pub trace: bool, // Record the matches, edits and data moves of each element, retrieved with ShadowApi::take_trace. The output is unchanged
+    #[serde(default)]
+    pub max_text_bytes: Option<usize>, // Bytes of text buffered per element. Past it, the text is passed through unchanged, its data truncated and its content edits skipped
+    #[serde(default)]
+    pub max_data_bytes: Option<usize>, // Bytes of data collected by the definition (keys and serialized values). The values which do not fit are dropped
+    #[serde(default)]
+    pub max_array_len: Option<usize>, // Items of each data array. The elements past it are still rewritten, but their items are dropped
+    #[serde(default)]
+    pub max_allowed_memory_usage: Option<usize>, // Hard limit of the lol_html rewriter memory, in bytes. Past it, the rewriter errors out. Unlimited by default
+    #[serde(default)]
+    pub preallocated_parsing_buffer_size: Option<usize>, // Bytes preallocated by the lol_html rewriter for the tags split over several chunks. 1024 by default
+    #[serde(default)]
+    pub max_injected_bytes: Option<usize>, // Bytes of the injected data (serialized json, before the data formatter). Past it, injection_overflow applies
+    #[serde(default)]
+    pub injection_overflow: ShadowInjectionOverflow, // How the injected data is brought within max_injected_bytes
+}
+
+/// How the injected data exceeding the max_injected_bytes option is handled. The collected data itself is left untouched (as_json output, callbacks)
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowInjectionOverflow {
+    #[default]
+    TruncateArrays, // Removes the last items of the longest arrays until the data fits
+    DropPaths, // Removes the low priority paths (see ShadowApi::set_low_priority_paths), in order, until the data fits
+    Skip, // Injects a warning comment instead of the data
+}
+
+// Pass run by the handlers. Single unless the two_pass option is set, in which case process_html runs a Collect pass over the buffered document, then a Rewrite pass
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum ShadowPass {
+    #[default]
+    Single,
+    Collect, // The data is collected, the output is discarded
+    Rewrite, // The document is rewritten with the complete data, which is not collected again
+}
+
+impl ShadowPass {
+    fn collects(self) -> bool {
+        self != ShadowPass::Rewrite
+    }
+
+    fn writes(self) -> bool {
+        self != ShadowPass::Collect
+    }
+}
+
+// Handlers built by parse : all of them, or only those collecting the data / editing the document (parse_collect_only, parse_mutate_only)
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum ShadowParseMode {
+    #[default]
+    Full,
+    CollectOnly, // The document is written unchanged, the data is collected but not injected
+    MutateOnly, // The document is edited, no data is collected nor injected
+}
+
+type ShadowHandlers<'a> = (Vec<(Cow<'a, Selector>, ElementContentHandlers<'a>)>, Vec<DocumentContentHandlers<'a>>);
+
+// Regexes of the match_replace operations and of the extract values of a node, compiled by parse_one and moved into its handlers
+// None for the other operations and values, as well as for invalid regexes (reported when parsing)
+#[derive(Default)]
+struct NodeRegexes {
+    attrs: Vec<Vec<Option<Regex>>>, // By attribute then operation, in the order of edit.attrs
+    content: Option<Regex>, // edit.content
+    values: Vec<Option<Regex>>, // In the order of data.values
+}
+
+// Element content handlers registered by parse, along with their full selector, by which merge_handlers groups them
+type KeyedHandlers<'h> = Vec<(String, Cow<'h, Selector>, ElementContentHandlers<'h>)>;
+
+// Progress of the data injection into the body
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyState {
+    #[default]
+    NotFound,
+    Opened, // <body> was found, the data is injected when it closes
+    Injected,
 }
 
 /// Owned object for initializing ShadowApi which has to be lifetime-tied to it
@@ -86,25 +316,59 @@ impl ShadowApiInit {
 
     /// Consumes ShadowApiInit to generate a ShadowApi bound to the provided lifetime
     pub fn init<'a>(self) -> ShadowApi<'a> {
-        let mut shadow_api_o = ShadowApi::new(self.options);
-        shadow_api_o.set_max_chunk_bytesize(self.max_chunk_bytesize);
-        shadow_api_o.set_data_formatter(Rc::new(self.data_formatter)); // Define a custom formatter for the generated JSON data
-
-        // Parse aggregated & transformed list of all ShadowJson definitions
-        shadow_api_o.parse(Rc::new(self.json_def), Rc::clone(&self.errors)); // This crawls ShadowJson and builds all element and text content handlers for LOLHTML.
-
-        shadow_api_o
+        let mut builder = ShadowApiBuilder::new()
+            .chunk_size(self.max_chunk_bytesize)
+            .formatter(self.data_formatter) // Define a custom formatter for the generated JSON data
+            .definitions(self.json_def) // Aggregated & transformed list of all ShadowJson definitions
+            .errors(self.errors);
+        if let Some(options) = self.options {
+            builder = builder.options(options);
+        }
+        builder.build() // This crawls ShadowJson and builds all element and text content handlers for LOLHTML.
     }
 }
 
 impl<'h> ShadowApi<'h> {
     pub fn new(options: Option<ShadowApiOptions>) -> Self {
+        let uids = options.filter(|options| options.debug_uids).map(|_| ShadowDataUids::default());
+        let shadow_data_cursor = {
+            let _scope = uids.as_ref().map(ShadowDataUids::enter); // The root data is the first one numbered
+            ShadowDataCursor::init()
+        };
         ShadowApi {
             data_formatter: Rc::new(Box::new(Self::default_data_formatter)),
             ech: RefCell::new(Vec::new()),
+            dch: RefCell::new(Vec::new()),
+            on_document_end: None,
+            on_complete: None,
+            on_missing_required: None,
+            data_transformer: None,
+            inline_rewriter: None,
+            csp_nonce: None,
+            sanitizer: None,
+            text_replacer: None,
+            masker: None,
+            flags: Rc::new(HashMap::new()),
+            item_sink: None,
+            data_listeners: Vec::new(),
+            stop_condition: None,
+            stop_requested: Rc::new(Cell::new(false)),
+            failed: Rc::new(Cell::new(false)),
+            pass: Rc::new(Cell::new(ShadowPass::default())),
+            second_pass: RefCell::new(None),
+            low_priority_paths: Vec::new(),
+            attribute_injection: None,
+            ld_json_key: None,
+            sentinel: None,
             max_chunk_bytesize: MAX_CHUNK_BYTESIZE,
             options,
-            shadow_data_cursor: Rc::new(RefCell::new(ShadowDataCursor::init()))
+            shadow_data_cursor: Rc::new(RefCell::new(shadow_data_cursor)),
+            uids,
+            stats: Rc::new(RefCell::new(ShadowApiStats::default())),
+            rules: Cell::new(0),
+            trace: options.filter(|options| options.trace).map(|_| Rc::new(RefCell::new(Vec::new()))),
+            plan: RefCell::new(Vec::new()),
+            parsed: RefCell::new(Vec::new()),
         }
     }
 
@@ -116,10 +380,212 @@ impl<'h> ShadowApi<'h> {
         self.data_formatter = formatter;
     }
 
+    /// Defines a function modifying the collected data right before it is passed to the data formatter (deduplicating arrays, renaming keys, computing derived fields...)
+    /// Must be set before calling parse
+    pub fn set_data_transformer(&mut self, data_transformer: DataTransformer) {
+        self.data_transformer = Some(data_transformer);
+    }
+
+    /// Defines a function rewriting the contents of the <style> and <script> elements matched by the nodes defining `inline`, after their rules
+    /// Must be set before calling parse
+    pub fn set_inline_rewriter(&mut self, inline_rewriter: InlineRewriter) {
+        self.inline_rewriter = Some(inline_rewriter);
+    }
+
+    /// Defines the Content-Security-Policy nonce attached to the injected data <script> tags, and to the <script> tags of the fragments of the nodes flagged with `nonce`
+    /// Must be set before calling parse
+    pub fn set_csp_nonce(&mut self, nonce: impl Into<String>) {
+        let nonce: String = nonce.into();
+        self.csp_nonce = Some(Rc::new(Box::new(move || nonce.clone())));
+    }
+
+    /// Same as set_csp_nonce, with a nonce generated by the provider. The provider is called once, by parse
+    pub fn set_csp_nonce_provider(&mut self, provider: CspNonceProvider) {
+        self.csp_nonce = Some(provider);
+    }
+
+    /// Defines the runtime flags (e.g. ("ab_test", "B"), ("locale", "ja")) the enabled_if conditions of the nodes are evaluated against : the nodes whose condition is false are not registered
+    /// Must be set before calling parse
+    pub fn set_flags<K: Into<String>, V: Into<String>>(&mut self, flags: impl IntoIterator<Item = (K, V)>) {
+        self.flags = Rc::new(flags.into_iter().map(|(name, value)| (name.into(), value.into())).collect());
+    }
+
+    /// Sanitizes the HTML fragments injected by the definition (append, prepend, insert_before, insert_after, wrap and set_html) when parsing it. Violations are reported to the errors
+    /// Must be set before calling parse
+    pub fn set_sanitizer(&mut self, sanitizer: ShadowSanitizer) {
+        self.sanitizer = Some(Rc::new(sanitizer));
+    }
+
+    /// Rewrites the texts of the whole document with the rules of the replacer, outside of its excluded elements. The data collected by the nodes is unchanged
+    /// Must be set before calling parse
+    pub fn set_text_replacer(&mut self, text_replacer: ShadowTextReplacer) {
+        self.text_replacer = Some(Rc::new(text_replacer));
+    }
+
+    /// Masks the personal data (emails, phone and card numbers, custom patterns) of the collected data as it is collected, and again before it leaves the ShadowApi
+    /// (injection, as_json output, document end callback), so that data filled by other means is masked too. With its `text` flag, the texts of the document are masked as well
+    /// Must be set before calling parse
+    pub fn set_masker(&mut self, masker: ShadowMasker) {
+        self.masker = Some(Rc::new(masker));
+    }
+
+    /// Defines the sink receiving the array items with the stream_items option : each item of an outermost array (path ending with a dot) is passed as a NDJSON line once its element closes, then removed from the data
+    /// Items of keyed (key_from), deduplicated (dedupe_by) or sorted (sort_by) collections are not streamed. Must be set before calling parse
+    pub fn set_item_sink(&mut self, item_sink: ItemSink) {
+        self.item_sink = Some(item_sink);
+    }
+
+    /// Registers a callback invoked as soon as a value of data.values is collected at a path matching path_pattern, while the page is still streaming
+    /// Paths are dot separated, with array items by index ("products.0.sku"). In the pattern, `*` matches a single segment and `**` any amount of segments ("products.*.sku", "**.sku")
+    /// Values of keyed collection items (key_from) are only reachable once their element closes, and are not reported. Must be set before calling parse
+    pub fn on_data(&mut self, path_pattern: &str, callback: DataCallback) {
+        self.data_listeners.push((path_pattern.to_string(), callback));
+    }
+
+    /// Defines a predicate checked against the collected data after each input chunk : once it returns true, process_html (and its variants) stop reading the input and end the rewriter
+    /// Only applies with the as_json option, as the html output would be truncated otherwise. See also the stop_after field of the definition
+    pub fn set_stop_condition(&mut self, stop_condition: StopCondition) {
+        self.stop_condition = Some(stop_condition);
+    }
+
+    /// Only rewrites the documents containing an element matching the sentinel selector (e.g. "meta[name=shadow-enabled]") within their first max_bytes bytes
+    /// process_html and its variants buffer the input until the sentinel is found or max_bytes were read. Without it, the input is passed through verbatim without running any handler (and no data is injected)
+    /// With the as_json option, only the data is written, and the rest of the input is not read
+    pub fn set_sentinel(&mut self, selector: &str, max_bytes: usize) -> Result<(), ShadowError> {
+        let selector_obj = Selector::from_str(selector).map_err(|err| ShadowError::Other(format!("[sentinel] invalid selector '{}' : {}", selector, err)))?;
+        self.sentinel = Some((selector_obj, max_bytes));
+        Ok(())
+    }
+
+    /// Defines the data paths (dot separated, e.g. "related" or "products.0.reviews") removed, in order, from the injected data exceeding the max_injected_bytes option
+    /// with the drop_paths overflow strategy. Must be set before calling parse
+    pub fn set_low_priority_paths<S: Into<String>>(&mut self, paths: impl IntoIterator<Item = S>) {
+        self.low_priority_paths = paths.into_iter().map(Into::into).collect();
+    }
+
+    /// Injects the data as data-* attributes of the first element matching the selector (e.g. "body") instead of a <script> tag, for consumers reading the DOM
+    /// rather than executing scripts (strict CSP without nonces, AMP-like environments). Each (name, path) pair sets the attribute `data-{name}` (or `name` if it already starts with "data-")
+    /// to the data at the dot separated path : strings as they are, other values as json. Missing paths are skipped
+    /// The attributes are set as the start tag of the element streams : only the data collected before it is available, use the two_pass option for the data found further down.
+    /// The data transformer and formatter are not applied. Must be set before calling parse
+    pub fn set_attribute_injection(&mut self, selector: &str, attributes: &[(&str, &str)]) -> Result<(), ShadowError> {
+        self.attribute_injection = Some(AttributeInjection::new(selector, attributes)?);
+        Ok(())
+    }
+
+    /// Collects the structured data of the document, the contents of its <script type="application/ld+json"> blocks, into an array of the data at key
+    /// (dot separated path from the root, e.g. "ld_json"). Each block is parsed as json, keeping its numbers and the order of its keys : an object is appended to the array,
+    /// and the items of an array are appended one by one. Invalid blocks are reported to the errors and skipped. Must be set before calling parse
+    pub fn set_ld_json_key(&mut self, key: &str) -> Result<(), ShadowError> {
+        if key.split('.').any(str::is_empty) {
+            return Err(ShadowError::Other(format!("[ld_json] invalid key '{}' : empty path segment", key)));
+        }
+        self.ld_json_key = Some(key.to_string());
+        Ok(())
+    }
+
+    /// Whether the rest of the input can be skipped (as_json option), either because an element of a node defining stop_after has closed or because the stop condition is met
+    /// process_html and its variants check it after each chunk. When feeding the replacer or the session yourself, check it to stop early
+    pub fn stop_requested(&self) -> bool {
+        if !self.options.map(|opts| opts.as_json).unwrap_or(false) {
+            return false;
+        }
+        self.stop_requested.get() || self.stop_condition.as_ref().is_some_and(|stop_condition| {
+            stop_condition(&self.shadow_data_cursor.borrow().root.borrow())
+        })
+    }
+
+    /// Whether a handler failed with the fail_open error policy, in which case the rest of the document was passed through as it is, without the data
+    pub fn has_failed(&self) -> bool {
+        self.failed.get()
+    }
+
+    /// Defines a callback invoked when the document ends, even if it lacks a </body> tag. It receives the collected data, and may append content to the output
+    /// Must be set before calling parse
+    pub fn set_on_document_end(&mut self, on_document_end: DocumentEndCallback) {
+        self.on_document_end = Some(on_document_end);
+    }
+
+    /// Defines a callback invoked once per document, after all the other handlers (data injection, masking, document end callback), with the final collected data
+    /// It gives the data to the caller without writing it to the output (response header, log record...). Not invoked when the sentinel is not found. Must be set before calling parse
+    pub fn on_complete(&mut self, on_complete: CompleteCallback) {
+        self.on_complete = Some(on_complete);
+    }
+
+    /// Defines a callback invoked when the document ends with required data missing (data.required, data.values.*.required), e.g. to mark the response as failed
+    /// The missing data is reported to the errors as well. Must be set before calling parse
+    pub fn set_on_missing_required(&mut self, on_missing_required: MissingRequiredCallback) {
+        self.on_missing_required = Some(on_missing_required);
+    }
+
+    /// Inserts request-scoped values (any serializable value : serde_json::Value, ShadowData, structs...) at the given dot separated path of the data, before streaming
+    /// Objects are merged with the data collected later on, as with the default collision policy. An empty path merges the value into the root object
+    /// Numbers are kept as numbers. A null value of an object leaves its key as it is, while null array items are kept
+    pub fn seed_data<T: Serialize + ?Sized>(&self, path: &str, value: &T) -> Result<(), ShadowError> {
+        let value = serde_json::to_value(value).map_err(|e| ShadowError::Data(format!("[seed] value cannot be serialized : {}", e)))?;
+        self.shadow_data_cursor.borrow().seed(path, &value)
+    }
+
+    /// Returns the statistics collected so far and resets them. Call after the rewriter has ended to get the complete report
+    pub fn take_stats(&self) -> ShadowApiStats {
+        self.stats.take()
+    }
+
+    /// Prepares the ShadowApi to process another document into the same data, e.g. the pages of a paginated listing :
+    /// the data collected so far is kept, the data cursor goes back to the root and the handlers are built again from the definitions given to parse,
+    /// their matching state (limit, skip, nth, required data...) starting over. Array paths append the items of the next document after the previous ones.
+    /// The handlers added to ech directly (e.g. by ShadowApiBuilder::element_handler) are not built again, and the statistics keep accumulating until take_stats
+    pub fn reset_for_next_document(&self) {
+        self.shadow_data_cursor.borrow_mut().rewind();
+        self.stop_requested.set(false);
+        self.failed.set(false);
+        self.pass.set(ShadowPass::default());
+        self.ech.borrow_mut().clear();
+        self.dch.borrow_mut().clear();
+        self.second_pass.replace(None);
+        self.plan.borrow_mut().clear();
+        self.rules.set(0);
+        for (json_def, errors, mode) in self.parsed.take() {
+            let reported = errors.borrow().len();
+            self.parse_with(json_def, Rc::clone(&errors), None, mode);
+            errors.borrow_mut().truncate(reported); // Definition errors were already reported for the first document
+        }
+    }
+
+    /// Returns the handlers registered by parse (and its variants) so far, in registration order : full selector, handler kinds and node of the definitions
+    /// they come from. Meant to audit a definition, e.g. supplied by a third party, before processing any document with it
+    pub fn plan(&self) -> Vec<ShadowPlanEntry> {
+        self.plan.borrow().clone()
+    }
+
+    /// Returns the events recorded so far with the trace option, in document order, and clears them. Empty without the option
+    pub fn take_trace(&self) -> Vec<ShadowTraceEvent> {
+        self.trace.as_ref().map(|trace| trace.take()).unwrap_or_default()
+    }
+
     /// Override this method to customize how you inject data.
     /// The contents will always be inserted at right before the end of </body> tag, as the data is collected while the body is streamed
+    /// If the body is never closed, the contents are appended at the end of the document instead
     fn default_data_formatter(data: String) -> String {
-        return format!(r##"<script>var shadow_api_data = {};</script>"##, data);
+        format!(r##"<script>var shadow_api_data = {};</script>"##, Self::escape_json_for_script(&data))
+    }
+
+    /// Escapes serialized json for a <script> element : `<`, `>` and `&` (so that no collected string can close the element with `</script>`
+    /// or open a comment with `<!--`), as well as the U+2028 and U+2029 line separators, become \uXXXX escapes, which leave the value unchanged
+    /// Custom data formatters injecting the data in a script should apply it as well
+    pub fn escape_json_for_script(json: &str) -> String {
+        let mut escaped = String::with_capacity(json.len());
+        for c in json.chars() {
+            match c {
+                '<' => escaped.push_str("\\u003c"),
+                '>' => escaped.push_str("\\u003e"),
+                '&' => escaped.push_str("\\u0026"),
+                '\u{2028}' => escaped.push_str("\\u2028"),
+                '\u{2029}' => escaped.push_str("\\u2029"),
+                c => escaped.push(c),
+            }
+        }
+        escaped
     }
 
     // Parses a ShadowJson into a Vec destined for building ElementContentHandlers of LOLHTML Crate
@@ -130,87 +596,510 @@ impl<'h> ShadowApi<'h> {
         &self,
         json_def: Rc<Vec<Rc<RefCell<ShadowJson>>>>,
         errors: Rc<RefCell<Vec<String>>>
-    ) -> Rc<RefCell<HashMap<String, Box<dyn Any>>>> {
-        let mut selector_stack: Vec<String> = Vec::with_capacity(10);
-        let mut ech_borrowed = self.ech.borrow_mut();
-        let ech = ech_borrowed.as_mut();
-        let cache: Rc<RefCell<HashMap<String, Box<dyn Any>>>> = Rc::new(RefCell::new(HashMap::new()));
-        {
-            let mut cache_borrowed = cache.borrow_mut();
+    ) -> Rc<RefCell<ShadowApiCache>> {
+        self.parse_with(json_def, errors, None, ShadowParseMode::Full)
+    }
+
+    /// Same as parse, building only the handlers collecting the data (headless data collection) :
+    /// the editing fields of the definitions are ignored, the document is written unchanged and the data is not injected into it
+    pub fn parse_collect_only(
+        &self,
+        json_def: Rc<Vec<Rc<RefCell<ShadowJson>>>>,
+        errors: Rc<RefCell<Vec<String>>>
+    ) -> Rc<RefCell<ShadowApiCache>> {
+        self.parse_with(Self::restrict_definitions(&json_def, ShadowJson::collect_only), errors, None, ShadowParseMode::CollectOnly)
+    }
+
+    /// Same as parse, building only the handlers editing the document : the data and form fields of the definitions are ignored,
+    /// nothing is collected nor injected. Placeholders and copy_from_data only get their default values
+    pub fn parse_mutate_only(
+        &self,
+        json_def: Rc<Vec<Rc<RefCell<ShadowJson>>>>,
+        errors: Rc<RefCell<Vec<String>>>
+    ) -> Rc<RefCell<ShadowApiCache>> {
+        self.parse_with(Self::restrict_definitions(&json_def, ShadowJson::mutate_only), errors, None, ShadowParseMode::MutateOnly)
+    }
+
+    // Copies of the definitions keeping the fields of one concern, so that parse_one does not build the handlers of the other one
+    fn restrict_definitions(json_def: &[Rc<RefCell<ShadowJson>>], restrict: fn(&ShadowJson) -> ShadowJson) -> Rc<Vec<Rc<RefCell<ShadowJson>>>> {
+        Rc::new(json_def.iter().map(|node| Rc::new(RefCell::new(restrict(&node.borrow())))).collect())
+    }
+
+    /// Same as parse, for definitions compiled once with CompiledShadowJson : the selectors and regexes are reused instead of being parsed again
+    pub fn parse_compiled(
+        &self,
+        compiled: &CompiledShadowJson,
+        errors: Rc<RefCell<Vec<String>>>
+    ) -> Rc<RefCell<ShadowApiCache>> {
+        match compiled.definitions() {
+            Ok(json_def) => self.parse_with(json_def, errors, Some(compiled), ShadowParseMode::Full),
+            Err(err) => {
+                errors.borrow_mut().push(err.to_string());
+                self.parse_with(Rc::new(Vec::new()), errors, Some(compiled), ShadowParseMode::Full)
+            },
+        }
+    }
 
-            // Cache for computed regex executed while stream processing the HTML
-            let regex_map: HashMap<String, Regex> = HashMap::new();
-            cache_borrowed.insert(String::from("regex_map"), Box::new(regex_map));
+    fn parse_with(
+        &self,
+        json_def: Rc<Vec<Rc<RefCell<ShadowJson>>>>,
+        errors: Rc<RefCell<Vec<String>>>,
+        compiled: Option<&CompiledShadowJson>,
+        mode: ShadowParseMode
+    ) -> Rc<RefCell<ShadowApiCache>> {
+        self.parsed.borrow_mut().push((Rc::clone(&json_def), Rc::clone(&errors), mode));
+        let nonce: Option<String> = self.csp_nonce.as_ref().map(|provider| provider());
+        let item_sink = if self.options.map(|opts| opts.stream_items).unwrap_or(false) {
+            if self.item_sink.is_none() {
+                errors.borrow_mut().push("The stream_items option requires an item sink (set_item_sink)".to_string());
+            }
+            self.item_sink.clone()
+        } else {
+            None
+        };
+        let first_rule = self.rules.get();
+        let cache = self.build_handlers(
+            Rc::clone(&json_def),
+            Rc::clone(&errors),
+            compiled,
+            nonce.clone(),
+            item_sink,
+            Rc::clone(&self.pass),
+            mode,
+            &mut self.ech.borrow_mut(),
+            &mut self.dch.borrow_mut()
+        );
+        // Collecting the data first is only needed when it is injected into the edited document
+        if mode == ShadowParseMode::Full && self.options.map(|opts| opts.two_pass && !opts.as_json).unwrap_or(false) {
+            // Same handlers again, rewriting the document once the first pass collected the data
+            let reported = errors.borrow().len();
+            let mut second_pass = self.second_pass.borrow_mut();
+            let (ech, dch) = second_pass.get_or_insert_with(Default::default);
+            self.rules.set(first_rule); // Same rules
+            self.build_handlers(json_def, Rc::clone(&errors), compiled, nonce, None, Rc::new(Cell::new(ShadowPass::Rewrite)), mode, ech, dch);
+            errors.borrow_mut().truncate(reported); // Definition errors were already reported by the first pass
         }
+        cache
+    }
+
+    // Registers the handlers of the definitions, running the pass of the pass cell
+    #[allow(clippy::too_many_arguments)]
+    fn build_handlers(
+        &self,
+        json_def: Rc<Vec<Rc<RefCell<ShadowJson>>>>,
+        errors: Rc<RefCell<Vec<String>>>,
+        compiled: Option<&CompiledShadowJson>,
+        nonce: Option<String>,
+        item_sink: Option<ItemSink>,
+        pass: Rc<Cell<ShadowPass>>,
+        mode: ShadowParseMode,
+        ech: &mut Vec<(Cow<'h, Selector>, ElementContentHandlers<'h>)>,
+        dch: &mut Vec<DocumentContentHandlers<'h>>
+    ) -> Rc<RefCell<ShadowApiCache>> {
+        let _scope = self.uids.as_ref().map(ShadowDataUids::enter); // Data initialized while parsing
+        let documents = dch.len();
+        let mut keyed: KeyedHandlers = Vec::new(); // Merged into ech once all the handlers are registered
+        let mut selector_stack: Vec<String> = Vec::with_capacity(10);
+        let mut match_scope: MatchScope = Vec::new();
+        let cache = Rc::new(RefCell::new(ShadowApiCache {
+            regex_map: compiled.map(|compiled| compiled.regexes.clone()).unwrap_or_default(),
+            selectors: compiled.map(|compiled| compiled.selectors.clone()).unwrap_or_default(),
+            stats: Rc::clone(&self.stats), // Statistics updated by the handlers
+            next_rule: self.rules.get(),
+            inline_rewriter: self.inline_rewriter.clone(),
+            sanitizer: self.sanitizer.clone(),
+            masker: self.masker.clone().filter(|masker| masker.data),
+            csp_nonce: nonce.clone(),
+            item_sink,
+            data_listeners: Rc::new(self.data_listeners.clone()),
+            stop_requested: Rc::clone(&self.stop_requested),
+            failed: Rc::clone(&self.failed),
+            pass: Rc::clone(&pass),
+            trace: self.trace.clone(),
+            flags: Rc::clone(&self.flags),
+            collision_policy: self.options.map(|opts| opts.collision_policy).unwrap_or_default(),
+            uids: self.uids.clone(),
+            ..ShadowApiCache::default()
+        }));
         Self::parse_rec(
             json_def,
-            errors,
-            ech,
+            Rc::clone(&errors),
+            &mut keyed,
             &mut selector_stack,
+            &mut match_scope,
             Rc::clone(&cache),
             Rc::clone(&self.shadow_data_cursor),
+            Rc::new(self.options.unwrap_or_default()),
         );
-        let dom_written = self.options.as_ref().and_then(|opt| Some(!opt.as_json)).unwrap_or(true);
+        self.rules.set(cache.borrow().next_rule);
+        if self.options.map(|opts| opts.collect_meta).unwrap_or(false) && pass.get().collects() && mode != ShadowParseMode::MutateOnly {
+            Self::meta_content_handlers(&mut keyed, &cache, Rc::clone(&self.shadow_data_cursor), Rc::clone(&errors));
+        }
+        if let Some(ld_json_key) = self.ld_json_key.as_ref().filter(|_| pass.get().collects() && mode != ShadowParseMode::MutateOnly) {
+            Self::ld_json_content_handler(ld_json_key, &mut keyed, &cache, Rc::clone(&self.shadow_data_cursor), Rc::clone(&errors));
+        }
+        let data_formatter = match nonce {
+            Some(nonce) => {
+                let formatter = Rc::clone(&self.data_formatter);
+                Rc::new(Box::new(move |data: String| Self::add_script_nonce(&formatter(data), &nonce)) as Box<dyn Fn(String) -> String>)
+            },
+            None => Rc::clone(&self.data_formatter),
+        };
+        let dom_written = self.options.as_ref().map(|opt| !opt.as_json).unwrap_or(true) && mode == ShadowParseMode::Full;
+        let text_masker = self.masker.clone().filter(|masker| masker.text);
+        let text_written = self.options.as_ref().map(|opt| !opt.as_json).unwrap_or(true) && mode != ShadowParseMode::CollectOnly && pass.get().writes();
+        if text_written && (self.text_replacer.is_some() || text_masker.is_some()) {
+            Self::text_replacer_handlers(self.text_replacer.clone(), text_masker, &mut keyed, dch, &cache);
+        }
+        let data_masker = self.masker.clone().filter(|masker| masker.data);
+        let data_transformer = Self::masking_transformer(self.data_transformer.clone(), data_masker.clone());
+        let budget = self.options.and_then(|opts| opts.max_injected_bytes.map(|max_bytes| Rc::new(InjectionBudget {
+            max_bytes,
+            overflow: opts.injection_overflow,
+            low_priority_paths: self.low_priority_paths.clone(),
+        })));
+        let body_state = Rc::new(Cell::new(BodyState::default()));
+        if let Some(attribute_injection) = self.attribute_injection.as_ref().filter(|_| dom_written) {
+            attribute_injection.register(&mut keyed, &cache, Rc::clone(&self.shadow_data_cursor), data_masker.clone(), Rc::clone(&pass), Rc::clone(&self.failed));
+        }
+        let dom_written = dom_written && self.attribute_injection.is_none(); // No <script> tag
         if dom_written {
             // No need for data content DOM injection if "as_json" option is set
             Self::data_content_handler(
-                Rc::clone(&self.data_formatter),
-                ech,
-                Rc::clone(&self.shadow_data_cursor)
+                Rc::clone(&data_formatter),
+                data_transformer.clone(),
+                budget.clone(),
+                &mut keyed,
+                &cache,
+                Rc::clone(&self.shadow_data_cursor),
+                Rc::clone(&body_state),
+                Rc::clone(&pass),
+                Rc::clone(&self.failed),
+                Rc::clone(&errors),
+                self.options.unwrap_or_default().error_policy
             ); // This will create a special handler to inject data at the end
         }
+        if pass.get() != ShadowPass::Rewrite {
+            // The handlers of the second pass of the two_pass option are the same
+            self.plan.borrow_mut().append(&mut cache.borrow_mut().plan);
+        }
+        let mut merged = Self::merge_handlers(keyed);
+        if let Some(uids) = &cache.borrow().uids {
+            Self::uid_scoped_handlers(uids, &mut merged, &mut []);
+        }
+        ech.append(&mut merged);
+        let required: Vec<Rc<RefCell<RequiredState>>> = cache.borrow().required_states.values().cloned().collect();
+        let aggregates = cache.borrow().aggregates.clone();
+        dch.push(Self::document_end_handler(
+            data_formatter,
+            data_transformer,
+            budget,
+            data_masker,
+            dom_written,
+            self.options.unwrap_or_default(),
+            Rc::clone(&self.shadow_data_cursor),
+            body_state,
+            self.on_document_end.clone(),
+            self.on_complete.clone(),
+            pass,
+            required,
+            aggregates,
+            self.on_missing_required.clone(),
+            errors,
+            Rc::clone(&self.failed)
+        ));
+        if let Some(uids) = &cache.borrow().uids {
+            Self::uid_scoped_handlers(uids, &mut [], &mut dch[documents..]);
+        }
         cache
     }
 
+    // Numbers the data created by the handlers, and by the end tag handlers they register, with the uid counter of the instance (debug_uids option)
+    fn uid_scoped_handlers(uids: &ShadowDataUids, ech: &mut [(Cow<Selector>, ElementContentHandlers)], dch: &mut [DocumentContentHandlers]) {
+        macro_rules! scoped {
+            ($handler:expr, $unit:ty) => {
+                $handler.take().map(|mut handler| {
+                    let uids = uids.clone();
+                    Box::new(move |unit: &mut $unit| {
+                        let _scope = uids.enter();
+                        handler(unit)
+                    }) as Box<dyn FnMut(&mut $unit) -> HandlerResult>
+                })
+            };
+        }
+        for (_, handlers) in ech {
+            handlers.element = handlers.element.take().map(|mut handler| {
+                let uids = uids.clone();
+                Box::new(move |el: &mut Element| {
+                    let _scope = uids.enter();
+                    let registered = el.end_tag_handlers().map_or(0, |handlers| handlers.len());
+                    let result = handler(el);
+                    for end_tag_handler in el.end_tag_handlers().into_iter().flatten().skip(registered) {
+                        let inner = std::mem::replace(end_tag_handler, Box::new(|_| Ok(())));
+                        let uids = uids.clone();
+                        *end_tag_handler = Box::new(move |end: &mut EndTag| {
+                            let _scope = uids.enter();
+                            inner(end)
+                        });
+                    }
+                    result
+                }) as Box<dyn FnMut(&mut Element) -> HandlerResult>
+            });
+            handlers.comments = scoped!(handlers.comments, Comment);
+            handlers.text = scoped!(handlers.text, TextChunk);
+        }
+        for handlers in dch {
+            handlers.doctype = scoped!(handlers.doctype, Doctype);
+            handlers.comments = scoped!(handlers.comments, Comment);
+            handlers.text = scoped!(handlers.text, TextChunk);
+            handlers.end = handlers.end.take().map(|handler| {
+                let uids = uids.clone();
+                Box::new(move |end: &mut DocumentEnd| {
+                    let _scope = uids.enter();
+                    handler(end)
+                }) as Box<dyn FnOnce(&mut DocumentEnd) -> HandlerResult>
+            });
+        }
+    }
+
+    // Planning pass merging the handlers registered for identical full selectors into a single entry, so that lol_html matches each selector once.
+    // The handlers of a kind (element, comments, text) run in registration order : an entry is only merged into an earlier one
+    // if no entry in between has a handler of the same kind
+    fn merge_handlers(keyed: KeyedHandlers) -> Vec<(Cow<Selector>, ElementContentHandlers)> {
+        macro_rules! chain {
+            ($first:expr, $second:expr, $unit:ty) => {
+                match ($first, $second) {
+                    (Some(mut first), Some(mut second)) => Some(Box::new(move |unit: &mut $unit| {
+                        first(unit)?;
+                        second(unit)
+                    }) as Box<dyn FnMut(&mut $unit) -> HandlerResult>),
+                    (first, second) => first.or(second),
+                }
+            };
+        }
+        let mut merged: Vec<(Cow<Selector>, ElementContentHandlers)> = Vec::with_capacity(keyed.len());
+        let mut by_selector: HashMap<String, usize> = HashMap::new();
+        let mut last_of_kind: [Option<usize>; 3] = [None; 3];
+        for (key, selector, handlers) in keyed {
+            let kinds = [handlers.element.is_some(), handlers.comments.is_some(), handlers.text.is_some()];
+            let target = by_selector.get(&key).copied().filter(|&idx| {
+                kinds.iter().zip(&last_of_kind).all(|(&has, last)| !has || last.is_none_or(|last| last <= idx))
+            });
+            let idx = match target {
+                Some(idx) => {
+                    let entry = &mut merged[idx].1;
+                    entry.element = chain!(entry.element.take(), handlers.element, Element);
+                    entry.comments = chain!(entry.comments.take(), handlers.comments, Comment);
+                    entry.text = chain!(entry.text.take(), handlers.text, TextChunk);
+                    idx
+                },
+                None => {
+                    merged.push((selector, handlers));
+                    by_selector.insert(key, merged.len() - 1);
+                    merged.len() - 1
+                },
+            };
+            for (&has, last) in kinds.iter().zip(last_of_kind.iter_mut()) {
+                if has {
+                    *last = Some(last.map_or(idx, |last| last.max(idx)));
+                }
+            }
+        }
+        merged
+    }
+
+    // Records the handlers just registered for a full selector into the plan of the cache, attributed to the node being parsed
+    fn record_plan(cache: &RefCell<ShadowApiCache>, selector: &str, handlers: &ElementContentHandlers, purpose: ShadowPlanPurpose, end_tag: bool) {
+        let mut cache_m = cache.borrow_mut();
+        let node = cache_m.node_path.clone(); // None for the handlers of the options, registered once the nodes are parsed
+        cache_m.plan.push(ShadowPlanEntry {
+            selector: selector.to_string(),
+            node,
+            purpose,
+            element: handlers.element.is_some(),
+            text: handlers.text.is_some(),
+            end_tag: end_tag && handlers.element.is_some(),
+        });
+    }
+
+    /// Amount of selectors registered with lol_html by parse, the handlers of identical selectors being merged. 0 once the document was processed
+    pub fn selector_count(&self) -> usize {
+        self.ech.borrow().len()
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn parse_rec(
         json_def: Rc<Vec<Rc<RefCell<ShadowJson>>>>,
         errors: Rc<RefCell<Vec<String>>>,
-        ech: &mut Vec<(Cow<Selector>, ElementContentHandlers)>,
+        ech: &mut KeyedHandlers,
         selector_stack: &mut Vec<String>, // To build full selector
-        cache: Rc<RefCell<HashMap<String, Box<dyn Any>>>>,
-        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>
+        match_scope: &mut MatchScope, // Matching states of the ancestor rules
+        cache: Rc<RefCell<ShadowApiCache>>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        options: Rc<ShadowApiOptions>
     ) {
-        for el in json_def.as_ref() {
+        let parent_path = cache.borrow().node_path.clone();
+        for (idx, el) in json_def.iter().enumerate() {
+            cache.borrow_mut().node_path = Some(match &parent_path {
+                Some(parent_path) => format!("{}.sub[{}]", parent_path, idx),
+                None => format!("[{}]", idx),
+            });
             Self::parse_one(
-                Rc::clone(&el),
+                Rc::clone(el),
                 Rc::clone(&errors),
                 ech,
                 selector_stack,
+                match_scope,
                 Rc::clone(&cache),
-                Rc::clone(&shadow_data_cursor)
+                Rc::clone(&shadow_data_cursor),
+                Rc::clone(&options)
             );
         }
+        cache.borrow_mut().node_path = parent_path;
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn parse_one(
         json_def: Rc<RefCell<ShadowJson>>,
         errors_rc: Rc<RefCell<Vec<String>>>,
-        ech: &mut Vec<(Cow<Selector>, ElementContentHandlers)>,
+        ech: &mut KeyedHandlers,
         selector_stack: &mut Vec<String>, // To build full selector
-        cache: Rc<RefCell<HashMap<String, Box<dyn Any>>>>,
-        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>
+        match_scope: &mut MatchScope, // Matching states of the ancestor rules
+        cache: Rc<RefCell<ShadowApiCache>>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        options: Rc<ShadowApiOptions>
     ) {
         static COUNTER: AtomicUsize = AtomicUsize::new(1);
         let selector_id = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
+        let sanitizer = cache.borrow().sanitizer.clone();
+        let json_def = match sanitizer {
+            Some(sanitizer) => {
+                // The handlers use a sanitized copy, the definition given to parse is left as it is.
+                // The sub nodes are shared rather than copied along, as they get their own copy once parsed
+                let sub = json_def.borrow_mut().sub.take();
+                let mut sanitized = json_def.borrow().clone();
+                json_def.borrow_mut().sub.clone_from(&sub);
+                sanitized.sub = sub;
+                Self::sanitize_fragments(&mut sanitized, &sanitizer, Rc::clone(&errors_rc));
+                Rc::new(RefCell::new(sanitized))
+            },
+            None => json_def,
+        };
+
         let json_def_b = json_def.borrow();
-        if json_def_b.s.as_str().len() == 0 {
+        let enabled = json_def_b.is_enabled(&cache.borrow().flags);
+        match enabled {
+            Ok(true) => {},
+            Ok(false) => return, // Along with the sub nodes
+            Err(err) => {
+                errors_rc.borrow_mut().push(format!("{} (selector {})", err, json_def_b.s));
+                return;
+            },
+        }
+        if json_def_b.s.is_empty() {
             let mut errors = errors_rc.borrow_mut();
             errors.push("Empty selector".to_string());
             return;
         }
-        selector_stack.push(json_def_b.s.clone());
-        let current_selector = selector_stack.join(" "); // Since LOLHTML is not building dom tree, we need to build the absolute selector
+        // A node restricted to some positions must not apply to all of them : an invalid nth skips the node, along with its sub nodes
+        let nth = match json_def_b.nth.as_ref().map(|nth| (nth, ShadowNth::parse(nth))) {
+            Some((nth, None)) => {
+                errors_rc.borrow_mut().push(format!("Invalid nth '{}' for selector {}", nth, json_def_b.s));
+                return;
+            },
+            Some((_, parsed)) => parsed,
+            None => None,
+        };
+        // An invalid wrap is skipped, the rest of the node still applies
+        let wrap_tags = json_def_b.wrap.as_ref().and_then(|wrap| match Self::wrap_tags(wrap) {
+            Ok(tags) => Some(tags),
+            Err(reason) => {
+                errors_rc.borrow_mut().push(format!("Invalid wrap '{}' for selector {} : {}", wrap, json_def_b.s, reason));
+                None
+            },
+        });
+        // Since LOLHTML is not building dom tree, we need to build the absolute selector. Each part of the stack holds its leading combinator
+        let sibling = SiblingCombinator::parse(&json_def_b.s);
+        let absolute = json_def_b.absolute.unwrap_or(false);
+        let mut anchor_parts = Vec::new(); // Parts of the parent node removed from the stack for a sibling or absolute node, restored when leaving it
+        let selector_part = if absolute {
+            if sibling.is_some() || json_def_b.combinator.is_some() {
+                errors_rc.borrow_mut().push(format!("Selector {} is invalid : an absolute node has no combinator", json_def_b.s));
+                return;
+            }
+            anchor_parts = std::mem::take(selector_stack);
+            json_def_b.s.clone()
+        } else if let Some((_, rest)) = sibling {
+            if selector_stack.is_empty() || rest.is_empty() || json_def_b.combinator.is_some() {
+                errors_rc.borrow_mut().push(format!(
+                    "Selector {} is invalid : a sibling combinator requires a parent node, a selector after it and no combinator field", json_def_b.s
+                ));
+                return;
+            }
+            // The siblings are in the same context as the parent element : its parts are replaced, up to its leading descendant or child combinator
+            let start = selector_stack.iter().rposition(|part| part.starts_with(' ')).unwrap_or(0);
+            anchor_parts = selector_stack.split_off(start);
+            match (selector_stack.is_empty(), anchor_parts[0].starts_with(" > ")) {
+                (true, _) => rest.to_string(),
+                (false, true) => format!(" > {}", rest),
+                (false, false) => format!(" {}", rest),
+            }
+        } else if selector_stack.is_empty() {
+            json_def_b.s.clone()
+        } else {
+            match json_def_b.combinator.unwrap_or_default() {
+                ShadowJsonCombinator::Descendant => format!(" {}", json_def_b.s),
+                ShadowJsonCombinator::Child => format!(" > {}", json_def_b.s),
+                ShadowJsonCombinator::SameElement => {
+                    if !json_def_b.s.starts_with(['.', '#', '[', ':']) {
+                        // A type selector would be glued to the end of the parent selector
+                        errors_rc.borrow_mut().push(format!(
+                            "Selector {} cannot refine the parent element (combinator self) : it must start with a class, id, attribute or pseudo-class",
+                            json_def_b.s
+                        ));
+                        return;
+                    }
+                    json_def_b.s.clone()
+                },
+            }
+        };
+        selector_stack.push(selector_part);
+        let current_selector = selector_stack.concat();
 
         // Validating the selector
-        let current_selector_obj = match Selector::from_str(&current_selector) {
+        let current_selector_obj = match Self::cached_selector(&cache, &current_selector) {
             Ok(s) => s,
             Err(e) => {
                 errors_rc.borrow_mut().push(format!("Selector {} is invalid : {}", &current_selector, e));
+                selector_stack.pop();
+                selector_stack.append(&mut anchor_parts);
                 return;
             },
         };
 
+        let stats = Rc::clone(&cache.borrow().stats);
+        let tracer: Option<Rc<NodeTracer>> = cache.borrow().trace.as_ref()
+            .map(|events| Rc::new(NodeTracer { selector: current_selector.clone(), events: Rc::clone(events) }));
+        let rule = {
+            let mut cache_m = cache.borrow_mut();
+            cache_m.next_rule += 1;
+            cache_m.next_rule - 1
+        };
+        if options.stats {
+            stats.borrow_mut().matches.entry(rule).or_insert_with(|| ShadowRuleMatches { selector: current_selector.clone(), count: 0 });
+        }
+
+        // Matches are counted in the element handler, for the rules restricted to some of their matches and their sub rules
+        let match_state = Rc::new(RefCell::new(ShadowMatchState::default()));
+        cache.borrow_mut().match_states.insert(selector_id, Rc::clone(&match_state));
+        let restriction = MatchRestriction {
+            limit: json_def_b.limit,
+            skip: json_def_b.skip.unwrap_or(0),
+            nth,
+        };
+        let regexes = Rc::new(Self::node_regexes(&json_def_b, &cache, &errors_rc));
+
         /* No need to prepare the data before the crawl, as it is dynamic
         let (next_data, parent_array) = match ShadowData::prepare_data(
             selector_id,
@@ -226,23 +1115,73 @@ impl<'h> ShadowApi<'h> {
         };
         */
 
+        let collects = cache.borrow().pass.get().collects();
+        for (target, expression) in json_def_b.aggregate.iter().flatten().filter(|_| collects) {
+            match ShadowAggregate::parse(expression) {
+                Ok(aggregate) => cache.borrow_mut().aggregates.push((target.clone(), aggregate)),
+                Err(err) => errors_rc.borrow_mut().push(format!("{} (selector {})", err, json_def_b.s)),
+            }
+        }
+        if let Some(label_selector) = json_def_b.data.as_ref().and_then(|d| d.label_selector.as_ref()).filter(|_| collects) {
+            Self::label_content_handlers(
+                label_selector,
+                selector_id,
+                &selector_stack[..selector_stack.len() - 1],
+                Rc::clone(&errors_rc),
+                ech,
+                Rc::clone(&cache)
+            );
+        }
+
+        let collects_value = json_def_b.data.as_ref()
+            .and_then(|d| d.values.as_ref())
+            .is_some_and(|values| values.values().any(|value| matches!(value.source, ShadowJsonValueSource::Value)));
+        if collects_value && collects && Self::targets_select(&json_def_b.s) {
+            Self::select_content_handlers(
+                &current_selector,
+                selector_id,
+                Rc::clone(&errors_rc),
+                ech,
+                Rc::clone(&cache),
+                Rc::clone(&stats)
+            );
+        }
+
+        if let Some(rewrite_urls) = &json_def_b.rewrite_urls {
+            Self::url_content_handlers(
+                rewrite_urls,
+                &current_selector,
+                Rc::clone(&errors_rc),
+                ech,
+                Rc::clone(&cache),
+                Rc::clone(&stats)
+            );
+        }
+
+        if let Some(inline) = &json_def_b.inline {
+            Self::inline_content_handlers(
+                inline,
+                &current_selector,
+                &current_selector_obj,
+                Rc::clone(&errors_rc),
+                ech,
+                Rc::clone(&cache),
+                Rc::clone(&stats)
+            );
+        }
+
         // Element handler function: it processes the node as an element
         let mut use_element_handler = false;
         let mut use_text_handler = false;
-        let empty_vec = Vec::new();
+        let has_absolute_sub = json_def_b.sub.iter().flat_map(|sub| sub.iter()).any(|sub| sub.borrow().absolute.unwrap_or(false));
 
         if // Listing all cases where we will need to generate an ECH for the element. Minimizing the cases will improve runtime performance
-            json_def_b.hide.unwrap_or(false)
-            || json_def_b.insert_after.as_ref().unwrap_or(&empty_vec).len() > 0
-            || json_def_b.insert_before.as_ref().unwrap_or(&empty_vec).len() > 0
-            || json_def_b.append.as_ref().unwrap_or(&empty_vec).len() > 0
-            || json_def_b.prepend.as_ref().unwrap_or(&empty_vec).len() > 0
-            || json_def_b.edit.is_some()
-            || json_def_b.delete.unwrap_or(false)
-            || json_def_b.data.as_ref()
-                .and_then(|sd| {
-                    Some(sd.path.as_ref().unwrap_or(&"".to_owned()).len() > 0)
-                })
+            Self::edits_element(&json_def_b)
+            || restriction.is_restricted()
+            || json_def_b.stop_after.unwrap_or(false)
+            || absolute
+            || has_absolute_sub
+            || json_def_b.data.as_ref().map(|sd| !sd.path.as_ref().unwrap_or(&"".to_owned()).is_empty())
                 .unwrap_or(false)
         {
             use_element_handler = true;
@@ -251,7 +1190,7 @@ impl<'h> ShadowApi<'h> {
             if let Some(values) = &data_def.values {
                 if !values.is_empty() {
                     for (_key, value) in values.iter() {
-                        match value {
+                        match &value.source {
                             ShadowJsonValueSource::Attribute(_attr_name) => {
                                 use_element_handler = true;
                             },
@@ -259,6 +1198,12 @@ impl<'h> ShadowApi<'h> {
                                 use_text_handler = true;
                             },
                             ShadowJsonValueSource::Value => {
+                                // The value of textarea and contenteditable elements is their text
+                                use_element_handler = true;
+                                use_text_handler = true;
+                                cache.borrow_mut().text_values.entry(selector_id).or_default();
+                            }
+                            ShadowJsonValueSource::HasAttribute(_) | ShadowJsonValueSource::DataAttributes(_) => {
                                 use_element_handler = true;
                             }
                         }
@@ -271,175 +1216,497 @@ impl<'h> ShadowApi<'h> {
                 }
             }
         }
+        if let Some(ShadowJsonValueSource::Contents) = json_def_b.data.as_ref().and_then(|d| d.key_from.as_ref()) {
+            use_text_handler = true;
+        }
+        if let Some(data_def) = &json_def_b.data {
+            let keys: Vec<String> = data_def.values.iter().flatten()
+                .filter(|(_, value)| value.required.unwrap_or(false))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if data_def.required.unwrap_or(false) || !keys.is_empty() {
+                // The element handler records the data items of the node
+                use_element_handler = true;
+                cache.borrow_mut().required_states.insert(selector_id, Rc::new(RefCell::new(RequiredState {
+                    selector: current_selector.clone(),
+                    data: data_def.required.unwrap_or(false),
+                    keys,
+                    items: Vec::new(),
+                })));
+            }
+        }
         if let Some(edit) = &json_def_b.edit {
-            if edit.content.is_some() {
-                use_text_handler = true;
+            if let Some(content) = &edit.content {
+                // set_html replaces the whole inner content from the element handler, text chunks don't need to be buffered for it
+                if content.op != "set_html" {
+                    use_text_handler = true;
+                }
             }
         }
 
-        if use_element_handler {
+        // Besides the element handlers of the node, the matches are counted for the stats, the trace, and the sibling and nth restrictions of the node or its sub nodes
+        let has_nth_sub = json_def_b.sub.iter().flat_map(|sub| sub.iter()).any(|sub| sub.borrow().nth.is_some());
+        let has_sibling_sub = json_def_b.sub.iter().flat_map(|sub| sub.iter()).any(|sub| SiblingCombinator::parse(&sub.borrow().s).is_some());
+        if use_element_handler || options.stats || tracer.is_some() || sibling.is_some() || has_sibling_sub || has_nth_sub {
             // Getting an extra RC before moving these into closure
             let eh_errors = Rc::clone(&errors_rc);
             let eh_json_def = Rc::clone(&json_def);
             let eh_cache = Rc::clone(&cache);
             let eh_shadow_data_cursor = Rc::clone(&shadow_data_cursor);
+            let eh_options = Rc::clone(&options);
+            let eh_match_scope = match_scope.clone();
+            let eh_match_state = Rc::clone(&match_state);
+            let eh_stats = Rc::clone(&stats);
+            let eh_regexes = Rc::clone(&regexes);
+            let eh_wrap_tags = wrap_tags.clone();
+            let eh_tracer = tracer.clone();
+            let eh_failed = Rc::clone(&cache.borrow().failed);
+            let eh_cursor = Rc::clone(&shadow_data_cursor);
+            let eh_sibling = sibling.map(|(combinator, _)| combinator);
+            let eh_tracker = (eh_sibling.is_some() || has_sibling_sub).then(|| Self::sibling_tracker(&cache, ech));
+            let eh_rule = options.stats.then_some(rule);
+            // Data items and absolute nodes leave the element as it closes, deferred fragments and stop_after act then
+            let node_end_tag = use_element_handler && (
+                (collects && json_def_b.data.is_some())
+                || absolute
+                || json_def_b.stop_after.unwrap_or(false)
+                || [&json_def_b.append, &json_def_b.insert_after].iter().any(|tags| tags.iter().flatten().any(|tag| tag.contains("{{")))
+                || (json_def_b.repeat_for.is_some() && json_def_b.append.is_some())
+            );
 
             ech.push((
+                current_selector.to_string(),
                 Cow::Owned(current_selector_obj.clone()),
                 ElementContentHandlers::default().element(move |el| {
-                    Self::element_content_handler(
+                    if eh_failed.get() || Self::is_suppressed(&eh_match_scope) {
+                        return Ok(());
+                    }
+                    if let (Some(combinator), Some(tracker), Some(parent_state)) = (eh_sibling, &eh_tracker, eh_match_scope.last()) {
+                        if !tracker.borrow().follows(&parent_state.borrow(), combinator) {
+                            Self::suppress(el, &eh_match_state);
+                            return Ok(());
+                        }
+                    }
+                    if let Some(rule) = eh_rule {
+                        if let Some(rule_matches) = eh_stats.borrow_mut().matches.get_mut(&rule) {
+                            rule_matches.count += 1;
+                        }
+                    }
+                    if let Some(tracer) = &eh_tracer {
+                        tracer.matched(el.tag_name());
+                    }
+                    if !Self::count_match(el, &eh_match_state, eh_match_scope.last(), &restriction) {
+                        return Ok(());
+                    }
+                    if let (true, Some(tracker)) = (has_sibling_sub, &eh_tracker) {
+                        tracker.borrow().record(&mut eh_match_state.borrow_mut());
+                    }
+                    if !use_element_handler {
+                        return Ok(());
+                    }
+                    if absolute {
+                        // The data goes to the data of the parent node, restored once the element and its sub nodes are processed
+                        let entry = eh_match_scope.last().and_then(|parent_state| parent_state.borrow().entry.upgrade());
+                        eh_cursor.borrow_mut().enter_at(entry);
+                    }
+                    let result = Self::element_content_handler(
                         el,
                         selector_id,
                         Rc::clone(&eh_json_def),
                         Rc::clone(&eh_errors),
                         Rc::clone(&eh_cache),
-                        Rc::clone(&eh_shadow_data_cursor)
-                    )
+                        Rc::clone(&eh_shadow_data_cursor),
+                        Rc::clone(&eh_options),
+                        Rc::clone(&eh_stats),
+                        &eh_regexes,
+                        eh_wrap_tags.as_ref(),
+                        eh_tracer.clone()
+                    );
+                    if has_absolute_sub {
+                        eh_match_state.borrow_mut().entry = Rc::downgrade(&eh_cursor.borrow().shadow_data);
+                    }
+                    if absolute {
+                        // After the handlers leaving the data of the element
+                        let can_have_content = el.can_have_content();
+                        match el.end_tag_handlers().filter(|_| can_have_content) {
+                            Some(handlers) => {
+                                let end_cursor = Rc::clone(&eh_cursor);
+                                handlers.push(Box::new(move |_end| Ok(end_cursor.borrow_mut().leave_escape()?)));
+                            },
+                            None => eh_cursor.borrow_mut().leave_escape()?,
+                        }
+                    }
+                    Self::recover(result, eh_options.error_policy, &eh_failed, &eh_errors)
                 })
             ));
+            Self::record_plan(&cache, &current_selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Node, node_end_tag);
         }
         if use_text_handler {
             // Getting an extra RC before moving these into closure
             let th_errors = Rc::clone(&errors_rc);
             let th_json_def = Rc::clone(&json_def);
-            let th_cache = Rc::clone(&cache);
-            let th_content_buffer = Rc::new(RefCell::new(String::new())); // Text content buffer is local for each selector
+            let th_content_buffer = Rc::new(RefCell::new(TextBuffer::default())); // Text content buffer is local for each selector
             let th_shadow_data_cursor = Rc::clone(&shadow_data_cursor);
+            let th_options = Rc::clone(&options);
+            let mut th_match_scope = match_scope.clone();
+            th_match_scope.push(Rc::clone(&match_state));
+            let th_stats = Rc::clone(&stats);
+            let th_regexes = Rc::clone(&regexes);
+            let th_data_listeners = Rc::clone(&cache.borrow().data_listeners);
+            let th_masker = cache.borrow().masker.clone();
+            let th_pass = Rc::clone(&cache.borrow().pass);
+            let th_value_from_text = cache.borrow().text_values.get(&selector_id).cloned();
+            let th_tracer = tracer.clone();
+            let th_failed = Rc::clone(&cache.borrow().failed);
 
             ech.push((
+                current_selector.to_string(),
                 Cow::Owned(current_selector_obj),
                 ElementContentHandlers::default().text(move |el| {
-                    Self::text_content_handler(
+                    // A text node already being buffered is completed, as its previous chunks were removed from the output
+                    if (th_failed.get() && th_content_buffer.borrow().text.is_empty()) || Self::is_suppressed(&th_match_scope) {
+                        return Ok(());
+                    }
+                    let result = Self::text_content_handler(
                         el,
                         selector_id,
                         Rc::clone(&th_json_def),
                         Rc::clone(&th_errors),
                         Rc::clone(&th_content_buffer),
-                        Rc::clone(&th_cache),
-                        Rc::clone(&th_shadow_data_cursor)
-                    )
+                        Rc::clone(&th_shadow_data_cursor),
+                        Rc::clone(&th_options),
+                        Rc::clone(&th_stats),
+                        &th_regexes,
+                        &th_data_listeners,
+                        th_masker.as_deref(),
+                        th_pass.get(),
+                        th_value_from_text.as_deref(),
+                        th_tracer.as_deref()
+                    );
+                    Self::recover(result, th_options.error_policy, &th_failed, &th_errors)
                 })
             ));
+            Self::record_plan(&cache, &current_selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Node, false);
         }
 
-        if let Some(sub) = &json_def_b.sub {
-            ShadowApi::parse_rec(
-                Rc::clone(&sub),
+        if json_def_b.form.unwrap_or(false) && cache.borrow().pass.get().collects() {
+            // After the element handler of the node, so that its data item is the current one when the form opens
+            Self::form_content_handlers(
+                &selector_stack.concat(),
+                selector_id,
                 Rc::clone(&errors_rc),
                 ech,
-                selector_stack,
                 Rc::clone(&cache),
-                Rc::clone(&shadow_data_cursor)
+                Rc::clone(&shadow_data_cursor),
+                Rc::clone(&stats)
             );
         }
 
-        selector_stack.pop();
-    }
+        if let Some(region) = &json_def_b.region {
+            if region.op != ShadowJsonRegionOp::Extract || cache.borrow().pass.get().collects() {
+                Self::region_content_handlers(
+                    region,
+                    &selector_stack.concat(),
+                    selector_id,
+                    Rc::clone(&errors_rc),
+                    ech,
+                    Rc::clone(&cache),
+                    Rc::clone(&shadow_data_cursor),
+                    Rc::clone(&stats)
+                );
+            }
+        }
 
+        if let Some(sub) = &json_def_b.sub {
+            match_scope.push(match_state);
+            ShadowApi::parse_rec(
+                Rc::clone(sub),
+                Rc::clone(&errors_rc),
+                ech,
+                selector_stack,
+                match_scope,
+                Rc::clone(&cache),
+                Rc::clone(&shadow_data_cursor),
+                Rc::clone(&options)
+            );
+            match_scope.pop();
+        }
+
+        selector_stack.pop();
+        selector_stack.append(&mut anchor_parts);
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn element_content_handler(
         el: &mut Element,
         selector_id: usize,
         json_def: Rc<RefCell<ShadowJson>>,
         errors: Rc<RefCell<Vec<String>>>,
-        cache: Rc<RefCell<HashMap<String, Box<dyn Any>>>>,
-        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>
+        cache: Rc<RefCell<ShadowApiCache>>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        options: Rc<ShadowApiOptions>,
+        stats: Rc<RefCell<ShadowApiStats>>,
+        regexes: &NodeRegexes,
+        wrap_tags: Option<&(String, String)>, // Validated opening and closing tags of wrap
+        tracer: Option<Rc<NodeTracer>>
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let json_def_c = Rc::clone(&json_def);
         let json_def_b = json_def.borrow();
         let delete = json_def_b.delete.unwrap_or(false);
+        let mut edits = AppliedEdits::new(tracer.is_some());
+        let mut data_values = 0usize;
 
-        if let Some(html_tags) = &json_def_b.insert_after {
-            for tag in html_tags {
-                el.after(tag.as_str(), ContentType::Html)
+        // Fragments containing {{path}} placeholders are rendered with the data collected so far.
+        // When the element has an end tag, append and insert_after fragments are rendered as it closes, so that they can include the data collected inside of it
+        let has_placeholder = |html_tags: &Option<Vec<String>>| {
+            html_tags.as_ref().is_some_and(|tags| tags.iter().any(|tag| tag.contains("{{")))
+        };
+        let repeat_for = json_def_b.repeat_for.as_deref();
+        let defer_insert_after = el.can_have_content() && has_placeholder(&json_def_b.insert_after);
+        let defer_append = el.can_have_content() && (has_placeholder(&json_def_b.append) || (repeat_for.is_some() && json_def_b.append.is_some()));
+        let nonce: Option<String> = if json_def_b.nonce.unwrap_or(false) {
+            cache.borrow().csp_nonce.clone()
+        } else {
+            None
+        };
+        let injection = Rc::new(FragmentInjection {
+            cursor: Rc::clone(&shadow_data_cursor),
+            nonce,
+            sanitizer: cache.borrow().sanitizer.clone(),
+            errors: Rc::clone(&errors),
+            selector: json_def_b.s.clone(),
+        });
+        let item_sink: Option<ItemSink> = cache.borrow().item_sink.clone();
+        let data_listeners: DataListeners = Rc::clone(&cache.borrow().data_listeners);
+
+        if json_def_b.stop_after.unwrap_or(false) {
+            let stop_requested = Rc::clone(&cache.borrow().stop_requested);
+            let can_have_content = el.can_have_content();
+            match el.end_tag_handlers().filter(|_| can_have_content) {
+                Some(handlers) => handlers.push(Box::new(move |_end| {
+                    stop_requested.set(true);
+                    Ok(())
+                })),
+                None => stop_requested.set(true),
+            }
+        }
+
+        if json_def_b.empty.unwrap_or(false) && !delete {
+            // Before the fragments, which would be removed along with the children otherwise
+            el.set_inner_content("", ContentType::Text);
+            edits.add(1, || "empty".to_string());
+        }
+
+        // Deferred fragments are counted here as well, as they are bound to be inserted
+        for (name, html_tags) in [
+            ("insert_after", &json_def_b.insert_after),
+            ("insert_before", &json_def_b.insert_before),
+            ("append", &json_def_b.append),
+            ("prepend", &json_def_b.prepend),
+        ] {
+            edits.add(html_tags.as_ref().map(Vec::len).unwrap_or(0), || name.to_string());
+        }
+        if !defer_insert_after {
+            if let Some(html_tags) = &json_def_b.insert_after {
+                for tag in html_tags {
+                    el.after(&injection.render("insert_after", tag), ContentType::Html)
+                }
             }
         }
         if let Some(html_tags) = &json_def_b.insert_before {
             for tag in html_tags {
-                el.before(tag.as_str(), ContentType::Html)
+                el.before(&injection.render("insert_before", tag), ContentType::Html)
             }
         }
-        if let Some(html_tags) = &json_def_b.append {
-            for tag in html_tags {
-                el.append(tag.as_str(), ContentType::Html)
+        if let Some((open_tag, close_tag)) = wrap_tags {
+            edits.add(1, || "wrap".to_string());
+            // Called after insert_before/insert_after so that the wrapper is the closest to the element
+            el.before(open_tag, ContentType::Html);
+            if !defer_insert_after {
+                el.after(close_tag, ContentType::Html);
+            }
+        }
+        if !defer_append {
+            if let Some(html_tags) = &json_def_b.append {
+                for tag in html_tags {
+                    el.append(&injection.render_repeated("append", tag, repeat_for), ContentType::Html)
+                }
             }
         }
         if let Some(html_tags) = &json_def_b.prepend {
             for tag in html_tags {
-                el.prepend(tag.as_str(), ContentType::Html)
+                el.prepend(&injection.render_repeated("prepend", tag, repeat_for), ContentType::Html)
+            }
+        }
+        if defer_insert_after || defer_append {
+            let end_json_def = Rc::clone(&json_def);
+            let end_injection = Rc::clone(&injection);
+            let close_tag = wrap_tags.map(|(_, close_tag)| close_tag.clone());
+            if let Some(handlers) = el.end_tag_handlers() {
+                handlers.push(Box::new(move |end| {
+                    // Same insertion order as the non-deferred case above
+                    let json_def_b = end_json_def.borrow();
+                    if defer_insert_after {
+                        for tag in json_def_b.insert_after.iter().flatten() {
+                            end.after(&end_injection.render("insert_after", tag), ContentType::Html);
+                        }
+                        if let Some(close_tag) = &close_tag {
+                            end.after(close_tag, ContentType::Html);
+                        }
+                    }
+                    if defer_append {
+                        for tag in json_def_b.append.iter().flatten() {
+                            end.before(&end_injection.render_repeated("append", tag, json_def_b.repeat_for.as_deref()), ContentType::Html);
+                        }
+                    }
+                    Ok(())
+                }));
             }
         }
 
         if !delete {
-            if json_def_b.hide.unwrap_or(false) {
-                match el.get_attribute("style") {
-                    Some(mut style) => style.push_str(";display: none"),
-                    None => el.set_attribute("style", "display: none").unwrap_or_else(|_| {}),
-                }
-            }
+            apply_style(el, &json_def_b, &mut edits, &errors);
             if let Some(edit) = &json_def_b.edit {
                 if let Some(attrs) = &edit.attrs {
-                    for (key, val) in attrs.iter() {
-                        match val.op.as_str() {
-                            "delete" => {
-                                el.remove_attribute(key);
-                            }
-                            "upsert" => {
-                                if let Some(value) = &val.val {
-                                    if let Err(e) = el.set_attribute(key, value.as_str()) {
-                                        errors.borrow_mut().push(format!("Unable to set attribute (edit.attrs.{}): {}", key, e));
+                    for (attr_idx, (key, ops)) in attrs.iter().enumerate() {
+                        for (op_idx, val) in ops.ops().iter().enumerate() {
+                            match val.op.as_str() {
+                                "delete" => {
+                                    el.remove_attribute(key);
+                                    edits.add(1, || format!("edit.attrs.{}:{}", key, val.op));
+                                }
+                                "upsert" => {
+                                    if let Some(value) = &val.val {
+                                        // Placeholders can only be resolved with data collected before this element (in document order).
+                                        // Data collected from this same element is not available yet, as edits are applied first
+                                        let value = Self::render_template(&shadow_data_cursor, value);
+                                        match el.set_attribute(key, &value) {
+                                            Ok(_) => edits.add(1, || format!("edit.attrs.{}:{}", key, val.op)),
+                                            Err(e) => errors.borrow_mut().push(format!("Unable to set attribute (edit.attrs.{}): {}", key, e)),
+                                        }
+                                    } else {
+                                        errors.borrow_mut().push(format!("Upsert requires val attribute (edit.attrs.{})", key));
                                     }
-                                } else {
-                                    errors.borrow_mut().push(format!("Upsert requires val attribute (edit.attrs.{})", key));
                                 }
-                            }
-                            "match_replace" => {
-                                if let Some(r#match) = &val.r#match {
-                                    if let Some(new_value) = &val.val {
-                                        let old_value = &el.get_attribute(key).unwrap_or("".to_owned());
-                                        if let Some(replacement) = Self::match_replace(
-                                            r#match,
-                                            old_value,
-                                            new_value,
-                                            Rc::clone(&errors),
-                                            Rc::clone(&cache)
-                                        ) {
-                                            if let Err(e) = el.set_attribute(key, &replacement) {
-                                                errors.borrow_mut().push(format!("Unable to set attribute via match_replace (edit.attrs.{}): {}", key, e));
+                                "match_replace" => {
+                                    let regex = regexes.attrs.get(attr_idx).and_then(|ops| ops.get(op_idx)).and_then(Option::as_ref);
+                                    if let Some(regex) = regex {
+                                        if let Some(new_value) = &val.val {
+                                            let old_value = &el.get_attribute(key).unwrap_or("".to_owned());
+                                            if let Some(replacement) = Self::match_replace(
+                                                regex,
+                                                old_value,
+                                                new_value,
+                                                val.limit.unwrap_or(0)
+                                            ) {
+                                                match el.set_attribute(key, &replacement) {
+                                                    Ok(_) => edits.add(1, || format!("edit.attrs.{}:{}", key, val.op)),
+                                                    Err(e) => errors.borrow_mut().push(format!("Unable to set attribute via match_replace (edit.attrs.{}): {}", key, e)),
+                                                }
                                             }
                                         }
                                     }
                                 }
-                            }
-                            other => {
-                                errors.borrow_mut().push(format!("Invalid operation (edit.attrs.{}): {}. Allowed values : delete/upsert/match_replace", key, other));
+                                "copy_from_data" => {
+                                    if let Some(path) = &val.path {
+                                        // Like placeholders, only data collected before this element is available
+                                        let value = shadow_data_cursor.borrow().lookup_string(path).or_else(|| val.val.clone());
+                                        if let Some(value) = value {
+                                            match el.set_attribute(key, &value) {
+                                                Ok(_) => edits.add(1, || format!("edit.attrs.{}:{}", key, val.op)),
+                                                Err(e) => errors.borrow_mut().push(format!("Unable to set attribute via copy_from_data (edit.attrs.{}): {}", key, e)),
+                                            }
+                                        }
+                                    } else {
+                                        errors.borrow_mut().push(format!("copy_from_data requires path (edit.attrs.{})", key));
+                                    }
+                                }
+                                "add_class" | "remove_class" | "replace_class" | "toggle_class" => {
+                                    apply_class_op(el, key, val, &mut edits, &errors);
+                                }
+                                other => {
+                                    errors.borrow_mut().push(format!("Invalid operation (edit.attrs.{}): {}. Allowed values : delete/upsert/match_replace/copy_from_data/add_class/remove_class/replace_class/toggle_class", key, other));
+                                }
                             }
                         }
                     }
                 }
+                if let Some(content) = &edit.content {
+                    if content.op == "set_html" {
+                        if let Some(value) = &content.val {
+                            el.set_inner_content(value, ContentType::Html);
+                            edits.add(1, || "edit.content:set_html".to_string());
+                        } else {
+                            errors.borrow_mut().push("set_html requires val content string (edit.content)".to_string());
+                        }
+                    }
+                }
             }
         }
 
-        match ShadowData::on_data_tag_open(
-            el,
-            selector_id,
-            Rc::clone(&json_def_c),
-            Rc::clone(&shadow_data_cursor)
-        ) {
+        // The second pass of the two_pass option rewrites the document with the data collected by the first one
+        let opened = if cache.borrow().pass.get().collects() {
+            ShadowData::on_data_tag_open(
+                el,
+                selector_id,
+                Rc::clone(&json_def_c),
+                Rc::clone(&shadow_data_cursor),
+                options.collision_policy,
+                options.max_array_len,
+                Rc::clone(&errors)
+            )
+        } else {
+            Ok(None)
+        };
+        match opened {
             Ok(maybe_data) => {
                 if let Some(data_item) = maybe_data {
+                    if let Some(required) = cache.borrow().required_states.get(&selector_id) {
+                        required.borrow_mut().items.push(Rc::clone(&data_item));
+                    }
+                    if let Some(tracer) = &tracer {
+                        // Keyed collection items are only attached to the data once their element closes
+                        if let Some(path) = Self::data_path(&shadow_data_cursor, &data_item) {
+                            tracer.data_entered(path);
+                        }
+                    }
                     // Register end tag action immediatly
+                    let mut leave_now = true; // Elements without end tag leave their data path once their values are collected
                     if el.can_have_content() {
                         if let Some(handlers) = el.end_tag_handlers() {
                             // None is returned if the end tag was not found
+                            let end_json_def = Rc::clone(&json_def_c);
+                            let end_shadow_data_cursor = Rc::clone(&shadow_data_cursor);
+                            let end_data_item = Rc::clone(&data_item);
+                            let end_item_sink = item_sink.clone();
+                            let end_tracer = tracer.clone();
+                            let end_errors = Rc::clone(&errors);
+                            let end_failed = Rc::clone(&cache.borrow().failed);
+                            let end_policy = options.error_policy;
+                            let end_collision_policy = options.collision_policy;
+                            // Still run once the handlers failed with fail_open, so that the data cursor leaves the paths it entered
                             handlers.push(Box::new(move |end| {
-                                ShadowData::on_data_tag_close(
-                                    end,
-                                    selector_id,
-                                    Rc::clone(&json_def_c),
-                                    Rc::clone(&shadow_data_cursor)
-                                )?;
-                                Ok(())
+                                if let Err(err) = Self::set_contents_defaults(&end_data_item, selector_id, &end_json_def.borrow(), end_collision_policy) {
+                                    end_errors.borrow_mut().push(err.to_string());
+                                }
+                                let result = (|| -> HandlerResult {
+                                    ShadowData::on_data_tag_close(
+                                        end,
+                                        selector_id,
+                                        Rc::clone(&end_json_def),
+                                        Rc::clone(&end_shadow_data_cursor)
+                                    )?;
+                                    if let Some(tracer) = &end_tracer {
+                                        Self::trace_data_left(tracer, &end_shadow_data_cursor);
+                                    }
+                                    if let Some(item_sink) = &end_item_sink {
+                                        Self::stream_item(&end_data_item, selector_id, &end_json_def, item_sink)?;
+                                    }
+                                    Ok(())
+                                })();
+                                Self::recover(result, end_policy, &end_failed, &end_errors)
                             }));
+                            leave_now = false;
                         }
                     }
                     let self_weak = Rc::downgrade(&data_item);
@@ -451,99 +1718,108 @@ impl<'h> ShadowApi<'h> {
                                 .iter()
                                 .map(|a| (a.name(), a.value()))
                                 .collect::<IndexMap<String, String>>();
-                            for (key, value) in values.iter() {
-                                match value {
+                            let mut collected: Vec<&String> = Vec::new(); // Keys of the values set, reported to the data listeners
+                            for (value_idx, (key, value)) in values.iter().enumerate() {
+                                let extract_regex = regexes.values.get(value_idx).and_then(Option::as_ref);
+                                match &value.source {
                                     ShadowJsonValueSource::Attribute(attr_name) => {
-                                        if attr_name.len() == 0 { continue; }
-                                        if let Some(attr_value) = attrs.get(attr_name) {
-                                            let mut new_data_m = data_item.borrow_mut();
-                                            new_data_m.set(key, ShadowData::wrap(ShadowData::new_string(
-                                                Some(selector_id),
-                                                Weak::clone(&self_weak),
-                                                attr_value.clone())
-                                            ));
+                                        if attr_name.is_empty() { continue; }
+                                        let new_data = match attrs.get(attr_name) {
+                                            Some(attr_value) => value.to_data(Some(selector_id), Weak::clone(&self_weak), attr_value, extract_regex),
+                                            None => match value.default_data(Some(selector_id), Weak::clone(&self_weak)) {
+                                                Some(new_data) => new_data,
+                                                None => continue,
+                                            },
+                                        };
+                                        let mut new_data_m = data_item.borrow_mut();
+                                        match new_data_m.set(key, ShadowData::wrap(new_data), options.collision_policy) {
+                                            Ok(_) => {
+                                                data_values += 1;
+                                                collected.push(key);
+                                            },
+                                            Err(err) => errors.borrow_mut().push(err.to_string()),
                                         }
                                     },
                                     ShadowJsonValueSource::Contents => {
                                         // This is handled by text_content_handler
                                     },
-                                    ShadowJsonValueSource::Value => {
-                                        // Fetch the current value from the different form elements
-                                        match el.tag_name().as_str() {
-                                            /* LOLHTML does not allow to operate on children, so to provide "select" shortcut we would need to create a new handler its children
-                                            * However whether the element is select or not is unknown before parsing the element itself, and it is too late to add
-                                            * a new handler at that point. So we cannot provide "select" shortcut. Instead use directly "select > option[selected=selected]"
-                                            "select" => {},
-                                            */
-                                            "input" => {
-                                                if let Some(input_type) = attrs.get("type") {
-                                                    let mut new_data_m = data_item.borrow_mut();
-                                                    match input_type.as_str() {
-                                                        "radio" => {
-                                                            if attrs.get("checked").is_some() {
-                                                                // For radio/checkbox, we only consider the box which is checked. Make sure def json contains all items
-                                                                new_data_m.set(key, ShadowData::wrap(
-                                                                    ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), attrs.get("value")
-                                                                    .unwrap_or(&String::from(""))
-                                                                    .to_owned())
-                                                                ));
-                                                            } else if new_data_m.get(key).is_none() {
-                                                                // Init
-                                                                new_data_m.set(key, ShadowData::wrap(
-                                                                    ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), "".to_string())
-                                                                ));
-                                                            }
-                                                        }
-                                                        "checkbox" => {
-                                                            if new_data_m.get(key).is_none() {
-                                                                new_data_m.set(key, ShadowData::wrap(
-                                                                    ShadowData::new_array(Some(selector_id), Weak::clone(&self_weak))
-                                                                ));
-                                                            }
-                                                            if attrs.get("checked").is_some() {
-                                                                // For radio/checkbox, we only consider the box which is checked. Make sure def json contains all items
-                                                                if let Some(arr) = new_data_m.get(key) {
-                                                                    let mut arr_borrowed = arr.borrow_mut();
-                                                                    arr_borrowed.push(ShadowData::wrap(
-                                                                        ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), attrs.get("value")
-                                                                        .unwrap_or(&String::from(""))
-                                                                        .to_owned())
-                                                                    ));
-                                                                }
-                                                            }
-                                                        }
-                                                        _ => {
-                                                            new_data_m.set(key, ShadowData::wrap(
-                                                                ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), attrs.get("value")
-                                                                .unwrap_or(&String::from("").to_string())
-                                                                .to_owned())
-                                                            ));
-                                                        }
-                                                    }
-                                                }
+                                    ShadowJsonValueSource::HasAttribute(attr_name) => {
+                                        if attr_name.is_empty() { continue; }
+                                        let mut new_data_m = data_item.borrow_mut();
+                                        match new_data_m.set(key, ShadowData::wrap(ShadowData::new_bool(
+                                            Some(selector_id),
+                                            Weak::clone(&self_weak),
+                                            attrs.contains_key(attr_name))
+                                        ), options.collision_policy) {
+                                            Ok(_) => {
+                                                data_values += 1;
+                                                collected.push(key);
                                             },
-                                            "option" => {
-                                                let mut new_data_m = data_item.borrow_mut();
-                                                new_data_m.set(key, ShadowData::wrap(
-                                                    ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), attrs.get("value")
-                                                    .unwrap_or(&String::from("")
-                                                    .to_string()).to_owned())
-                                                ));
-                                            },
-                                            _ => {
-                                                let mut errors_m = errors.borrow_mut();
-                                                errors_m.push(format!("Unimplemented input: '{}' (TODO)",el.tag_name().as_str()));
+                                            Err(err) => errors.borrow_mut().push(err.to_string()),
+                                        }
+                                    },
+                                    ShadowJsonValueSource::DataAttributes(key_case) => {
+                                        let data_attrs = ShadowData::wrap(ShadowData::new_object(Some(selector_id), Weak::clone(&self_weak)));
+                                        {
+                                            let data_attrs_weak = Rc::downgrade(&data_attrs);
+                                            let mut data_attrs_m = data_attrs.borrow_mut();
+                                            for (attr_name, attr_value) in attrs.iter() {
+                                                if let Some(name) = attr_name.strip_prefix("data-").filter(|name| !name.is_empty()) {
+                                                    let name = match key_case.unwrap_or_default() {
+                                                        ShadowJsonKeyCase::KebabCase => name.to_string(),
+                                                        ShadowJsonKeyCase::CamelCase => Self::camel_case(name),
+                                                    };
+                                                    data_attrs_m.set(&name, ShadowData::wrap(
+                                                        ShadowData::new_string(Some(selector_id), Weak::clone(&data_attrs_weak), attr_value.clone())
+                                                    ), options.collision_policy).unwrap_or(()); // Object built above
+                                                }
                                             }
                                         }
+                                        match data_item.borrow_mut().set(key, data_attrs, options.collision_policy) {
+                                            Ok(_) => {
+                                                data_values += 1;
+                                                collected.push(key);
+                                            },
+                                            Err(err) => errors.borrow_mut().push(err.to_string()),
+                                        }
+                                    },
+                                    ShadowJsonValueSource::Value => {
+                                        if collect_value(el, &attrs, &data_item, key, value, extract_regex, selector_id, data_def.label_selector.is_some(), &cache, &stats, options.collision_policy, &errors) {
+                                            data_values += 1;
+                                            collected.push(key);
+                                        }
                                     }
                                 }
                             }
+                            Self::mask_collected(cache.borrow().masker.as_deref(), &data_item, &collected);
+                            data_values -= Self::charge_data(options.max_data_bytes, &stats, &errors, &data_item, &mut collected);
+                            Self::notify_data(&data_listeners, &shadow_data_cursor, &data_item, &collected);
+                            if let Some(tracer) = &tracer {
+                                Self::trace_collected(tracer, &shadow_data_cursor, &data_item, &collected);
+                            }
                         } else {
                             let mut errors_m = errors.borrow_mut();
                             errors_m.push("Invalid def : 'data.values' is not an object".to_string());
                             return Ok(());
                         }
                     }
+                    if leave_now {
+                        if let Err(err) = Self::set_contents_defaults(&data_item, selector_id, &json_def_b, options.collision_policy) {
+                            errors.borrow_mut().push(err.to_string());
+                        }
+                        if let Err(err) = ShadowData::on_data_leave(selector_id, Rc::clone(&json_def_c), Rc::clone(&shadow_data_cursor)) {
+                            errors.borrow_mut().push(err.to_string());
+                        } else {
+                            if let Some(tracer) = &tracer {
+                                Self::trace_data_left(tracer, &shadow_data_cursor);
+                            }
+                            if let Some(item_sink) = &item_sink {
+                                if let Err(err) = Self::stream_item(&data_item, selector_id, &json_def_c, item_sink) {
+                                    errors.borrow_mut().push(err.to_string());
+                                }
+                            }
+                        }
+                    }
                 }
             },
             Err(err) => {
@@ -552,176 +1828,1671 @@ impl<'h> ShadowApi<'h> {
         }
         if delete {
             el.remove();
+            edits.add(1, || "delete".to_string());
+        } else if json_def_b.unwrap.unwrap_or(false) {
+            el.remove_and_keep_content();
+            edits.add(1, || "unwrap".to_string());
+        }
+        let mut stats_m = stats.borrow_mut();
+        stats_m.edits += edits.count;
+        if let (Some(tracer), Some(ops)) = (&tracer, edits.ops) {
+            tracer.edited(ops);
         }
+        stats_m.data_values += data_values;
 
         Ok(())
     }
 
-    // Applies a regex to old_value and replaces with new_value
-    // First access regex will be cached
-    // Return None if no matches or error computing the regex
-    fn match_replace<'a>(
-        r#match: &'a String,
-        old_value: &'a String,
-        new_value: &'a String,
-        errors: Rc<RefCell<Vec<String>>>,
-        cache: Rc<RefCell<HashMap<String, Box<dyn Any>>>>
-    ) -> Option<Cow<'a, str>> {
-        let mut cache_borrowed = cache.borrow_mut();
-        let regex_map: &mut HashMap<String, Regex> = cache_borrowed
-            .get_mut("regex_map")
-            .unwrap() // Instantiated during cache creation
-            .downcast_mut::<HashMap<String, Regex>>()
-            .unwrap(); // The type is known and fixed
-        let mut regex_not_computed = regex_map.get(r#match).is_none();
-        if regex_not_computed {
-            // Not cached. Attempt to compute regex and cache it
-            regex_not_computed = match Regex::new(r#match) {
-                Ok(r_computed) => {
-                    regex_map.insert(r#match.to_string(), r_computed);
+    // Whether the node edits the matched elements themselves, from their element handler
+    fn edits_element(json_def: &ShadowJson) -> bool {
+        let has_fragments = |html_tags: &Option<Vec<String>>| html_tags.as_ref().is_some_and(|tags| !tags.is_empty());
+        json_def.hide.unwrap_or(false)
+            || has_fragments(&json_def.insert_after)
+            || has_fragments(&json_def.insert_before)
+            || has_fragments(&json_def.append)
+            || has_fragments(&json_def.prepend)
+            || json_def.edit.is_some()
+            || json_def.delete.unwrap_or(false)
+            || json_def.wrap.is_some()
+            || json_def.unwrap.unwrap_or(false)
+            || json_def.empty.unwrap_or(false)
+    }
+
+    // Applies the sanitizer to all the HTML fragments injected by the node (sub nodes excluded)
+    fn sanitize_fragments(json_def: &mut ShadowJson, sanitizer: &ShadowSanitizer, errors: Rc<RefCell<Vec<String>>>) {
+        let selector = json_def.s.clone();
+        let sanitize = |field: &str, fragment: &mut String| {
+            if let Some(sanitized) = sanitizer.sanitize_reported(fragment, &errors, field, &selector) {
+                *fragment = sanitized;
+            }
+        };
+        for (field, fragments) in [
+            ("append", &mut json_def.append),
+            ("prepend", &mut json_def.prepend),
+            ("insert_before", &mut json_def.insert_before),
+            ("insert_after", &mut json_def.insert_after),
+        ] {
+            for fragment in fragments.iter_mut().flatten() {
+                sanitize(field, fragment);
+            }
+        }
+        if let Some(html) = json_def.region.as_mut().and_then(|region| region.html.as_mut()) {
+            sanitize("region.html", html);
+        }
+        if let Some(content) = json_def.edit.as_mut().and_then(|edit| edit.content.as_mut()) {
+            if content.op == "set_html" {
+                if let Some(val) = &mut content.val {
+                    sanitize("edit.content", val);
+                }
+            }
+        }
+        // An invalid wrap is left as is, to be reported when the node is parsed
+        let open_tag = json_def.wrap.as_deref().and_then(|wrap| Self::wrap_tags(wrap).ok()).map(|(open_tag, _)| open_tag);
+        if let Some(mut open_tag) = open_tag {
+            sanitize("wrap", &mut open_tag);
+            // The closing tag is derived from the opening tag : a removed wrapper element is not wrapping anymore
+            json_def.wrap = open_tag.trim_start().starts_with('<').then_some(open_tag);
+        }
+    }
+
+    // Reports an error unless the same one was already reported, for the limits which would otherwise report it for every element
+    fn report_once(errors: &RefCell<Vec<String>>, message: String) {
+        let mut errors_m = errors.borrow_mut();
+        if !errors_m.contains(&message) {
+            errors_m.push(message);
+        }
+    }
+
+    // Masks the values set at keys of container (see ShadowApi::set_masker), before they are counted and reported to the data listeners
+    fn mask_collected(masker: Option<&ShadowMasker>, container: &Rc<RefCell<ShadowData>>, keys: &[&String]) {
+        let Some(masker) = masker else {
+            return;
+        };
+        for key in keys {
+            if let Some(value) = container.borrow().get(key) {
+                masker.mask_data(&mut value.borrow_mut());
+            }
+        }
+    }
+
+    // Counts the values set at keys of container into the data_bytes stat, dropping those which do not fit within max_data_bytes.
+    // A value is counted with its key each time it is collected, so that text appended over several nodes counts more than once.
+    // Returns the amount of values dropped
+    fn charge_data(
+        max_data_bytes: Option<usize>,
+        stats: &RefCell<ShadowApiStats>,
+        errors: &RefCell<Vec<String>>,
+        container: &Rc<RefCell<ShadowData>>,
+        keys: &mut Vec<&String>
+    ) -> usize {
+        let before = keys.len();
+        keys.retain(|key| {
+            let bytes = key.len() + container.borrow().get(key).map(|value| value.borrow().to_string().len()).unwrap_or_default();
+            let mut stats_m = stats.borrow_mut();
+            match max_data_bytes {
+                Some(max) if stats_m.data_bytes + bytes > max => {
+                    if let Some(obj) = container.borrow_mut().as_object_mut() {
+                        obj.shift_remove(key.as_str());
+                    }
+                    Self::report_once(errors, format!("[max_data_bytes] The data exceeds {} bytes : the values which do not fit are dropped", max));
                     false
                 },
-                Err(e) => {
-                    errors.borrow_mut().push(format!("Invalid regex: {} | Error: {}", r#match, e));
+                _ => {
+                    stats_m.data_bytes += bytes;
                     true
                 },
             }
+        });
+        before - keys.len()
+    }
+
+    // Reports the values set at keys of container to the data listeners whose pattern matches their path
+    // The path is looked up from the root, as only the data handlers know where the container is
+    fn notify_data(
+        data_listeners: &DataListeners,
+        shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>,
+        container: &Rc<RefCell<ShadowData>>,
+        keys: &[&String]
+    ) {
+        if data_listeners.is_empty() || keys.is_empty() {
+            return;
         }
-        if !regex_not_computed { // If still not computed => There was an error during computation. In that case do nothing
-            let regex = regex_map.get(r#match).unwrap(); // We are certain it must exist now
-            let new_val = regex.replace_all(
-                old_value,
-                new_value
-            ); // If no match, replace returns the original old_value
-            if &new_val != old_value {
-                return Some(new_val)
+        let Some(container_path) = Self::data_path(shadow_data_cursor, container) else {
+            return; // Not attached to the data yet (keyed collection item)
+        };
+        for key in keys {
+            let Some(value) = container.borrow().get(key) else { continue };
+            let path = Self::join_path(&container_path, key);
+            for (pattern, callback) in data_listeners.iter() {
+                if Self::path_matches(pattern, &path) {
+                    callback(&path, &value.borrow());
+                }
             }
         }
+    }
+
+    // Dot separated path of container within the data, "" for the root. None while it is not attached to the data (keyed collection item)
+    fn data_path(shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>, container: &Rc<RefCell<ShadowData>>) -> Option<String> {
+        let root = Rc::clone(&shadow_data_cursor.borrow().root);
+        if Rc::ptr_eq(&root, container) {
+            return Some(String::new());
+        }
+        let path = root.borrow().path_of(container);
+        path
+    }
+
+    fn join_path(container_path: &str, key: &str) -> String {
+        if container_path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", container_path, key)
+        }
+    }
+
+    fn trace_collected(tracer: &NodeTracer, shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>, container: &Rc<RefCell<ShadowData>>, keys: &[&String]) {
+        if let Some(container_path) = Self::data_path(shadow_data_cursor, container) {
+            for key in keys {
+                tracer.collected(Self::join_path(&container_path, key));
+            }
+        }
+    }
+
+    fn trace_data_left(tracer: &NodeTracer, shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>) {
+        let current = Rc::clone(&shadow_data_cursor.borrow().shadow_data);
+        if let Some(path) = Self::data_path(shadow_data_cursor, &current) {
+            tracer.data_left(path);
+        }
+    }
+
+    // Dot separated glob : `*` matches a single segment, `**` any amount of segments (none included)
+    fn path_matches(pattern: &str, path: &str) -> bool {
+        fn matches(pattern: &[&str], path: &[&str]) -> bool {
+            match pattern.split_first() {
+                None => path.is_empty(),
+                Some((&"**", rest)) => (0..=path.len()).any(|skip| matches(rest, &path[skip..])),
+                Some((segment, rest)) => match path.split_first() {
+                    Some((first, path_rest)) => (*segment == "*" || segment == first) && matches(rest, path_rest),
+                    None => false,
+                },
+            }
+        }
+        matches(&pattern.split('.').collect::<Vec<_>>(), &path.split('.').collect::<Vec<_>>())
+    }
+
+    // stream_items option : the array item is complete, it is passed to the item sink as a NDJSON line and removed from its array
+    // Only the items of outermost arrays are streamed, the items of nested arrays being part of their ancestor item
+    fn stream_item(
+        item: &Rc<RefCell<ShadowData>>,
+        selector_id: usize,
+        json_def: &Rc<RefCell<ShadowJson>>,
+        item_sink: &ItemSink
+    ) -> Result<(), ShadowError> {
+        let json_def_b = json_def.borrow();
+        let Some(data_def) = json_def_b.data.as_ref() else {
+            return Ok(());
+        };
+        let is_array_item = data_def.path.as_ref().is_some_and(|path| path.ends_with('.'));
+        if !is_array_item || data_def.key_from.is_some() || data_def.dedupe_by.is_some() || data_def.sort_by.is_some() || item.borrow().id != Some(selector_id) {
+            return Ok(());
+        }
+        let Some(array) = item.borrow().parent.upgrade().filter(|parent| parent.borrow().is_array()) else {
+            return Ok(());
+        };
+        let mut ancestor = array.borrow().parent.upgrade();
+        while let Some(current) = ancestor {
+            let parent = current.borrow().parent.upgrade();
+            if parent.as_ref().is_some_and(|parent| parent.borrow().is_array()) {
+                return Ok(()); // Nested in an array item
+            }
+            ancestor = parent;
+        }
+        let mut line = Vec::new();
+        item.borrow().to_json_writer(&mut line)?;
+        line.push(b'\n');
+        if let Some(items) = array.borrow_mut().as_array_mut() {
+            items.retain(|other| !Rc::ptr_eq(other, item));
+        }
+        item_sink(&line);
+        Ok(())
+    }
+
+    // Selectors precompiled by CompiledShadowJson are reused (cloning a parsed selector is cheap), the others are parsed and added to the cache
+    fn cached_selector(cache: &Rc<RefCell<ShadowApiCache>>, selector: &str) -> Result<Selector, SelectorError> {
+        let selectors = &mut cache.borrow_mut().selectors;
+        if let Some(selector_obj) = selectors.get(selector) {
+            return Ok(selector_obj.clone());
+        }
+        let selector_obj = Selector::from_str(selector)?;
+        selectors.insert(selector.to_string(), selector_obj.clone());
+        Ok(selector_obj)
+    }
+
+    // Registers the handlers rewriting the URLs of the element matched by the selector and of all its descendants
+    fn url_content_handlers(
+        rewrite_urls: &ShadowJsonRewriteUrls,
+        current_selector: &str,
+        errors: Rc<RefCell<Vec<String>>>,
+        ech: &mut KeyedHandlers,
+        cache: Rc<RefCell<ShadowApiCache>>,
+        stats: Rc<RefCell<ShadowApiStats>>
+    ) {
+        let rewriter = match ShadowUrlRewriter::from_json(rewrite_urls) {
+            Ok(rewriter) => Rc::new(rewriter),
+            Err(e) => {
+                errors.borrow_mut().push(e.to_string());
+                return;
+            },
+        };
+        let attrs = Rc::new(rewrite_urls.attrs.clone().unwrap_or_else(|| {
+            ["href", "src", "srcset", "action", "style"].iter().map(|a| a.to_string()).collect()
+        }));
+        for selector in [current_selector.to_string(), format!("{} *", current_selector)] {
+            let selector_obj = match Self::cached_selector(&cache, &selector) {
+                Ok(s) => s,
+                Err(e) => {
+                    errors.borrow_mut().push(format!("Selector {} is invalid : {}", &selector, e));
+                    return;
+                },
+            };
+            let eh_rewriter = Rc::clone(&rewriter);
+            let eh_attrs = Rc::clone(&attrs);
+            let eh_errors = Rc::clone(&errors);
+            let eh_stats = Rc::clone(&stats);
+            ech.push((
+                selector.to_string(),
+                Cow::Owned(selector_obj),
+                ElementContentHandlers::default().element(move |el| {
+                    for attr in eh_attrs.iter() {
+                        let rewritten = el.get_attribute(attr).and_then(|value| eh_rewriter.rewrite_attribute(attr, &value));
+                        if let Some(rewritten) = rewritten {
+                            match el.set_attribute(attr, &rewritten) {
+                                Ok(_) => eh_stats.borrow_mut().edits += 1,
+                                Err(e) => eh_errors.borrow_mut().push(format!("Unable to set attribute (rewrite_urls.{}): {}", attr, e)),
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            ));
+            Self::record_plan(&cache, &selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::RewriteUrls, false);
+        }
+    }
+
+    // Registers the handlers buffering and rewriting the whole contents of the <style> and <script> elements matched by the selector
+    fn inline_content_handlers(
+        inline: &ShadowJsonInline,
+        current_selector: &str,
+        selector_obj: &Selector,
+        errors: Rc<RefCell<Vec<String>>>,
+        ech: &mut KeyedHandlers,
+        cache: Rc<RefCell<ShadowApiCache>>,
+        stats: Rc<RefCell<ShadowApiStats>>
+    ) {
+        // Validating the definition once, rather than for each element
+        let rules: Rc<Vec<(Regex, String, usize)>> = Rc::new(inline.rules.iter().flatten()
+            .filter_map(|rule| match (rule.op.as_str(), &rule.r#match, &rule.val) {
+                ("match_replace", Some(r#match), Some(val)) => {
+                    Self::compile_regex(r#match, &cache, &errors).map(|regex| (regex, val.clone(), rule.limit.unwrap_or(0)))
+                },
+                ("match_replace", _, _) => {
+                    errors.borrow_mut().push("match_replace requires match and val (inline.rules)".to_string());
+                    None
+                },
+                (other, _, _) => {
+                    errors.borrow_mut().push(format!("Invalid operation (inline.rules): {}. Allowed values : match_replace", other));
+                    None
+                },
+            })
+            .collect());
+        let url_rewriter = inline.rewrite_urls.as_ref().and_then(|def| match ShadowUrlRewriter::from_json(def) {
+            Ok(rewriter) => Some(rewriter),
+            Err(e) => {
+                errors.borrow_mut().push(e.to_string());
+                None
+            },
+        });
+        let mut vars_js = String::new();
+        for (name, value) in inline.vars.iter().flatten() {
+            let valid_name = !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+            if !valid_name {
+                errors.borrow_mut().push(format!("Invalid JS variable name (inline.vars): {}", name));
+                continue;
+            }
+            // The closing script tag must not appear inside the script contents
+            vars_js.push_str(&format!("var {} = {};\n", name, value.to_string().replace("</", "<\\/")));
+        }
+        let inline_rewriter = cache.borrow().inline_rewriter.clone();
+
+        let current_tag: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None)); // Style and script elements cannot be nested
+        let buffer = Rc::new(RefCell::new(String::new()));
+
+        let eh_current_tag = Rc::clone(&current_tag);
+        let eh_stats = Rc::clone(&stats);
+        let th_current_tag = current_tag;
+        let th_stats = stats;
+        ech.push((
+            current_selector.to_string(),
+            Cow::Owned(selector_obj.clone()),
+            ElementContentHandlers::default()
+                .element(move |el| {
+                    let tag = el.tag_name();
+                    let is_js = el.get_attribute("type")
+                        .map(|t| t.is_empty() || t.contains("javascript") || t == "module")
+                        .unwrap_or(true);
+                    *eh_current_tag.borrow_mut() = match tag.as_str() {
+                        "style" | "script" => Some(tag.clone()),
+                        _ => None,
+                    };
+                    if tag == "script" && is_js && !vars_js.is_empty() {
+                        el.prepend(&vars_js, ContentType::Html);
+                        eh_stats.borrow_mut().edits += 1;
+                    }
+                    Ok(())
+                })
+                .text(move |t| {
+                    let current_tag_b = th_current_tag.borrow();
+                    let Some(tag) = current_tag_b.as_ref() else {
+                        return Ok(());
+                    };
+                    let mut buffer_b = buffer.borrow_mut();
+                    buffer_b.push_str(t.as_str());
+                    t.remove();
+                    if t.last_in_text_node() {
+                        let original = buffer_b.clone();
+                        for (regex, val, limit) in rules.iter() {
+                            if let Some(replacement) = Self::match_replace(regex, &buffer_b, val, *limit) {
+                                *buffer_b = replacement.into_owned();
+                            }
+                        }
+                        if tag == "style" {
+                            if let Some(rewritten) = url_rewriter.as_ref().and_then(|r| r.rewrite_css(&buffer_b)) {
+                                *buffer_b = rewritten;
+                            }
+                        }
+                        if let Some(inline_rewriter) = &inline_rewriter {
+                            inline_rewriter(tag, &mut buffer_b);
+                        }
+                        if *buffer_b != original {
+                            th_stats.borrow_mut().edits += 1;
+                        }
+                        // Raw text : the contents must not be escaped
+                        t.replace(&buffer_b, ContentType::Html);
+                        buffer_b.clear();
+                    }
+                    Ok(())
+                })
+        ));
+        Self::record_plan(&cache, current_selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Inline, false);
+    }
+
+    // Registers the handlers collecting the text of the labels associated with checkbox/radio inputs (see ShadowJsonData.label_selector)
+    // The label selector is relative to the parent node, as is the node's own selector
+    fn label_content_handlers(
+        label_selector: &str,
+        selector_id: usize,
+        parent_selectors: &[String],
+        errors: Rc<RefCell<Vec<String>>>,
+        ech: &mut KeyedHandlers,
+        cache: Rc<RefCell<ShadowApiCache>>
+    ) {
+        let mut full_selector = parent_selectors.concat();
+        if !full_selector.is_empty() {
+            full_selector.push(' ');
+        }
+        full_selector.push_str(label_selector);
+        let selector_obj = match Self::cached_selector(&cache, &full_selector) {
+            Ok(s) => s,
+            Err(e) => {
+                errors.borrow_mut().push(format!("Selector {} is invalid : {}", &full_selector, e));
+                return;
+            },
+        };
+
+        let state = Rc::new(RefCell::new(LabelState::default()));
+        cache.borrow_mut().label_states.insert(selector_id, Rc::clone(&state));
+
+        let eh_state = Rc::clone(&state);
+        let th_state = state;
+        ech.push((
+            full_selector.to_string(),
+            Cow::Owned(selector_obj),
+            ElementContentHandlers::default()
+                .element(move |el| {
+                    eh_state.borrow_mut().open_label = Some((el.get_attribute("for"), String::new()));
+                    let state = Rc::clone(&eh_state);
+                    if let Some(handlers) = el.end_tag_handlers() {
+                        handlers.push(Box::new(move |_end| {
+                            state.borrow_mut().close_label();
+                            Ok(())
+                        }));
+                    }
+                    Ok(())
+                })
+                .text(move |t| {
+                    if let Some((_, text)) = th_state.borrow_mut().open_label.as_mut() {
+                        text.push_str(t.as_str());
+                    }
+                    Ok(())
+                })
+        ));
+        Self::record_plan(&cache, &full_selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Label, true);
+    }
+
+    // Registers the handlers of a region (see ShadowJsonRegion) : the marker comments found inside the elements matched by the node toggle the state,
+    // and the text, comments and elements streamed in between are removed or collected according to the operation
+    #[allow(clippy::too_many_arguments)]
+    fn region_content_handlers(
+        region: &ShadowJsonRegion,
+        node_selector: &str,
+        selector_id: usize,
+        errors: Rc<RefCell<Vec<String>>>,
+        ech: &mut KeyedHandlers,
+        cache: Rc<RefCell<ShadowApiCache>>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        stats: Rc<RefCell<ShadowApiStats>>
+    ) {
+        let elements_selector = format!("{} *", node_selector);
+        let (node_selector_obj, elements_selector_obj) = match (Self::cached_selector(&cache, node_selector), Self::cached_selector(&cache, &elements_selector)) {
+            (Ok(node_selector_obj), Ok(elements_selector_obj)) => (node_selector_obj, elements_selector_obj),
+            (Err(e), _) | (_, Err(e)) => {
+                errors.borrow_mut().push(format!("Selector {} is invalid : {}", &elements_selector, e));
+                return;
+            },
+        };
+        let state = Rc::new(RefCell::new(RegionState::default()));
+        cache.borrow_mut().region_states.insert(selector_id, Rc::clone(&state));
+        let removes = region.op != ShadowJsonRegionOp::Extract;
+
+        let (start, end, op) = (region.start.trim().to_string(), region.end.trim().to_string(), region.op);
+        let html = region.html.clone().unwrap_or_default();
+        let key = region.key.clone().unwrap_or_else(|| "region".to_string());
+        let policy = cache.borrow().collision_policy;
+        let (ch_state, th_state) = (Rc::clone(&state), Rc::clone(&state));
+        ech.push((
+            node_selector.to_string(),
+            Cow::Owned(node_selector_obj),
+            ElementContentHandlers::default()
+                .comments(move |c| {
+                    let mut state = ch_state.borrow_mut();
+                    let text = c.text();
+                    if !state.inside && text.trim() == start {
+                        state.inside = true;
+                        state.text.clear();
+                        if op == ShadowJsonRegionOp::Replace {
+                            c.after(&html, ContentType::Html);
+                        }
+                    } else if state.inside && text.trim() == end {
+                        state.inside = false;
+                        if op == ShadowJsonRegionOp::Extract {
+                            let target = Rc::clone(&shadow_data_cursor.borrow().shadow_data);
+                            if target.borrow().is_object() {
+                                let item = ShadowData::wrap(ShadowData::new_string(Some(selector_id), Rc::downgrade(&target), std::mem::take(&mut state.text)));
+                                target.borrow_mut().set(&key, item, policy)?;
+                                stats.borrow_mut().data_values += 1;
+                            }
+                        }
+                    } else if state.inside && removes {
+                        c.remove();
+                    }
+                    Ok(())
+                })
+                .text(move |t| {
+                    let mut state = th_state.borrow_mut();
+                    if state.inside {
+                        if removes {
+                            t.remove();
+                        } else {
+                            state.text.push_str(t.as_str());
+                        }
+                    }
+                    Ok(())
+                })
+        ));
+        Self::record_plan(&cache, node_selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Region, false);
+
+        if removes {
+            let eh_state = state;
+            ech.push((
+                elements_selector.to_string(),
+                Cow::Owned(elements_selector_obj),
+                ElementContentHandlers::default().element(move |el| {
+                    if eh_state.borrow().inside {
+                        el.remove();
+                    }
+                    Ok(())
+                })
+            ));
+            Self::record_plan(&cache, &elements_selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Region, false);
+        }
+    }
+
+    // Whether the last compound of a selector targets <select> elements ("form select", "select[name=size]"...)
+    fn targets_select(selector: &str) -> bool {
+        let last = selector.rsplit(|c: char| c.is_whitespace() || c == '>' || c == '+' || c == '~').next().unwrap_or_default();
+        let last = last.to_ascii_lowercase();
+        last.strip_prefix("select")
+            .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    }
+
+    // Registers the handlers collecting the selected options of the <select> elements matched by a node collecting their Value
+    // The value of an option without value attribute is its text
+    fn select_content_handlers(
+        select_selector: &str,
+        selector_id: usize,
+        errors: Rc<RefCell<Vec<String>>>,
+        ech: &mut KeyedHandlers,
+        cache: Rc<RefCell<ShadowApiCache>>,
+        stats: Rc<RefCell<ShadowApiStats>>
+    ) {
+        let full_selector = format!("{} option[selected]", select_selector);
+        let selector_obj = match Self::cached_selector(&cache, &full_selector) {
+            Ok(s) => s,
+            Err(e) => {
+                errors.borrow_mut().push(format!("Selector {} is invalid : {}", &full_selector, e));
+                return;
+            },
+        };
+        let state = Rc::new(RefCell::new(SelectState::default()));
+        cache.borrow_mut().select_states.insert(selector_id, Rc::clone(&state));
+
+        let eh_state = Rc::clone(&state);
+        let th_state = state;
+        ech.push((
+            full_selector.to_string(),
+            Cow::Owned(selector_obj),
+            ElementContentHandlers::default()
+                .element(move |el| {
+                    let mut state = eh_state.borrow_mut();
+                    let mut selected = state.close_option(selector_id); // Previous option, if its end tag was omitted
+                    match el.get_attribute("value") {
+                        Some(value) => selected += state.select(value, selector_id),
+                        None if state.target.is_some() => {
+                            state.open_option = Some(String::new());
+                            let end_state = Rc::clone(&eh_state);
+                            let end_stats = Rc::clone(&stats);
+                            if let Some(handlers) = el.end_tag_handlers() {
+                                handlers.push(Box::new(move |_end| {
+                                    end_stats.borrow_mut().data_values += end_state.borrow_mut().close_option(selector_id);
+                                    Ok(())
+                                }));
+                            }
+                        },
+                        None => {},
+                    }
+                    stats.borrow_mut().data_values += selected;
+                    Ok(())
+                })
+                .text(move |t| {
+                    if let Some(text) = th_state.borrow_mut().open_option.as_mut() {
+                        text.push_str(t.as_str());
+                    }
+                    Ok(())
+                })
+        ));
+        Self::record_plan(&cache, &full_selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Select, true);
+    }
+
+    // Registers the handlers serializing the fields of the forms matched by a node defining form, following the browser rules :
+    // disabled fields, fields without name and buttons are left out, checkboxes and radios are only submitted when checked ("on" by default),
+    // a single select without selected option submits its first option
+    #[allow(clippy::too_many_arguments)]
+    fn form_content_handlers(
+        form_selector: &str,
+        selector_id: usize,
+        errors: Rc<RefCell<Vec<String>>>,
+        ech: &mut KeyedHandlers,
+        cache: Rc<RefCell<ShadowApiCache>>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        stats: Rc<RefCell<ShadowApiStats>>
+    ) {
+        let mut selectors = Vec::new();
+        let mut selector_names = Vec::new();
+        for suffix in ["", " input[name]", " select[name]", " select[name] option", " textarea[name]"] {
+            let full_selector = format!("{}{}", form_selector, suffix);
+            match Self::cached_selector(&cache, &full_selector) {
+                Ok(selector_obj) => {
+                    selectors.push(Cow::Owned(selector_obj));
+                    selector_names.push(full_selector);
+                },
+                Err(e) => {
+                    errors.borrow_mut().push(format!("Selector {} is invalid : {}", &full_selector, e));
+                    return;
+                },
+            }
+        }
+        let state = Rc::new(RefCell::new(FormState { selector_id, policy: cache.borrow().collision_policy, ..FormState::default() }));
+        let mut selectors = selectors.into_iter();
+
+        let (form_state, form_stats) = (Rc::clone(&state), Rc::clone(&stats));
+        ech.push((
+            selector_names[0].to_string(),
+            selectors.next().unwrap(),
+            ElementContentHandlers::default().element(move |el| {
+                form_state.borrow_mut().target = Some(Rc::clone(&shadow_data_cursor.borrow().shadow_data));
+                let (end_state, end_stats) = (Rc::clone(&form_state), Rc::clone(&form_stats));
+                if let Some(handlers) = el.end_tag_handlers() {
+                    handlers.push(Box::new(move |_end| {
+                        let mut state = end_state.borrow_mut();
+                        end_stats.borrow_mut().data_values += state.close_select()?;
+                        state.target = None;
+                        Ok(())
+                    }));
+                }
+                Ok(())
+            })
+        ));
+        Self::record_plan(&cache, &selector_names[0], &ech[ech.len() - 1].2, ShadowPlanPurpose::Form, true);
+
+        let (input_state, input_stats) = (Rc::clone(&state), Rc::clone(&stats));
+        ech.push((
+            selector_names[1].to_string(),
+            selectors.next().unwrap(),
+            ElementContentHandlers::default().element(move |el| {
+                let input_type = el.get_attribute("type").unwrap_or_default().to_ascii_lowercase();
+                let checkable = input_type == "checkbox" || input_type == "radio";
+                if el.has_attribute("disabled")
+                    || ["submit", "button", "reset", "image", "file"].contains(&input_type.as_str())
+                    || (checkable && !el.has_attribute("checked"))
+                {
+                    return Ok(());
+                }
+                let name = el.get_attribute("name").unwrap_or_default();
+                let value = el.get_attribute("value").unwrap_or_else(|| if checkable { "on".to_string() } else { String::new() });
+                input_stats.borrow_mut().data_values += input_state.borrow().add(&name, value)?;
+                Ok(())
+            })
+        ));
+        Self::record_plan(&cache, &selector_names[1], &ech[ech.len() - 1].2, ShadowPlanPurpose::Form, false);
+
+        let (select_state, select_stats) = (Rc::clone(&state), Rc::clone(&stats));
+        ech.push((
+            selector_names[2].to_string(),
+            selectors.next().unwrap(),
+            ElementContentHandlers::default().element(move |el| {
+                if el.has_attribute("disabled") {
+                    return Ok(());
+                }
+                select_state.borrow_mut().select = Some(FormSelect {
+                    name: el.get_attribute("name").unwrap_or_default(),
+                    multiple: el.has_attribute("multiple"),
+                    selected: false,
+                    first: None,
+                });
+                let (end_state, end_stats) = (Rc::clone(&select_state), Rc::clone(&select_stats));
+                if let Some(handlers) = el.end_tag_handlers() {
+                    handlers.push(Box::new(move |_end| {
+                        end_stats.borrow_mut().data_values += end_state.borrow_mut().close_select()?;
+                        Ok(())
+                    }));
+                }
+                Ok(())
+            })
+        ));
+        Self::record_plan(&cache, &selector_names[2], &ech[ech.len() - 1].2, ShadowPlanPurpose::Form, true);
+
+        let (option_state, option_stats) = (Rc::clone(&state), Rc::clone(&stats));
+        let option_text_state = Rc::clone(&state);
+        ech.push((
+            selector_names[3].to_string(),
+            selectors.next().unwrap(),
+            ElementContentHandlers::default()
+                .element(move |el| {
+                    let mut state = option_state.borrow_mut();
+                    let mut added = state.close_text()?; // Previous option, if its end tag was omitted
+                    let selected = el.has_attribute("selected");
+                    if el.has_attribute("disabled") {
+                        // Not submitted, nor used as the default value
+                    } else if let Some(value) = el.get_attribute("value") {
+                        added += state.option(value, selected)?;
+                    } else if state.select.is_some() {
+                        state.open_text = Some((FormText::Option(selected), String::new()));
+                        let (end_state, end_stats) = (Rc::clone(&option_state), Rc::clone(&option_stats));
+                        if let Some(handlers) = el.end_tag_handlers() {
+                            handlers.push(Box::new(move |_end| {
+                                end_stats.borrow_mut().data_values += end_state.borrow_mut().close_text()?;
+                                Ok(())
+                            }));
+                        }
+                    }
+                    option_stats.borrow_mut().data_values += added;
+                    Ok(())
+                })
+                .text(move |t| {
+                    if let Some((_, text)) = option_text_state.borrow_mut().open_text.as_mut() {
+                        text.push_str(t.as_str());
+                    }
+                    Ok(())
+                })
+        ));
+        Self::record_plan(&cache, &selector_names[3], &ech[ech.len() - 1].2, ShadowPlanPurpose::Form, true);
+
+        let (textarea_state, textarea_stats) = (Rc::clone(&state), stats);
+        let textarea_text_state = state;
+        ech.push((
+            selector_names[4].to_string(),
+            selectors.next().unwrap(),
+            ElementContentHandlers::default()
+                .element(move |el| {
+                    if el.has_attribute("disabled") {
+                        return Ok(());
+                    }
+                    textarea_state.borrow_mut().open_text = Some((FormText::Textarea(el.get_attribute("name").unwrap_or_default()), String::new()));
+                    let (end_state, end_stats) = (Rc::clone(&textarea_state), Rc::clone(&textarea_stats));
+                    if let Some(handlers) = el.end_tag_handlers() {
+                        handlers.push(Box::new(move |_end| {
+                            end_stats.borrow_mut().data_values += end_state.borrow_mut().close_text()?;
+                            Ok(())
+                        }));
+                    }
+                    Ok(())
+                })
+                .text(move |t| {
+                    if let Some((_, text)) = textarea_text_state.borrow_mut().open_text.as_mut() {
+                        text.push_str(t.as_str());
+                    }
+                    Ok(())
+                })
+        ));
+        Self::record_plan(&cache, &selector_names[4], &ech[ech.len() - 1].2, ShadowPlanPurpose::Form, true);
+    }
+
+    // Collects a checkbox/radio input as a {"value", "label", "checked"} object appended to the array at key. Returns whether it was stored,
+    // the collision policy possibly keeping another value at key. The label is filled once found by the handlers registered in label_content_handlers
+    fn labelled_input(
+        data_item: &Rc<RefCell<ShadowData>>,
+        key: &str,
+        selector_id: usize,
+        attrs: &IndexMap<String, String>,
+        cache: Rc<RefCell<ShadowApiCache>>,
+        policy: ShadowDataCollisionPolicy
+    ) -> Result<bool, ShadowError> {
+        let array = {
+            let mut data_m = data_item.borrow_mut();
+            match data_m.get(key) {
+                Some(existing) if existing.borrow().is_array() => existing,
+                _ => {
+                    let new_array = ShadowData::wrap(ShadowData::new_array(Some(selector_id), Rc::downgrade(data_item)));
+                    data_m.set(key, Rc::clone(&new_array), policy)?;
+                    if !data_m.get(key).is_some_and(|stored| Rc::ptr_eq(&stored, &new_array)) {
+                        return Ok(false);
+                    }
+                    new_array
+                }
+            }
+        };
+        let item = ShadowData::wrap(ShadowData::new_object(Some(selector_id), Rc::downgrade(&array)));
+        {
+            let item_weak = Rc::downgrade(&item);
+            let mut item_m = item.borrow_mut();
+            let value = attrs.get("value").cloned().unwrap_or_default();
+            item_m.set("value", ShadowData::wrap(ShadowData::new_string(Some(selector_id), Weak::clone(&item_weak), value)), policy)?;
+            item_m.set("label", ShadowData::wrap(ShadowData::new_string(Some(selector_id), Weak::clone(&item_weak), String::new())), policy)?;
+            item_m.set("checked", ShadowData::wrap(ShadowData::new_bool(Some(selector_id), item_weak, attrs.contains_key("checked"))), policy)?;
+        }
+        array.borrow_mut().push(Rc::clone(&item))?;
+
+        if let Some(state) = cache.borrow().label_states.get(&selector_id) {
+            state.borrow_mut().add_input(attrs.get("id"), item);
+        }
+        Ok(true)
+    }
+
+    /// Adds the nonce attribute to all the <script> opening tags of the html which do not define one yet
+    pub fn add_script_nonce(html: &str, nonce: &str) -> String {
+        let nonce_attr = format!(" nonce=\"{}\"", nonce.replace('&', "&amp;").replace('"', "&quot;"));
+        let lowercase = html.to_ascii_lowercase();
+        let mut result = String::with_capacity(html.len() + nonce_attr.len());
+        let mut last = 0;
+        for (start, _) in lowercase.match_indices("<script") {
+            let name_end = start + "<script".len();
+            let is_script_tag = lowercase[name_end..].starts_with(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/');
+            if !is_script_tag {
+                continue;
+            }
+            let tag_end = lowercase[name_end..].find('>').map(|i| name_end + i).unwrap_or(lowercase.len());
+            if lowercase[name_end..tag_end].contains("nonce=") {
+                continue;
+            }
+            result.push_str(&html[last..name_end]);
+            result.push_str(&nonce_attr);
+            last = name_end;
+        }
+        result.push_str(&html[last..]);
+        result
+    }
+
+    // Replaces {{path}} placeholders with the data collected so far. The template is borrowed as-is if it has no placeholder
+    fn render_template<'t>(shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>, template: &'t str) -> Cow<'t, str> {
+        if template.contains("{{") {
+            Cow::Owned(shadow_data_cursor.borrow().render(template))
+        } else {
+            Cow::Borrowed(template)
+        }
+    }
+
+    // Whether an ancestor rule skipped the element currently being processed
+    fn is_suppressed(match_scope: &MatchScope) -> bool {
+        match_scope.iter().any(|match_state| match_state.borrow().suppressed > 0)
+    }
+
+    // Counts a match of a rule, and returns whether the rule applies to it according to its restrictions (limit/skip/nth)
+    // When it does not, the rule and its sub rules are suppressed until the element closes
+    // The nth position is counted within the current element of the parent rule, starting over each time the parent matches
+    fn count_match(
+        el: &mut Element,
+        match_state: &Rc<RefCell<ShadowMatchState>>,
+        parent_state: Option<&Rc<RefCell<ShadowMatchState>>>,
+        restriction: &MatchRestriction
+    ) -> bool {
+        let context = parent_state.map(|parent_state| parent_state.borrow().count).unwrap_or(0);
+        let (idx, position) = {
+            let mut match_state_m = match_state.borrow_mut();
+            if match_state_m.context != context {
+                match_state_m.context = context;
+                match_state_m.context_count = 0;
+            }
+            match_state_m.count += 1;
+            match_state_m.context_count += 1;
+            (match_state_m.count - 1, match_state_m.context_count)
+        };
+        let applies = idx >= restriction.skip
+            && restriction.limit.map(|limit| idx < restriction.skip + limit).unwrap_or(true)
+            && restriction.nth.map(|nth| nth.matches(position)).unwrap_or(true);
+        if !applies {
+            Self::suppress(el, match_state);
+        }
+        applies
+    }
+
+    // Suppresses the rule and its sub rules until the element closes
+    fn suppress(el: &mut Element, match_state: &Rc<RefCell<ShadowMatchState>>) {
+        if let Some(handlers) = el.end_tag_handlers() {
+            match_state.borrow_mut().suppressed += 1;
+            let match_state = Rc::clone(match_state);
+            handlers.push(Box::new(move |_end| {
+                match_state.borrow_mut().suppressed -= 1;
+                Ok(())
+            }));
+        }
+    }
+
+    // Returns the SiblingTracker of the handlers, registering its handler before all the others the first time
+    fn sibling_tracker(cache: &Rc<RefCell<ShadowApiCache>>, ech: &mut KeyedHandlers) -> Rc<RefCell<SiblingTracker>> {
+        if let Some(tracker) = &cache.borrow().sibling_tracker {
+            return Rc::clone(tracker);
+        }
+        let tracker = Rc::new(RefCell::new(SiblingTracker { serial: 0, open: vec![(0, 0)], current: (0, 0) }));
+        cache.borrow_mut().sibling_tracker = Some(Rc::clone(&tracker));
+        let handler_tracker = Rc::clone(&tracker);
+        ech.insert(0, (
+            "*".to_string(),
+            Cow::Owned("*".parse().unwrap()),
+            ElementContentHandlers::default().element(move |el| {
+                let mut tracker = handler_tracker.borrow_mut();
+                tracker.serial += 1;
+                let serial = tracker.serial;
+                if let Some((parent, children)) = tracker.open.last_mut() {
+                    *children += 1;
+                    let current = (*parent, *children);
+                    tracker.current = current;
+                }
+                if let Some(handlers) = el.end_tag_handlers() {
+                    tracker.open.push((serial, 0));
+                    let end_tracker = Rc::clone(&handler_tracker);
+                    handlers.push(Box::new(move |_end| {
+                        end_tracker.borrow_mut().open.pop();
+                        Ok(())
+                    }));
+                }
+                Ok(())
+            })
+        ));
+        Self::record_plan(cache, "*", &ech[0].2, ShadowPlanPurpose::Sibling, true);
+        tracker
+    }
+
+    // Builds the opening and closing tags used by the "wrap" operation
+    // Accepts either a full opening tag ("<div class=\"a\">") or a bare tag name ("div")
+    // Opening and closing tags of a wrap field, which is either a tag name ("div") or a single opening tag ("<div class=\"a\">").
+    // Contents, closing or self-closing tags and void elements would leave the wrapper unbalanced : they are rejected with the reason
+    fn wrap_tags(wrap: &str) -> Result<(String, String), &'static str> {
+        const VOID_ELEMENTS: [&str; 14] = ["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr"];
+        let wrap = wrap.trim();
+        let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '-';
+        let (name, open_tag) = match wrap.strip_prefix('<') {
+            Some(tag) => {
+                let name = &tag[..tag.find(|c: char| !is_name_char(c)).unwrap_or(tag.len())];
+                // The first '<' or '>' outside of the attribute values must be the '>' ending the wrap
+                let mut quote = None;
+                let end = tag.find(|c: char| match quote {
+                    Some(open) => {
+                        if c == open {
+                            quote = None;
+                        }
+                        false
+                    },
+                    None if c == '"' || c == '\'' => {
+                        quote = Some(c);
+                        false
+                    },
+                    None => c == '<' || c == '>',
+                });
+                if end != Some(tag.len() - 1) || !tag.ends_with('>') || !tag[name.len()..].starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+                    return Err("expected a tag name or a single opening tag");
+                }
+                if tag[..tag.len() - 1].trim_end().ends_with('/') {
+                    return Err("a self-closing tag cannot wrap the element");
+                }
+                (name, wrap.to_string())
+            },
+            None if wrap.chars().all(is_name_char) => (wrap, format!("<{}>", wrap)),
+            None => return Err("expected a tag name or a single opening tag"),
+        };
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return Err("expected a tag name or a single opening tag");
+        }
+        if VOID_ELEMENTS.iter().any(|void| name.eq_ignore_ascii_case(void)) {
+            return Err("a void element cannot wrap the element");
+        }
+        Ok((open_tag, format!("</{}>", name)))
+    }
+
+    // Compiles the regexes of the match_replace operations of a node (edit.attrs and edit.content) and of its data.values extract
+    fn node_regexes(
+        json_def: &ShadowJson,
+        cache: &Rc<RefCell<ShadowApiCache>>,
+        errors: &Rc<RefCell<Vec<String>>>
+    ) -> NodeRegexes {
+        let compile_op = |op: &ShadowJsonEditOne| match (op.op.as_str(), &op.r#match) {
+            ("match_replace", Some(r#match)) => Self::compile_regex(r#match, cache, errors),
+            _ => None,
+        };
+        let edit = json_def.edit.as_ref();
+        NodeRegexes {
+            attrs: edit.and_then(|edit| edit.attrs.as_ref()).into_iter().flatten()
+                .map(|(_, ops)| ops.ops().iter().map(compile_op).collect())
+                .collect(),
+            content: edit.and_then(|edit| edit.content.as_ref()).and_then(compile_op),
+            values: json_def.data.iter().flat_map(|data| data.values.iter().flatten())
+                .map(|(_, value)| value.extract.as_ref().and_then(|extract| Self::compile_regex(&extract.r#match, cache, errors)))
+                .collect(),
+        }
+    }
+
+    // Stores the default of the Contents values no text was collected for, once the element is closed
+    fn set_contents_defaults(data_item: &Rc<RefCell<ShadowData>>, selector_id: usize, json_def: &ShadowJson, policy: ShadowDataCollisionPolicy) -> Result<(), ShadowError> {
+        let values = json_def.data.iter().flat_map(|data| data.values.iter().flatten());
+        for (key, value) in values.filter(|(_, value)| matches!(value.source, ShadowJsonValueSource::Contents)) {
+            if data_item.borrow().get(key).is_some() {
+                continue;
+            }
+            if let Some(default) = value.default_data(Some(selector_id), Rc::downgrade(data_item)) {
+                data_item.borrow_mut().set(key, ShadowData::wrap(default), policy)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Compiles a regex once per parse (or reuses the one precompiled by CompiledShadowJson). Invalid regexes are reported and return None
+    fn compile_regex(
+        r#match: &str,
+        cache: &Rc<RefCell<ShadowApiCache>>,
+        errors: &Rc<RefCell<Vec<String>>>
+    ) -> Option<Regex> {
+        let regex_map = &mut cache.borrow_mut().regex_map;
+        if let Some(regex) = regex_map.get(r#match) {
+            return Some(regex.clone()); // The compiled program is shared
+        }
+        match Regex::new(r#match) {
+            Ok(regex) => {
+                regex_map.insert(r#match.to_string(), regex.clone());
+                Some(regex)
+            },
+            Err(e) => {
+                errors.borrow_mut().push(format!("Invalid regex: {} | Error: {}", r#match, e));
+                None
+            },
+        }
+    }
+
+    // Converts a dash separated attribute name to camelCase, as the DOM dataset does ("price-amount" => "priceAmount")
+    // Only the dashes followed by a lowercase ASCII letter are removed
+    fn camel_case(name: &str) -> String {
+        let mut camel = String::with_capacity(name.len());
+        let mut chars = name.chars().peekable();
+        while let Some(c) = chars.next() {
+            match chars.peek() {
+                Some(next) if c == '-' && next.is_ascii_lowercase() => {
+                    camel.push(next.to_ascii_uppercase());
+                    chars.next();
+                }
+                _ => camel.push(c),
+            }
+        }
+        camel
+    }
+
+    // Applies a regex to old_value and replaces with new_value
+    // Return None if no matches
+    fn match_replace<'a>(
+        regex: &Regex,
+        old_value: &'a str,
+        new_value: &str,
+        limit: usize
+    ) -> Option<Cow<'a, str>> {
+        // Named groups are referenced in new_value as $name or ${name}. A limit of 0 replaces all matches
+        let new_val = regex.replacen(
+            old_value,
+            limit,
+            new_value
+        ); // If no match, replace returns the original old_value
+        if new_val != old_value {
+            return Some(new_val)
+        }
         None
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn text_content_handler(
         el: &mut TextChunk,
         selector_id: usize,
         json_def: Rc<RefCell<ShadowJson>>,
         errors: Rc<RefCell<Vec<String>>>,
-        content_buffer: Rc<RefCell<String>>,
-        cache: Rc<RefCell<HashMap<String, Box<dyn Any>>>>,
-        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>
+        content_buffer: Rc<RefCell<TextBuffer>>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        options: Rc<ShadowApiOptions>,
+        stats: Rc<RefCell<ShadowApiStats>>,
+        regexes: &NodeRegexes,
+        data_listeners: &DataListeners,
+        masker: Option<&ShadowMasker>,
+        pass: ShadowPass,
+        value_from_text: Option<&Cell<bool>>,
+        tracer: Option<&NodeTracer>
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let json_def_b = json_def.borrow();
-        let mut content_buffer_b = content_buffer.borrow_mut();
-        content_buffer_b.push_str(el.as_str()); // Saved chunk to buffer
-        el.remove();
+        let edits_content = json_def_b.edit.as_ref().is_some_and(|edit| edit.content.is_some());
+        let mut buffer = content_buffer.borrow_mut();
+        if let Some(max) = buffer.push(el, options.max_text_bytes, edits_content) {
+            Self::report_once(&errors, format!(
+                "[max_text_bytes] A text of '{}' exceeds {} bytes : its data is truncated and its content edits are skipped", json_def_b.s, max
+            ));
+        }
+        let TextBuffer { text: content_buffer_b, overflow } = &mut *buffer;
         if el.last_in_text_node() {
             // Last text chunk reached : process the buffer, send it back and reset it
             // PROCESSING BEGINS
-            if let Some(edit) = &json_def_b.edit {
+            if let Some(edit) = json_def_b.edit.as_ref().filter(|_| !*overflow) {
                 if let Some(content) = &edit.content {
                     match content.op.as_str() {
                         "delete" => {
                             *content_buffer_b = String::new();
+                            stats.borrow_mut().edits += 1;
+                            if let Some(tracer) = tracer {
+                                tracer.edited(vec!["edit.content:delete".to_string()]);
+                            }
                         }
                         "upsert" => {
                             if let Some(value) = &content.val {
                                 *content_buffer_b = value.clone();
+                                stats.borrow_mut().edits += 1;
+                                if let Some(tracer) = tracer {
+                                    tracer.edited(vec!["edit.content:upsert".to_string()]);
+                                }
                             } else {
                                 let mut errors_m = errors.borrow_mut();
-                                errors_m.push(format!("Upsert requires an existing val content string"));
+                                errors_m.push("Upsert requires an existing val content string".to_string());
                             }
                         }
                         "match_replace" => {
-                            if let Some(r#match) = &content.r#match {
+                            if let Some(regex) = &regexes.content {
                                 if let Some(new_value) = &content.val {
                                     if let Some(replacement) = Self::match_replace(
-                                        r#match,
-                                        &content_buffer_b,
+                                        regex,
+                                        content_buffer_b,
                                         new_value,
-                                        Rc::clone(&errors),
-                                        Rc::clone(&cache)
+                                        content.limit.unwrap_or(0)
                                     ) {
                                         *content_buffer_b = replacement.to_string();
+                                        stats.borrow_mut().edits += 1;
+                                        if let Some(tracer) = tracer {
+                                            tracer.edited(vec!["edit.content:match_replace".to_string()]);
+                                        }
                                     }
                                 }
                             }
                         }
+                        "set_html" => {
+                            // Handled by element_content_handler
+                        }
                         other => {
                             let mut errors_m = errors.borrow_mut();
-                            errors_m.push(format!("Invalid operation (edit.content): {}. Allowed values : delete/upsert/match_replace", other));
+                            errors_m.push(format!("Invalid operation (edit.content): {}. Allowed values : delete/upsert/match_replace/set_html", other));
                         }
                     }
                 }
             }
-            if let Some(data_def) = &json_def_b.data {
-                let data = &shadow_data_cursor.borrow().shadow_data;
+            if let Some(data_def) = json_def_b.data.as_ref().filter(|_| pass.collects()) {
+                if let Some(ShadowJsonValueSource::Contents) = &data_def.key_from {
+                    shadow_data_cursor.borrow_mut().set_keyed_key(selector_id, content_buffer_b.trim().to_string());
+                }
+                let data = Rc::clone(&shadow_data_cursor.borrow().shadow_data);
                 let parent = Rc::downgrade(&data);
                 if let Some(values) = &data_def.values {
                     if !values.is_empty() {
-                        for (key, value) in values.iter() {
-                            match value {
+                        let mut collected: Vec<&String> = Vec::new();
+                        for (value_idx, (key, value)) in values.iter().enumerate() {
+                            let extract_regex = regexes.values.get(value_idx).and_then(Option::as_ref);
+                            match &value.source {
                                 ShadowJsonValueSource::Contents => {
                                         let mut new_data_m = data.borrow_mut();
-                                        new_data_m.set(key, ShadowData::wrap(
+                                        match new_data_m.set(key, ShadowData::wrap(
+                                            value.to_data(Some(selector_id), Weak::clone(&parent), content_buffer_b, extract_regex)
+                                        ), options.collision_policy) {
+                                            Ok(_) => {
+                                                stats.borrow_mut().data_values += 1;
+                                                collected.push(key);
+                                            },
+                                            Err(err) => errors.borrow_mut().push(err.to_string()),
+                                        }
+                                },
+                                ShadowJsonValueSource::Value if value_from_text.is_some_and(Cell::get) => {
+                                    // Each text node of the element is appended to the value initialized by element_content_handler
+                                    let existing = data.borrow().get(key).and_then(|value| value.borrow().as_string());
+                                    let stored = match existing {
+                                        Some(text) => {
+                                            text.borrow_mut().push_str(content_buffer_b);
+                                            Ok(())
+                                        },
+                                        None => data.borrow_mut().set(key, ShadowData::wrap(
                                             ShadowData::new_string(Some(selector_id), Weak::clone(&parent), content_buffer_b.clone())
-                                        ));
+                                        ), options.collision_policy),
+                                    };
+                                    match stored {
+                                        Ok(_) => collected.push(key),
+                                        Err(err) => errors.borrow_mut().push(err.to_string()),
+                                    }
                                 },
                                 _ => {
                                     // Handled by element_content_handler
                                 }
                             }
                         }
+                        Self::mask_collected(masker, &data, &collected);
+                        let dropped = Self::charge_data(options.max_data_bytes, &stats, &errors, &data, &mut collected);
+                        stats.borrow_mut().data_values -= dropped;
+                        Self::notify_data(data_listeners, &shadow_data_cursor, &data, &collected);
+                        if let Some(tracer) = tracer {
+                            Self::trace_collected(tracer, &shadow_data_cursor, &data, &collected);
+                        }
                     }
                 }
             }
             // PROCESSING ENDS
-            el.replace(&content_buffer_b, ContentType::Text);
-            content_buffer_b.clear(); // Reset
+            if edits_content && !*overflow {
+                el.replace(content_buffer_b, ContentType::Text);
+            }
+            buffer.clear(); // Reset
         }
         Ok(())
     }
 
+    // Registers the handlers of the text replacer and of the text masker : a document text handler buffering each text until its last chunk to apply the rules,
+    // and the handlers tracking whether an element excluded from the text replacer is being streamed
+    fn text_replacer_handlers(
+        text_replacer: Option<Rc<ShadowTextReplacer>>,
+        text_masker: Option<Rc<ShadowMasker>>,
+        ech: &mut KeyedHandlers,
+        dch: &mut Vec<DocumentContentHandlers>,
+        cache: &Rc<RefCell<ShadowApiCache>>
+    ) {
+        let excluded = Rc::new(Cell::new(0usize)); // Excluded elements open around the text being streamed
+        for (selector, selector_obj) in text_replacer.iter().flat_map(|text_replacer| &text_replacer.exclude) {
+            let eh_excluded = Rc::clone(&excluded);
+            ech.push((
+                selector.to_string(),
+                Cow::Owned(selector_obj.clone()),
+                ElementContentHandlers::default().element(move |el| {
+                    let can_have_content = el.can_have_content();
+                    if let Some(handlers) = el.end_tag_handlers().filter(|_| can_have_content) {
+                        eh_excluded.set(eh_excluded.get() + 1);
+                        let end_excluded = Rc::clone(&eh_excluded);
+                        handlers.push(Box::new(move |_end| {
+                            end_excluded.set(end_excluded.get().saturating_sub(1));
+                            Ok(())
+                        }));
+                    }
+                    Ok(())
+                })
+            ));
+            Self::record_plan(cache, selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::TextReplacer, true);
+        }
+
+        let mut buffer = String::new();
+        dch.push(DocumentContentHandlers::default().text(move |t| {
+            if !matches!(t.text_type(), TextType::Data | TextType::RCData) {
+                return Ok(()); // <script> and <style>
+            }
+            let text_replacer = text_replacer.as_ref().filter(|_| excluded.get() == 0);
+            if text_replacer.is_none() && text_masker.is_none() {
+                return Ok(()); // Excluded element
+            }
+            if t.removed() {
+                // Text edited by a node
+                buffer.clear();
+                return Ok(());
+            }
+            buffer.push_str(t.as_str());
+            if t.last_in_text_node() {
+                let text = std::mem::take(&mut buffer);
+                let mut replaced = Cow::Borrowed(text.as_str());
+                if let Some(text_replacer) = text_replacer {
+                    replaced = Cow::Owned(text_replacer.replace(&replaced).into_owned());
+                }
+                if let Some(text_masker) = &text_masker {
+                    replaced = Cow::Owned(text_masker.mask(&replaced).into_owned());
+                }
+                if replaced != text {
+                    t.replace(&replaced, ContentType::Html);
+                } else if text.len() > t.as_str().len() {
+                    t.replace(&text, ContentType::Html); // The previous chunks were removed
+                }
+            } else {
+                t.remove();
+            }
+            Ok(())
+        }));
+    }
+
+    // Registers the handlers of the collect_meta option, which build the "head" object of the data :
+    // {"title": "...", "canonical": "...", "meta": {name: content}, "property": {property: content}}
+    fn meta_content_handlers(
+        ech: &mut KeyedHandlers,
+        cache: &RefCell<ShadowApiCache>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        errors: Rc<RefCell<Vec<String>>>
+    ) {
+        let policy = cache.borrow().collision_policy;
+        // Attribute holding the key under which the content is stored, and the group it belongs to
+        for (selector, key_attr, value_attr, group) in [
+            ("meta[name]", "name", "content", Some("meta")),
+            ("meta[property]", "property", "content", Some("property")),
+            ("link[rel=canonical]", "rel", "href", None),
+        ] {
+            let cursor = Rc::clone(&shadow_data_cursor);
+            let errors = Rc::clone(&errors);
+            ech.push((
+                selector.to_string(),
+                Cow::Owned(selector.parse().unwrap()),
+                ElementContentHandlers::default().element(move |el| {
+                    let (Some(key), Some(value)) = (el.get_attribute(key_attr), el.get_attribute(value_attr)) else {
+                        return Ok(());
+                    };
+                    let stored = Self::head_data(&cursor, group, policy).and_then(|head| {
+                        let parent = Rc::downgrade(&head);
+                        let stored = head.borrow_mut().set(&key, ShadowData::wrap(ShadowData::new_string(None, parent, value)), policy);
+                        stored
+                    });
+                    if let Err(err) = stored {
+                        errors.borrow_mut().push(err.to_string());
+                    }
+                    Ok(())
+                })
+            ));
+            Self::record_plan(cache, selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Meta, false);
+        }
+
+        let title_buffer = Rc::new(RefCell::new(String::new()));
+        ech.push((
+            "head title".to_string(),
+            Cow::Owned("head title".parse().unwrap()),
+            ElementContentHandlers::default().text(move |t| {
+                let mut title_buffer_m = title_buffer.borrow_mut();
+                title_buffer_m.push_str(t.as_str());
+                if t.last_in_text_node() {
+                    let title = std::mem::take(&mut *title_buffer_m).trim().to_string();
+                    let stored = Self::head_data(&shadow_data_cursor, None, policy).and_then(|head| {
+                        let parent = Rc::downgrade(&head);
+                        let stored = head.borrow_mut().set("title", ShadowData::wrap(ShadowData::new_string(None, parent, title)), policy);
+                        stored
+                    });
+                    if let Err(err) = stored {
+                        errors.borrow_mut().push(err.to_string());
+                    }
+                }
+                Ok(())
+            })
+        ));
+        Self::record_plan(cache, "head title", &ech[ech.len() - 1].2, ShadowPlanPurpose::Meta, false);
+    }
+
+    // Registers the handler of set_ld_json_key, which parses the contents of each ld+json block once complete and appends it to the array at key
+    fn ld_json_content_handler(
+        key: &str,
+        ech: &mut KeyedHandlers,
+        cache: &RefCell<ShadowApiCache>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        errors: Rc<RefCell<Vec<String>>>
+    ) {
+        let selector = r#"script[type="application/ld+json"]"#;
+        let key = key.to_string();
+        let policy = cache.borrow().collision_policy;
+        let buffer = Rc::new(RefCell::new(String::new()));
+        ech.push((
+            selector.to_string(),
+            Cow::Owned(selector.parse().unwrap()),
+            ElementContentHandlers::default().text(move |t| {
+                let mut buffer_m = buffer.borrow_mut();
+                buffer_m.push_str(t.as_str());
+                if !t.last_in_text_node() {
+                    return Ok(());
+                }
+                let block = std::mem::take(&mut *buffer_m);
+                if let Err(err) = Self::append_ld_json(&shadow_data_cursor, &key, &block, policy) {
+                    errors.borrow_mut().push(format!("[ld_json] {}", err));
+                }
+                Ok(())
+            })
+        ));
+        Self::record_plan(cache, selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::LdJson, false);
+    }
+
+    // Appends the entities of an ld+json block to the array at key, creating it if missing
+    fn append_ld_json(shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>, key: &str, block: &str, policy: ShadowDataCollisionPolicy) -> Result<(), ShadowError> {
+        let (name, parents) = key.rsplit_once('.').map_or((key, None), |(parents, name)| (name, Some(parents)));
+        let container = Self::object_data(shadow_data_cursor, parents.into_iter().flat_map(|parents| parents.split('.')), policy)?;
+        let existing = container.borrow().get(name).filter(|existing| existing.borrow().is_array());
+        let array = match existing {
+            Some(existing) => existing,
+            None => {
+                let new_array = ShadowData::wrap(ShadowData::new_array(None, Rc::downgrade(&container)));
+                container.borrow_mut().set(name, Rc::clone(&new_array), policy)?;
+                new_array
+            }
+        };
+        let parsed = ShadowData::wrap(ShadowData::from_json_str(None, Rc::downgrade(&array), block.trim())?);
+        let entities = match parsed.borrow().as_array() {
+            Some(items) => items.clone(),
+            None => vec![Rc::clone(&parsed)],
+        };
+        let mut array_m = array.borrow_mut();
+        for entity in entities {
+            entity.borrow_mut().parent = Rc::downgrade(&array);
+            array_m.push(entity)?;
+        }
+        Ok(())
+    }
+
+    // Fetches the "head" object of the data (or one of its groups), creating it if missing
+    fn head_data(shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>, group: Option<&str>, policy: ShadowDataCollisionPolicy) -> Result<Rc<RefCell<ShadowData>>, ShadowError> {
+        Self::object_data(shadow_data_cursor, std::iter::once("head").chain(group), policy)
+    }
+
+    // Fetches the object of the data found by following keys from the root, creating the missing ones. A key holding another kind of value
+    // is resolved by policy : the object is created detached from the data when the value is kept, so that what is written to it is discarded
+    fn object_data<'k>(
+        shadow_data_cursor: &Rc<RefCell<ShadowDataCursor>>,
+        keys: impl Iterator<Item = &'k str>,
+        policy: ShadowDataCollisionPolicy
+    ) -> Result<Rc<RefCell<ShadowData>>, ShadowError> {
+        let root = Rc::clone(&shadow_data_cursor.borrow().root);
+        let mut current = root;
+        for key in keys {
+            let existing = current.borrow().get(key).filter(|existing| existing.borrow().is_object());
+            current = match existing {
+                Some(existing) => existing,
+                None => {
+                    let new_object = ShadowData::wrap(ShadowData::new_object(None, Rc::downgrade(&current)));
+                    current.borrow_mut().set(key, Rc::clone(&new_object), policy)?;
+                    new_object
+                }
+            };
+        }
+        Ok(current)
+    }
+
+    // Applies the error_policy to the result of a handler. Only fail_closed lets the error through, which aborts the rewriting
+    fn recover(result: HandlerResult, policy: ShadowErrorPolicy, failed: &Cell<bool>, errors: &RefCell<Vec<String>>) -> HandlerResult {
+        let Err(err) = result else {
+            return Ok(());
+        };
+        match policy {
+            ShadowErrorPolicy::Collect => errors.borrow_mut().push(err.to_string()),
+            ShadowErrorPolicy::FailOpen => {
+                failed.set(true);
+                errors.borrow_mut().push(format!("[fail_open] {} : the rest of the document is passed through", err));
+            },
+            ShadowErrorPolicy::FailClosed => return Err(err),
+        }
+        Ok(())
+    }
+
+    // Data transformer masking the data before the user transformer runs, so that the injected data is masked
+    fn masking_transformer(data_transformer: Option<DataTransformer>, data_masker: Option<Rc<ShadowMasker>>) -> Option<DataTransformer> {
+        let Some(data_masker) = data_masker else {
+            return data_transformer;
+        };
+        Some(Rc::new(Box::new(move |data: &mut ShadowData| {
+            data_masker.mask_data(data);
+            if let Some(data_transformer) = &data_transformer {
+                data_transformer(data);
+            }
+        })))
+    }
+
+    // Applies the data transformer, if any, then the data formatter, to the data brought within the max_injected_bytes budget
+    // Their panics are caught and returned as errors, so that a failing user closure does not take the response down (unless built with panic = "abort")
+    fn format_data(
+        data_formatter: &Rc<Box<dyn Fn(String) -> String>>,
+        data_transformer: &Option<DataTransformer>,
+        budget: Option<&InjectionBudget>,
+        data: &Rc<RefCell<ShadowData>>,
+        errors: &RefCell<Vec<String>>
+    ) -> Result<String, ShadowError> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            if let Some(data_transformer) = data_transformer {
+                data_transformer(&mut data.borrow_mut());
+            }
+            let json = data.borrow().to_string();
+            match budget.filter(|budget| json.len() > budget.max_bytes) {
+                Some(budget) => match budget.fit(data, json.len()) {
+                    Some(json) => {
+                        errors.borrow_mut().push(format!("[max_injected_bytes] The injected data was reduced to fit within {} bytes ({})", budget.max_bytes, json.len()));
+                        (data_formatter)(json)
+                    },
+                    None => {
+                        let message = format!("[max_injected_bytes] The data ({} bytes) exceeds {} bytes : it was not injected", json.len(), budget.max_bytes);
+                        let comment = format!("<!-- {} -->", message);
+                        errors.borrow_mut().push(message);
+                        comment
+                    },
+                },
+                None => (data_formatter)(json),
+            }
+        })).map_err(|panic| {
+            let msg = panic.downcast_ref::<&str>().map(|msg| msg.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            ShadowError::Other(format!("[format_data] the data transformer or formatter panicked : {}", msg))
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn data_content_handler(
         data_formatter: Rc<Box<dyn Fn(String) -> String>>,
-        ech: &mut Vec<(Cow<Selector>, ElementContentHandlers)>,
-        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>
+        data_transformer: Option<DataTransformer>,
+        budget: Option<Rc<InjectionBudget>>,
+        ech: &mut KeyedHandlers,
+        cache: &RefCell<ShadowApiCache>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        body_state: Rc<Cell<BodyState>>,
+        pass: Rc<Cell<ShadowPass>>,
+        failed: Rc<Cell<bool>>,
+        errors: Rc<RefCell<Vec<String>>>,
+        error_policy: ShadowErrorPolicy
     ) {
+        let aggregates = Rc::new(cache.borrow().aggregates.clone());
         ech.push((
+            "body".to_string(),
             Cow::Owned("body".parse().unwrap()),
             ElementContentHandlers::default().element(move |el| {
+                if !pass.get().writes() || failed.get() {
+                    return Ok(()); // The data is injected by the second pass, or not at all once the handlers failed
+                }
+                body_state.set(BodyState::Opened);
                 let data = Rc::clone(&shadow_data_cursor.borrow().root);
                 let data_formatter_c = Rc::clone(&data_formatter);
+                let data_transformer_c = data_transformer.clone();
+                let budget_c = budget.clone();
                 let data_c = Rc::clone(&data);
+                let shadow_data_cursor_c = Rc::clone(&shadow_data_cursor);
+                let aggregates_c = Rc::clone(&aggregates);
+                let body_state_c = Rc::clone(&body_state);
+                let failed_c = Rc::clone(&failed);
+                let errors_c = Rc::clone(&errors);
                 if let Some(handlers) = el.end_tag_handlers() {
                     handlers.push(Box::new(move |end| {
-                        let data_b = data_c.borrow_mut();
-                        let props_html: String = (data_formatter_c)(data_b.to_string());
-                        end.before(props_html.as_str(), ContentType::Html);
+                        if failed_c.get() {
+                            return Ok(());
+                        }
+                        // Set even if the formatting failed, so that it is not attempted again at the end of the document
+                        body_state_c.set(BodyState::Injected);
+                        Self::finalize_data(&shadow_data_cursor_c, &aggregates_c, &errors_c);
+                        match Self::format_data(&data_formatter_c, &data_transformer_c, budget_c.as_deref(), &data_c, &errors_c) {
+                            Ok(props_html) => end.before(props_html.as_str(), ContentType::Html),
+                            Err(err) => return Self::recover(Err(err.into()), error_policy, &failed_c, &errors_c),
+                        }
                         Ok(())
                     }));
                 }
-                Ok(())
-            })
-        ));
+                Ok(())
+            })
+        ));
+        Self::record_plan(cache, "body", &ech[ech.len() - 1].2, ShadowPlanPurpose::Inject, true);
+    }
+
+    // Handler run at the end of the document. If the body was opened but never closed, the data is injected there instead of before </body>
+    // With the fallback_injection option, this also applies when no body was found. The on_document_end then on_complete callbacks are invoked last
+    #[allow(clippy::too_many_arguments)]
+    fn document_end_handler<'a>(
+        data_formatter: Rc<Box<dyn Fn(String) -> String>>,
+        data_transformer: Option<DataTransformer>,
+        budget: Option<Rc<InjectionBudget>>,
+        data_masker: Option<Rc<ShadowMasker>>,
+        dom_written: bool,
+        options: ShadowApiOptions,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        body_state: Rc<Cell<BodyState>>,
+        on_document_end: Option<DocumentEndCallback>,
+        on_complete: Option<CompleteCallback>,
+        pass: Rc<Cell<ShadowPass>>,
+        required: Vec<Rc<RefCell<RequiredState>>>,
+        aggregates: Vec<(String, ShadowAggregate)>,
+        on_missing_required: Option<MissingRequiredCallback>,
+        errors: Rc<RefCell<Vec<String>>>,
+        failed: Rc<Cell<bool>>
+    ) -> DocumentContentHandlers<'a> {
+        DocumentContentHandlers::default().end(move |end| {
+            Self::finalize_data(&shadow_data_cursor, &aggregates, &errors);
+            if let Some(data_masker) = &data_masker {
+                // The values which were not collected by the nodes (collect_meta, form, region, data transformer...) are masked before the data leaves
+                data_masker.mask_data(&mut shadow_data_cursor.borrow().root.borrow_mut());
+            }
+            if pass.get().collects() {
+                // Checked by the pass collecting the data
+                let missing: Vec<ShadowError> = required.iter().flat_map(|state| state.borrow().missing()).collect();
+                if !missing.is_empty() {
+                    errors.borrow_mut().extend(missing.iter().map(ShadowError::to_string));
+                    if let Some(on_missing_required) = &on_missing_required {
+                        on_missing_required(&missing);
+                    }
+                }
+            }
+            if !pass.get().writes() {
+                return Ok(()); // The output of the first pass is discarded, the second pass ends the document
+            }
+            let data = Rc::clone(&shadow_data_cursor.borrow().root);
+            let inject = match body_state.get() {
+                BodyState::NotFound => options.fallback_injection,
+                BodyState::Opened => true,
+                BodyState::Injected => false,
+            };
+            if dom_written && inject && !failed.get() {
+                match Self::format_data(&data_formatter, &data_transformer, budget.as_deref(), &data, &errors) {
+                    Ok(props_html) => {
+                        end.append(props_html.as_str(), ContentType::Html);
+                        if options.fallback_close_body {
+                            end.append("</body></html>", ContentType::Html);
+                        }
+                        body_state.set(BodyState::Injected);
+                    },
+                    Err(err) => Self::recover(Err(err.into()), options.error_policy, &failed, &errors)?,
+                }
+            }
+            if let Some(on_document_end) = &on_document_end {
+                on_document_end(end, &data.borrow());
+            }
+            if let Some(on_complete) = &on_complete {
+                on_complete(&data.borrow());
+            }
+            Ok(())
+        })
+    }
+
+    // Sorts the collections of data.sort_keys and data.sort_by, then computes the values of the aggregate sections, before the data is injected
+    fn finalize_data(shadow_data_cursor: &RefCell<ShadowDataCursor>, aggregates: &[(String, ShadowAggregate)], errors: &RefCell<Vec<String>>) {
+        let cursor = shadow_data_cursor.borrow();
+        cursor.apply_sorts();
+        for (target, aggregate) in aggregates {
+            let Some(value) = aggregate.compute(&cursor) else {
+                continue; // Nothing to aggregate : the key is left out
+            };
+            let set = ShadowData::set_path(&cursor.root, target, value);
+            if let Err(err) = set {
+                errors.borrow_mut().push(format!("[aggregate] {} : {}", target, err));
+            }
+        }
     }
 
+    /// Writes the collected data, encoded according to the output_encoding option
     pub fn process_json<W>(
         &self,
         writer : &mut W
     ) -> Result<(), ShadowError>
+    where
+        W: Write
+    {
+        self.process_data(writer)
+    }
+
+    /// Writes the collected data encoded according to the output_encoding option (json by default), for the as_json mode
+    pub fn process_data<W>(
+        &self,
+        writer : &mut W
+    ) -> Result<(), ShadowError>
     where
         W: Write
     {
         let data = Rc::clone(&self.shadow_data_cursor.borrow().root);
-        let data_str = data.borrow().to_string();
-        // Write string chunk by chunk
-        for chunk in data_str
-            .bytes().collect::<Vec<u8>>()
-            .chunks(self.max_chunk_bytesize) {
-                if let Err(e) = writer.write(chunk) {
-                    return Err(ShadowError { msg: format!("Error writing to client body : {}",e) });
-                }
-            }
-        Ok(())
+        // Serialized straight into the writer, chunk by chunk
+        let mut chunked_writer = ChunkedWriter { writer, chunk_size: self.max_chunk_bytesize, written: 0 };
+        let result = data.borrow().to_writer(self.output_encoding(), &mut chunked_writer);
+        self.stats.borrow_mut().bytes_out += chunked_writer.written;
+        result.map_err(|e| ShadowError::Other(format!("Error writing to client body : {}", e)))
+    }
+
+    /// Encoding of the data written in as_json mode, from the options
+    pub fn output_encoding(&self) -> ShadowOutputEncoding {
+        self.options.map(|opts| opts.output_encoding).unwrap_or_default()
+    }
+
+    /// Collected data serialized as json, once the document has been processed (as_json mode). Its length is known upfront, and it can be read or iterated by chunks
+    pub fn json_output(&self) -> Result<ShadowJsonOutput, ShadowError> {
+        let data = Rc::clone(&self.shadow_data_cursor.borrow().root);
+        let mut bytes = Vec::new();
+        data.borrow().to_json_writer(&mut bytes)?;
+        Ok(ShadowJsonOutput::new(bytes, self.max_chunk_bytesize))
     }
 
     pub fn finalize_rewriter<'w, W: Write>(
@@ -729,21 +3500,46 @@ impl<'h> ShadowApi<'h> {
         writer: &'w mut W,
         errors: Rc<RefCell<Vec<String>>>
     ) -> ShadowApiRewriter<'h, impl OutputSink + 'w>
+    {
+        self.rewriter_into(writer, errors, None)
+    }
+
+    // Rewriter writing its output to the writer. The first write error is stored in write_error if set (nothing more is written then),
+    // so that the caller can stop feeding the rewriter, else it is pushed to the errors
+    fn rewriter_into<'w, W: Write>(
+        &self,
+        writer: &'w mut W,
+        errors: Rc<RefCell<Vec<String>>>,
+        write_error: Option<Rc<RefCell<Option<std::io::Error>>>>
+    ) -> ShadowApiRewriter<'h, impl OutputSink + 'w>
     {
         let ech = self.ech.take(); // This is the last time we use ech, so we can remove it
-        let as_json = self.options.and_then(|opts| Some(opts.as_json)).unwrap_or(false);
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
         let max_byte_chunksize = self.max_chunk_bytesize;
+        let stats = Rc::clone(&self.stats);
         
         let rewriter = HtmlRewriter::new(
             Settings {
                 element_content_handlers: ech,
+                document_content_handlers: self.dch.take(),
+                memory_settings: self.memory_settings(),
                 ..Settings::default()
             },
             move |c: &[u8]| {
                 if !as_json {
+                    if write_error.as_ref().is_some_and(|write_error| write_error.borrow().is_some()) {
+                        return; // The output failed
+                    }
                     for chunk in c.chunks(max_byte_chunksize) { // Setting upper limit to writable chunk size
-                        if let Err(e) = writer.write(chunk) {
-                            Rc::clone(&errors).borrow_mut().push(format!("Error writing to client body : {}",e));
+                        match write_fully(writer, chunk) {
+                            Ok(()) => stats.borrow_mut().bytes_out += chunk.len(),
+                            Err(e) => match &write_error {
+                                Some(write_error) => {
+                                    *write_error.borrow_mut() = Some(e);
+                                    return;
+                                },
+                                None => errors.borrow_mut().push(format!("Error writing to client body : {}",e)),
+                            },
                         }
                     }
                 } else {
@@ -751,7 +3547,7 @@ impl<'h> ShadowApi<'h> {
                 }
             }
         );
-        ShadowApiRewriter::new(rewriter)
+        ShadowApiRewriter::new(rewriter).with_stats(Rc::clone(&self.stats))
     }
 
     pub fn finalize_replacer(
@@ -759,39 +3555,72 @@ impl<'h> ShadowApi<'h> {
     ) -> ShadowApiReplacer<'h>
     {
         let ech = self.ech.take(); // This is the last time we use ech, so we can remove it
-        ShadowApiReplacer::new(
+        let replacer = ShadowApiReplacer::new(
             Settings {
                 element_content_handlers: ech,
+                document_content_handlers: self.dch.take(),
+                memory_settings: self.memory_settings(),
                 ..Settings::default()
             }
-        )
+        ).with_stats(Rc::clone(&self.stats));
+        if self.options.map(|opts| opts.as_json).unwrap_or(false) {
+            replacer.with_json_tail(Rc::clone(&self.shadow_data_cursor.borrow().root), self.output_encoding())
+        } else {
+            replacer
+        }
+    }
+
+    pub fn finalize_session(
+        &self
+    ) -> ShadowApiSession<'h>
+    {
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+        ShadowApiSession::new(
+            self.finalize_replacer(),
+            Rc::clone(&self.shadow_data_cursor.borrow().root),
+            as_json
+        ).with_output_encoding(self.output_encoding())
+    }
+
+    /// Returns a sans-io stream : chunks are pushed into it and the rewritten bytes pulled out of it, see ShadowApiStream
+    pub fn finalize_stream(
+        &self
+    ) -> ShadowApiStream<'h>
+    {
+        ShadowApiStream::new(self.finalize_replacer())
+    }
+
+    /// Wraps an http body (hyper, axum...) so that its data frames are rewritten as they flow
+    #[cfg(feature = "http-body")]
+    pub fn finalize_body<B>(&self, body: B) -> ShadowApiBody<'h, B> {
+        ShadowApiBody::new(body, self.finalize_session())
     }
 
-    #[cfg(feature = "async")]
+    /// The returned rewriter implements futures::AsyncWrite (futures feature) or tokio::io::AsyncWrite (tokio feature), according to the writer. It must be closed (shut down) to end the document
+    #[cfg(any(feature = "futures", feature = "tokio"))]
     pub fn finalize_rewriter_async<W>(
         &self,
         writer: &'h mut W
-    ) -> self::shadow_api_async::ShadowApiRewriterAsync<'h, W>
-    where
-        W: futures::AsyncWrite + Unpin
+    ) -> ShadowApiRewriterAsync<'h, W>
     {
-        use self::shadow_api_async::ShadowApiRewriterAsync;
-        let as_json = self.options.and_then(|opts| Some(opts.as_json)).unwrap_or(false);
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
 
         ShadowApiRewriterAsync::new(
             Settings {
                 element_content_handlers: self.ech.take(), // This is the last time we use ech, so we can remove it
+                document_content_handlers: self.dch.take(),
+                memory_settings: self.memory_settings(),
                 ..Settings::default()
             },
             writer,
             as_json
-        )
+        ).with_stats(Rc::clone(&self.stats))
     }
 
-    #[cfg(feature = "async")]
+    #[cfg(feature = "futures")]
     pub async fn process_json_async<W>(
         &self,
-        mut writer: std::pin::Pin<&'h mut W>
+        mut writer: std::pin::Pin<&mut W>
     ) -> Result<(), ShadowError>
     where
         W: futures::AsyncWrite + Unpin
@@ -799,18 +3628,402 @@ impl<'h> ShadowApi<'h> {
         use futures::AsyncWriteExt;
 
         let data = Rc::clone(&self.shadow_data_cursor.borrow().root);
-        let data_str = data.borrow().to_string();
-        // Write string chunk by chunk
-        for chunk in data_str
-            .bytes().collect::<Vec<u8>>()
-            .chunks(self.max_chunk_bytesize) {
-                if let Err(e) = writer.write(chunk).await {
-                    return Err(ShadowError { msg: format!("Error writing to client body : {}",e) });
+        let mut bytes = Vec::new();
+        data.borrow().to_writer(self.output_encoding(), &mut bytes)?;
+        // Write chunk by chunk
+        for chunk in bytes.chunks(self.max_chunk_bytesize) {
+                if let Err(e) = writer.write_all(chunk).await {
+                    return Err(ShadowError::Other(format!("Error writing to client body : {}",e)));
+                }
+                self.stats.borrow_mut().bytes_out += chunk.len();
+            }
+        Ok(())
+    }
+
+    // Rewriter buffering its output, to be written asynchronously by the caller after each input chunk
+    #[cfg(feature = "futures")]
+    fn finalize_buffered_rewriter(&self) -> (HtmlRewriter<'h, impl OutputSink>, Rc<RefCell<Vec<u8>>>) {
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+        let buffer: Rc<RefCell<Vec<u8>>> = Rc::new(RefCell::new(Vec::new()));
+        let sink_buffer = Rc::clone(&buffer);
+        let rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: self.ech.take(), // This is the last time we use ech, so we can remove it
+                document_content_handlers: self.dch.take(),
+                memory_settings: self.memory_settings(),
+                ..Settings::default()
+            },
+            move |c: &[u8]| {
+                if !as_json {
+                    sink_buffer.borrow_mut().extend_from_slice(c);
+                } else {
+                    // Discard HTML data, no write
+                }
+            }
+        );
+        (rewriter, buffer)
+    }
+
+    // Writes the output buffered so far
+    #[cfg(feature = "futures")]
+    async fn drain_async<W>(&self, buffer: &Rc<RefCell<Vec<u8>>>, writer: &mut W) -> Result<(), ShadowError>
+    where
+        W: futures::AsyncWrite + Unpin
+    {
+        use futures::AsyncWriteExt;
+
+        let output = buffer.take();
+        for chunk in output.chunks(self.max_chunk_bytesize) { // Setting upper limit to writable chunk size
+            writer.write_all(chunk).await.map_err(|e| ShadowError::Other(format!("Error writing to client body : {}", e)))?;
+        }
+        self.stats.borrow_mut().bytes_out += output.len();
+        Ok(())
+    }
+
+    // Ends the rewriter, writes the remaining output (or the collected data with the as_json option) and flushes the writer
+    #[cfg(feature = "futures")]
+    async fn end_async<W>(
+        &self,
+        rewriter: HtmlRewriter<'h, impl OutputSink>,
+        buffer: &Rc<RefCell<Vec<u8>>>,
+        writer: &mut W
+    ) -> Result<(), ShadowError>
+    where
+        W: futures::AsyncWrite + Unpin
+    {
+        use futures::AsyncWriteExt;
+
+        rewriter.end().map_err(|e| ShadowError::Other(format!("Error ending the rewriter : {}", e)))?;
+        self.drain_async(buffer, writer).await?;
+        if self.options.map(|opts| opts.as_json).unwrap_or(false) {
+            // The data is complete only once the rewriter has ended
+            self.process_json_async(std::pin::Pin::new(&mut *writer)).await?;
+        }
+        writer.flush().await.map_err(|e| ShadowError::Other(format!("Error flushing client body : {}", e)))
+    }
+
+    // Writes a chunk of input verbatim, as the sentinel was not found. Nothing is written with the as_json option, which only outputs the data (see end_passthrough_async)
+    #[cfg(feature = "futures")]
+    async fn passthrough_async<W>(&self, chunk: &[u8], writer: &mut W) -> Result<(), ShadowError>
+    where
+        W: futures::AsyncWrite + Unpin
+    {
+        use futures::AsyncWriteExt;
+
+        self.stats.borrow_mut().bytes_in += chunk.len();
+        if self.options.map(|opts| opts.as_json).unwrap_or(false) {
+            return Ok(());
+        }
+        for part in chunk.chunks(self.max_chunk_bytesize) {
+            writer.write_all(part).await.map_err(|e| ShadowError::Other(format!("Error writing to client body : {}", e)))?;
+        }
+        self.stats.borrow_mut().bytes_out += chunk.len();
+        Ok(())
+    }
+
+    #[cfg(feature = "futures")]
+    async fn end_passthrough_async<W>(&self, writer: &mut W) -> Result<(), ShadowError>
+    where
+        W: futures::AsyncWrite + Unpin
+    {
+        use futures::AsyncWriteExt;
+
+        if self.options.map(|opts| opts.as_json).unwrap_or(false) {
+            self.process_json_async(std::pin::Pin::new(&mut *writer)).await?;
+        }
+        writer.flush().await.map_err(|e| ShadowError::Other(format!("Error flushing client body : {}", e)))
+    }
+
+    /// Async counterpart of process_html : reads the html from an AsyncRead and writes the result to an AsyncWrite (the collected data with the as_json option)
+    #[cfg(feature = "futures")]
+    pub async fn process_html_async<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        errors: Rc<RefCell<Vec<String>>>
+    )
+    where
+        R: futures::AsyncRead + Unpin,
+        W: futures::AsyncWrite + Unpin
+    {
+        use futures::AsyncReadExt;
+
+        let mut buf: [u8; MAX_CHUNK_BYTESIZE] = [0; MAX_CHUNK_BYTESIZE];
+        let mut pending: Option<Vec<u8>> = None;
+        if let Some((selector, max_bytes)) = &self.sentinel {
+            let mut scanner = SentinelScanner::new(selector, *max_bytes);
+            let found = loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) => break false,
+                    Ok(n_bytes) => {
+                        if let Some(found) = scanner.feed(&buf[0..n_bytes]) {
+                            break found;
+                        }
+                    },
+                    Err(err) => {
+                        errors.borrow_mut().push(format!("[process_html_async] read error : {}", err));
+                        return;
+                    },
+                }
+            };
+            let buffered = scanner.into_buffered();
+            if !found {
+                let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+                let mut result = self.passthrough_async(&buffered, writer).await;
+                while result.is_ok() && !as_json {
+                    result = match reader.read(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(n_bytes) => self.passthrough_async(&buf[0..n_bytes], writer).await,
+                        Err(err) => Err(ShadowError::Other(format!("read error : {}", err))),
+                    };
+                }
+                if result.is_ok() {
+                    result = self.end_passthrough_async(writer).await;
+                }
+                if let Err(err) = result {
+                    errors.borrow_mut().push(format!("[process_html_async] {}", err));
+                }
+                return;
+            }
+            pending = Some(buffered);
+        }
+        if self.is_two_pass() {
+            let mut document = pending.take().unwrap_or_default();
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n_bytes) => document.extend_from_slice(&buf[0..n_bytes]),
+                    Err(err) => {
+                        errors.borrow_mut().push(format!("[process_html_async] read error : {}", err));
+                        return;
+                    },
+                }
+            }
+            pending = Some(self.first_pass(document, Rc::clone(&errors)));
+        }
+        let (mut rewriter, buffer) = self.finalize_buffered_rewriter();
+        if let Some(pending) = pending {
+            // Input read while looking for the sentinel, or whole document with the two_pass option
+            if let Err(err) = rewriter.write(&pending) {
+                errors.borrow_mut().push(format!("[process_html_async] write error : {}", err));
+                return;
+            }
+            self.stats.borrow_mut().bytes_in += pending.len();
+        }
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => break, // Reading complete
+                Ok(n_bytes) => {
+                    if let Err(err) = rewriter.write(&buf[0..n_bytes]) {
+                        errors.borrow_mut().push(format!("[process_html_async] write error : {}", err));
+                        return;
+                    }
+                    self.stats.borrow_mut().bytes_in += n_bytes;
+                    if let Err(err) = self.drain_async(&buffer, writer).await {
+                        errors.borrow_mut().push(format!("[process_html_async] {}", err));
+                        return;
+                    }
+                    if self.stop_requested() {
+                        break;
+                    }
+                },
+                Err(err) => {
+                    errors.borrow_mut().push(format!("[process_html_async] read error : {}", err));
+                    return;
+                },
+            }
+        }
+        if let Err(err) = self.end_async(rewriter, &buffer, writer).await {
+            errors.borrow_mut().push(format!("[process_html_async] {}", err));
+        }
+    }
+
+    /// Async processing of a stream of html chunks (such as an http body), writing the result to an AsyncWrite (the collected data with the as_json option)
+    #[cfg(feature = "futures")]
+    pub async fn process_stream<S, B, E, W>(
+        &self,
+        stream: S,
+        writer: &mut W,
+        errors: Rc<RefCell<Vec<String>>>
+    )
+    where
+        S: futures::Stream<Item = Result<B, E>>,
+        B: AsRef<[u8]>,
+        E: std::fmt::Display,
+        W: futures::AsyncWrite + Unpin
+    {
+        use futures::StreamExt;
+
+        let mut stream = std::pin::pin!(stream);
+        let mut pending: Option<Vec<u8>> = None;
+        if let Some((selector, max_bytes)) = &self.sentinel {
+            let mut scanner = SentinelScanner::new(selector, *max_bytes);
+            let mut found = false;
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(chunk_data) => {
+                        if let Some(decision) = scanner.feed(chunk_data.as_ref()) {
+                            found = decision;
+                            break;
+                        }
+                    },
+                    Err(err) => {
+                        errors.borrow_mut().push(format!("[process_stream] invalid chunk : {}", err));
+                        return;
+                    },
+                }
+            }
+            let buffered = scanner.into_buffered();
+            if !found {
+                let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+                let mut result = self.passthrough_async(&buffered, writer).await;
+                while result.is_ok() && !as_json {
+                    result = match stream.next().await {
+                        None => break,
+                        Some(Ok(chunk_data)) => self.passthrough_async(chunk_data.as_ref(), writer).await,
+                        Some(Err(err)) => Err(ShadowError::Other(format!("invalid chunk : {}", err))),
+                    };
+                }
+                if result.is_ok() {
+                    result = self.end_passthrough_async(writer).await;
+                }
+                if let Err(err) = result {
+                    errors.borrow_mut().push(format!("[process_stream] {}", err));
+                }
+                return;
+            }
+            pending = Some(buffered);
+        }
+        if self.is_two_pass() {
+            let mut document = pending.take().unwrap_or_default();
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(chunk_data) => document.extend_from_slice(chunk_data.as_ref()),
+                    Err(err) => {
+                        errors.borrow_mut().push(format!("[process_stream] invalid chunk : {}", err));
+                        return;
+                    },
                 }
             }
+            pending = Some(self.first_pass(document, Rc::clone(&errors)));
+        }
+        let (mut rewriter, buffer) = self.finalize_buffered_rewriter();
+        if let Some(pending) = pending {
+            // Input read while looking for the sentinel, or whole document with the two_pass option
+            if let Err(err) = rewriter.write(&pending) {
+                errors.borrow_mut().push(format!("[process_stream] write error : {}", err));
+                return;
+            }
+            self.stats.borrow_mut().bytes_in += pending.len();
+        }
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk_data) => {
+                    let chunk_data = chunk_data.as_ref();
+                    if let Err(err) = rewriter.write(chunk_data) {
+                        errors.borrow_mut().push(format!("[process_stream] write error : {}", err));
+                        return;
+                    }
+                    self.stats.borrow_mut().bytes_in += chunk_data.len();
+                    if let Err(err) = self.drain_async(&buffer, writer).await {
+                        errors.borrow_mut().push(format!("[process_stream] {}", err));
+                        return;
+                    }
+                    if self.stop_requested() {
+                        break;
+                    }
+                },
+                Err(err) => {
+                    errors.borrow_mut().push(format!("[process_stream] invalid chunk : {}", err));
+                    return;
+                },
+            }
+        }
+        if let Err(err) = self.end_async(rewriter, &buffer, writer).await {
+            errors.borrow_mut().push(format!("[process_stream] {}", err));
+        }
+    }
+
+    // Sentinel option : reads the first chunks until the sentinel selector is found or its byte limit is reached
+    // Returns None without a sentinel, otherwise whether it was found along with the bytes read
+    fn scan_sentinel(&self, chunks: &mut dyn Iterator<Item = Vec<u8>>) -> Option<(bool, Vec<u8>)> {
+        let (selector, max_bytes) = self.sentinel.as_ref()?;
+        let mut scanner = SentinelScanner::new(selector, *max_bytes);
+        let mut found = false;
+        for chunk in chunks {
+            if let Some(decision) = scanner.feed(&chunk) {
+                found = decision;
+                break;
+            }
+        }
+        Some((found, scanner.into_buffered()))
+    }
+
+    // Writes the input verbatim, as the sentinel was not found. With the as_json option, only the data is written
+    fn passthrough<W: Write>(
+        &self,
+        writer: &mut W,
+        buffered: Vec<u8>,
+        chunks: &mut dyn Iterator<Item = Vec<u8>>
+    ) -> Result<(), ShadowError> {
+        if self.options.map(|opts| opts.as_json).unwrap_or(false) {
+            self.stats.borrow_mut().bytes_in += buffered.len();
+            return self.process_json(writer);
+        }
+        for chunk in std::iter::once(buffered).chain(chunks) {
+            self.stats.borrow_mut().bytes_in += chunk.len();
+            for part in chunk.chunks(self.max_chunk_bytesize) {
+                write_fully(writer, part).map_err(ShadowError::Io)?;
+                self.stats.borrow_mut().bytes_out += part.len();
+            }
+        }
         Ok(())
     }
 
+    // lol_html memory settings of the rewriters, from the options. The lol_html defaults apply to the unset ones
+    fn memory_settings(&self) -> MemorySettings {
+        let defaults = MemorySettings::default();
+        MemorySettings {
+            max_allowed_memory_usage: self.options.and_then(|opts| opts.max_allowed_memory_usage).unwrap_or(defaults.max_allowed_memory_usage),
+            preallocated_parsing_buffer_size: self.options.and_then(|opts| opts.preallocated_parsing_buffer_size).unwrap_or(defaults.preallocated_parsing_buffer_size),
+        }
+    }
+
+    fn is_two_pass(&self) -> bool {
+        self.second_pass.borrow().is_some()
+    }
+
+    // two_pass option : runs the collecting handlers over the whole document, discarding their output, then installs the rewriting handlers of the second pass
+    // Returns the document, to be written to the rewriter of the second pass
+    fn first_pass(&self, document: Vec<u8>, errors: Rc<RefCell<Vec<String>>>) -> Vec<u8> {
+        let Some((ech, dch)) = self.second_pass.take() else {
+            return document;
+        };
+        self.pass.set(ShadowPass::Collect);
+        let stats = self.stats.borrow().clone();
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: self.ech.replace(ech),
+                document_content_handlers: self.dch.replace(dch),
+                memory_settings: self.memory_settings(),
+                ..Settings::default()
+            },
+            |_: &[u8]| {}
+        );
+        if let Err(err) = rewriter.write(&document) {
+            errors.borrow_mut().push(format!("[first_pass] write error : {}", err));
+        } else if let Err(err) = rewriter.end() {
+            errors.borrow_mut().push(format!("[first_pass] rewriter not ending : {}", err));
+        }
+        // Bytes, matches and edits are counted by the second pass
+        let (data_values, data_bytes) = {
+            let stats = self.stats.borrow();
+            (stats.data_values, stats.data_bytes)
+        };
+        *self.stats.borrow_mut() = ShadowApiStats { data_values, data_bytes, ..stats };
+        document
+    }
+
     // Process providing just the reader, and use shadowapi's default chunk size
     pub fn process_html<'w, W, R>(
         &self,
@@ -823,34 +4036,103 @@ impl<'h> ShadowApi<'h> {
         R: Read,
         'w: 'h
     {
-        let as_json = self.options.and_then(|opts| Some(opts.as_json)).unwrap_or(false);
-        let mut shadow_api_rewriter = self.finalize_rewriter(writer, Rc::clone(&errors));
+        if let Err(err) = self.try_process_html(writer, reader, Rc::clone(&errors)) {
+            errors.borrow_mut().push(format!("[process_html] {}", err));
+        }
+    }
+
+    /// Same as process_html, returning the amount of bytes read and written, or the error which stopped the processing.
+    /// The output of each chunk is fully written before the next one is read, so that a slow writer slows the reading down :
+    /// partial writes are completed, and Interrupted reads and writes are retried. Any other read or write error is fatal, WouldBlock included :
+    /// the reader and the writer must block until they are ready (a non-blocking socket would have the processing spin instead)
+    pub fn try_process_html<'w, W, R>(
+        &self,
+        writer: &'w mut W,
+        reader: &mut R,
+        errors: Rc<RefCell<Vec<String>>>
+    ) -> Result<ShadowProcessReport, ShadowError>
+    where
+        W: Write,
+        R: Read,
+        'w: 'h
+    {
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+        let start = (self.stats.borrow().bytes_out, errors.borrow().len());
+        let mut bytes_read = 0;
         let mut buf: [u8; MAX_CHUNK_BYTESIZE] = [0; MAX_CHUNK_BYTESIZE];
-        loop {
-            match reader.read(&mut buf) {
+        let mut pending: Option<Vec<u8>> = None;
+        let mut read_error: Option<std::io::Error> = None;
+        let passthrough = {
+            let mut chunks = std::iter::from_fn(|| match read_chunk(reader, &mut buf) {
+                Ok(0) => None,
                 Ok(n_bytes) => {
-                    if n_bytes > 0 {
-                        if let Err(err) =  std::io::Write::write(&mut shadow_api_rewriter, &buf[0..n_bytes]) {
-                            errors.borrow_mut().push(format!("[process_html] write err : {}", err.to_string()));
-                        }
-                    } else {
-                        break; // Writing complete
-                    }
+                    bytes_read += n_bytes;
+                    Some(buf[0..n_bytes].to_vec())
                 },
                 Err(err) => {
-                    errors.borrow_mut().push(format!("[process_html] read error : {}", err.to_string()));
+                    read_error = Some(err);
+                    None
+                },
+            })
+            .fuse();
+            match self.scan_sentinel(&mut chunks) {
+                Some((false, buffered)) => Some(self.passthrough(writer, buffered, &mut chunks)),
+                found => {
+                    pending = found.map(|(_, buffered)| buffered);
+                    if self.is_two_pass() {
+                        let mut document = pending.take().unwrap_or_default();
+                        chunks.for_each(|chunk| document.extend(chunk));
+                        pending = Some(document);
+                    }
+                    None
                 },
             }
+        };
+        if let Some(err) = read_error.take() {
+            return Err(ShadowError::Io(err)); // Incomplete document
         }
-        if as_json {
-            if let Err(err) = self.process_json(
-                &mut shadow_api_rewriter
-            ) {
-                errors.borrow_mut().push(format!("[process_json] {}", err.to_string()));
+        if let Some(result) = passthrough {
+            return result.map(|_| self.process_report(start, bytes_read, &errors));
+        }
+        if self.is_two_pass() {
+            pending = Some(self.first_pass(pending.take().unwrap_or_default(), Rc::clone(&errors)));
+        }
+        let write_error = Rc::new(RefCell::new(None));
+        let mut shadow_api_rewriter = self.rewriter_into(&mut *writer, Rc::clone(&errors), Some(Rc::clone(&write_error)));
+        // The output of the chunk is written once the rewriter returns. The rewriter cannot be used after an error (fail_closed error policy)
+        let mut rewrite = |chunk: &[u8]| -> Result<(), ShadowError> {
+            shadow_api_rewriter.write_chunk(chunk)?;
+            write_error.borrow_mut().take().map_or(Ok(()), |err| Err(ShadowError::Io(err)))
+        };
+        if let Some(pending) = pending {
+            // Input read while looking for the sentinel, or whole document with the two_pass option
+            rewrite(&pending)?;
+        }
+        while !self.stop_requested() {
+            let n_bytes = read_chunk(reader, &mut buf).map_err(ShadowError::Io)?;
+            if n_bytes == 0 {
+                break; // Writing complete
             }
+            bytes_read += n_bytes;
+            rewrite(&buf[0..n_bytes])?;
+        }
+        shadow_api_rewriter.end()?;
+        if let Some(err) = write_error.borrow_mut().take() {
+            return Err(ShadowError::Io(err));
+        }
+        if as_json {
+            // The data is complete only once the rewriter has ended
+            self.process_json(writer)?;
         }
-        if let Err(err) = shadow_api_rewriter.end() {
-            errors.borrow_mut().push(format!("Error ending the rewriter : {}", err.to_string()));
+        Ok(self.process_report(start, bytes_read, &errors))
+    }
+
+    // Report of a processed document. start holds the bytes_out stat and the amount of errors when the processing started
+    fn process_report(&self, start: (usize, usize), bytes_read: usize, errors: &RefCell<Vec<String>>) -> ShadowProcessReport {
+        ShadowProcessReport {
+            bytes_read,
+            bytes_written: self.stats.borrow().bytes_out - start.0,
+            warnings: errors.borrow().get(start.1..).unwrap_or_default().to_vec(),
         }
     }
 
@@ -865,30 +4147,90 @@ impl<'h> ShadowApi<'h> {
         W: Write,
         I: Iterator<Item = Result<Vec<u8>, std::io::Error>>
     {
-        let as_json = self.options.and_then(|opts| Some(opts.as_json)).unwrap_or(false);
-        let mut shadow_api_rewriter = self.finalize_rewriter(writer, Rc::clone(&errors));
+        if let Err(err) = self.try_process_html_iter(writer, chunk_iter, Rc::clone(&errors)) {
+            errors.borrow_mut().push(format!("[process_html_iter] {}", err));
+        }
+    }
 
-        for chunk in chunk_iter {
-            if let Ok(chunk_data) = chunk {
-                if let Err(e) = std::io::Write::write(&mut shadow_api_rewriter, &chunk_data) {
-                    errors.borrow_mut().push(format!("[process_html_iter] write error : {}", e));
-                    return;
-                }
-            } else if let Err(err) = chunk {
-                errors.borrow_mut().push(format!("[process_html_iter] invalid chunk : {}", err.to_string()));
-                return;
+    /// Same as process_html_iter, returning the amount of bytes read and written, or the error which stopped the processing (see try_process_html)
+    pub fn try_process_html_iter<W, I>(
+        &self,
+        writer: &'h mut W,
+        chunk_iter: &mut I,
+        errors: Rc<RefCell<Vec<String>>>
+    ) -> Result<ShadowProcessReport, ShadowError>
+    where
+        W: Write,
+        I: Iterator<Item = Result<Vec<u8>, std::io::Error>>
+    {
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+        let start = (self.stats.borrow().bytes_out, errors.borrow().len());
+        let mut bytes_read = 0;
+        let mut pending: Option<Vec<u8>> = None;
+        let mut read_error: Option<std::io::Error> = None;
+        let passthrough = {
+            let mut chunks = chunk_iter
+                .by_ref()
+                .map_while(|chunk| match chunk {
+                    Ok(chunk) => {
+                        bytes_read += chunk.len();
+                        Some(chunk)
+                    },
+                    Err(err) => {
+                        read_error = Some(err);
+                        None
+                    },
+                })
+                .fuse();
+            match self.scan_sentinel(&mut chunks) {
+                Some((false, buffered)) => Some(self.passthrough(writer, buffered, &mut chunks)),
+                found => {
+                    pending = found.map(|(_, buffered)| buffered);
+                    if self.is_two_pass() {
+                        let mut document = pending.take().unwrap_or_default();
+                        chunks.for_each(|chunk| document.extend(chunk));
+                        pending = Some(document);
+                    }
+                    None
+                },
             }
+        };
+        if let Some(err) = read_error.take() {
+            return Err(ShadowError::Io(err)); // Incomplete document
         }
-        if as_json {
-            if let Err(err) = self.process_json(
-                &mut shadow_api_rewriter
-            ) {
-                errors.borrow_mut().push(format!("[process_json] error : {}", err.to_string()));
-            }
+        if let Some(result) = passthrough {
+            return result.map(|_| self.process_report(start, bytes_read, &errors));
         }
-        if let Err(err) = shadow_api_rewriter.end() {
-            errors.borrow_mut().push(format!("[process_html_iter] rewriter not ending : {}", err.to_string()));
-            return;
+        if self.is_two_pass() {
+            pending = Some(self.first_pass(pending.take().unwrap_or_default(), Rc::clone(&errors)));
+        }
+        let write_error = Rc::new(RefCell::new(None));
+        let mut shadow_api_rewriter = self.rewriter_into(&mut *writer, Rc::clone(&errors), Some(Rc::clone(&write_error)));
+        // The output of the chunk is written once the rewriter returns. The rewriter cannot be used after an error (fail_closed error policy)
+        let mut rewrite = |chunk: &[u8]| -> Result<(), ShadowError> {
+            shadow_api_rewriter.write_chunk(chunk)?;
+            write_error.borrow_mut().take().map_or(Ok(()), |err| Err(ShadowError::Io(err)))
+        };
+        if let Some(pending) = pending {
+            // Input read while looking for the sentinel, or whole document with the two_pass option
+            rewrite(&pending)?;
+        }
+        while !self.stop_requested() {
+            let Some(chunk) = chunk_iter.next() else {
+                break; // Writing complete
+            };
+            let chunk = chunk.map_err(ShadowError::Io)?;
+            bytes_read += chunk.len();
+            rewrite(&chunk)?;
+        }
+        shadow_api_rewriter.end()?;
+        if let Some(err) = write_error.borrow_mut().take() {
+            return Err(ShadowError::Io(err));
+        }
+        if as_json {
+            // The data is complete only once the rewriter has ended
+            self.process_json(writer)?;
         }
+        Ok(self.process_report(start, bytes_read, &errors))
     }
 }
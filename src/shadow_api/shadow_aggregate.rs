@@ -0,0 +1,90 @@
+use std::{cell::RefCell, rc::{Rc, Weak}};
+
+use super::{ShadowData, ShadowDataCursor, ShadowError};
+use super::shadow_json::parse_number;
+
+/// Functions of the aggregate section of ShadowJson
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowAggregateFn {
+    Count, // Amount of values (null excluded)
+    Sum, // Sum of the numbers and of the strings holding a number, read as by the parse_number transform
+    Min,
+    Max,
+    First, // First value, of any kind
+}
+
+/// Derived value computed from the collected data before it is injected, e.g. "count(items)" or "min(items.*.price)"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowAggregate {
+    pub function: ShadowAggregateFn,
+    pub path: String, // Dot separated path from the root. "*" stands for every item of an array (or value of an object)
+}
+
+impl ShadowAggregate {
+    /// Parses an expression of the form function(path)
+    pub fn parse(expression: &str) -> Result<Self, ShadowError> {
+        let invalid = |reason: &str| ShadowError::Other(format!("[aggregate] invalid expression '{}' : {}", expression, reason));
+        let (name, rest) = expression.trim().split_once('(').ok_or_else(|| invalid("expected function(path)"))?;
+        let path = rest.strip_suffix(')').ok_or_else(|| invalid("expected function(path)"))?.trim();
+        let function = match name.trim() {
+            "count" => ShadowAggregateFn::Count,
+            "sum" => ShadowAggregateFn::Sum,
+            "min" => ShadowAggregateFn::Min,
+            "max" => ShadowAggregateFn::Max,
+            "first" => ShadowAggregateFn::First,
+            other => return Err(invalid(&format!("unknown function '{}', expected count, sum, min, max or first", other))),
+        };
+        if path.is_empty() {
+            return Err(invalid("the path is empty"));
+        }
+        Ok(Self { function, path: path.to_string() })
+    }
+
+    /// Value of the aggregate over the data collected so far, detached from the data. None when there is no value to aggregate (except for count)
+    pub fn compute(&self, cursor: &ShadowDataCursor) -> Option<Rc<RefCell<ShadowData>>> {
+        let mut values = vec![Rc::clone(&cursor.root)];
+        for segment in self.path.split('.').filter(|segment| !segment.is_empty()) {
+            values = values.iter().flat_map(|value| Self::children(value, segment)).collect();
+        }
+        // The path designates the array of the values, or each of them
+        let values: Vec<_> = values.iter()
+            .flat_map(|value| match value.borrow().as_array() {
+                Some(items) => items.clone(),
+                None => vec![Rc::clone(value)],
+            })
+            .filter(|value| !value.borrow().is_null())
+            .collect();
+        let numbers = || values.iter().filter_map(|value| {
+            let value_b = value.borrow();
+            value_b.as_number().and_then(|n| n.as_f64()).or_else(|| value_b.as_string().and_then(|s| parse_number(&s.borrow(), None)).and_then(|n| n.as_f64()))
+        });
+        match self.function {
+            ShadowAggregateFn::Count => Some(Self::number(values.len() as f64)),
+            ShadowAggregateFn::Sum => Some(Self::number(numbers().sum())),
+            ShadowAggregateFn::Min => numbers().reduce(f64::min).map(Self::number),
+            ShadowAggregateFn::Max => numbers().reduce(f64::max).map(Self::number),
+            ShadowAggregateFn::First => values.first().map(ShadowData::deep_clone),
+        }
+    }
+
+    fn children(data: &Rc<RefCell<ShadowData>>, segment: &str) -> Vec<Rc<RefCell<ShadowData>>> {
+        let data_b = data.borrow();
+        match (segment, data_b.as_array(), data_b.as_object()) {
+            ("*", Some(items), _) => items.clone(),
+            ("*", _, Some(obj)) => obj.values().cloned().collect(),
+            (_, Some(items), _) => segment.parse::<usize>().ok().and_then(|idx| items.get(idx)).into_iter().cloned().collect(),
+            (_, _, Some(obj)) => obj.get(segment).into_iter().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Integral results are kept as integers : 1280, not 1280.0
+    fn number(value: f64) -> Rc<RefCell<ShadowData>> {
+        let number = if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+            serde_json::Number::from(value as i64)
+        } else {
+            serde_json::Number::from_f64(value).unwrap_or(serde_json::Number::from(0))
+        };
+        ShadowData::wrap(ShadowData::new_number(None, Weak::new(), number))
+    }
+}
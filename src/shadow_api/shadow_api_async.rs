@@ -1,9 +1,12 @@
 use std::{task::{Poll, Context}, rc::Rc, cell::RefCell, pin::Pin};
 use pin_project_lite::pin_project;
 
-use futures::AsyncWrite;
+use super::ShadowApiStats;
 use lol_html::{Settings, HtmlRewriter, OutputSink};
 
+// poll_write function of the inner writer, from the futures or tokio AsyncWrite trait
+type PollWrite<W> = fn(Pin<&mut W>, &mut Context<'_>, &[u8]) -> Poll<std::io::Result<usize>>;
+
 pub struct LoLOutputter {
     done: Rc<RefCell<bool>>,
     //waker: Rc<Waker>,
@@ -22,20 +25,20 @@ impl OutputSink for LoLOutputter {
 }
 
 pin_project! {
+    /// Async rewriter writing its output to an async writer : either a `futures::AsyncWrite` (`futures` feature) or a `tokio::io::AsyncWrite` (`tokio` feature)
+    /// Closing it ends the rewriter (document end handlers are called, the data is injected), writes the remaining output and closes (shuts down) the writer
     pub struct ShadowApiRewriterAsync<'h, W> {
         buffer: Rc<RefCell<Vec<u8>>>,
-        rewriter: HtmlRewriter<'h, LoLOutputter>,
+        rewriter: Option<HtmlRewriter<'h, LoLOutputter>>, // None once ended
         #[pin]
         writer: &'h mut W,
         no_output: bool,
-        is_write_pending: bool // If the previous poll_write returned Pending, then we don't want to write any more - so this flag helps tracking the state
+        is_write_pending: bool, // If the previous poll_write returned Pending, then we don't want to write any more - so this flag helps tracking the state
+        stats: Option<Rc<RefCell<ShadowApiStats>>>, // Counts the bytes going through the rewriter, if set
     }
 }
 
-impl<'h, W> ShadowApiRewriterAsync<'h, W>
-where
-    W: AsyncWrite + Unpin
-{
+impl<'h, W> ShadowApiRewriterAsync<'h, W> {
     /// If 'no_output' is set to true, LolHtml processing will still apply on the input, but the output won't be written
     pub fn new(
         settings: Settings<'h, '_>,
@@ -45,7 +48,7 @@ where
         //let waker = Rc::new(Waker::new());
         let done = Rc::new(RefCell::new(false));
         let buffer: Rc<RefCell<Vec<u8>>> = Rc::new(RefCell::new(Vec::new()));
-        
+
         let output_sink = LoLOutputter {
             //waker: waker.clone(),
             done: Rc::clone(&done),
@@ -57,54 +60,144 @@ where
 
         Self {
             buffer,
-            rewriter,
+            rewriter: Some(rewriter),
             writer,
             no_output,
-            is_write_pending: false
+            is_write_pending: false,
+            stats: None,
         }
     }
+
+    pub fn with_stats(mut self, stats: Rc<RefCell<ShadowApiStats>>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
 }
 
-impl<'h, W> AsyncWrite for ShadowApiRewriterAsync<'h, W>
-where
-    W: AsyncWrite + Unpin
-{
-    fn poll_write(
+impl<'h, W: Unpin> ShadowApiRewriterAsync<'h, W> {
+    // Shared by the futures and tokio implementations. poll_inner writes to the inner writer
+    fn poll_write_with(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
+        poll_inner: PollWrite<W>,
     ) -> Poll<std::io::Result<usize>> {
         let this = self.project();
         if !*this.is_write_pending {
-            if let Err(err) = this.rewriter.write(buf) {
-                return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Other, format!("[HtmlRewriterError] {}", err))));
+            let Some(rewriter) = this.rewriter.as_mut() else {
+                return Poll::Ready(Err(std::io::Error::other("[HtmlRewriterError] write after close")));
+            };
+            if let Err(err) = rewriter.write(buf) {
+                return Poll::Ready(Err(std::io::Error::other(format!("[HtmlRewriterError] {}", err))));
             };
+            if let Some(stats) = this.stats.as_ref() {
+                stats.borrow_mut().bytes_in += buf.len();
+            }
         }
-        if *this.no_output {
-            Poll::Ready(Ok(0))
-        } else {
-            let buffer_rc = Rc::clone(&this.buffer);
-            let mut buffer = buffer_rc.borrow_mut();
-            match this.writer.poll_write(cx, &buffer) {
-                Poll::Ready(done) => {
-                    *this.is_write_pending = false;
-                    // Buffered data dumped
-                    buffer.clear();
-                    Poll::Ready(done)
-                },
-                Poll::Pending => {
-                    *this.is_write_pending = true;
-                    Poll::Pending
+        match Self::poll_drain(this.buffer, this.writer.get_mut(), this.stats.as_ref(), cx, poll_inner) {
+            Poll::Ready(Ok(())) => {
+                *this.is_write_pending = false;
+                // The whole input has been consumed, even if nothing is written (no_output)
+                Poll::Ready(Ok(buf.len()))
+            },
+            Poll::Ready(Err(err)) => {
+                *this.is_write_pending = false;
+                Poll::Ready(Err(err))
+            },
+            Poll::Pending => {
+                *this.is_write_pending = true;
+                Poll::Pending
+            },
+        }
+    }
+
+    // Writes the buffered output to the inner writer, until the buffer is empty
+    fn poll_drain(
+        buffer: &Rc<RefCell<Vec<u8>>>,
+        writer: &mut W,
+        stats: Option<&Rc<RefCell<ShadowApiStats>>>,
+        cx: &mut Context<'_>,
+        poll_inner: PollWrite<W>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut buffer = buffer.borrow_mut();
+        while !buffer.is_empty() {
+            match poll_inner(Pin::new(&mut *writer), cx, &buffer) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => {
+                    if let Some(stats) = stats {
+                        stats.borrow_mut().bytes_out += written;
+                    }
+                    buffer.drain(..written);
                 },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
             }
         }
+        Poll::Ready(Ok(()))
+    }
+
+    // Ends the rewriter (once) and writes the remaining output
+    fn poll_end(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        poll_inner: PollWrite<W>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        if let Some(rewriter) = this.rewriter.take() {
+            if let Err(err) = rewriter.end() {
+                return Poll::Ready(Err(std::io::Error::other(format!("[HtmlRewriterError] {}", err))));
+            }
+        }
+        Self::poll_drain(this.buffer, this.writer.get_mut(), this.stats.as_ref(), cx, poll_inner)
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<'h, W> futures::AsyncWrite for ShadowApiRewriterAsync<'h, W>
+where
+    W: futures::AsyncWrite + Unpin
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_with(cx, buf, <W as futures::AsyncWrite>::poll_write)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        AsyncWrite::poll_flush(Pin::new(&mut self.writer), cx)
+        futures::AsyncWrite::poll_flush(Pin::new(&mut self.writer), cx)
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        AsyncWrite::poll_close(Pin::new(&mut self.writer), cx)
+        match self.as_mut().poll_end(cx, <W as futures::AsyncWrite>::poll_write) {
+            Poll::Ready(Ok(())) => futures::AsyncWrite::poll_close(Pin::new(&mut self.writer), cx),
+            other => other,
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "tokio")]
+impl<'h, W> tokio::io::AsyncWrite for ShadowApiRewriterAsync<'h, W>
+where
+    W: tokio::io::AsyncWrite + Unpin
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_with(cx, buf, <W as tokio::io::AsyncWrite>::poll_write)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.writer), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.as_mut().poll_end(cx, <W as tokio::io::AsyncWrite>::poll_write) {
+            Poll::Ready(Ok(())) => tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.writer), cx),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::{pin::Pin, task::{Context, Poll}};
+use bytes::{Buf, Bytes};
+use http_body::{Body, Frame};
+use pin_project_lite::pin_project;
+
+use super::{ShadowApiSession, ShadowError};
+
+pin_project! {
+    /// http_body::Body adapter rewriting the data frames of the wrapped body as they flow, for hyper/axum based proxies
+    /// The output emitted at the end of the document (the data injection, or the whole data with the as_json option) is sent as the last data frame, before the trailers if any
+    /// As the handlers rely on Rc, the body is not Send : it is meant for single threaded (local) executors
+    pub struct ShadowApiBody<'h, B> {
+        #[pin]
+        inner: B,
+        session: Option<ShadowApiSession<'h>>, // None once ended
+        trailers: Option<http::HeaderMap>, // Trailers received from the inner body, sent after the last data frame
+    }
+}
+
+impl<'h, B> ShadowApiBody<'h, B> {
+    pub fn new(inner: B, session: ShadowApiSession<'h>) -> Self {
+        Self { inner, session: Some(session), trailers: None }
+    }
+}
+
+impl<'h, B> Body for ShadowApiBody<'h, B>
+where
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        loop {
+            let Some(session) = this.session.as_mut() else {
+                // Ended : only the trailers may remain
+                return Poll::Ready(this.trailers.take().map(|trailers| Ok(Frame::trailers(trailers))));
+            };
+            let data = match this.inner.as_mut().poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => Some(data),
+                    Err(frame) => {
+                        // Trailers : the document is complete
+                        *this.trailers = frame.into_trailers().ok();
+                        None
+                    },
+                },
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err.into()))),
+                Poll::Ready(None) => None,
+                Poll::Pending => return Poll::Pending,
+            };
+            let output = match data {
+                Some(mut data) => session.write(&data.copy_to_bytes(data.remaining()))?,
+                None => {
+                    let session = this.session.take().ok_or_else(|| ShadowError::from("Session already ended"))?;
+                    session.end()?.output
+                },
+            };
+            if !output.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(Bytes::from(output)))));
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.session.is_none() && self.trailers.is_none()
+    }
+}
@@ -0,0 +1,314 @@
+use std::{borrow::Cow, cell::RefCell, rc::Rc, str::FromStr};
+use lol_html::{html_content::DocumentEnd, DocumentContentHandlers, ElementContentHandlers, Selector};
+
+use super::{CompleteCallback, CspNonceProvider, DataCallback, DataTransformer, DocumentEndCallback, InlineRewriter, ItemSink, MissingRequiredCallback, ShadowApi, ShadowApiOptions, ShadowData, ShadowError, ShadowJson, ShadowMasker, ShadowSanitizer, ShadowTextReplacer, StopCondition};
+
+/// Builds a ready to use ShadowApi : settings are applied in the right order, then the definitions are parsed
+/// ```ignore
+/// let shadow_api_o = ShadowApiBuilder::new()
+///     .options(options)
+///     .formatter(|data: String| format!("<script>{}</script>", data))
+///     .definitions(json_def)
+///     .errors(Rc::clone(&errors))
+///     .build();
+/// ```
+pub struct ShadowApiBuilder<'h> {
+    options: Option<ShadowApiOptions>,
+    max_chunk_bytesize: Option<usize>,
+    data_formatter: Option<Box<dyn Fn(String) -> String>>,
+    json_def: Vec<Rc<RefCell<ShadowJson>>>,
+    errors: Rc<RefCell<Vec<String>>>,
+    data_transformer: Option<DataTransformer>,
+    on_document_end: Option<DocumentEndCallback>,
+    on_complete: Option<CompleteCallback>,
+    on_missing_required: Option<MissingRequiredCallback>,
+    inline_rewriter: Option<InlineRewriter>,
+    csp_nonce: Option<CspNonceProvider>,
+    sanitizer: Option<ShadowSanitizer>,
+    text_replacer: Option<ShadowTextReplacer>,
+    masker: Option<ShadowMasker>,
+    flags: Vec<(String, String)>,
+    item_sink: Option<ItemSink>,
+    data_listeners: Vec<(String, DataCallback)>,
+    stop_condition: Option<StopCondition>,
+    sentinel: Option<(String, usize)>,
+    attribute_injection: Option<(String, Vec<(String, String)>)>,
+    low_priority_paths: Vec<String>,
+    ld_json_key: Option<String>,
+    element_handlers: Vec<(String, ElementContentHandlers<'h>)>,
+    document_handlers: Vec<DocumentContentHandlers<'h>>,
+}
+
+impl Default for ShadowApiBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'h> ShadowApiBuilder<'h> {
+    pub fn new() -> Self {
+        Self {
+            options: None,
+            max_chunk_bytesize: None,
+            data_formatter: None,
+            json_def: Vec::new(),
+            errors: Rc::new(RefCell::new(Vec::new())),
+            data_transformer: None,
+            on_document_end: None,
+            on_complete: None,
+            on_missing_required: None,
+            inline_rewriter: None,
+            csp_nonce: None,
+            sanitizer: None,
+            text_replacer: None,
+            masker: None,
+            flags: Vec::new(),
+            item_sink: None,
+            data_listeners: Vec::new(),
+            stop_condition: None,
+            sentinel: None,
+            attribute_injection: None,
+            low_priority_paths: Vec::new(),
+            ld_json_key: None,
+            element_handlers: Vec::new(),
+            document_handlers: Vec::new(),
+        }
+    }
+
+    pub fn options(mut self, options: ShadowApiOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    /// Size of the chunks written to the output, see ShadowApi::set_max_chunk_bytesize
+    pub fn chunk_size(mut self, bytesize: usize) -> Self {
+        self.max_chunk_bytesize = Some(bytesize);
+        self
+    }
+
+    /// Formats the collected data for injection. Defaults to `<script>var shadow_api_data = {data};</script>`
+    pub fn formatter(mut self, formatter: impl Fn(String) -> String + 'static) -> Self {
+        self.data_formatter = Some(Box::new(formatter));
+        self
+    }
+
+    /// Adds definitions, parsed by build in the order they were added
+    pub fn definitions(mut self, json_def: impl IntoIterator<Item = Rc<RefCell<ShadowJson>>>) -> Self {
+        self.json_def.extend(json_def);
+        self
+    }
+
+    pub fn definition(mut self, json_def: ShadowJson) -> Self {
+        self.json_def.push(Rc::new(RefCell::new(json_def)));
+        self
+    }
+
+    /// Container the definition errors are written to by build. Pass the same container to process_html to collect the processing errors as well
+    /// Without it, the definition errors are only kept by the builder and lost
+    pub fn errors(mut self, errors: Rc<RefCell<Vec<String>>>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    pub fn data_transformer(mut self, data_transformer: impl Fn(&mut ShadowData) + 'static) -> Self {
+        self.data_transformer = Some(Rc::new(Box::new(data_transformer)));
+        self
+    }
+
+    pub fn on_document_end(mut self, on_document_end: impl Fn(&mut DocumentEnd, &ShadowData) + 'static) -> Self {
+        self.on_document_end = Some(Rc::new(Box::new(on_document_end)));
+        self
+    }
+
+    /// See ShadowApi::set_on_missing_required
+    /// See ShadowApi::on_complete
+    pub fn on_complete(mut self, on_complete: impl Fn(&ShadowData) + 'static) -> Self {
+        self.on_complete = Some(Rc::new(Box::new(on_complete)));
+        self
+    }
+
+    pub fn on_missing_required(mut self, on_missing_required: impl Fn(&[ShadowError]) + 'static) -> Self {
+        self.on_missing_required = Some(Rc::new(Box::new(on_missing_required)));
+        self
+    }
+
+    pub fn inline_rewriter(mut self, inline_rewriter: impl Fn(&str, &mut String) + 'static) -> Self {
+        self.inline_rewriter = Some(Rc::new(Box::new(inline_rewriter)));
+        self
+    }
+
+    pub fn csp_nonce(mut self, nonce: impl Into<String>) -> Self {
+        let nonce: String = nonce.into();
+        self.csp_nonce = Some(Rc::new(Box::new(move || nonce.clone())));
+        self
+    }
+
+    pub fn csp_nonce_provider(mut self, provider: impl Fn() -> String + 'static) -> Self {
+        self.csp_nonce = Some(Rc::new(Box::new(provider)));
+        self
+    }
+
+    pub fn sanitizer(mut self, sanitizer: ShadowSanitizer) -> Self {
+        self.sanitizer = Some(sanitizer);
+        self
+    }
+
+    /// See ShadowApi::set_text_replacer
+    pub fn text_replacer(mut self, text_replacer: ShadowTextReplacer) -> Self {
+        self.text_replacer = Some(text_replacer);
+        self
+    }
+
+    /// See ShadowApi::set_masker
+    pub fn masker(mut self, masker: ShadowMasker) -> Self {
+        self.masker = Some(masker);
+        self
+    }
+
+    /// See ShadowApi::set_flags
+    pub fn flags<K: Into<String>, V: Into<String>>(mut self, flags: impl IntoIterator<Item = (K, V)>) -> Self {
+        self.flags = flags.into_iter().map(|(name, value)| (name.into(), value.into())).collect();
+        self
+    }
+
+    pub fn item_sink(mut self, item_sink: impl Fn(&[u8]) + 'static) -> Self {
+        self.item_sink = Some(Rc::new(Box::new(item_sink)));
+        self
+    }
+
+    /// See ShadowApi::on_data
+    pub fn on_data(mut self, path_pattern: &str, callback: impl Fn(&str, &ShadowData) + 'static) -> Self {
+        self.data_listeners.push((path_pattern.to_string(), Rc::new(Box::new(callback))));
+        self
+    }
+
+    /// See ShadowApi::set_stop_condition
+    pub fn stop_condition(mut self, stop_condition: impl Fn(&ShadowData) -> bool + 'static) -> Self {
+        self.stop_condition = Some(Rc::new(Box::new(stop_condition)));
+        self
+    }
+
+    /// See ShadowApi::set_sentinel. An invalid selector is reported to the errors by build
+    pub fn sentinel(mut self, selector: &str, max_bytes: usize) -> Self {
+        self.sentinel = Some((selector.to_string(), max_bytes));
+        self
+    }
+
+    /// See ShadowApi::set_low_priority_paths
+    pub fn low_priority_paths<S: Into<String>>(mut self, paths: impl IntoIterator<Item = S>) -> Self {
+        self.low_priority_paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// See ShadowApi::set_attribute_injection. An invalid selector is reported to the errors by build
+    pub fn attribute_injection(mut self, selector: &str, attributes: &[(&str, &str)]) -> Self {
+        let attributes = attributes.iter().map(|(name, path)| (name.to_string(), path.to_string())).collect();
+        self.attribute_injection = Some((selector.to_string(), attributes));
+        self
+    }
+
+    /// See ShadowApi::set_ld_json_key. An invalid key is reported to the errors by build
+    pub fn ld_json_key(mut self, key: &str) -> Self {
+        self.ld_json_key = Some(key.to_string());
+        self
+    }
+
+    /// Adds LOLHTML element content handlers, run after the handlers of the definitions for the same element. An invalid selector is reported to the errors by build
+    pub fn element_handler(mut self, selector: &str, handlers: ElementContentHandlers<'h>) -> Self {
+        self.element_handlers.push((selector.to_string(), handlers));
+        self
+    }
+
+    /// Adds LOLHTML document content handlers, run after the handlers of the definitions
+    pub fn document_handler(mut self, handlers: DocumentContentHandlers<'h>) -> Self {
+        self.document_handlers.push(handlers);
+        self
+    }
+
+    /// Applies the settings, parses the definitions and registers the custom handlers
+    pub fn build(self) -> ShadowApi<'h> {
+        let mut shadow_api_o = ShadowApi::new(self.options);
+        if let Some(bytesize) = self.max_chunk_bytesize {
+            shadow_api_o.set_max_chunk_bytesize(bytesize);
+        }
+        if let Some(formatter) = self.data_formatter {
+            shadow_api_o.set_data_formatter(Rc::new(formatter));
+        }
+        if let Some(data_transformer) = self.data_transformer {
+            shadow_api_o.set_data_transformer(data_transformer);
+        }
+        if let Some(on_document_end) = self.on_document_end {
+            shadow_api_o.set_on_document_end(on_document_end);
+        }
+        if let Some(on_complete) = self.on_complete {
+            shadow_api_o.on_complete(on_complete);
+        }
+        if let Some(on_missing_required) = self.on_missing_required {
+            shadow_api_o.set_on_missing_required(on_missing_required);
+        }
+        if let Some(inline_rewriter) = self.inline_rewriter {
+            shadow_api_o.set_inline_rewriter(inline_rewriter);
+        }
+        if let Some(provider) = self.csp_nonce {
+            shadow_api_o.set_csp_nonce_provider(provider);
+        }
+        if let Some(sanitizer) = self.sanitizer {
+            shadow_api_o.set_sanitizer(sanitizer);
+        }
+        if let Some(text_replacer) = self.text_replacer {
+            shadow_api_o.set_text_replacer(text_replacer);
+        }
+        if let Some(masker) = self.masker {
+            shadow_api_o.set_masker(masker);
+        }
+        if let Some(item_sink) = self.item_sink {
+            shadow_api_o.set_item_sink(item_sink);
+        }
+        shadow_api_o.set_flags(self.flags);
+        shadow_api_o.set_low_priority_paths(self.low_priority_paths);
+        if let Some(stop_condition) = self.stop_condition {
+            shadow_api_o.set_stop_condition(stop_condition);
+        }
+        if let Some((selector, max_bytes)) = self.sentinel {
+            if let Err(err) = shadow_api_o.set_sentinel(&selector, max_bytes) {
+                self.errors.borrow_mut().push(err.to_string());
+            }
+        }
+        if let Some((selector, attributes)) = self.attribute_injection {
+            let attributes: Vec<(&str, &str)> = attributes.iter().map(|(name, path)| (name.as_str(), path.as_str())).collect();
+            if let Err(err) = shadow_api_o.set_attribute_injection(&selector, &attributes) {
+                self.errors.borrow_mut().push(err.to_string());
+            }
+        }
+        if let Some(key) = self.ld_json_key {
+            if let Err(err) = shadow_api_o.set_ld_json_key(&key) {
+                self.errors.borrow_mut().push(err.to_string());
+            }
+        }
+        for (path_pattern, callback) in self.data_listeners {
+            shadow_api_o.on_data(&path_pattern, callback);
+        }
+
+        shadow_api_o.parse(Rc::new(self.json_def), Rc::clone(&self.errors));
+
+        {
+            // With the two_pass option, the custom handlers run along with the rewriting handlers of the second pass
+            let mut ech_borrowed = shadow_api_o.ech.borrow_mut();
+            let mut dch_borrowed = shadow_api_o.dch.borrow_mut();
+            let mut second_pass = shadow_api_o.second_pass.borrow_mut();
+            let (ech, dch) = match second_pass.as_mut() {
+                Some((ech, dch)) => (ech, dch),
+                None => (&mut *ech_borrowed, &mut *dch_borrowed),
+            };
+            for (selector, handlers) in self.element_handlers {
+                match Selector::from_str(&selector) {
+                    Ok(selector_obj) => ech.push((Cow::Owned(selector_obj), handlers)),
+                    Err(err) => self.errors.borrow_mut().push(format!("[builder] invalid selector '{}' : {}", selector, err)),
+                }
+            }
+            dch.extend(self.document_handlers);
+        }
+
+        shadow_api_o
+    }
+}
@@ -0,0 +1,76 @@
+use std::{any::{Any, TypeId}, cell::{Cell, RefCell}, collections::HashMap, rc::Rc};
+use lol_html::Selector;
+use indexmap::IndexMap;
+use regex::Regex;
+
+use super::{DataListeners, ShadowDataCollisionPolicy, InlineRewriter, ShadowAggregate, ItemSink, LabelState, RegionState, RequiredState, SelectState, ShadowApiStats, ShadowMatchState, ShadowPass, ShadowPlanEntry, ShadowMasker, ShadowSanitizer, ShadowTraceEvent, ShadowDataUids, SiblingTracker};
+
+/// State shared by the handlers built by `ShadowApi::parse`, which returns it.
+/// User closures may keep their own state in it as typed extensions (one value per type), e.g. `cache.borrow_mut().insert(MyCounter(0))`
+#[derive(Default)]
+pub struct ShadowApiCache {
+    pub(super) regex_map: HashMap<String, Regex>, // Regexes compiled while parsing, by expression. Shared between nodes, seeded by CompiledShadowJson
+    pub(super) selectors: HashMap<String, Selector>, // Parsed selectors, by full selector
+    pub(super) match_states: HashMap<usize, Rc<RefCell<ShadowMatchState>>>, // Matching state of each node, by selector id
+    pub(super) label_states: HashMap<usize, Rc<RefCell<LabelState>>>, // Inputs and labels of the nodes defining data.label_selector, by selector id
+    pub(super) region_states: HashMap<usize, Rc<RefCell<RegionState>>>, // Position relative to the marker comments of the nodes defining a region, by selector id
+    pub(super) text_values: HashMap<usize, Rc<Cell<bool>>>, // Whether the element streamed by a node collecting a Value takes it from its text (textarea, contenteditable), by selector id
+    pub(super) select_states: HashMap<usize, Rc<RefCell<SelectState>>>, // Select element streamed by the nodes collecting its Value, by selector id
+    pub(super) required_states: IndexMap<usize, Rc<RefCell<RequiredState>>>, // Data items of the nodes defining required data, by selector id in definition order
+    pub(super) aggregates: Vec<(String, ShadowAggregate)>, // Values of the aggregate sections, by target path in definition order
+    pub(super) stats: Rc<RefCell<ShadowApiStats>>,
+    pub(super) next_rule: usize, // Index of the next rule registered, numbering the rules of the statistics
+    pub(super) inline_rewriter: Option<InlineRewriter>,
+    pub(super) sanitizer: Option<Rc<ShadowSanitizer>>,
+    pub(super) masker: Option<Rc<ShadowMasker>>, // Masker of the collected data, see ShadowApi::set_masker
+    pub(super) csp_nonce: Option<String>,
+    pub(super) item_sink: Option<ItemSink>,
+    pub(super) data_listeners: DataListeners,
+    pub(super) stop_requested: Rc<Cell<bool>>, // Set once an element of a node defining stop_after closes
+    pub(super) failed: Rc<Cell<bool>>, // Set once a handler failed with the fail_open error policy : the handlers stop applying
+    pub(super) pass: Rc<Cell<ShadowPass>>, // Pass run by the handlers, see the two_pass option
+    pub(super) trace: Option<Rc<RefCell<Vec<ShadowTraceEvent>>>>, // Events recorded with the trace option
+    pub(super) flags: Rc<HashMap<String, String>>, // Runtime flags of the enabled_if conditions, see ShadowApi::set_flags
+    pub(super) collision_policy: ShadowDataCollisionPolicy, // See the collision_policy option, for the handlers registered without the options
+    pub(super) uids: Option<ShadowDataUids>, // Uid counter of the data created by the handlers, see the debug_uids option
+    pub(super) sibling_tracker: Option<Rc<RefCell<SiblingTracker>>>, // Positions of the elements among their siblings, when a node starts with a sibling combinator
+    pub(super) node_path: Option<String>, // Path of the node being parsed, e.g. "[0].sub[1]"
+    pub(super) plan: Vec<ShadowPlanEntry>, // Handlers registered while parsing, moved into the plan of the ShadowApi
+    pub(super) extensions: HashMap<TypeId, Box<dyn Any>>, // User values, by type
+}
+
+impl ShadowApiCache {
+    /// Amount of elements matched by the node so far, by selector id. Only the nodes registering an element handler count their matches,
+    /// which all of them do with the stats option
+    pub fn match_count(&self, selector_id: usize) -> Option<usize> {
+        self.match_states.get(&selector_id).map(|state| state.borrow().count)
+    }
+
+    /// Amount of regexes compiled for the match_replace operations and the extract values
+    pub fn regex_count(&self) -> usize {
+        self.regex_map.len()
+    }
+
+    /// Stores a user value, returning the previous value of the same type if any
+    pub fn insert<T: 'static>(&mut self, value: T) -> Option<T> {
+        self.extensions
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast::<T>().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.extensions.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref::<T>())
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut::<T>())
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.extensions
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast::<T>().ok())
+            .map(|value| *value)
+    }
+}
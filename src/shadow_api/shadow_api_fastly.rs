@@ -0,0 +1,59 @@
+use std::io::{Read, Write};
+use std::{cell::RefCell, rc::Rc};
+use fastly::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use fastly::Response;
+
+use super::{ShadowApi, ShadowError, MAX_CHUNK_BYTESIZE};
+
+impl<'h> ShadowApi<'h> {
+    /// Fastly Compute helper : streams the backend response to the client, rewriting its body chunk by chunk
+    /// With the as_json option, the response is sent once the whole body has been processed, with the collected data as body and the content type of the output encoding
+    /// The backend body must not be compressed (send the backend request with an identity Accept-Encoding, or enable Fastly's automatic decompression)
+    pub fn stream_fastly_response(
+        &self,
+        mut beresp: Response,
+        errors: Rc<RefCell<Vec<String>>>
+    ) -> Result<(), ShadowError> {
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+        let mut backend_body = beresp.take_body();
+        // The length of the body changes
+        beresp.remove_header(CONTENT_LENGTH);
+
+        let mut session = self.finalize_session();
+        let mut buf: [u8; MAX_CHUNK_BYTESIZE] = [0; MAX_CHUNK_BYTESIZE];
+        if as_json {
+            loop {
+                let n_bytes = backend_body.read(&mut buf)?;
+                if n_bytes == 0 {
+                    break; // Reading complete
+                }
+                session.write(&buf[0..n_bytes])?; // Nothing is emitted in as_json mode
+            }
+            let end = session.end()?;
+            beresp.set_header(CONTENT_TYPE, self.output_encoding().content_type());
+            beresp.set_body(end.output);
+            beresp.send_to_client();
+        } else {
+            let mut client_body = beresp.stream_to_client();
+            loop {
+                let n_bytes = match backend_body.read(&mut buf) {
+                    Ok(n_bytes) => n_bytes,
+                    Err(err) => {
+                        // The headers are already sent : the truncated response is ended as is
+                        errors.borrow_mut().push(format!("[stream_fastly_response] read error : {}", err));
+                        break;
+                    },
+                };
+                if n_bytes == 0 {
+                    break; // Reading complete
+                }
+                let output = session.write(&buf[0..n_bytes])?;
+                client_body.write_all(&output)?;
+            }
+            let end = session.end()?;
+            client_body.write_all(&end.output)?;
+            client_body.finish()?;
+        }
+        Ok(())
+    }
+}
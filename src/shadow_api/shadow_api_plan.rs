@@ -0,0 +1,31 @@
+use serde::Serialize;
+
+/// Handlers registered by `ShadowApi::parse` for a full selector, listed by `ShadowApi::plan` to audit what the definitions will do
+/// before any document is processed. Entries of identical selectors are matched once (see `ShadowApi::selector_count`)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShadowPlanEntry {
+    pub selector: String, // Full selector matched by lol_html
+    pub node: Option<String>, // Path of the node the handlers come from, e.g. "[0].sub[1]" for the second sub node of the first definition. None for the handlers of the options (collect_meta, data injection)
+    pub purpose: ShadowPlanPurpose,
+    pub element: bool, // Whether an element handler is registered
+    pub text: bool, // Whether a text handler is registered
+    pub end_tag: bool, // Whether the element handler may register end tag handlers, to act once the element closes
+}
+
+/// What the handlers of a plan entry are registered for
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowPlanPurpose {
+    Node, // Matching, edits and data collection of the node
+    RewriteUrls, // rewrite_urls of the node, for the element and its descendants
+    Inline, // inline of the node
+    Label, // Labels of the inputs collected with data.label_selector
+    Select, // Selected options of the select elements collected as a Value
+    Form, // Fields of the forms serialized with form
+    Region, // Marker comments and contents of region
+    Sibling, // Positions of the elements among their siblings, for the nodes starting with a sibling combinator
+    Meta, // collect_meta option
+    LdJson, // ld+json blocks collected with ShadowApi::set_ld_json_key
+    TextReplacer, // Elements excluded from the text replacer (see ShadowApi::set_text_replacer)
+    Inject, // Injection of the data before </body>
+}
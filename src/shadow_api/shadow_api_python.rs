@@ -0,0 +1,33 @@
+use std::{cell::RefCell, rc::Rc};
+use pyo3::{exceptions::PyValueError, prelude::*};
+
+use super::{ShadowApi, ShadowApiOptions, ShadowJson};
+
+/// Processes a whole document with rules (a definition or an array of definitions), returning the rewritten html (the encoded data
+/// with the as_json option), the collected data as a dict and the errors reported while processing. Invalid rules raise a ValueError
+#[pyfunction]
+#[pyo3(signature = (html, rules, options = None))]
+fn process<'py>(py: Python<'py>, html: &str, rules: &str, options: Option<&str>) -> PyResult<(String, Bound<'py, PyAny>, Vec<String>)> {
+    let defs = ShadowJson::try_parse_list(rules).map_err(|err| PyValueError::new_err(err.to_string()))?;
+    let options = options
+        .map(serde_json::from_str::<ShadowApiOptions>)
+        .transpose()
+        .map_err(|err| PyValueError::new_err(format!("[ShadowError] Invalid options : {}", err)))?;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = Vec::new();
+    let shadow_api = ShadowApi::new(options);
+    shadow_api.parse(Rc::new(defs.into_iter().map(|def| Rc::new(RefCell::new(def))).collect()), Rc::clone(&errors));
+    shadow_api.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+    let data = shadow_api.shadow_data_cursor.borrow().root.borrow().to_string();
+    drop(shadow_api);
+    // Loaded by the json module, which keeps the order of the keys
+    let data = py.import("json")?.call_method1("loads", (data,))?;
+    let errors = errors.take();
+    Ok((String::from_utf8_lossy(&output).into_owned(), data, errors))
+}
+
+/// Python module of the python feature, built with maturin
+#[pymodule]
+fn shadow_api_py(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(process, module)?)
+}
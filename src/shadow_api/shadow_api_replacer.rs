@@ -1,11 +1,20 @@
 use std::{cell::RefCell, rc::Rc};
 use lol_html::{HtmlRewriter, errors::RewritingError, Settings};
 
+use super::{ShadowApiStats, ShadowData, ShadowError, ShadowOutputEncoding};
+
+/// Output sink of the replacer : every rewritten chunk is copied into the shared buffer
+type ReplacerSink = Box<dyn FnMut(&[u8])>;
+/// Shared output buffer along with the amount of bytes written into it by the last call
+pub type ReplacedChunk = (Rc<RefCell<Vec<u8>>>, usize);
+
 
 pub struct ShadowApiReplacer<'h> {
-    pub rewriter: HtmlRewriter<'h, Box<dyn FnMut(&[u8])>>,
+    pub rewriter: HtmlRewriter<'h, ReplacerSink>,
     pub buffer: Rc<RefCell<Vec<u8>>>,
-    pub write_idx: Rc<RefCell<usize>>
+    pub write_idx: Rc<RefCell<usize>>,
+    pub stats: Option<Rc<RefCell<ShadowApiStats>>>, // Counts the bytes going through the replacer, if set
+    pub json_tail: Option<(Rc<RefCell<ShadowData>>, ShadowOutputEncoding)>, // Data appended by finish_into, set by ShadowApi::finalize_replacer in as_json mode
 }
 
 impl<'h> ShadowApiReplacer<'h> {
@@ -14,31 +23,47 @@ impl<'h> ShadowApiReplacer<'h> {
         let write_idx: Rc<RefCell<usize>> = Rc::new(RefCell::new(0usize));
         let buffer_to_move = Rc::clone(&buffer);
         let write_idx_to_move = Rc::clone(&write_idx);
-        let rewriter: HtmlRewriter<'h, Box<dyn FnMut(&[u8])>> = HtmlRewriter::new(
+        let rewriter: HtmlRewriter<'h, ReplacerSink> = HtmlRewriter::new(
             settings,
             Box::new(move |c: &[u8]| {
                 let mut buffer_ref = buffer_to_move.borrow_mut();
                 let mut write_idx_borrowed = write_idx_to_move.borrow_mut();
-                if buffer_ref.len() < *write_idx_borrowed + c.len() {
-                    buffer_ref.resize(*write_idx_borrowed + c.len(), b'\0');
-                }
-                let initial_pos = *write_idx_borrowed;
+                // Bytes past write_idx were already consumed, they can be overwritten
+                buffer_ref.truncate(*write_idx_borrowed);
+                buffer_ref.extend_from_slice(c);
                 *write_idx_borrowed += c.len();
-                buffer_ref[initial_pos..*write_idx_borrowed].copy_from_slice(c)
             })
         );
         Self {
             rewriter,
             buffer,
             write_idx,
+            stats: None,
+            json_tail: None,
         }
     }
 
+    pub fn with_stats(mut self, stats: Rc<RefCell<ShadowApiStats>>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Makes finish_into append the data, encoded with the given encoding
+    pub fn with_json_tail(mut self, data: Rc<RefCell<ShadowData>>, encoding: ShadowOutputEncoding) -> Self {
+        self.json_tail = Some((data, encoding));
+        self
+    }
+
     /// Writes data in the internal buffer. Written amount of bytes is returned along with the reference to the buffer
     /// Make sure to read only the amount of bytes written
-    pub fn replace(&mut self, chunk: &[u8]) -> Result<(Rc<RefCell<Vec<u8>>>, usize), RewritingError> {
+    pub fn replace(&mut self, chunk: &[u8]) -> Result<ReplacedChunk, RewritingError> {
         self.rewriter.write(chunk)?;
         let mut write_idx = self.write_idx.borrow_mut();
+        if let Some(stats) = &self.stats {
+            let mut stats_m = stats.borrow_mut();
+            stats_m.bytes_in += chunk.len();
+            stats_m.bytes_out += *write_idx;
+        }
         if *write_idx > 0 {
             let written = *write_idx;
             *write_idx = 0; // Reset becase we are about to consume it
@@ -48,7 +73,45 @@ impl<'h> ShadowApiReplacer<'h> {
         }
     }
 
+    /// Writes data directly at the end of the caller's buffer, and returns the bytes appended to it.
+    /// The buffer can be cleared and reused between calls, in which case no allocation happens once its capacity is large enough
+    pub fn replace_into<'o>(&mut self, chunk: &[u8], out: &'o mut Vec<u8>) -> Result<&'o [u8], RewritingError> {
+        let start = out.len();
+        // The caller's buffer temporarily takes the place of the internal one, so the output sink appends to it
+        std::mem::swap(&mut *self.buffer.borrow_mut(), out);
+        *self.write_idx.borrow_mut() = start;
+        let result = self.rewriter.write(chunk);
+        std::mem::swap(&mut *self.buffer.borrow_mut(), out);
+        let written = std::mem::take(&mut *self.write_idx.borrow_mut()) - start;
+        result?;
+        if let Some(stats) = &self.stats {
+            let mut stats_m = stats.borrow_mut();
+            stats_m.bytes_in += chunk.len();
+            stats_m.bytes_out += written;
+        }
+        Ok(&out[start..])
+    }
+
+    /// Ends the rewriter. The output emitted at the end of the document is left in the buffer, up to write_idx
     pub fn finish(self) -> Result<(), RewritingError> {
-        self.rewriter.end()
+        self.rewriter.end()?;
+        if let Some(stats) = &self.stats {
+            stats.borrow_mut().bytes_out += *self.write_idx.borrow();
+        }
+        Ok(())
+    }
+
+    /// Ends the rewriter and appends the remaining output to the caller's buffer, followed by the encoded data in as_json mode (see with_json_tail)
+    pub fn finish_into(self, out: &mut Vec<u8>) -> Result<(), ShadowError> {
+        let start = out.len();
+        self.rewriter.end()?;
+        out.extend_from_slice(&self.buffer.borrow()[..*self.write_idx.borrow()]);
+        if let Some((data, encoding)) = &self.json_tail {
+            data.borrow().to_writer(*encoding, &mut *out)?;
+        }
+        if let Some(stats) = &self.stats {
+            stats.borrow_mut().bytes_out += out.len() - start;
+        }
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,14 +1,31 @@
-use std::io;
+use std::{io, cell::RefCell, rc::Rc};
 use lol_html::{OutputSink, HtmlRewriter, errors::RewritingError};
 
+use super::ShadowApiStats;
+
 
 pub struct ShadowApiRewriter<'a, O: OutputSink> {
-    pub rewriter: HtmlRewriter<'a, O>
+    pub rewriter: HtmlRewriter<'a, O>,
+    pub stats: Option<Rc<RefCell<ShadowApiStats>>>, // Counts the bytes written to the rewriter, if set
 }
 
 impl<'a, O: OutputSink> ShadowApiRewriter<'a, O> {
     pub fn new(rewriter: HtmlRewriter<'a, O>) -> Self {
-        Self { rewriter }
+        Self { rewriter, stats: None }
+    }
+
+    pub fn with_stats(mut self, stats: Rc<RefCell<ShadowApiStats>>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Writes a chunk of the input html to the rewriter, counting it in the stats
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), RewritingError> {
+        self.rewriter.write(chunk)?;
+        if let Some(stats) = &self.stats {
+            stats.borrow_mut().bytes_in += chunk.len();
+        }
+        Ok(())
     }
 
     pub fn end(self) -> Result<(), RewritingError> {
@@ -25,8 +42,8 @@ impl<'a, O: OutputSink> AsMut<HtmlRewriter<'a, O>> for ShadowApiRewriter<'a, O>
 
 impl<O: OutputSink> io::Write for ShadowApiRewriter<'_, O> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.rewriter.write(buf) {
-            Ok(_) => Ok(buf.len()),
+        match self.write_chunk(buf) {
+            Ok(()) => Ok(buf.len()),
             Err(e) => Err(
                 std::io::Error::new(
                     io::ErrorKind::Interrupted, e.to_string()
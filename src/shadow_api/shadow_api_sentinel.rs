@@ -0,0 +1,55 @@
+use std::{borrow::Cow, cell::Cell, rc::Rc};
+use lol_html::{ElementContentHandlers, HtmlRewriter, Selector, Settings};
+
+// Looks for the sentinel selector (see ShadowApi::set_sentinel) in the first bytes of the input, buffering them until the decision is made
+pub(crate) struct SentinelScanner {
+    rewriter: HtmlRewriter<'static, fn(&[u8])>, // Only runs the sentinel handler, its output is discarded
+    found: Rc<Cell<bool>>,
+    max_bytes: usize,
+    buffered: Vec<u8>,
+}
+
+impl SentinelScanner {
+    pub(crate) fn new(selector: &Selector, max_bytes: usize) -> Self {
+        let found = Rc::new(Cell::new(false));
+        let found_c = Rc::clone(&found);
+        let rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![(
+                    Cow::Owned(selector.clone()),
+                    ElementContentHandlers::default().element(move |_el| {
+                        found_c.set(true);
+                        Ok(())
+                    })
+                )],
+                ..Settings::default()
+            },
+            (|_: &[u8]| {}) as fn(&[u8])
+        );
+        Self { rewriter, found, max_bytes, buffered: Vec::new() }
+    }
+
+    // Buffers the chunk and scans the part of it within the first max_bytes of the input
+    // Returns Some(true) once the sentinel is found, Some(false) once max_bytes were scanned without finding it, None while undecided
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Option<bool> {
+        let scanned = self.buffered.len();
+        self.buffered.extend_from_slice(chunk);
+        let scan_len = self.max_bytes.saturating_sub(scanned).min(chunk.len());
+        // A malformed document cannot hold the sentinel : the rewriter error is treated as its absence
+        if self.rewriter.write(&chunk[..scan_len]).is_err() {
+            return Some(false);
+        }
+        if self.found.get() {
+            Some(true)
+        } else if self.buffered.len() >= self.max_bytes {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    // Input read so far, to be processed (sentinel found) or passed through
+    pub(crate) fn into_buffered(self) -> Vec<u8> {
+        self.buffered
+    }
+}
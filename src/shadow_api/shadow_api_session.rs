@@ -0,0 +1,61 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{ShadowApiReplacer, ShadowData, ShadowOutputEncoding};
+
+use super::ShadowError;
+
+/// Push-parser facade over ShadowApiReplacer, for hosts where neither `Read` nor `Write` adapters are available.
+/// Every call to `write` returns the bytes emitted for that chunk, and `end` returns the remaining output along with the collected data
+pub struct ShadowApiSession<'h> {
+    replacer: ShadowApiReplacer<'h>,
+    data: Rc<RefCell<ShadowData>>,
+    as_json: bool,
+    output_encoding: ShadowOutputEncoding,
+}
+
+/// Result of a finished ShadowApiSession
+#[derive(Debug, Default)]
+pub struct ShadowApiSessionEnd {
+    pub output: Vec<u8>, // Output emitted while ending the rewriter. In as_json mode, this is the data serialized with the output encoding
+    pub data: String, // Collected data, serialized as json
+}
+
+impl<'h> ShadowApiSession<'h> {
+    pub fn new(replacer: ShadowApiReplacer<'h>, data: Rc<RefCell<ShadowData>>, as_json: bool) -> Self {
+        Self { replacer, data, as_json, output_encoding: ShadowOutputEncoding::default() }
+    }
+
+    /// Encoding of the data output by `end` in as_json mode
+    pub fn with_output_encoding(mut self, output_encoding: ShadowOutputEncoding) -> Self {
+        self.output_encoding = output_encoding;
+        self
+    }
+
+    /// Processes a chunk of the input html and returns the rewritten bytes emitted so far
+    /// In as_json mode the html is discarded, so nothing is returned until `end`
+    pub fn write(&mut self, chunk: &[u8]) -> Result<Vec<u8>, ShadowError> {
+        let mut output = Vec::new();
+        self.replacer.replace_into(chunk, &mut output)?;
+        if self.as_json {
+            output.clear();
+        }
+        Ok(output)
+    }
+
+    /// Ends the rewriter, returning the output emitted at the end of the document and the collected data
+    pub fn end(self) -> Result<ShadowApiSessionEnd, ShadowError> {
+        let buffer = Rc::clone(&self.replacer.buffer);
+        let write_idx = Rc::clone(&self.replacer.write_idx);
+        self.replacer.finish()?;
+
+        let data = self.data.borrow().to_string();
+        let output = if self.as_json {
+            let mut output = Vec::new();
+            self.data.borrow().to_writer(self.output_encoding, &mut output)?;
+            output
+        } else {
+            buffer.borrow()[..*write_idx.borrow()].to_vec()
+        };
+        Ok(ShadowApiSessionEnd { output, data })
+    }
+}
@@ -0,0 +1,36 @@
+use indexmap::IndexMap;
+use serde::Serialize;
+
+/// Statistics collected while streaming a document, retrieved with `ShadowApi::take_stats` once the rewriter is ended.
+/// A selector matching 0 elements usually means that the origin markup changed and that the definition needs to be updated
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShadowApiStats {
+    pub matches: IndexMap<usize, ShadowRuleMatches>, // Amount of elements matched by each rule, by rule index (definition order, including the rules which never matched). Empty unless the stats option is set
+    pub edits: usize, // Amount of edit operations applied (fragment insertions, attribute and content edits, hide/wrap/unwrap/empty/delete)
+    pub data_values: usize, // Amount of values captured into the data
+    pub data_bytes: usize, // Bytes of the values captured into the data, with their keys, as counted for the max_data_bytes option
+    pub bytes_in: usize, // Amount of html bytes written to the rewriter
+    pub bytes_out: usize, // Amount of bytes emitted by the rewriter
+}
+
+/// Elements matched by a rule of the definition
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShadowRuleMatches {
+    pub selector: String, // Full selector of the rule
+    pub count: usize,
+}
+
+/// Summary of a document processed by `ShadowApi::try_process_html` or `try_process_html_iter`
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShadowProcessReport {
+    pub bytes_read: usize, // Amount of bytes read from the input
+    pub bytes_written: usize, // Amount of bytes written to the output (the html, or the encoded data in as_json mode)
+    pub warnings: Vec<String>, // Non-fatal errors reported to the errors container while processing the document
+}
+
+impl ShadowApiStats {
+    /// Selectors of the definition which did not match any element
+    pub fn unmatched(&self) -> impl Iterator<Item = &str> {
+        self.matches.values().filter(|rule| rule.count == 0).map(|rule| rule.selector.as_str())
+    }
+}
@@ -0,0 +1,53 @@
+use crate::ShadowApiReplacer;
+
+use super::ShadowError;
+
+/// Sans-io driver of the rewriter, for runtimes with their own event loop (Fastly, custom proxies) : input chunks are pushed,
+/// and the rewritten bytes are pulled whenever the runtime is ready to send them, without any Read or Write adapter.
+/// In as_json mode the html is discarded, and the encoded data is the only output, available once finished
+pub struct ShadowApiStream<'h> {
+    replacer: Option<ShadowApiReplacer<'h>>, // Taken by finish
+    pending: Vec<u8>, // Output not pulled yet
+}
+
+impl<'h> ShadowApiStream<'h> {
+    pub fn new(replacer: ShadowApiReplacer<'h>) -> Self {
+        Self { replacer: Some(replacer), pending: Vec::new() }
+    }
+
+    /// Processes a chunk of the input html. The bytes it emits are kept until pulled
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), ShadowError> {
+        let replacer = self.replacer.as_mut().ok_or_else(|| ShadowError::Other("[stream] push after finish".to_string()))?;
+        let start = self.pending.len();
+        replacer.replace_into(chunk, &mut self.pending)?;
+        if replacer.json_tail.is_some() {
+            self.pending.truncate(start);
+        }
+        Ok(())
+    }
+
+    /// Appends the output emitted since the last pull at the end of out, returning the amount of bytes appended
+    pub fn pull(&mut self, out: &mut Vec<u8>) -> usize {
+        let pulled = self.pending.len();
+        out.append(&mut self.pending);
+        pulled
+    }
+
+    /// Ends the document. Its remaining output (followed by the encoded data in as_json mode) is then pulled as usual
+    pub fn finish(&mut self) -> Result<(), ShadowError> {
+        let mut replacer = self.replacer.take().ok_or_else(|| ShadowError::Other("[stream] finish called twice".to_string()))?;
+        match replacer.json_tail.take() {
+            Some((data, encoding)) => {
+                replacer.finish()?;
+                data.borrow().to_writer(encoding, &mut self.pending)?;
+            },
+            None => replacer.finish_into(&mut self.pending)?,
+        }
+        Ok(())
+    }
+
+    /// Whether finish was called
+    pub fn is_finished(&self) -> bool {
+        self.replacer.is_none()
+    }
+}
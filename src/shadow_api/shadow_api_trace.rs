@@ -0,0 +1,66 @@
+use std::{cell::RefCell, rc::Rc};
+use serde::Serialize;
+
+/// Action taken by the handlers while streaming a document, recorded with the trace option and retrieved with `ShadowApi::take_trace`.
+/// Selectors are the full selectors of the nodes, data paths are dot separated from the root ("" being the root itself)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ShadowTraceEvent {
+    Matched { selector: String, tag: String }, // An element matched the node (before limit/skip/nth are applied)
+    Edited { selector: String, ops: Vec<String> }, // Edit operations applied to the element, e.g. "append", "edit.attrs.href:upsert", "edit.content:match_replace"
+    DataEntered { selector: String, path: String }, // The element opened its data item at path
+    DataLeft { selector: String, path: String }, // The element closed, moving the data cursor back to path
+    Collected { selector: String, path: String }, // A value of data.values was stored at path
+}
+
+// Records the events of a node into the trace shared by all the nodes
+pub(crate) struct NodeTracer {
+    pub(crate) selector: String,
+    pub(crate) events: Rc<RefCell<Vec<ShadowTraceEvent>>>,
+}
+
+impl NodeTracer {
+    pub(crate) fn matched(&self, tag: String) {
+        self.events.borrow_mut().push(ShadowTraceEvent::Matched { selector: self.selector.clone(), tag });
+    }
+
+    pub(crate) fn edited(&self, ops: Vec<String>) {
+        if !ops.is_empty() {
+            self.events.borrow_mut().push(ShadowTraceEvent::Edited { selector: self.selector.clone(), ops });
+        }
+    }
+
+    pub(crate) fn data_entered(&self, path: String) {
+        self.events.borrow_mut().push(ShadowTraceEvent::DataEntered { selector: self.selector.clone(), path });
+    }
+
+    pub(crate) fn data_left(&self, path: String) {
+        self.events.borrow_mut().push(ShadowTraceEvent::DataLeft { selector: self.selector.clone(), path });
+    }
+
+    pub(crate) fn collected(&self, path: String) {
+        self.events.borrow_mut().push(ShadowTraceEvent::Collected { selector: self.selector.clone(), path });
+    }
+}
+
+// Edit operations applied to an element : counted for the stats, and named for the trace when it is enabled
+pub(crate) struct AppliedEdits {
+    pub(crate) count: usize,
+    pub(crate) ops: Option<Vec<String>>,
+}
+
+impl AppliedEdits {
+    pub(crate) fn new(trace: bool) -> Self {
+        Self { count: 0, ops: trace.then(Vec::new) }
+    }
+
+    pub(crate) fn add(&mut self, amount: usize, op: impl FnOnce() -> String) {
+        if amount == 0 {
+            return;
+        }
+        self.count += amount;
+        if let Some(ops) = &mut self.ops {
+            ops.push(op());
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::prelude::*;
+
+use super::{CompiledShadowJson, ShadowApiOptions, ShadowJson};
+
+/// WebAssembly bindings (wasm feature), to preview the definitions in a browser or in Node with the engine running at the edge :
+/// `new ShadowApiWasm(rulesJson, optionsJson)` then `process(html)`, returning `{html, data, errors}`
+#[wasm_bindgen]
+pub struct ShadowApiWasm {
+    compiled: CompiledShadowJson,
+    options: Option<ShadowApiOptions>,
+}
+
+#[wasm_bindgen]
+impl ShadowApiWasm {
+    /// rules_json holds a definition or an array of definitions, options_json the options, if any. Invalid ones throw an Error
+    #[wasm_bindgen(constructor)]
+    pub fn new(rules_json: &str, options_json: Option<String>) -> Result<ShadowApiWasm, JsError> {
+        let defs = ShadowJson::try_parse_list(rules_json)?.into_iter().map(|def| Rc::new(RefCell::new(def))).collect();
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let compiled = CompiledShadowJson::compile(&Rc::new(defs), Rc::clone(&errors))?;
+        if let Some(error) = errors.borrow().first() {
+            return Err(JsError::new(error));
+        }
+        let options = options_json.map(|json| serde_json::from_str::<ShadowApiOptions>(&json)).transpose()?;
+        Ok(Self { compiled, options })
+    }
+
+    /// Processes a whole document, returning an object : html (the rewritten html, or the encoded data with the as_json option),
+    /// data (the collected data) and errors (the errors reported while processing, as strings)
+    pub fn process(&self, html: &str) -> Result<JsValue, JsError> {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output = Vec::new();
+        let shadow_api = self.compiled.instantiate(self.options, Rc::clone(&errors));
+        shadow_api.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+        let data = shadow_api.shadow_data_cursor.borrow().root.borrow().to_string();
+        drop(shadow_api);
+        // Assembled as a string to keep the order of the data keys
+        let result = format!(
+            "{{\"html\":{},\"data\":{},\"errors\":{}}}",
+            serde_json::to_string(&String::from_utf8_lossy(&output))?,
+            data,
+            serde_json::to_string(&*errors.borrow())?
+        );
+        js_sys::JSON::parse(&result).map_err(|_| JsError::new("[ShadowApiWasm] invalid result"))
+    }
+}
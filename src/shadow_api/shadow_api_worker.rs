@@ -0,0 +1,49 @@
+use std::pin::Pin;
+use futures::{Stream, StreamExt};
+
+use super::{ShadowApi, ShadowApiSession};
+
+type UpstreamState = (Option<ShadowApiSession<'static>>, Pin<Box<dyn Stream<Item = worker::Result<Vec<u8>>>>>);
+
+impl ShadowApi<'static> {
+    /// Cloudflare Workers adapter : rewrites a stream of html chunks (such as the `ByteStream` of a fetched `worker::Response`)
+    /// The returned stream can be passed to `worker::Response::from_stream`. In as_json mode, the collected data is its only non empty chunk
+    pub fn finalize_worker_stream<S>(&self, upstream: S) -> impl Stream<Item = worker::Result<Vec<u8>>> + 'static
+    where
+        S: Stream<Item = worker::Result<Vec<u8>>> + 'static
+    {
+        let state: UpstreamState = (Some(self.finalize_session()), Box::pin(upstream));
+        futures::stream::unfold(state, |(session, mut upstream)| async move {
+            let mut session = session?; // Ended
+            match upstream.next().await {
+                Some(Ok(chunk)) => {
+                    let output = session.write(&chunk).map_err(|e| worker::Error::RustError(e.to_string()));
+                    Some((output, (Some(session), upstream)))
+                },
+                Some(Err(err)) => Some((Err(err), (None, upstream))),
+                None => {
+                    let output = session.end()
+                        .map(|end| end.output)
+                        .map_err(|e| worker::Error::RustError(e.to_string()));
+                    Some((output, (None, upstream)))
+                },
+            }
+        })
+    }
+
+    /// Cloudflare Workers adapter : streams the fetched response back, rewriting its body. Status and headers are kept, except for Content-Length
+    /// With the as_json option, the content type is set to the one of the output encoding (application/json by default)
+    pub fn finalize_worker_response(&self, mut upstream: worker::Response) -> worker::Result<worker::Response> {
+        let as_json = self.options.map(|opts| opts.as_json).unwrap_or(false);
+        let headers = upstream.headers().clone();
+        headers.delete("content-length")?; // The length of the body changes
+        if as_json {
+            headers.set("content-type", self.output_encoding().content_type())?;
+        }
+        let status = upstream.status_code();
+        let stream = self.finalize_worker_stream(upstream.stream()?);
+        Ok(worker::Response::from_stream(stream)?
+            .with_status(status)
+            .with_headers(headers))
+    }
+}
@@ -0,0 +1,66 @@
+use std::{borrow::Cow, cell::{Cell, RefCell}, rc::Rc, str::FromStr};
+
+use lol_html::{ElementContentHandlers, Selector};
+
+use super::{KeyedHandlers, ShadowApi, ShadowApiCache, ShadowDataCursor, ShadowError, ShadowMasker, ShadowPass, ShadowPlanPurpose};
+
+// Element receiving the data as data-* attributes, see ShadowApi::set_attribute_injection
+pub(super) struct AttributeInjection {
+    pub selector: String,
+    pub selector_obj: Selector,
+    pub attributes: Vec<(String, String)>, // Attribute name (data-*) and data path
+}
+
+impl AttributeInjection {
+    // The attribute names not starting with "data-" are prefixed with it
+    pub fn new(selector: &str, attributes: &[(&str, &str)]) -> Result<Self, ShadowError> {
+        let selector_obj = Selector::from_str(selector).map_err(|err| ShadowError::Other(format!("[attribute_injection] invalid selector '{}' : {}", selector, err)))?;
+        let attributes = attributes.iter()
+            .map(|(name, path)| {
+                let name = if name.starts_with("data-") { name.to_string() } else { format!("data-{}", name) };
+                (name, path.to_string())
+            })
+            .collect();
+        Ok(Self { selector: selector.to_string(), selector_obj, attributes })
+    }
+
+    // Registers the handler of the attribute injection, setting the data-* attributes on the first element matching its selector
+    pub fn register(
+        &self,
+        ech: &mut KeyedHandlers,
+        cache: &RefCell<ShadowApiCache>,
+        shadow_data_cursor: Rc<RefCell<ShadowDataCursor>>,
+        data_masker: Option<Rc<ShadowMasker>>,
+        pass: Rc<Cell<ShadowPass>>,
+        failed: Rc<Cell<bool>>
+    ) {
+        let attributes = self.attributes.clone();
+        let injected = Cell::new(false);
+        ech.push((
+            self.selector.to_string(),
+            Cow::Owned(self.selector_obj.clone()),
+            ElementContentHandlers::default().element(move |el| {
+                if !pass.get().writes() || failed.get() || injected.replace(true) {
+                    return Ok(()); // Injected by the second pass, once, and not at all once the handlers failed
+                }
+                let root = Rc::clone(&shadow_data_cursor.borrow().root);
+                for (name, path) in &attributes {
+                    let Some(value) = root.borrow().get_path(path) else {
+                        continue;
+                    };
+                    let value_b = value.borrow();
+                    let mut text = match value_b.as_string() {
+                        Some(string) => string.borrow().clone(),
+                        None => value_b.to_string(),
+                    };
+                    if let Some(data_masker) = &data_masker {
+                        text = data_masker.mask(&text).into_owned();
+                    }
+                    el.set_attribute(name, &text)?;
+                }
+                Ok(())
+            })
+        ));
+        ShadowApi::record_plan(cache, &self.selector, &ech[ech.len() - 1].2, ShadowPlanPurpose::Inject, false);
+    }
+}
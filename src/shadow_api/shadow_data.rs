@@ -1,251 +1,679 @@
 use core::fmt;
-use rand::prelude::*;
-use std::{cell::RefCell, rc::{Rc, Weak}};
+use std::{cell::{Cell, RefCell}, io::Write, rc::{Rc, Weak}};
 
 use indexmap::IndexMap;
 use lol_html::html_content::{Element, EndTag};
+use serde::{Deserialize, Serialize, Serializer, ser::{SerializeMap, SerializeSeq}};
 
 use crate::{ShadowJson, ShadowDataCursor};
 
+use super::shadow_json::{parse_number, ShadowJsonData, ShadowJsonValueSource};
+
 use super::ShadowError;
 
-// ShadowData is a minimalistic tree structure representing json value which contains only Objects, Arrays or Strings, wrapped in Rc<RefCell<T>>
+// ShadowData is a minimalistic tree structure representing json value which contains only Objects, Arrays, Strings or Booleans, wrapped in Rc<RefCell<T>>
 // The reason we don't use serde::json for this is that while serde::json is able to deserialize into Rc (through a feature), RefCells are not supported
 #[derive(Debug)]
 pub struct ShadowData {
     pub id: Option<usize>, // Selector identifier
     pub parent: Weak<RefCell<ShadowData>>,
     pub v: ShadowDataValue,
-    pub uid: String, // Unique data element identified
+    pub uid: String, // Unique data element identifier, for debugging (see ShadowData::set_uid_mode and the debug_uids option). Empty unless enabled
+}
+
+/// How the uid of the data elements created by the current thread is generated, see ShadowData::set_uid_mode
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowDataUidMode {
+    #[default]
+    Disabled, // Uids are left empty
+    Counter, // Number incremented for each element
+    Seeded(usize), // Same as Counter, restarting at the seed : the uids (and ShadowData::visualize) are reproducible. Selector ids are not part of uids as they differ between instances
+}
+
+// ShadowData is not Send : the uid counter is kept per thread, so that a seeded sequence is not disturbed by other threads
+thread_local! {
+    static UID_COUNTER: Cell<Option<usize>> = const { Cell::new(None) }; // None when disabled
+    static UID_SCOPE: RefCell<Option<ShadowDataUids>> = const { RefCell::new(None) }; // Counter of the ShadowApi whose handlers are running, used instead of UID_COUNTER
+}
+
+// Uid counter of a ShadowApi with the debug_uids option, starting at 0. It is made current while the ShadowApi builds its data, so that
+// the uids of an instance depend neither on the other instances of the thread nor on ShadowData::set_uid_mode
+#[derive(Default, Clone)]
+pub(crate) struct ShadowDataUids(Rc<Cell<usize>>);
+
+impl ShadowDataUids {
+    // The uids of the data elements created until the returned scope is dropped are taken from this counter
+    pub fn enter(&self) -> ShadowDataUidScope {
+        ShadowDataUidScope(UID_SCOPE.with(|scope| scope.replace(Some(self.clone()))))
+    }
+}
+
+// Restores the counter which was current before ShadowDataUids::enter
+pub(crate) struct ShadowDataUidScope(Option<ShadowDataUids>);
+
+impl Drop for ShadowDataUidScope {
+    fn drop(&mut self) {
+        UID_SCOPE.with(|scope| *scope.borrow_mut() = self.0.take());
+    }
+}
+
+/// Defines how ShadowData::set resolves a key which already holds a value.
+/// This typically happens when a `data.values` key collides with a `path` built by another rule
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowDataCollisionPolicy {
+    Error, // The new value is rejected and an error is reported
+    KeepFirst, // The existing value is kept, the new value is discarded
+    KeepLast, // The new value replaces the existing one
+    #[default]
+    Merge, // Objects are merged recursively, any other combination behaves as KeepLast
+}
+
+/// Encoding of the collected data written by ShadowApi::process_data (as_json mode).
+/// MsgPack and Cbor require the msgpack and cbor features
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowOutputEncoding {
+    #[default]
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl ShadowOutputEncoding {
+    /// Content type to send along with the encoded data
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ShadowOutputEncoding::Json => "application/json",
+            ShadowOutputEncoding::MsgPack => "application/msgpack",
+            ShadowOutputEncoding::Cbor => "application/cbor",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ShadowDataValue {
     String(Rc<RefCell<String>>),
+    Bool(bool),
+    Number(serde_json::Number),
+    Null,
     Array(Vec<Rc<RefCell<ShadowData>>>),
     Object(IndexMap<String, Rc<RefCell<ShadowData>>>)
 }
 
+// Json value keeping the order of the keys of its objects, which serde_json::Value sorts
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OrderedJson {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+    Array(Vec<OrderedJson>),
+    Object(IndexMap<String, OrderedJson>),
+}
+
+// Value of an item compared by ShadowData::sort_by : numbers come before texts
+#[derive(Debug, PartialEq, PartialOrd)]
+enum SortValue {
+    Number(f64),
+    Text(String),
+}
+
+impl SortValue {
+    fn from_text(text: &str) -> Self {
+        match parse_number(text, None).and_then(|number| number.as_f64()) {
+            Some(number) => SortValue::Number(number),
+            None => SortValue::Text(text.to_string()),
+        }
+    }
+
+    fn compare(&self, other: &Self) -> std::cmp::Ordering {
+        self.partial_cmp(other).unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
 impl Default for ShadowData {
     fn default() -> Self {
         ShadowData {
             id: None,
             parent: Weak::new(),
-            uid: String::from("0_0000"),
+            uid: String::new(),
             v: ShadowDataValue::String(Rc::new(RefCell::new(String::new())))
         }
     }
 }
 
-impl fmt::Display for ShadowData {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+// Serialized as the json value it represents : ids, uids and parents are internal and left out
+impl Serialize for ShadowData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         match &self.v {
-            ShadowDataValue::String(v) => {
-                let val = v.borrow();
-                let parsed: String = serde_json::from_str(&format!("\"{}\"", val)).unwrap_or(String::from("")); // Using serde to escape the value
-                write!(f, "\"{}\"", parsed)
-            },
+            ShadowDataValue::String(v) => serializer.serialize_str(&v.borrow()),
+            ShadowDataValue::Bool(v) => serializer.serialize_bool(*v),
+            ShadowDataValue::Number(v) => v.serialize(serializer),
+            ShadowDataValue::Null => serializer.serialize_unit(),
             ShadowDataValue::Array(v) => {
-                write!(f, "[{}]", v.iter().fold(String::new(), |mut carry, x| {
-                    let borrowed = x.borrow();
-                    if carry.len() != 0 {
-                        carry += ",";
-                    }
-                    carry += &borrowed.to_string();
-                    carry
-                }))
+                let mut seq = serializer.serialize_seq(Some(v.len()))?;
+                for item in v {
+                    seq.serialize_element(&*item.borrow())?;
+                }
+                seq.end()
             },
             ShadowDataValue::Object(v) => {
-                write!(f, "{{{}}}", v.into_iter().fold(String::new(), |mut carry, (key,  value)| {
-                    let borrowed = value.borrow();
-                    if carry.len() != 0 {
-                        carry += ",";
-                    }
-                    format!("{}\"{}\":{}", carry, key, borrowed.to_string()).as_str().to_string()
-                }))
+                let mut map = serializer.serialize_map(Some(v.len()))?;
+                for (key, value) in v {
+                    map.serialize_entry(key, &*value.borrow())?;
+                }
+                map.end()
             },
         }
     }
 }
 
+// Thin wrapper over the serde serialization, producing compact json
+impl fmt::Display for ShadowData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+// Deep copy : the copied children point at their copied parent once it is wrapped (see ShadowData::wrap). The copy keeps the parent of the original
+impl Clone for ShadowData {
+    fn clone(&self) -> Self {
+        ShadowData { id: self.id, parent: Weak::clone(&self.parent), uid: self.uid.clone(), v: self.clone_value(&Weak::new()) }
+    }
+}
+
 impl ShadowData {
     pub fn wrap(s: Self) -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(s))
+        let wrapped = Rc::new(RefCell::new(s));
+        // Children built without their parent (e.g. by clone) are linked to it
+        for child in wrapped.borrow().children() {
+            if let Ok(mut child) = child.try_borrow_mut() {
+                if child.parent.upgrade().is_none() {
+                    child.parent = Rc::downgrade(&wrapped);
+                }
+            }
+        }
+        wrapped
     }
-    fn uid(id: Option<usize>) -> String {
-        // Pseudo random internal id for el identification
-        let mut nums: Vec<i32> = (1000..9999).collect();
-        nums.shuffle(&mut rand::thread_rng());
-        format!("{}_{}", id.unwrap_or(0), nums.first().unwrap())
+    /// Deep copy of data, linked to the parent of the original like ShadowData::clone, with every descendant linked to its copied parent
+    pub fn deep_clone(data: &Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
+        let data = data.borrow();
+        Rc::new_cyclic(|copy| RefCell::new(ShadowData { id: data.id, parent: Weak::clone(&data.parent), uid: data.uid.clone(), v: data.clone_value(copy) }))
+    }
+    // Copy of the value, the copied children pointing at parent
+    fn clone_value(&self, parent: &Weak<RefCell<Self>>) -> ShadowDataValue {
+        let clone_child = |child: &Rc<RefCell<Self>>| {
+            let child = child.borrow();
+            Rc::new_cyclic(|copy| RefCell::new(ShadowData { id: child.id, parent: Weak::clone(parent), uid: child.uid.clone(), v: child.clone_value(copy) }))
+        };
+        match &self.v {
+            ShadowDataValue::String(s) => ShadowDataValue::String(Rc::new(RefCell::new(s.borrow().clone()))),
+            ShadowDataValue::Bool(b) => ShadowDataValue::Bool(*b),
+            ShadowDataValue::Number(n) => ShadowDataValue::Number(n.clone()),
+            ShadowDataValue::Null => ShadowDataValue::Null,
+            ShadowDataValue::Array(arr) => ShadowDataValue::Array(arr.iter().map(clone_child).collect()),
+            ShadowDataValue::Object(obj) => ShadowDataValue::Object(obj.iter().map(|(key, child)| (key.clone(), clone_child(child))).collect()),
+        }
+    }
+    fn children(&self) -> Vec<&Rc<RefCell<Self>>> {
+        match &self.v {
+            ShadowDataValue::Array(arr) => arr.iter().collect(),
+            ShadowDataValue::Object(obj) => obj.values().collect(),
+            _ => Vec::new(),
+        }
+    }
+    /// Builds the data tree of a json value, as it is : numbers stay numbers and null values are kept
+    pub fn from_json(id: Option<usize>, parent: Weak<RefCell<ShadowData>>, value: &serde_json::Value) -> Rc<RefCell<Self>> {
+        match value {
+            serde_json::Value::Null => Self::wrap(Self::new_null(id, parent)),
+            serde_json::Value::Bool(b) => Self::wrap(Self::new_bool(id, parent, *b)),
+            serde_json::Value::Number(n) => Self::wrap(Self::new_number(id, parent, n.clone())),
+            serde_json::Value::String(s) => Self::wrap(Self::new_string(id, parent, s.clone())),
+            serde_json::Value::Array(arr) => {
+                let data = Self::wrap(Self::new_array(id, parent));
+                for item in arr {
+                    let item = Self::from_json(id, Rc::downgrade(&data), item);
+                    data.borrow_mut().push(item).unwrap_or(()); // Array built above
+                }
+                data
+            },
+            serde_json::Value::Object(obj) => {
+                let data = Self::wrap(Self::new_object(id, parent));
+                for (key, item) in obj {
+                    let item = Self::from_json(id, Rc::downgrade(&data), item);
+                    data.borrow_mut().set(key, item, ShadowDataCollisionPolicy::default()).unwrap_or(()); // Object built above
+                }
+                data
+            },
+        }
+    }
+    /// Builds the data tree of a json document embedded in the page (see the parse_json transform), keeping its numbers, its null values and the order of its keys.
+    /// The data is not wrapped yet, its children being linked to it by ShadowData::wrap
+    pub fn from_json_str(id: Option<usize>, parent: Weak<RefCell<ShadowData>>, json: &str) -> Result<Self, ShadowError> {
+        let json: OrderedJson = serde_json::from_str(json).map_err(|err| ShadowError::Data(format!("Invalid json : {}", err)))?;
+        let built = Self::build_ordered(id, Weak::new(), json);
+        let mut data = Self::new_null(id, parent);
+        data.v = std::mem::replace(&mut built.borrow_mut().v, ShadowDataValue::Null);
+        Ok(data)
+    }
+    fn build_ordered(id: Option<usize>, parent: Weak<RefCell<ShadowData>>, json: OrderedJson) -> Rc<RefCell<Self>> {
+        match json {
+            OrderedJson::Null => Self::wrap(Self::new_null(id, parent)),
+            OrderedJson::Bool(b) => Self::wrap(Self::new_bool(id, parent, b)),
+            OrderedJson::Number(n) => Self::wrap(Self::new_number(id, parent, n)),
+            OrderedJson::String(s) => Self::wrap(Self::new_string(id, parent, s)),
+            OrderedJson::Array(arr) => {
+                let data = Self::wrap(Self::new_array(id, parent));
+                for item in arr {
+                    let item = Self::build_ordered(id, Rc::downgrade(&data), item);
+                    data.borrow_mut().push(item).unwrap_or(()); // Array built above
+                }
+                data
+            },
+            OrderedJson::Object(obj) => {
+                let data = Self::wrap(Self::new_object(id, parent));
+                for (key, item) in obj {
+                    let item = Self::build_ordered(id, Rc::downgrade(&data), item);
+                    data.borrow_mut().set(&key, item, ShadowDataCollisionPolicy::default()).unwrap_or(()); // Object built above
+                }
+                data
+            },
+        }
+    }
+    /// Serializes the data as json directly into the writer, without building the whole string first
+    pub fn to_json_writer<W: Write>(&self, writer: W) -> Result<(), ShadowError> {
+        serde_json::to_writer(writer, self).map_err(|e| ShadowError::Data(format!("Unable to serialize data : {}", e)))
+    }
+    /// Serializes the data with the given encoding directly into the writer. The data is serialized once, without going through json
+    pub fn to_writer<W: Write>(&self, encoding: ShadowOutputEncoding, writer: W) -> Result<(), ShadowError> {
+        match encoding {
+            ShadowOutputEncoding::Json => self.to_json_writer(writer),
+            #[cfg(feature = "msgpack")]
+            ShadowOutputEncoding::MsgPack => {
+                let mut writer = writer;
+                rmp_serde::encode::write_named(&mut writer, self).map_err(|e| ShadowError::Data(format!("Unable to serialize data : {}", e)))
+            },
+            #[cfg(feature = "cbor")]
+            ShadowOutputEncoding::Cbor => ciborium::into_writer(self, writer).map_err(|e| ShadowError::Data(format!("Unable to serialize data : {}", e))),
+            #[allow(unreachable_patterns)]
+            _ => Err(ShadowError::Other(format!("The {:?} output encoding requires the corresponding crate feature", encoding))),
+        }
+    }
+    /// Sets how the uid of the data elements later created by the current thread is generated.
+    /// The data of a ShadowApi with the debug_uids option is numbered by the ShadowApi instead
+    pub fn set_uid_mode(mode: ShadowDataUidMode) {
+        UID_COUNTER.with(|counter| match mode {
+            ShadowDataUidMode::Disabled => counter.set(None),
+            ShadowDataUidMode::Counter => counter.set(Some(counter.get().unwrap_or(0))),
+            ShadowDataUidMode::Seeded(seed) => counter.set(Some(seed)),
+        });
+    }
+    fn uid() -> String {
+        let scoped = UID_SCOPE.with(|scope| scope.borrow().as_ref().map(|uids| uids.0.replace(uids.0.get() + 1)));
+        if let Some(n) = scoped {
+            return n.to_string();
+        }
+        UID_COUNTER.with(|counter| match counter.get() {
+            Some(n) => {
+                counter.set(Some(n + 1));
+                n.to_string()
+            },
+            None => String::new(),
+        })
     }
     pub fn new_string(id: Option<usize>, parent: Weak<RefCell<ShadowData>>, v: String) -> Self {
-        return ShadowData { id, parent, uid: Self::uid(id), v: ShadowDataValue::String(Rc::new(RefCell::new(v))) };
+        ShadowData { id, parent, uid: Self::uid(), v: ShadowDataValue::String(Rc::new(RefCell::new(v))) }
+    }
+    pub fn new_bool(id: Option<usize>, parent: Weak<RefCell<ShadowData>>, v: bool) -> Self {
+        ShadowData { id, parent, uid: Self::uid(), v: ShadowDataValue::Bool(v) }
+    }
+    pub fn new_number(id: Option<usize>, parent: Weak<RefCell<ShadowData>>, v: serde_json::Number) -> Self {
+        ShadowData { id, parent, uid: Self::uid(), v: ShadowDataValue::Number(v) }
+    }
+    pub fn new_null(id: Option<usize>, parent: Weak<RefCell<ShadowData>>) -> Self {
+        ShadowData { id, parent, uid: Self::uid(), v: ShadowDataValue::Null }
     }
     pub fn new_array(id: Option<usize>, parent: Weak<RefCell<ShadowData>>) -> Self {
-        return ShadowData { id, parent, uid: Self::uid(id), v: ShadowDataValue::Array(Vec::new()) };
+        ShadowData { id, parent, uid: Self::uid(), v: ShadowDataValue::Array(Vec::new()) }
     }
     pub fn new_object(id: Option<usize>, parent: Weak<RefCell<ShadowData>>) -> Self {
-        return ShadowData { id, parent, uid: Self::uid(id), v: ShadowDataValue::Object(IndexMap::new()) };
+        ShadowData { id, parent, uid: Self::uid(), v: ShadowDataValue::Object(IndexMap::new()) }
     }
     pub fn is_string(&self) -> bool {
-        return match &self.v {
-            ShadowDataValue::String(_) => true,
-            _ => false
-        }
+        matches!(&self.v, ShadowDataValue::String(_))
     }
     pub fn as_string(&self) -> Option<Rc<RefCell<String>>> {
-        return match &self.v {
+        match &self.v {
             ShadowDataValue::String(s) => Some(Rc::clone(s)),
             _ => None
         }
     }
-    pub fn is_array(&self) -> bool {
-        return match &self.v {
-            ShadowDataValue::Array(_) => true,
-            _ => false
+    pub fn is_bool(&self) -> bool {
+        matches!(&self.v, ShadowDataValue::Bool(_))
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match &self.v {
+            ShadowDataValue::Bool(b) => Some(*b),
+            _ => None
         }
     }
+    pub fn is_number(&self) -> bool {
+        matches!(&self.v, ShadowDataValue::Number(_))
+    }
+    pub fn as_number(&self) -> Option<&serde_json::Number> {
+        match &self.v {
+            ShadowDataValue::Number(n) => Some(n),
+            _ => None
+        }
+    }
+    pub fn is_null(&self) -> bool {
+        matches!(&self.v, ShadowDataValue::Null)
+    }
+    /// Whether the data holds nothing : empty string, null, empty array or empty object. Numbers and booleans are never empty
+    pub fn is_empty(&self) -> bool {
+        match &self.v {
+            ShadowDataValue::String(s) => s.borrow().is_empty(),
+            ShadowDataValue::Bool(_) | ShadowDataValue::Number(_) => false,
+            ShadowDataValue::Null => true,
+            ShadowDataValue::Array(a) => a.is_empty(),
+            ShadowDataValue::Object(o) => o.is_empty(),
+        }
+    }
+    pub fn is_array(&self) -> bool {
+        matches!(&self.v, ShadowDataValue::Array(_))
+    }
     pub fn as_array(&self) -> Option<&Vec<Rc<RefCell<Self>>>> {
-        return match &self.v {
+        match &self.v {
             ShadowDataValue::Array(s) => Some(s),
             _ => None
         }
     }
     pub fn as_array_mut(&mut self) -> Option<&mut Vec<Rc<RefCell<Self>>>> {
-        return match &mut self.v {
+        match &mut self.v {
             ShadowDataValue::Array(s) => Some(s),
             _ => None
         }
     }
     pub fn is_object(&self) -> bool {
-        return match &self.v {
-            ShadowDataValue::Object(_) => true,
-            _ => false
-        }
+        matches!(&self.v, ShadowDataValue::Object(_))
     }
     pub fn as_object(&self) -> Option<&IndexMap<String, Rc<RefCell<Self>>>> {
-        return match &self.v {
+        match &self.v {
             ShadowDataValue::Object(s) => Some(s),
             _ => None
         }
     }
     pub fn as_object_mut(&mut self) -> Option<&mut IndexMap<String, Rc<RefCell<Self>>>> {
-        return match &mut self.v {
+        match &mut self.v {
             ShadowDataValue::Object(s) => Some(s),
             _ => None
         }
     }
-    pub fn get(&self, key: &str) -> Option<Rc<RefCell<ShadowData>>> {
+    // Name of the subtype, for error messages
+    fn subtype(&self) -> &'static str {
         match &self.v {
-            ShadowDataValue::String(_) => panic!("ShadowData::get cannot be applied on String subtype"),
-            ShadowDataValue::Array(_) => panic!("ShadowData::get cannot be applied on Array subtype"),
-            ShadowDataValue::Object(o) => {
-                if let Some(val) = o.get(key) {
-                    Some(Rc::clone(val))
-                } else {
-                    None
-                }
-            }
+            ShadowDataValue::String(_) => "String",
+            ShadowDataValue::Bool(_) => "Bool",
+            ShadowDataValue::Number(_) => "Number",
+            ShadowDataValue::Null => "Null",
+            ShadowDataValue::Array(_) => "Array",
+            ShadowDataValue::Object(_) => "Object",
         }
     }
-    // Merges map2 into map1
-    pub fn merge(map1: &mut IndexMap<String, Rc<RefCell<ShadowData>>>, map2: &mut IndexMap<String, Rc<RefCell<ShadowData>>>) {
+    /// Value stored at key. None if the key is missing, or if the data is not an object
+    pub fn get(&self, key: &str) -> Option<Rc<RefCell<ShadowData>>> {
+        self.as_object().and_then(|o| o.get(key)).map(Rc::clone)
+    }
+    // Merges map2 into map1, the object map1_owner holds. The values moved from map2 are linked to map1_owner
+    pub fn merge(map1: &mut IndexMap<String, Rc<RefCell<ShadowData>>>, map1_owner: &Weak<RefCell<ShadowData>>, map2: &mut IndexMap<String, Rc<RefCell<ShadowData>>>) {
         for (subkey, map2_subval) in map2 {
-            let map2_rc = Rc::clone(map2_subval);
             let map1_subval_opt = map1.get_mut(subkey);
             if let Some(map1_subval) = map1_subval_opt {
-                let both_objects: bool;
-                {
-                    let map1_subval_borrowed = &map1_subval.borrow().v;
-                    let map2_subval_borrowed = &map2_rc.borrow().v;
-                    both_objects = match (map1_subval_borrowed, map2_subval_borrowed) {
-                        (ShadowDataValue::Object(_), ShadowDataValue::Object(_)) => true,
-                        _ => false
-                    }
-                }
+                let both_objects = matches!((&map1_subval.borrow().v, &map2_subval.borrow().v), (ShadowDataValue::Object(_), ShadowDataValue::Object(_)));
                 if both_objects {
                     // Recursive merge
+                    let map1_subval_weak = Rc::downgrade(map1_subval);
                     let mut map1_subval_borrowed = map1_subval.borrow_mut();
                     let mut map2_subval_borrowed = map2_subval.borrow_mut();
-                    let map1_subval_borrowed = map1_subval_borrowed.as_object_mut().unwrap();
-                    let map2_subval_borrowed = map2_subval_borrowed.as_object_mut().unwrap();
-                    Self::merge(map1_subval_borrowed, map2_subval_borrowed);
+                    if let (Some(sub_map1), Some(sub_map2)) = (map1_subval_borrowed.as_object_mut(), map2_subval_borrowed.as_object_mut()) {
+                        Self::merge(sub_map1, &map1_subval_weak, sub_map2);
+                    }
                 } else {
                     // Overriding the meaningful map1 value by map2 by repointing to the relevant data of map2
-                    map1_subval.swap(map2_subval);
+                    map2_subval.borrow_mut().parent = Weak::clone(map1_owner);
+                    *map1_subval = Rc::clone(map2_subval);
                 }
             } else {
+                map2_subval.borrow_mut().parent = Weak::clone(map1_owner);
                 map1.insert(subkey.clone(), Rc::clone(map2_subval));
             }
         }
     }
-    pub fn set(&mut self, key: &str, val: Rc<RefCell<ShadowData>>) {
+    // Sets val at key. If the key already holds a value, the collision is resolved according to policy. Fails if the data is not an object
+    pub fn set(&mut self, key: &str, val: Rc<RefCell<ShadowData>>, policy: ShadowDataCollisionPolicy) -> Result<(), ShadowError> {
+        let subtype = self.subtype();
         match &mut self.v {
-            ShadowDataValue::String(_) => panic!("ShadowData::set cannot be applied on String subtype"),
-            ShadowDataValue::Array(_) => panic!("ShadowData::set cannot be applied on Array subtype"),
+            ShadowDataValue::String(_) | ShadowDataValue::Bool(_) | ShadowDataValue::Number(_) | ShadowDataValue::Null | ShadowDataValue::Array(_) => {
+                Err(ShadowError::Data(format!("Cannot set '{}' : the data is a {}, not an Object", key, subtype)))
+            },
             ShadowDataValue::Object(ref mut o) => {
                 let existing_key_opt = o.get_mut(key);
                 if let Some(existing_key_rc) = existing_key_opt {
-                    // Data found at this key => merge
-                    let mut existing_key_borrowed = existing_key_rc.borrow_mut();
                     let mut override_flag = false;
-                    match &mut existing_key_borrowed.v {
-                        ShadowDataValue::String(_)
-                        | ShadowDataValue::Array(_) => {
-                            // A case where user definition writes into non-object key => override (avoid panic)
+                    match policy {
+                        ShadowDataCollisionPolicy::Error => {
+                            return Err(ShadowError::Data(format!("Data key collision : '{}' is already set", key)));
+                        },
+                        ShadowDataCollisionPolicy::KeepFirst => {
+                            // Existing data wins, val is discarded
+                        },
+                        ShadowDataCollisionPolicy::KeepLast => {
                             override_flag = true;
                         },
-                        ShadowDataValue::Object(sub_o) => {
-                            // self is an object => if val is an object too, merge . if val is not an object, override yet again
-                            let val_rc = Rc::clone(&val);
-                            let mut val_borrowed = val_rc.borrow_mut();
-                            match &mut val_borrowed.v {
+                        ShadowDataCollisionPolicy::Merge => {
+                            // Data found at this key => merge
+                            let mut existing_key_borrowed = existing_key_rc.borrow_mut();
+                            match &mut existing_key_borrowed.v {
                                 ShadowDataValue::String(_)
+                                | ShadowDataValue::Bool(_)
+                                | ShadowDataValue::Number(_)
+                                | ShadowDataValue::Null
                                 | ShadowDataValue::Array(_) => {
+                                    // A case where user definition writes into non-object key => override (avoid panic)
                                     override_flag = true;
                                 },
-                                ShadowDataValue::Object(val_object) => {
-                                    // Merging two objects
-                                    Self::merge(sub_o, val_object);
+                                ShadowDataValue::Object(sub_o) => {
+                                    // self is an object => if val is an object too, merge . if val is not an object, override yet again
+                                    let existing_key_weak = Rc::downgrade(existing_key_rc);
+                                    let val_rc = Rc::clone(&val);
+                                    let mut val_borrowed = val_rc.borrow_mut();
+                                    match &mut val_borrowed.v {
+                                        ShadowDataValue::String(_)
+                                        | ShadowDataValue::Bool(_)
+                                        | ShadowDataValue::Number(_)
+                                        | ShadowDataValue::Null
+                                        | ShadowDataValue::Array(_) => {
+                                            override_flag = true;
+                                        },
+                                        ShadowDataValue::Object(val_object) => {
+                                            // Merging two objects
+                                            Self::merge(sub_o, &existing_key_weak, val_object);
+                                        },
+                                    }
                                 },
                             }
                         },
                     }
                     if override_flag {
-                        *existing_key_borrowed = val.take(); // val's contents get swapped out, as they will now belong to the current structure
+                        // Repoint the key to val, so that references held on val (e.g. by the cursor) keep pointing at the stored data
+                        *existing_key_rc = Rc::clone(&val);
                     }
                 } else {
                     // There is no data in the object at this key
                     o.insert(key.to_string(), Rc::clone(&val));
                 }
+                Ok(())
+            }
+        }
+    }
+    // Appends val. Fails if the data is not an array
+    pub fn push(&mut self, val: Rc<RefCell<ShadowData>>) -> Result<(), ShadowError> {
+        match self.as_array_mut() {
+            Some(o) => {
+                o.push(val);
+                Ok(())
+            },
+            None => Err(ShadowError::Data(format!("Cannot push : the data is a {}, not an Array", self.subtype()))),
+        }
+    }
+    /// Dot separated path of target within this data, array items being designated by their index ("products.0.sku"). None if target is not part of it
+    pub fn path_of(&self, target: &Rc<RefCell<ShadowData>>) -> Option<String> {
+        self.segments_of(target).map(|segments| segments.join("."))
+    }
+    /// Keys and array indexes leading to target within this data, empty if target is this data. None if target is not part of it
+    pub fn segments_of(&self, target: &Rc<RefCell<ShadowData>>) -> Option<Vec<String>> {
+        if std::ptr::eq(self, target.as_ptr()) {
+            return Some(Vec::new());
+        }
+        let children: Vec<(String, &Rc<RefCell<ShadowData>>)> = match &self.v {
+            ShadowDataValue::Array(arr) => arr.iter().enumerate().map(|(idx, item)| (idx.to_string(), item)).collect(),
+            ShadowDataValue::Object(obj) => obj.iter().map(|(key, item)| (key.clone(), item)).collect(),
+            _ => return None,
+        };
+        for (key, child) in children {
+            if Rc::ptr_eq(child, target) {
+                return Some(vec![key]);
             }
+            // Children currently borrowed mutably are skipped
+            if let Some(mut sub_segments) = child.try_borrow().ok().and_then(|child| child.segments_of(target)) {
+                sub_segments.insert(0, key);
+                return Some(sub_segments);
+            }
+        }
+        None
+    }
+    // Segments of a path, either dot separated ("products.0.sku") or a RFC6901 JSON pointer ("/products/0/sku"). The empty path designates the data itself
+    fn path_segments(path: &str) -> Vec<String> {
+        if path.is_empty() {
+            Vec::new()
+        } else if let Some(pointer) = path.strip_prefix('/') {
+            pointer.split('/').map(|segment| segment.replace("~1", "/").replace("~0", "~")).collect()
+        } else {
+            path.split('.').map(|segment| segment.to_string()).collect()
+        }
+    }
+    // Child designated by segment : key of an object, index of an array
+    fn child(&self, segment: &str) -> Option<Rc<RefCell<ShadowData>>> {
+        match &self.v {
+            ShadowDataValue::Array(arr) => segment.parse::<usize>().ok().and_then(|idx| arr.get(idx)).map(Rc::clone),
+            ShadowDataValue::Object(obj) => obj.get(segment).map(Rc::clone),
+            _ => None,
+        }
+    }
+    /// Value found at path (see ShadowData::path_of), which is either dot separated ("products.0.sku") or a JSON pointer ("/products/0/sku")
+    /// None if nothing is found at path, or if path is empty since the data itself is not wrapped
+    pub fn get_path(&self, path: &str) -> Option<Rc<RefCell<ShadowData>>> {
+        self.get_segments(&Self::path_segments(path))
+    }
+    fn get_segments(&self, segments: &[String]) -> Option<Rc<RefCell<ShadowData>>> {
+        let (first, rest) = segments.split_first()?;
+        let mut current = self.child(first)?;
+        for segment in rest {
+            let next = current.borrow().child(segment)?;
+            current = next;
         }
+        Some(current)
     }
-    pub fn push(&mut self, val: Rc<RefCell<ShadowData>>) {
-        match self.v {
-            ShadowDataValue::String(_) => panic!("ShadowData::push cannot be applied on String subtype"),
-            ShadowDataValue::Array(ref mut o) => {
-                o.push(Rc::clone(&val));
+    /// Sets val at path (dot separated or JSON pointer), replacing the value found there. Missing intermediate keys are created as objects
+    /// Array items are designated by their index, the index following the last item (or "-") appending to the array
+    /// Fails if path is empty or goes through a string, bool, number or null, or through an array with a non index segment
+    /// The values set and the intermediate objects created are linked to their container, so that they can be walked back up from
+    pub fn set_path(data: &Rc<RefCell<ShadowData>>, path: &str, val: Rc<RefCell<ShadowData>>) -> Result<(), ShadowError> {
+        let segments = Self::path_segments(path);
+        if segments.is_empty() {
+            return Err(ShadowError::Data("Invalid data path : the path is empty".to_string()));
+        }
+        let result = data.borrow_mut().set_segments(&Rc::downgrade(data), path, &segments, val);
+        result
+    }
+    fn set_segments(&mut self, this: &Weak<RefCell<ShadowData>>, path: &str, segments: &[String], val: Rc<RefCell<ShadowData>>) -> Result<(), ShadowError> {
+        let segment = &segments[0];
+        let invalid = |reason: &str| ShadowError::Data(format!("Invalid data path '{}' at '{}' : {}", path, segment, reason));
+        if segments.len() == 1 {
+            val.borrow_mut().parent = Weak::clone(this);
+            match &mut self.v {
+                ShadowDataValue::Array(arr) => {
+                    let idx = if segment == "-" { arr.len() } else { segment.parse::<usize>().map_err(|_| invalid("not an array index"))? };
+                    match idx.cmp(&arr.len()) {
+                        std::cmp::Ordering::Less => arr[idx] = val,
+                        std::cmp::Ordering::Equal => arr.push(val),
+                        std::cmp::Ordering::Greater => return Err(invalid("array index out of bounds")),
+                    }
+                },
+                ShadowDataValue::Object(obj) => {
+                    obj.insert(segment.clone(), val);
+                },
+                _ => return Err(invalid("not an object or an array")),
             }
-            ShadowDataValue::Object(_) => panic!("ShadowData::push cannot be applied on Object subtype. Self : {:#?} Val: {:#?}", self, val),
+            return Ok(());
+        }
+        let child = match self.child(segment) {
+            Some(child) => child,
+            None => {
+                let child = ShadowData::wrap(ShadowData::new_object(self.id, Weak::clone(this)));
+                match &mut self.v {
+                    ShadowDataValue::Array(arr) if segment == "-" || segment.parse::<usize>().ok() == Some(arr.len()) => arr.push(Rc::clone(&child)),
+                    ShadowDataValue::Array(_) => return Err(invalid("array index out of bounds")),
+                    ShadowDataValue::Object(obj) => {
+                        obj.insert(segment.clone(), Rc::clone(&child));
+                    },
+                    _ => return Err(invalid("not an object or an array")),
+                }
+                child
+            },
+        };
+        let child_weak = Rc::downgrade(&child);
+        let result = child.borrow_mut().set_segments(&child_weak, path, &segments[1..], val);
+        result
+    }
+    /// Removes the value found at path (dot separated or JSON pointer) and returns it. Following array items are shifted
+    pub fn remove_path(&mut self, path: &str) -> Option<Rc<RefCell<ShadowData>>> {
+        let segments = Self::path_segments(path);
+        let (last, parents) = segments.split_last()?;
+        let remove = |data: &mut ShadowData| match &mut data.v {
+            ShadowDataValue::Array(arr) => last.parse::<usize>().ok().filter(|idx| *idx < arr.len()).map(|idx| arr.remove(idx)),
+            ShadowDataValue::Object(obj) => obj.shift_remove(last),
+            _ => None,
+        };
+        if parents.is_empty() {
+            remove(self)
+        } else {
+            let container = self.get_segments(parents)?;
+            let removed = remove(&mut container.borrow_mut());
+            removed
         }
     }
-    // Force conversion of data_orig into object, by pushing a new element into the array if it is one
-    pub fn force_object(data_orig: Rc<RefCell<ShadowData>>) -> Option<Rc<RefCell<ShadowData>>> {
+    // Force conversion of data_orig into object, by pushing a new element into the array if it is one. Fails for the other subtypes
+    pub fn force_object(data_orig: Rc<RefCell<ShadowData>>) -> Result<Option<Rc<RefCell<ShadowData>>>, ShadowError> {
         let rc_data_orig = Rc::clone(&data_orig);
         let mut borrowed = rc_data_orig.borrow_mut();
         let parent = Weak::clone(&borrowed.parent);
         let id = borrowed.id;
         match borrowed.v {
-            ShadowDataValue::String(_) => {
-                panic!("ShadowData::get_map_mut.force_object is neither object nor array. Program bug");
+            ShadowDataValue::String(_) | ShadowDataValue::Bool(_) | ShadowDataValue::Number(_) | ShadowDataValue::Null => {
+                Err(ShadowError::Data(format!("Cannot convert a {} into an Object", borrowed.subtype())))
             },
             ShadowDataValue::Array(ref mut data) => {
                 let new_data = ShadowData::wrap(ShadowData::new_object(id, parent));
                 data.push(Rc::clone(&new_data));
-                Some(new_data)
+                Ok(Some(new_data))
             },
-            ShadowDataValue::Object(_) => None, // Perfect as-is
+            ShadowDataValue::Object(_) => Ok(None), // Perfect as-is
         }
     }
     pub fn transform_strings(&mut self, f: &dyn Fn(&mut String)) {
@@ -253,6 +681,7 @@ impl ShadowData {
             ShadowDataValue::String(s) => {
                 f(&mut s.borrow_mut());
             },
+            ShadowDataValue::Bool(_) | ShadowDataValue::Number(_) | ShadowDataValue::Null => {},
             ShadowDataValue::Array(arr) => {
                 arr.iter().for_each(|a| {
                     // Cannot change keys (would require removing and reinserting new). Don't do for now
@@ -268,22 +697,99 @@ impl ShadowData {
         }
     }
 
+    /// Sorts the keys of the object and of all the objects it contains, arrays keeping the order of their items
+    pub fn sort_keys(&mut self) {
+        match &mut self.v {
+            ShadowDataValue::Array(arr) => arr.iter().for_each(|a| a.borrow_mut().sort_keys()),
+            ShadowDataValue::Object(obj) => {
+                obj.sort_keys();
+                obj.values().for_each(|a| a.borrow_mut().sort_keys());
+            },
+            _ => {},
+        }
+    }
+
+    /// Sorts the items of the array (or the entries of the object) by their value at key. Numbers and strings holding a number ("$1,280.50", read as by the parse_number transform) are compared
+    /// as numbers and come before the other strings. Items without the key are put last, in both orders. The sort is stable
+    pub fn sort_by(&mut self, key: &str, descending: bool) {
+        let sort_value = |data: &Rc<RefCell<ShadowData>>| {
+            let value = data.borrow().get(key)?;
+            let value_b = value.borrow();
+            match &value_b.v {
+                ShadowDataValue::Number(n) => Some(SortValue::Number(n.as_f64().unwrap_or_default())),
+                ShadowDataValue::String(s) => Some(SortValue::from_text(&s.borrow())),
+                ShadowDataValue::Bool(b) => Some(SortValue::Text(b.to_string())),
+                _ => None,
+            }
+        };
+        let compare = |a: &Option<SortValue>, b: &Option<SortValue>| match (a, b) {
+            (Some(a), Some(b)) if descending => b.compare(a),
+            (Some(a), Some(b)) => a.compare(b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+        match &mut self.v {
+            ShadowDataValue::Array(arr) => {
+                let mut keyed = arr.drain(..).map(|a| (sort_value(&a), a)).collect::<Vec<_>>();
+                keyed.sort_by(|(a, _), (b, _)| compare(a, b));
+                arr.extend(keyed.into_iter().map(|(_, a)| a));
+            },
+            ShadowDataValue::Object(obj) => obj.sort_by(|_, a, _, b| compare(&sort_value(a), &sort_value(b))),
+            _ => {},
+        }
+    }
+
+    // Fetches the nested data stored at key, or creates it when missing.
+    // If key holds data of another kind (e.g. a string where a path expects an object), the collision is resolved according to policy.
+    // With KeepFirst and Error, the created data is returned detached from the tree so that the rule can still be processed without altering the existing data
+    // Fails if data is not an object (e.g. a path going through an array or a value)
+    fn get_or_create(
+        data: &mut ShadowData,
+        key: &str,
+        policy: ShadowDataCollisionPolicy,
+        errors: &Rc<RefCell<Vec<String>>>,
+        create: impl FnOnce() -> ShadowData
+    ) -> Result<Rc<RefCell<ShadowData>>, ShadowError> {
+        if let Some(existing) = data.get(key) {
+            let new_data = create();
+            let same_kind = {
+                let existing_b = existing.borrow();
+                existing_b.is_array() == new_data.is_array() && existing_b.is_object() == new_data.is_object()
+            };
+            if same_kind {
+                return Ok(existing);
+            }
+            let new_data = ShadowData::wrap(new_data);
+            if policy != ShadowDataCollisionPolicy::KeepFirst {
+                if let Err(err) = data.set(key, Rc::clone(&new_data), policy) {
+                    errors.borrow_mut().push(err.to_string());
+                }
+            }
+            Ok(new_data)
+        } else {
+            let new_data = ShadowData::wrap(create());
+            data.set(key, Rc::clone(&new_data), policy)?;
+            Ok(new_data)
+        }
+    }
+
     // Returns the current Cell 
     pub fn on_data_tag_open(
-        _el: &mut Element,
+        el: &mut Element,
         selector_id: usize,
         json_def: Rc<RefCell<ShadowJson>>,
-        cursor: Rc<RefCell<ShadowDataCursor>>
+        cursor: Rc<RefCell<ShadowDataCursor>>,
+        collision_policy: ShadowDataCollisionPolicy,
+        max_array_len: Option<usize>,
+        errors: Rc<RefCell<Vec<String>>>
     ) -> Result<Option<Rc<RefCell<ShadowData>>>, ShadowError> {
         if let Some(data_def) = json_def.borrow().data.as_ref() {
             let path = data_def.path.clone();
             let mut cursor = cursor.borrow_mut();
 
-            let is_current = {
-                cursor.shadow_data.borrow_mut().id
-                    .and_then(|cur_id| {
-                        Some(cur_id == selector_id)
-                    })
+            let mut is_current = {
+                cursor.shadow_data.borrow_mut().id.map(|cur_id| cur_id == selector_id)
                     .unwrap_or(false)
             };
             let is_current_an_array = {
@@ -297,20 +803,32 @@ impl ShadowData {
             }
 
             if let Some(mut path) = path {
+                if let Some(rest) = cursor.enter_escape(&path)? {
+                    // Escaping paths start from another location (root or ancestor), where the cursor now points. It is restored on tag close
+                    path = rest.to_string();
+                    is_current = false;
+                    if path.is_empty() {
+                        return Ok(Some(Rc::clone(&cursor.shadow_data)));
+                    }
+                }
                 // A path is specified => we need to create (or reuse) a deeper element, and overwrite next_data
                 let mut is_array = false;
-                if path.chars().last().unwrap() == '.' {
+                if path.ends_with('.') {
                     // Determine whether this element is part of an array of elements
                     is_array = true;
                     path = (path[..path.len() - 1]).to_string(); // Remove the last dot
 
-                    if path.len() == 0 {
-                        return Err(ShadowError {
-                            msg: "Invalid def : single dot is not a valid path".to_string()
-                        });
+                    if path.is_empty() {
+                        return Err(ShadowError::Data("Invalid def : single dot is not a valid path".to_string()));
                     }
                 }
 
+                // Keyed collections are objects whose items are inserted on tag close, the cursor does not stay on them as with arrays
+                let keyed = is_array && data_def.key_from.is_some();
+                if keyed {
+                    is_current = false;
+                }
+
                 let mut split = path.split('.').peekable();
                 let mut current_data = Rc::clone(&cursor.shadow_data);
 
@@ -319,70 +837,62 @@ impl ShadowData {
                 while let Some(word) = split.next() {
                     let current_data_c = Rc::clone(&current_data);
                     let current_ref = Rc::clone(&current_data);
-                    if split.peek().unwrap_or(&"").len() == 0 { // Found last word
+                    if split.peek().unwrap_or(&"").is_empty() { // Found last word
                         // Here, we either build a new nested object or fetch an existing one, and assign it to next_data for further processing
-                        if is_array {
+                        if keyed {
+                            let container = {
+                                let mut temp_data = current_data_c.borrow_mut();
+                                Self::get_or_create(&mut temp_data, word, collision_policy, &errors, || {
+                                    ShadowData::new_object(Some(selector_id), Rc::downgrade(&current_ref))
+                                })?
+                            };
+                            let key = match data_def.key_from.as_ref() {
+                                Some(ShadowJsonValueSource::Attribute(name)) => el.get_attribute(name),
+                                Some(ShadowJsonValueSource::Value) => el.get_attribute("value"),
+                                _ => None, // Contents : set by the text handler
+                            };
+                            let policy = data_def.key_collision.unwrap_or(collision_policy);
+                            cursor.enter_keyed(selector_id, container, key, policy);
+                        } else if is_array {
                             let data_array = if is_current && is_current_an_array {
                                 // Case of coming back to non-first detected element for this selector => Simply reuse current_data
                                 current_data_c
                             } else {
                                 // Case when a new array needs to be built at the given path (ending with dot)
                                 let mut temp_data = current_data_c.borrow_mut();
-                                match temp_data.get(word) {
-                                    Some(existing_el) => {
-                                        let existing_el_rc = Rc::clone(&existing_el);
-                                        let array_el = match existing_el_rc.borrow().v {
-                                            ShadowDataValue::String(_) | ShadowDataValue::Object(_) => {
-                                                let new_array = ShadowData::wrap(
-                                                    ShadowData::new_array(Some(selector_id), Rc::downgrade(&current_ref)
-                                                ));
-                                                temp_data.set(word, Rc::clone(&new_array));
-                                                new_array
-                                            },
-                                            ShadowDataValue::Array(_) => existing_el
-                                        };
-                                        array_el
-                                    },
-                                    None => {
-                                        let array_el = ShadowData::wrap(
-                                            ShadowData::new_array(Some(selector_id), Rc::downgrade(&current_ref)
-                                        ));
-                                        temp_data.set(word, Rc::clone(&array_el));
-                                        array_el
-                                    }
-                                }
+                                Self::get_or_create(&mut temp_data, word, collision_policy, &errors, || {
+                                    ShadowData::new_array(Some(selector_id), Rc::downgrade(&current_ref))
+                                })?
                             };
                             let parent_array = Rc::downgrade(&data_array); // Creating weak reference to parent array
                             let new_data = ShadowData::wrap(ShadowData::new_object(Some(selector_id), parent_array));
-                            *cursor = ShadowDataCursor::new(Rc::clone(&new_data), Rc::clone(&cursor.root)); // Next data is now pointing to the first (empty) object of the array
-                            data_array.borrow_mut().push(Rc::clone(&new_data));
-                        } else {
-                            let mut temp_data = current_data_c.borrow_mut();
-                            if let Some(temp_data_existing) = temp_data.get(word) {
-                                // The data at this location already exists
-                                *cursor = ShadowDataCursor::new(Rc::clone(&temp_data_existing), Rc::clone(&cursor.root));
+                            let full = max_array_len.filter(|max| data_array.borrow().as_array().is_some_and(|items| items.len() >= *max));
+                            if let Some(max) = full {
+                                // The item is still entered so that the cursor moves as usual, but it is not part of the array
+                                let message = format!("[max_array_len] '{}' is limited to {} items : the next items are dropped", path, max);
+                                let mut errors_m = errors.borrow_mut();
+                                if !errors_m.contains(&message) {
+                                    errors_m.push(message);
+                                }
                             } else {
-                                // This is the first time this nested object is reached : create data
-                                let new_data = ShadowData::wrap(ShadowData::new_object(
-                                    Some(selector_id), Weak::clone(&parent)
-                                ));
-                                temp_data.set(word, Rc::clone(&new_data));
-                                *cursor = ShadowDataCursor::new(Rc::clone(&new_data), Rc::clone(&cursor.root));
+                                data_array.borrow_mut().push(Rc::clone(&new_data))?;
                             }
+                            cursor.shadow_data = Rc::clone(&new_data); // Next data is now pointing to the first (empty) object of the array
+                        } else {
+                            // Reuse the data at this location if it already exists, otherwise this is the first time this nested object is reached
+                            let mut temp_data = current_data_c.borrow_mut();
+                            let next_data = Self::get_or_create(&mut temp_data, word, collision_policy, &errors, || {
+                                ShadowData::new_object(Some(selector_id), Weak::clone(&parent))
+                            })?;
+                            cursor.shadow_data = next_data;
                         }
                     } else {
                         if !(is_current && is_current_an_array) {
                             // Assigning intermediate nesting : only when the array is being newly built
                             let mut temp_data = current_data_c.borrow_mut();
-                            if let Some(temp_data_existing) = temp_data.get(word) {
-                                current_data = Rc::clone(&temp_data_existing);
-                            } else {
-                                let new_temp_data = ShadowData::wrap(ShadowData::new_object(
-                                    Some(selector_id), Weak::clone(&parent)
-                                ));
-                                temp_data.set(word, Rc::clone(&new_temp_data));
-                                current_data = Rc::clone(&new_temp_data);
-                            }
+                            current_data = Self::get_or_create(&mut temp_data, word, collision_policy, &errors, || {
+                                ShadowData::new_object(Some(selector_id), Weak::clone(&parent))
+                            })?;
                         }
                     }
                 }
@@ -397,35 +907,135 @@ impl ShadowData {
 
     pub fn on_data_tag_close(
         _tag: &mut EndTag,
-        _selector_id: usize,
+        selector_id: usize,
+        json_def: Rc<RefCell<ShadowJson>>,
+        cursor: Rc<RefCell<ShadowDataCursor>>
+    ) -> Result<(), ShadowError> {
+        Self::on_data_leave(selector_id, json_def, cursor)
+    }
+
+    // Moves the cursor back to where it was before on_data_tag_open. Called on tag close, or right away for elements without end tag
+    pub fn on_data_leave(
+        selector_id: usize,
         json_def: Rc<RefCell<ShadowJson>>,
         cursor: Rc<RefCell<ShadowDataCursor>>
     ) -> Result<(), ShadowError> {
         if let Some(data_def) = json_def.borrow().data.as_ref() {
-            if data_def.path.as_ref().is_some() {
-                // A path had been defined : after finishing with this element, go back up
-                cursor.borrow_mut().go_up()?;
+            if let Some(path) = data_def.path.as_ref() {
+                if data_def.sort_keys == Some(true) || data_def.sort_by.is_some() {
+                    Self::register_sort(selector_id, data_def, path, &mut cursor.borrow_mut());
+                }
+                if path.ends_with('.') && data_def.key_from.is_some() {
+                    // The keyed item is complete : insert it under its key
+                    let mut cursor = cursor.borrow_mut();
+                    let inserted = cursor.leave_keyed(selector_id);
+                    if ShadowDataCursor::is_escape(path) {
+                        cursor.leave_escape()?;
+                    }
+                    inserted?;
+                    return Ok(());
+                }
+                let mut deduped = Ok(());
+                if let (true, Some(dedupe_by)) = (path.ends_with('.'), data_def.dedupe_by.as_ref()) {
+                    // The array item is complete : compare it with the previous items
+                    let item = Rc::clone(&cursor.borrow().shadow_data);
+                    if item.borrow().id == Some(selector_id) {
+                        let policy = data_def.key_collision.unwrap_or_default();
+                        deduped = Self::dedupe(&item, dedupe_by, policy);
+                    }
+                }
+                if ShadowDataCursor::is_escape(path) {
+                    // The path escaped from the parent : come back to where the cursor was before
+                    cursor.borrow_mut().leave_escape()?;
+                } else {
+                    // A path had been defined : after finishing with this element, go back up
+                    cursor.borrow_mut().go_up()?;
+                }
+                deduped?;
             }
         }
         Ok(())
     }
 
+    // Registers the collection filled by the element being left, sorted before the data is injected : the array or the keyed collection for array paths,
+    // the object at path otherwise
+    fn register_sort(selector_id: usize, data_def: &ShadowJsonData, path: &str, cursor: &mut ShadowDataCursor) {
+        let target = if !path.ends_with('.') {
+            Some(Rc::clone(&cursor.shadow_data))
+        } else if data_def.key_from.is_some() {
+            cursor.keyed_stack.last().filter(|keyed_item| keyed_item.selector_id == selector_id).map(|keyed_item| Rc::clone(&keyed_item.container))
+        } else {
+            Some(&cursor.shadow_data).filter(|item| item.borrow().id == Some(selector_id)).and_then(|item| item.borrow().parent.upgrade())
+        };
+        if let Some(target) = target {
+            cursor.register_sort(&target, data_def.sort_keys == Some(true), data_def.sort_order());
+        }
+    }
+
+    // Resolves an array item having the same value at key as an earlier item of the array, according to policy.
+    // Merge merges the item into the earlier one, KeepFirst drops it, KeepLast puts it in place of the earlier one and Error drops it with an error
+    fn dedupe(item: &Rc<RefCell<ShadowData>>, key: &str, policy: ShadowDataCollisionPolicy) -> Result<(), ShadowError> {
+        let value_at_key = |data: &Rc<RefCell<ShadowData>>| {
+            data.borrow().as_object().and_then(|obj| obj.get(key)).map(|value| value.borrow().to_string())
+        };
+        let Some(value) = value_at_key(item) else {
+            return Ok(()); // Items without the key are never duplicates
+        };
+        let Some(array) = item.borrow().parent.upgrade() else {
+            return Ok(());
+        };
+        let mut array_m = array.borrow_mut();
+        let Some(items) = array_m.as_array_mut() else {
+            return Ok(());
+        };
+        let Some(new_idx) = items.iter().rposition(|other| Rc::ptr_eq(other, item)) else {
+            return Ok(());
+        };
+        let Some(existing_idx) = items[..new_idx].iter().position(|other| value_at_key(other).as_ref() == Some(&value)) else {
+            return Ok(());
+        };
+        let new_item = items.remove(new_idx);
+        match policy {
+            ShadowDataCollisionPolicy::Error => {
+                return Err(ShadowError::Data(format!("Duplicate item : '{}' is already collected with {}", key, value)));
+            },
+            ShadowDataCollisionPolicy::KeepFirst => {},
+            ShadowDataCollisionPolicy::KeepLast => {
+                items[existing_idx] = new_item;
+            },
+            ShadowDataCollisionPolicy::Merge => {
+                let existing = Rc::clone(&items[existing_idx]);
+                let existing_weak = Rc::downgrade(&existing);
+                let mut existing_m = existing.borrow_mut();
+                let mut new_item_m = new_item.borrow_mut();
+                if let (Some(existing_obj), Some(new_obj)) = (existing_m.as_object_mut(), new_item_m.as_object_mut()) {
+                    Self::merge(existing_obj, &existing_weak, new_obj);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Indented tree of the data, each element prefixed by its uid and the uid of its parent ("#uid ^ parent_uid") when uids are enabled
     pub fn visualize(&self, tabs: usize) -> String {
         let tab = "  ";
         let tabs_str = tab.repeat(tabs);
+        let prefix = if self.uid.is_empty() {
+            String::new()
+        } else {
+            format!("#{} ^ {} ", self.uid, self.parent.upgrade().map(|parent| parent.borrow().uid.clone()).unwrap_or_else(|| "-".to_string()))
+        };
         match &self.v {
-            ShadowDataValue::String(s) => format!("#{} ^ {} \"{}\"",
-                self.uid,
-                self.parent.upgrade().unwrap_or_default().borrow().uid,
-                s.borrow()
-            ),
+            ShadowDataValue::String(s) => format!("{}{}", prefix, serde_json::Value::from(s.borrow().as_str())), // Escaped like in the json
+            ShadowDataValue::Bool(b) => format!("{}{}", prefix, b),
+            ShadowDataValue::Number(n) => format!("{}{}", prefix, n),
+            ShadowDataValue::Null => format!("{}null", prefix),
             ShadowDataValue::Array(a) => {
-                format!("#{} ^ {} [\n{}{}\n{}]",
-                    self.uid,
-                    self.parent.upgrade().unwrap_or_default().borrow().uid,
+                format!("{}[\n{}{}\n{}]",
+                    prefix,
                     tab.repeat(tabs+1),
                     a.iter().fold(String::new(), |mut acc, s| {
-                        if acc.len() > 0 {
+                        if !acc.is_empty() {
                             acc.push_str(&format!(",\n{}", tab.repeat(tabs+1)));
                         }
                         acc.push_str(&s.borrow().visualize(tabs + 1));
@@ -435,12 +1045,11 @@ impl ShadowData {
                 )
             },
             ShadowDataValue::Object(o) => {
-                format!("#{} ^ {} {{{}\n{}}}",
-                    self.uid,
-                    self.parent.upgrade().unwrap_or_default().borrow().uid,
+                format!("{}{{{}\n{}}}",
+                    prefix,
                     o.iter().fold(String::new(), |mut acc, (k,v)| {
-                        if acc.len() > 0 {
-                            acc.push_str(",");
+                        if !acc.is_empty() {
+                            acc.push(',');
                         }
                         acc.push_str(&format!("\n{}{}: {}", tab.repeat(tabs+1), k, v.borrow().visualize(tabs + 1)));
                         acc
@@ -1,44 +1,380 @@
 use core::fmt;
 use std::{rc::{Weak, Rc}, cell::RefCell};
 
-use crate::ShadowData;
+use crate::{ShadowData, ShadowDataCollisionPolicy};
 
 use super::ShadowError;
+use super::shadow_entities::escape_html;
 
 #[derive(Debug)]
 pub struct ShadowDataCursor {
     pub root: Rc<RefCell<ShadowData>>,
-    pub shadow_data: Rc<RefCell<ShadowData>>
+    pub shadow_data: Rc<RefCell<ShadowData>>,
+    pub return_stack: Vec<Rc<RefCell<ShadowData>>>, // Positions to come back to when leaving an element whose path escaped its parent ("/" or "../"), or a keyed item
+    pub(crate) keyed_stack: Vec<ShadowKeyedItem>, // Items of keyed collections (data.key_from) being collected, inserted into their collection on tag close
+    pub(crate) sorts: Vec<ShadowSort>, // Collections to sort before the data is injected (data.sort_keys and data.sort_by)
 }
 
+// An item of a keyed collection, inserted into its container once its key is known
+#[derive(Debug)]
+pub(crate) struct ShadowKeyedItem {
+    pub selector_id: usize,
+    pub container: Rc<RefCell<ShadowData>>,
+    pub item: Rc<RefCell<ShadowData>>,
+    pub key: Option<String>,
+    pub policy: ShadowDataCollisionPolicy, // How an item with an already existing key is resolved
+}
+
+// A collection registered by a node defining data.sort_keys or data.sort_by
+#[derive(Debug)]
+pub(crate) struct ShadowSort {
+    pub target: Weak<RefCell<ShadowData>>,
+    pub sort_keys: bool,
+    pub sort_by: Option<(String, bool)>, // Key and descending
+}
+
+// Where a placeholder of an html fragment stands : in text, in a tag, or in a quoted attribute value (the quote character)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HtmlContext {
+    Text,
+    Tag,
+    Quoted(char),
+}
+
+impl HtmlContext {
+    // Context at the end of html, starting in self
+    fn after(self, html: &str) -> HtmlContext {
+        html.chars().fold(self, |context, c| match (context, c) {
+            (HtmlContext::Text, '<') => HtmlContext::Tag,
+            (HtmlContext::Tag, '>') => HtmlContext::Text,
+            (HtmlContext::Tag, '"' | '\'') => HtmlContext::Quoted(c),
+            (HtmlContext::Quoted(quote), c) if c == quote => HtmlContext::Tag,
+            (context, _) => context,
+        })
+    }
+}
 
 impl fmt::Display for ShadowDataCursor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.root.borrow().to_string())
+        write!(f, "{}", self.root.borrow())
     }
 }
 
 impl ShadowDataCursor {
     pub fn new(shadow_data: Rc<RefCell<ShadowData>>, root: Rc<RefCell<ShadowData>>) -> ShadowDataCursor {
-        ShadowDataCursor { root, shadow_data }
+        ShadowDataCursor { root, shadow_data, return_stack: Vec::new(), keyed_stack: Vec::new(), sorts: Vec::new() }
     }
     pub fn init() -> ShadowDataCursor {
         let new_shadow_data = ShadowData::wrap(ShadowData::new_object(Some(0), Weak::new()));
-        ShadowDataCursor { root: Rc::clone(&new_shadow_data), shadow_data: new_shadow_data }
+        ShadowDataCursor { root: Rc::clone(&new_shadow_data), shadow_data: new_shadow_data, return_stack: Vec::new(), keyed_stack: Vec::new(), sorts: Vec::new() }
+    }
+    // Registers a collection to sort with apply_sorts, once per collection
+    pub(crate) fn register_sort(&mut self, target: &Rc<RefCell<ShadowData>>, sort_keys: bool, sort_by: Option<(String, bool)>) {
+        let target = Rc::downgrade(target);
+        if !self.sorts.iter().any(|sort| Weak::ptr_eq(&sort.target, &target)) {
+            self.sorts.push(ShadowSort { target, sort_keys, sort_by });
+        }
+    }
+    // Sorts the registered collections still part of the data. Sorting again is harmless : the sorts are stable
+    pub fn apply_sorts(&self) {
+        for sort in &self.sorts {
+            let Some(target) = sort.target.upgrade() else {
+                continue;
+            };
+            let mut target_m = target.borrow_mut();
+            match &sort.sort_by {
+                Some((key, descending)) => {
+                    target_m.sort_by(key, *descending);
+                    if sort.sort_keys {
+                        // The order of the entries of a keyed collection is the one of sort_by : only the items get sorted keys
+                        let items = target_m.as_object().map(|obj| obj.values().cloned().collect::<Vec<_>>())
+                            .or_else(|| target_m.as_array().cloned())
+                            .unwrap_or_default();
+                        items.iter().for_each(|item| item.borrow_mut().sort_keys());
+                    }
+                },
+                None if sort.sort_keys => target_m.sort_keys(),
+                None => {},
+            }
+        }
+    }
+    // Moves the cursor back to the root, keeping the data. Items of keyed collections still being collected are dropped
+    pub fn rewind(&mut self) {
+        self.shadow_data = Rc::clone(&self.root);
+        self.return_stack.clear();
+        self.keyed_stack.clear();
     }
     // Print tree structure, for debugging
     pub fn visualize(&self) -> String {
         self.root.borrow().visualize(0)
     }
+    // JSON pointer (RFC 6901) of the current position, "" at the root, e.g. "/products/0". For debugging and tests
+    // A keyed collection item (data.key_from) is designated by its key before being inserted. None while that key is unknown
+    pub fn current_path(&self) -> Option<String> {
+        Some(self.current_segments()?.iter().fold(String::new(), |mut pointer, segment| {
+            pointer.push('/');
+            pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+            pointer
+        }))
+    }
+    // Amount of objects and arrays entered from the root to reach the current position, 0 at the root
+    pub fn depth(&self) -> usize {
+        self.current_segments().map(|segments| segments.len()).unwrap_or(0)
+    }
+    // Deep copy of the data collected so far, unaffected by the rest of the processing
+    pub fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(&*self.root.borrow()).unwrap_or_default()
+    }
+    fn current_segments(&self) -> Option<Vec<String>> {
+        if let Some(segments) = self.root.borrow().segments_of(&self.shadow_data) {
+            return Some(segments);
+        }
+        // Within a keyed item, not attached to its container yet
+        for keyed_item in self.keyed_stack.iter().rev() {
+            if let Some(item_segments) = keyed_item.item.borrow().segments_of(&self.shadow_data) {
+                let mut segments = self.root.borrow().segments_of(&keyed_item.container)?;
+                segments.push(keyed_item.key.clone()?);
+                segments.extend(item_segments);
+                return Some(segments);
+            }
+        }
+        None
+    }
+    // Fetches the data at the given dot separated path, starting from the root. Array elements are addressed by their index
+    pub fn lookup(&self, path: &str) -> Option<Rc<RefCell<ShadowData>>> {
+        let mut current = Rc::clone(&self.root);
+        for word in path.split('.').filter(|w| !w.is_empty()) {
+            let next = {
+                let current_b = current.borrow();
+                if let Some(obj) = current_b.as_object() {
+                    obj.get(word).map(Rc::clone)
+                } else if let Some(arr) = current_b.as_array() {
+                    word.parse::<usize>().ok().and_then(|idx| arr.get(idx)).map(Rc::clone)
+                } else {
+                    None
+                }
+            }?;
+            current = next;
+        }
+        Some(current)
+    }
+    // Inserts a json value at the given dot separated path from the root, creating the missing intermediate objects. An empty path targets the root itself
+    // Objects are merged key by key into the objects already present, any other value replaces the existing one.
+    // A null value of an object leaves its key as it is, while the null items of arrays are kept
+    pub fn seed(&self, path: &str, value: &serde_json::Value) -> Result<(), ShadowError> {
+        let mut words: Vec<&str> = path.split('.').filter(|w| !w.is_empty()).collect();
+        let last = words.pop();
+        let mut current = Rc::clone(&self.root);
+        for word in words {
+            let next = {
+                let mut current_m = current.borrow_mut();
+                match current_m.get(word) {
+                    Some(existing) if existing.borrow().is_object() => existing,
+                    Some(_) => return Err(ShadowError::Data(format!("[seed] '{}' in path '{}' is not an object", word, path))),
+                    None => {
+                        let new_object = ShadowData::wrap(ShadowData::new_object(None, Rc::downgrade(&current)));
+                        current_m.set(word, Rc::clone(&new_object), ShadowDataCollisionPolicy::Merge)?;
+                        new_object
+                    }
+                }
+            };
+            current = next;
+        }
+        match last {
+            Some(key) => Self::seed_key(&current, key, value)?,
+            None => match value.as_object() {
+                Some(obj) => {
+                    for (key, item) in obj {
+                        Self::seed_key(&current, key, item)?;
+                    }
+                },
+                None => return Err(ShadowError::Data("[seed] only an object can be seeded at the root".to_string())),
+            },
+        }
+        Ok(())
+    }
+    fn seed_key(target: &Rc<RefCell<ShadowData>>, key: &str, value: &serde_json::Value) -> Result<(), ShadowError> {
+        if value.is_null() {
+            return Ok(());
+        }
+        let existing = target.borrow().get(key);
+        match (existing, value.as_object()) {
+            (Some(existing), Some(obj)) if existing.borrow().is_object() => {
+                for (sub_key, item) in obj {
+                    Self::seed_key(&existing, sub_key, item)?;
+                }
+                Ok(())
+            },
+            (None, Some(obj)) => {
+                let new_object = ShadowData::wrap(ShadowData::new_object(None, Rc::downgrade(target)));
+                target.borrow_mut().set(key, Rc::clone(&new_object), ShadowDataCollisionPolicy::Merge)?;
+                for (sub_key, item) in obj {
+                    Self::seed_key(&new_object, sub_key, item)?;
+                }
+                Ok(())
+            },
+            _ => {
+                let data = ShadowData::from_json(None, Rc::downgrade(target), value);
+                target.borrow_mut().set(key, data, ShadowDataCollisionPolicy::Merge)
+            }
+        }
+    }
+    // Replaces {{path}} placeholders in template with the data collected so far at that path (see lookup)
+    // Strings are inserted as-is, objects and arrays as json. Paths without data are replaced with an empty string
+    // Text of the data at path : strings as they are, other values serialized as json
+    pub fn lookup_string(&self, path: &str) -> Option<String> {
+        self.lookup(path).map(|data| Self::text_of(&data))
+    }
+    fn text_of(data: &Rc<RefCell<ShadowData>>) -> String {
+        let data_b = data.borrow();
+        match data_b.as_string() {
+            Some(s) => s.borrow().clone(),
+            None => data_b.to_string(),
+        }
+    }
+    pub fn render(&self, template: &str) -> String {
+        Self::render_with(template, false, |path| self.lookup_string(path))
+    }
+    // Same as render for an html fragment, the values being escaped for their context (text, or within a tag)
+    pub fn render_html(&self, template: &str) -> String {
+        Self::render_with(template, true, |path| self.lookup_string(path))
+    }
+    // Same as render_html, the {{item}} and {{item.path}} placeholders resolving within item (an item of an array of the data, see repeat_for)
+    pub fn render_item(&self, template: &str, item: &Rc<RefCell<ShadowData>>) -> String {
+        Self::render_with(template, true, |path| match path.strip_prefix("item") {
+            Some("") => Some(Self::text_of(item)),
+            Some(sub_path) if sub_path.starts_with('.') => item.borrow().get_path(&sub_path[1..]).map(|data| Self::text_of(&data)),
+            _ => self.lookup_string(path),
+        })
+    }
+    fn render_with(template: &str, html: bool, lookup: impl Fn(&str) -> Option<String>) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        let mut context = HtmlContext::Text;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break; // Unclosed placeholder : kept as-is
+            };
+            rendered.push_str(&rest[..start]);
+            context = context.after(&rest[..start]);
+            let path = rest[start + 2..start + 2 + len].trim();
+            if let Some(value) = lookup(path) {
+                if html {
+                    rendered.push_str(&escape_html(&value, context != HtmlContext::Text));
+                } else {
+                    rendered.push_str(&value);
+                }
+            }
+            rest = &rest[start + 2 + len + 2..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+    // Moves the cursor to the starting point of an escaping path, and returns the rest of the path (relative to that point).
+    // "/" starts from the root, each leading "../" moves up one object (arrays are skipped), and a path ending with ".." targets the ancestor itself.
+    // The current position is saved, to be restored by leave_escape. Returns None if the path does not escape
+    pub fn enter_escape<'p>(&mut self, path: &'p str) -> Result<Option<&'p str>, ShadowError> {
+        if !Self::is_escape(path) {
+            return Ok(None);
+        }
+        // The position is saved even if the path is invalid, as leave_escape is called on tag close in any case
+        self.return_stack.push(Rc::clone(&self.shadow_data));
+        let mut target = Rc::clone(&self.shadow_data);
+        let mut rest = path;
+        if let Some(absolute) = rest.strip_prefix('/') {
+            target = Rc::clone(&self.root);
+            rest = absolute;
+        } else {
+            loop {
+                let next = if let Some(next) = rest.strip_prefix("../") {
+                    next
+                } else if rest == ".." {
+                    ""
+                } else {
+                    break;
+                };
+                target = Self::parent_object(&target).ok_or_else(|| ShadowError::Data(format!("[path] '{}' escapes above the root", path)))?;
+                rest = next;
+            }
+        }
+        if !target.borrow().is_object() {
+            return Err(ShadowError::Data(format!("[path] '{}' does not lead to an object", path)));
+        }
+        self.shadow_data = target;
+        Ok(Some(rest))
+    }
+    // Moves the cursor to target, or keeps it in place without target. The current position is saved, to be restored by leave_escape
+    pub(crate) fn enter_at(&mut self, target: Option<Rc<RefCell<ShadowData>>>) {
+        self.return_stack.push(Rc::clone(&self.shadow_data));
+        if let Some(target) = target {
+            self.shadow_data = target;
+        }
+    }
+    // Whether the path starts from the root or from an ancestor, instead of the current position
+    pub fn is_escape(path: &str) -> bool {
+        path.starts_with('/') || path.starts_with("../") || path == ".."
+    }
+    // Restores the position saved by enter_escape
+    pub fn leave_escape(&mut self) -> Result<(), ShadowError> {
+        match self.return_stack.pop() {
+            Some(previous) => {
+                self.shadow_data = previous;
+                Ok(())
+            },
+            None => Err(ShadowError::Data("[leave_escape] no position to return to".to_string())),
+        }
+    }
+    // Moves the cursor into a new item of a keyed collection. The item is inserted into the container by leave_keyed, once its key is known
+    pub(crate) fn enter_keyed(
+        &mut self,
+        selector_id: usize,
+        container: Rc<RefCell<ShadowData>>,
+        key: Option<String>,
+        policy: ShadowDataCollisionPolicy
+    ) -> Rc<RefCell<ShadowData>> {
+        let item = ShadowData::wrap(ShadowData::new_object(Some(selector_id), Rc::downgrade(&container)));
+        self.return_stack.push(std::mem::replace(&mut self.shadow_data, Rc::clone(&item)));
+        self.keyed_stack.push(ShadowKeyedItem { selector_id, container, item: Rc::clone(&item), key, policy });
+        item
+    }
+    // Sets the key of the innermost keyed item being collected for the selector (keys extracted from the contents are only known after the start tag)
+    // The first non-empty text of the element is used
+    pub(crate) fn set_keyed_key(&mut self, selector_id: usize, key: String) {
+        if key.is_empty() {
+            return;
+        }
+        if let Some(keyed_item) = self.keyed_stack.iter_mut().rev().find(|keyed_item| keyed_item.selector_id == selector_id) {
+            keyed_item.key.get_or_insert(key);
+        }
+    }
+    // Inserts the innermost keyed item into its container under its key, and restores the position the cursor had before enter_keyed
+    pub(crate) fn leave_keyed(&mut self, selector_id: usize) -> Result<(), ShadowError> {
+        if self.keyed_stack.last().map(|keyed_item| keyed_item.selector_id != selector_id).unwrap_or(true) {
+            return Ok(()); // The item was not created (invalid path)
+        }
+        let keyed_item = self.keyed_stack.pop().unwrap();
+        self.leave_escape()?;
+        match keyed_item.key {
+            Some(key) => keyed_item.container.borrow_mut().set(&key, keyed_item.item, keyed_item.policy),
+            None => Err(ShadowError::Data("[key_from] no key could be extracted, the item is discarded".to_string())),
+        }
+    }
+    // Nearest ancestor object of data, skipping arrays
+    fn parent_object(data: &Rc<RefCell<ShadowData>>) -> Option<Rc<RefCell<ShadowData>>> {
+        let mut current = data.borrow().parent.upgrade()?;
+        while current.borrow().is_array() {
+            let parent = current.borrow().parent.upgrade()?;
+            current = parent;
+        }
+        Some(current)
+    }
     pub fn go_up(&mut self) -> Result<(), ShadowError> {
         // If a path is defined, then a new nested element must had been added => go up the tree once
         let parent_weak = Weak::clone(&self.shadow_data.borrow().parent);
         if let Some(parent) = parent_weak.upgrade() {
-            *self = ShadowDataCursor::new(parent, Rc::clone(&self.root));
+            self.shadow_data = parent;
         } else {
-            return Err(ShadowError {
-                msg: format!("[go_up] cannot move up")
-            });
+            return Err(ShadowError::Data("[go_up] cannot move up".to_string()));
         }
         Ok(())
     }
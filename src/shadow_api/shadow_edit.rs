@@ -0,0 +1,159 @@
+use std::cell::RefCell;
+
+use lol_html::html_content::Element;
+
+use super::{AppliedEdits, ShadowJson, ShadowJsonEditOne};
+
+// Applies edit.style and hide to the inline style of the element. hide is applied after edit.style, so that it prevails
+pub(super) fn apply_style(el: &mut Element, json_def: &ShadowJson, edits: &mut AppliedEdits, errors: &RefCell<Vec<String>>) {
+    let mut style_edits: Vec<(&str, Option<&str>)> = json_def.edit.as_ref()
+        .and_then(|edit| edit.style.as_ref())
+        .map(|style| style.iter().map(|(name, value)| (name.as_str(), value.as_deref())).collect())
+        .unwrap_or_default();
+    if json_def.hide.unwrap_or(false) {
+        style_edits.push(("display", Some("none")));
+    }
+    if style_edits.is_empty() {
+        return
+    }
+    edits.add(style_edits.len(), || "edit.style".to_string());
+    let old_style = el.get_attribute("style").unwrap_or_default();
+    match edit_style(&old_style, &style_edits) {
+        Some(style) if style.is_empty() => el.remove_attribute("style"),
+        Some(style) => {
+            if let Err(e) = el.set_attribute("style", &style) {
+                errors.borrow_mut().push(format!("Unable to set attribute (edit.style): {}", e));
+            }
+        }
+        None => {}
+    }
+}
+
+// Applies a class operation (add_class/remove_class/replace_class/toggle_class) to the attribute key of the element
+pub(super) fn apply_class_op(el: &mut Element, key: &str, val: &ShadowJsonEditOne, edits: &mut AppliedEdits, errors: &RefCell<Vec<String>>) {
+    let Some(classes) = &val.val else {
+        errors.borrow_mut().push(format!("{} requires val (edit.attrs.{})", val.op, key));
+        return
+    };
+    if val.op == "replace_class" && val.r#match.is_none() {
+        errors.borrow_mut().push(format!("replace_class requires match (edit.attrs.{})", key));
+        return
+    }
+    let old_value = el.get_attribute(key).unwrap_or_default();
+    if let Some(new_value) = edit_class_tokens(&old_value, &val.op, classes, val.r#match.as_deref()) {
+        match el.set_attribute(key, &new_value) {
+            Ok(_) => edits.add(1, || format!("edit.attrs.{}:{}", key, val.op)),
+            Err(e) => errors.borrow_mut().push(format!("Unable to set attribute via {} (edit.attrs.{}): {}", val.op, key, e)),
+        }
+    }
+}
+
+// Splits an inline style into (property, value) declarations. Semicolons within quotes or parentheses (e.g. url(...)) do not end a declaration
+// Property names are lowercased, except for custom properties. Declarations without a colon are dropped
+fn parse_style(style: &str) -> Vec<(String, String)> {
+    let mut parts: Vec<&str> = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (idx, c) in style.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ';') if depth == 0 => {
+                parts.push(&style[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&style[start..]);
+    parts
+        .into_iter()
+        .filter_map(|part| part.split_once(':'))
+        .map(|(name, value)| (style_property(name), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn style_property(name: &str) -> String {
+    let name = name.trim();
+    if name.starts_with("--") {
+        name.to_string()
+    } else {
+        name.to_ascii_lowercase()
+    }
+}
+
+// Sets (Some) or removes (None) the properties of an inline style, keeping the other declarations in place
+// Returns the serialized style, empty if no declaration is left, or None if the declarations are unchanged
+fn edit_style(old_style: &str, style_edits: &[(&str, Option<&str>)]) -> Option<String> {
+    let old_declarations = parse_style(old_style);
+    let mut declarations = old_declarations.clone();
+    for (name, value) in style_edits {
+        let name = style_property(name);
+        let pos = declarations.iter().position(|(declared, _)| *declared == name);
+        match (pos, value) {
+            (Some(pos), Some(value)) => declarations[pos].1 = value.trim().to_string(),
+            (Some(pos), None) => {
+                declarations.remove(pos);
+            }
+            (None, Some(value)) => declarations.push((name, value.trim().to_string())),
+            (None, None) => {}
+        }
+    }
+    if declarations == old_declarations {
+        return None
+    }
+    Some(declarations.iter().map(|(name, value)| format!("{}: {}", name, value)).collect::<Vec<String>>().join("; "))
+}
+
+// Applies a class operation to the whitespace separated tokens of old_value, for each token of classes
+// replace_class replaces the `replaced` token with the classes, at its position. Return None if the tokens are unchanged
+fn edit_class_tokens(old_value: &str, op: &str, classes: &str, replaced: Option<&str>) -> Option<String> {
+    let mut tokens: Vec<&str> = Vec::new();
+    for token in old_value.split_ascii_whitespace() {
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    let old_tokens = tokens.clone();
+    match op {
+        "add_class" => {
+            for class in classes.split_ascii_whitespace() {
+                if !tokens.contains(&class) {
+                    tokens.push(class);
+                }
+            }
+        }
+        "remove_class" => {
+            let removed: Vec<&str> = classes.split_ascii_whitespace().collect();
+            tokens.retain(|token| !removed.contains(token));
+        }
+        "toggle_class" => {
+            for class in classes.split_ascii_whitespace() {
+                match tokens.iter().position(|token| *token == class) {
+                    Some(pos) => {
+                        tokens.remove(pos);
+                    }
+                    None => tokens.push(class),
+                }
+            }
+        }
+        "replace_class" => {
+            let replaced = replaced.map(str::trim).unwrap_or_default();
+            if let Some(pos) = tokens.iter().position(|token| *token == replaced) {
+                tokens.remove(pos);
+                let added: Vec<&str> = classes.split_ascii_whitespace().filter(|class| !tokens.contains(class)).collect();
+                tokens.splice(pos..pos, added);
+            }
+        }
+        _ => {}
+    }
+    if tokens == old_tokens {
+        return None
+    }
+    Some(tokens.join(" "))
+}
@@ -1,50 +1,77 @@
 use std::{error::Error, fmt};
+use serde::{Deserialize, Serialize};
+
+/// Defines how the errors of the handlers (a data path running into an array, a failing item sink, a panicking data formatter...) affect the response
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowErrorPolicy {
+    #[default]
+    Collect, // The error is reported and the handlers keep applying to the rest of the document
+    FailOpen, // The error is reported and the handlers stop applying : the rest of the document is passed through as it is, without the data
+    FailClosed, // The rewriting is aborted : nothing more is written, the error is reported by the process methods
+}
 
 #[derive(Debug)]
-pub struct ShadowError {
-    pub(crate) msg: String,
+pub enum ShadowError {
+    InvalidJson { // A ShadowJson definition could not be deserialized
+        path: String, // Path of the failing field within the definition (serde_path_to_error), "." for the root
+        line: usize, // 1-based position of the error in the definition source, 0 if unknown
+        column: usize,
+        msg: String,
+    },
+    Data(String), // Data collection failed : key collision, invalid path, cursor move, serialization...
+    Io(std::io::Error), // Reading the input or writing the output failed
+    Rewriting(String), // The html rewriter failed
+    MissingRequired { // A node defining data.required never matched, or a required value was never collected (see data.values.*.required)
+        selector: String, // Full selector of the node
+        key: Option<String>, // Key of the required value, None for data.required
+    },
+    Other(String),
 }
 
 impl fmt::Display for ShadowError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "[ShadowError] {}", self.msg)
+        match self {
+            ShadowError::InvalidJson { path, line, column, msg } => {
+                write!(f, "[ShadowError] Invalid json at '{}' (line {}, column {}) : {}", path, line, column, msg)
+            },
+            ShadowError::Data(msg) | ShadowError::Rewriting(msg) | ShadowError::Other(msg) => write!(f, "[ShadowError] {}", msg),
+            ShadowError::Io(err) => write!(f, "[ShadowError] {}", err),
+            ShadowError::MissingRequired { selector, key: None } => write!(f, "[ShadowError] Required selector {} never matched", selector),
+            ShadowError::MissingRequired { selector, key: Some(key) } => write!(f, "[ShadowError] Required value '{}' of selector {} was not collected", key, selector),
+        }
     }
 }
 
 impl Error for ShadowError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+        match self {
+            ShadowError::Io(err) => Some(err),
+            _ => None,
+        }
     }
 }
 
 impl From<&str> for ShadowError {
     fn from(value: &str) -> Self {
-        ShadowError {
-            msg: value.to_owned(),
-        }
+        ShadowError::Other(value.to_owned())
     }
 }
 
 impl From<String> for ShadowError {
     fn from(msg: String) -> Self {
-        ShadowError {
-            msg,
-        }
+        ShadowError::Other(msg)
     }
 }
 
 impl From<std::io::Error> for ShadowError {
-    fn from(msg: std::io::Error) -> Self {
-        ShadowError {
-            msg: msg.to_string(),
-        }
+    fn from(err: std::io::Error) -> Self {
+        ShadowError::Io(err)
     }
 }
 
 impl From<lol_html::errors::RewritingError> for ShadowError {
     fn from(value: lol_html::errors::RewritingError) -> Self {
-        ShadowError {
-            msg: value.to_string(),
-        }
+        ShadowError::Rewriting(value.to_string())
     }
 }
\ No newline at end of file
@@ -0,0 +1,90 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{ShadowData, ShadowDataCollisionPolicy, ShadowError};
+
+// Form streamed by a node defining form : its fields are serialized into the data item of the node (see form_content_handlers)
+#[derive(Default)]
+pub(super) struct FormState {
+    pub target: Option<Rc<RefCell<ShadowData>>>, // Object the fields are written to, set while the form is open
+    pub select: Option<FormSelect>, // Select currently streamed
+    pub open_text: Option<(FormText, String)>, // Element whose text is currently streamed, and its text
+    pub selector_id: usize,
+    pub policy: ShadowDataCollisionPolicy, // Resolves the fields colliding with a value the form did not collect
+}
+
+pub(super) struct FormSelect {
+    pub name: String,
+    pub multiple: bool,
+    pub selected: bool, // Whether an option was selected
+    pub first: Option<String>, // Value of the first option, submitted by a single select without selected option
+}
+
+pub(super) enum FormText {
+    Textarea(String), // Field name
+    Option(bool), // Whether the option is selected. Options without value attribute submit their text
+}
+
+impl FormState {
+    // Adds a field value. Names submitted more than once (checkboxes, multiple selects...) hold an array of their values,
+    // a name colliding with a value the form did not collect is resolved by the collision policy
+    pub fn add(&self, name: &str, value: String) -> Result<usize, ShadowError> {
+        let Some(target) = self.target.as_ref().filter(|target| target.borrow().is_object()) else {
+            return Ok(0);
+        };
+        let existing = target.borrow().get(name).filter(|existing| existing.borrow().id == Some(self.selector_id));
+        match existing {
+            Some(existing) if existing.borrow().is_array() => {
+                let item = ShadowData::wrap(ShadowData::new_string(Some(self.selector_id), Rc::downgrade(&existing), value));
+                existing.borrow_mut().push(item)?;
+            },
+            Some(existing) => {
+                let array = ShadowData::wrap(ShadowData::new_array(Some(self.selector_id), Rc::downgrade(target)));
+                existing.borrow_mut().parent = Rc::downgrade(&array);
+                let item = ShadowData::wrap(ShadowData::new_string(Some(self.selector_id), Rc::downgrade(&array), value));
+                array.borrow_mut().push(existing)?;
+                array.borrow_mut().push(item)?;
+                target.borrow_mut().set(name, array, ShadowDataCollisionPolicy::KeepLast)?; // Replaces the first value of the field
+            },
+            None => {
+                let item = ShadowData::wrap(ShadowData::new_string(Some(self.selector_id), Rc::downgrade(target), value));
+                target.borrow_mut().set(name, item, self.policy)?;
+            },
+        }
+        Ok(1)
+    }
+
+    pub fn option(&mut self, value: String, selected: bool) -> Result<usize, ShadowError> {
+        let Some(select) = self.select.as_mut() else {
+            return Ok(0);
+        };
+        if select.first.is_none() {
+            select.first = Some(value.clone());
+        }
+        if !selected {
+            return Ok(0);
+        }
+        select.selected = true;
+        let name = select.name.clone();
+        self.add(&name, value)
+    }
+
+    pub fn close_text(&mut self) -> Result<usize, ShadowError> {
+        match self.open_text.take() {
+            Some((FormText::Textarea(name), text)) => self.add(&name, text),
+            Some((FormText::Option(selected), text)) => self.option(text.trim().to_string(), selected),
+            None => Ok(0),
+        }
+    }
+
+    pub fn close_select(&mut self) -> Result<usize, ShadowError> {
+        let mut added = self.close_text()?; // Last option, if its end tag was omitted
+        if let Some(select) = self.select.take() {
+            if !select.selected && !select.multiple {
+                if let Some(first) = select.first {
+                    added += self.add(&select.name, first)?;
+                }
+            }
+        }
+        Ok(added)
+    }
+}
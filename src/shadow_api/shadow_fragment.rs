@@ -0,0 +1,48 @@
+use std::{borrow::Cow, cell::RefCell, rc::Rc};
+
+use super::{ShadowApi, ShadowDataCursor, ShadowSanitizer};
+
+// Renders the html fragments injected by a node : the {{path}} placeholders with the data collected so far, then the sanitizer and the CSP nonce
+pub(super) struct FragmentInjection {
+    pub cursor: Rc<RefCell<ShadowDataCursor>>,
+    pub nonce: Option<String>, // Attached to the <script> tags of the fragments, for the nodes flagged with nonce
+    pub sanitizer: Option<Rc<ShadowSanitizer>>,
+    pub errors: Rc<RefCell<Vec<String>>>,
+    pub selector: String, // Selector of the node, for the errors of the sanitizer
+}
+
+impl FragmentInjection {
+    // Renders a fragment of field (append, insert_after...)
+    pub fn render<'t>(&self, field: &str, fragment: &'t str) -> Cow<'t, str> {
+        if !fragment.contains("{{") {
+            return self.finish(field, Cow::Borrowed(fragment), false);
+        }
+        let rendered = self.cursor.borrow().render_html(fragment);
+        self.finish(field, Cow::Owned(rendered), true)
+    }
+
+    // Renders an append/prepend fragment, once per item of the array found at repeat_for if set (nothing if there is no such array)
+    pub fn render_repeated<'t>(&self, field: &str, fragment: &'t str, repeat_for: Option<&str>) -> Cow<'t, str> {
+        let Some(path) = repeat_for else {
+            return self.render(field, fragment);
+        };
+        let rendered: String = {
+            let cursor = self.cursor.borrow();
+            let items = cursor.lookup(path).and_then(|data| data.borrow().as_array().cloned()).unwrap_or_default();
+            items.iter().map(|item| cursor.render_item(fragment, item)).collect()
+        };
+        self.finish(field, Cow::Owned(rendered), fragment.contains("{{"))
+    }
+
+    // The fragments of the definition were sanitized by parse : they are sanitized again once values are interpolated into them
+    fn finish<'t>(&self, field: &str, rendered: Cow<'t, str>, interpolated: bool) -> Cow<'t, str> {
+        let sanitized = self.sanitizer.as_ref()
+            .filter(|_| interpolated)
+            .and_then(|sanitizer| sanitizer.sanitize_reported(&rendered, &self.errors, field, &self.selector));
+        let rendered = sanitized.map(Cow::Owned).unwrap_or(rendered);
+        match &self.nonce {
+            Some(nonce) => Cow::Owned(ShadowApi::add_script_nonce(&rendered, nonce)),
+            None => rendered,
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{ShadowData, ShadowInjectionOverflow};
+
+// max_injected_bytes option, with the paths dropped first by the drop_paths overflow strategy
+pub(super) struct InjectionBudget {
+    pub max_bytes: usize,
+    pub overflow: ShadowInjectionOverflow,
+    pub low_priority_paths: Vec<String>,
+}
+
+impl InjectionBudget {
+    // Serialized copy of the data reduced according to the overflow strategy of the budget, None if it cannot fit (or with the skip strategy)
+    pub fn fit(&self, data: &Rc<RefCell<ShadowData>>, size: usize) -> Option<String> {
+        let copy = ShadowData::deep_clone(data);
+        match self.overflow {
+            ShadowInjectionOverflow::Skip => None,
+            ShadowInjectionOverflow::DropPaths => {
+                for path in &self.low_priority_paths {
+                    copy.borrow_mut().remove_path(path);
+                    let json = copy.borrow().to_string();
+                    if json.len() <= self.max_bytes {
+                        return Some(json);
+                    }
+                }
+                None
+            },
+            ShadowInjectionOverflow::TruncateArrays => {
+                // The size is estimated from the removed items, and only measured again once the estimate fits
+                let mut estimate = size;
+                loop {
+                    if estimate <= self.max_bytes {
+                        let json = copy.borrow().to_string();
+                        if json.len() <= self.max_bytes {
+                            return Some(json);
+                        }
+                        estimate = json.len();
+                    }
+                    let longest = arrays_of(&copy).into_iter()
+                        .max_by_key(|array| array.borrow().as_array().map(Vec::len).unwrap_or_default())
+                        .filter(|array| !array.borrow().is_empty())?;
+                    let item = longest.borrow_mut().as_array_mut().and_then(Vec::pop)?;
+                    let removed = item.borrow().to_string().len() + 1; // With its comma
+                    estimate = estimate.saturating_sub(removed);
+                }
+            },
+        }
+    }
+}
+
+// Arrays of the data and of all its descendants
+fn arrays_of(data: &Rc<RefCell<ShadowData>>) -> Vec<Rc<RefCell<ShadowData>>> {
+    let data_b = data.borrow();
+    let mut arrays = Vec::new();
+    if let Some(items) = data_b.as_array() {
+        arrays.push(Rc::clone(data));
+        arrays.extend(items.iter().flat_map(arrays_of));
+    } else if let Some(obj) = data_b.as_object() {
+        arrays.extend(obj.values().flat_map(arrays_of));
+    }
+    arrays
+}
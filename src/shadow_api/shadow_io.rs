@@ -0,0 +1,48 @@
+use std::io::{ErrorKind, Read, Write};
+
+/// Writer splitting the writes into chunks of a maximum size, and counting the written bytes
+pub(crate) struct ChunkedWriter<'w, W: Write> {
+    pub writer: &'w mut W,
+    pub chunk_size: usize,
+    pub written: usize,
+}
+
+impl<'w, W: Write> Write for ChunkedWriter<'w, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for chunk in buf.chunks(self.chunk_size.max(1)) { // Setting upper limit to writable chunk size
+            write_fully(self.writer, chunk)?;
+            self.written += chunk.len();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Writes the whole buffer, honoring the partial writes of the writer : a slow client slows the processing down instead of failing it.
+// Interrupted writes are retried. The writer is expected to block until it is ready : a WouldBlock error is returned, as any other error,
+// the amount of bytes of buf already written being unknown to the caller
+pub(crate) fn write_fully<W: Write + ?Sized>(writer: &mut W, mut buf: &[u8]) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match writer.write(buf) {
+            Ok(0) => return Err(std::io::Error::new(ErrorKind::WriteZero, "failed to write the whole buffer")),
+            Ok(n_bytes) => buf = &buf[n_bytes..],
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// Reads the next chunk of the input, 0 once complete. Interrupted reads are retried. The other errors are fatal, WouldBlock included :
+// the reader is expected to block until data is available
+pub(crate) fn read_chunk<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            result => return result,
+        }
+    }
+}
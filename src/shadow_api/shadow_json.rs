@@ -1,19 +1,221 @@
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::str;
+use std::collections::HashMap;
 use indexmap::IndexMap;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::{ShadowData, ShadowDataCollisionPolicy, ShadowError};
+
+use super::shadow_entities::decode_html_entities;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(tag = "source", content = "name")]
 // We use adjacently tagged representation. Refer to https://serde.rs/enum-representations.html
 pub enum ShadowJsonValueSource {
     Contents, // Current node's contents will be used (as string)
     Attribute(String), // Current node's specified attribute will be used
     Value, // Current node's value will be used. This is useful with various form elements such as Select, Input etc. An error will be pushed if current node does not implement support for Value
+    HasAttribute(String), // Whether the current node has the specified attribute, as a boolean (e.g. "disabled", "checked", "data-in-stock")
+    DataAttributes(Option<ShadowJsonKeyCase>), // All data-* attributes of the current node, as an object keyed by the attribute names without the prefix
 }
-#[derive(Default, Serialize, Deserialize, Debug)]
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+// A value of data.values : its source, and how the extracted string is processed before being stored
+pub struct ShadowJsonValue {
+    #[serde(flatten)]
+    pub source: ShadowJsonValueSource,
+    pub decode: Option<Vec<ShadowJsonDecoder>>, // Applied in order to the extracted string before extract and the transforms, e.g. ["html_entities", "url"]
+    pub transforms: Option<Vec<ShadowJsonTransform>>, // Applied in order to the strings extracted by the Contents, Attribute and Value sources, e.g. ["strip_tags", "collapse_whitespace"]
+    pub decimal_separator: Option<char>, // Decimal separator of the numbers read by the parse_number transform, e.g. "," for "1.280,50". Guessed from the number when not set
+    pub extract: Option<ShadowJsonExtract>, // Regex capture applied to the extracted string before the transforms
+    pub default: Option<String>, // Stored (through the transforms) when the attribute is missing, the element has no contents, the extract regex does not match or the transforms produce an empty string
+    pub required: Option<bool>, // Reports a ShadowError::MissingRequired at the end of the document if no element of the node produced a non-empty value
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+// Keeps the part of the extracted string captured by a regex, e.g. {"match": "SKU: (\\w+)", "group": 1}
+pub struct ShadowJsonExtract {
+    pub r#match: String,
+    pub group: Option<usize>, // Capture group stored, defaults to 1 (0 being the whole match, the default of a regex without group)
+}
+
+impl ShadowJsonValue {
+    pub fn transform(&self, value: &str) -> String {
+        self.transforms.iter().flatten().fold(value.to_string(), |value, transform| transform.apply(&value))
+    }
+    // Data stored for the extracted value : the default when the value is empty, null when the extract regex does not match, a number with the parse_number transform, unless the transformed string holds none
+    // extract_regex is the compiled extract.match, None when invalid (reported when parsing) in which case the value is stored whole
+    pub fn to_data(&self, id: Option<usize>, parent: Weak<RefCell<ShadowData>>, value: &str, extract_regex: Option<&Regex>) -> ShadowData {
+        let decoded = self.decode.iter().flatten().fold(value.to_string(), |value, decoder| decoder.apply(&value));
+        let value = decoded.as_str();
+        let value = match (&self.extract, extract_regex) {
+            (Some(extract), Some(regex)) => {
+                let group = extract.group.unwrap_or(if regex.captures_len() > 1 { 1 } else { 0 });
+                match regex.captures(value).and_then(|captures| captures.get(group)) {
+                    Some(captured) => captured.as_str(),
+                    None => return self.default_data(id, Weak::clone(&parent)).unwrap_or_else(|| ShadowData::new_null(id, parent)),
+                }
+            },
+            _ => value,
+        };
+        let value = self.transform(value);
+        if value.is_empty() {
+            if let Some(data) = self.default_data(id, Weak::clone(&parent)) {
+                return data;
+            }
+        }
+        self.typed_data(id, parent, value)
+    }
+    // Data stored when the source is absent, None without default
+    pub fn default_data(&self, id: Option<usize>, parent: Weak<RefCell<ShadowData>>) -> Option<ShadowData> {
+        self.default.as_ref().map(|default| self.typed_data(id, parent, self.transform(default)))
+    }
+    fn typed_data(&self, id: Option<usize>, parent: Weak<RefCell<ShadowData>>, value: String) -> ShadowData {
+        if self.transforms.iter().flatten().any(|transform| *transform == ShadowJsonTransform::ParseJson) {
+            if let Ok(data) = ShadowData::from_json_str(id, Weak::clone(&parent), &value) {
+                return data;
+            }
+        }
+        if self.transforms.iter().flatten().any(|transform| *transform == ShadowJsonTransform::ParseNumber) {
+            if let Some(number) = parse_number(&value, self.decimal_separator) {
+                return ShadowData::new_number(id, parent, number);
+            }
+        }
+        ShadowData::new_string(id, parent, value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowJsonDecoder {
+    HtmlEntities, // Decodes the character references, named ("&amp;", "&eacute;") or numeric ("&#233;", "&#xE9;"), of the raw html value
+    Url, // Decodes the percent-encoded bytes ("%20", "%E3%81%82"), invalid UTF-8 sequences becoming U+FFFD. "+" is kept
+    Base64, // Decodes a base64 payload, standard or URL-safe, padded or not. A value which is not base64 is kept as-is
+}
+
+impl ShadowJsonDecoder {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            ShadowJsonDecoder::HtmlEntities => decode_html_entities(value).into_owned(),
+            ShadowJsonDecoder::Url => percent_encoding::percent_decode_str(value).decode_utf8_lossy().into_owned(),
+            ShadowJsonDecoder::Base64 => {
+                let payload = value.trim();
+                let engine = if payload.contains(['-', '_']) { &base64::engine::general_purpose::URL_SAFE_NO_PAD_INDIFFERENT } else { &base64::engine::general_purpose::STANDARD_NO_PAD_INDIFFERENT };
+                match base64::Engine::decode(engine, payload) {
+                    Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                    Err(_) => value.to_string(),
+                }
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowJsonTransform {
+    Trim, // Removes the leading and trailing whitespace
+    CollapseWhitespace, // Replaces each run of whitespace (indentation, newlines) with a single space, and trims
+    Lowercase,
+    StripTags, // Removes the <...> tags, e.g. of html stored in an attribute or escaped in a text
+    ParseNumber, // Stores the first number of the string as a number, e.g. "¥1,280" => 1280. Currency symbols, units and thousands separators are dropped
+    ParseJson, // Stores the json document held by the string as data (objects, arrays, numbers...), e.g. data-props='{"a":1}'. Invalid json is stored as a string
+}
+
+impl ShadowJsonTransform {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            ShadowJsonTransform::Trim => value.trim().to_string(),
+            ShadowJsonTransform::CollapseWhitespace => value.split_whitespace().collect::<Vec<&str>>().join(" "),
+            ShadowJsonTransform::Lowercase => value.to_lowercase(),
+            ShadowJsonTransform::StripTags => {
+                let mut stripped = String::with_capacity(value.len());
+                let mut rest = value;
+                while let Some(start) = rest.find('<') {
+                    stripped.push_str(&rest[..start]);
+                    match rest[start..].find('>') {
+                        Some(len) => rest = &rest[start + len + 1..],
+                        None => {
+                            rest = &rest[start..]; // Not a tag : kept as-is
+                            break;
+                        }
+                    }
+                }
+                stripped.push_str(rest);
+                stripped
+            },
+            ShadowJsonTransform::ParseNumber | ShadowJsonTransform::ParseJson => value.to_string(), // Applied by ShadowJsonValue::to_data once the string is final
+        }
+    }
+}
+
+// Reads the first number of value, e.g. "¥1,280" => 1280, "-12.5 %" => -12.5, "1.280,50 €" => 1280.5
+// Without decimal_separator, the last of '.' and ',' is the decimal separator when both are found. A single one is a thousands separator
+// when it repeats ("1,280,000") or is followed by exactly 3 digits ("1,280"), unless the integer part is 0 ("0.125")
+pub(crate) fn parse_number(value: &str, decimal_separator: Option<char>) -> Option<serde_json::Number> {
+    const SEPARATORS: [char; 6] = ['.', ',', '\'', ' ', '\u{a0}', '\u{202f}'];
+    let start = value.find(|c: char| c.is_ascii_digit())?;
+    // A minus sign before the number and its currency symbol ("-$12"), not part of a word ("SKU-12")
+    let prefix = value[..start].trim_end_matches(|c: char| !c.is_alphanumeric() && c != '-' && c != '\u{2212}');
+    let negative = prefix.strip_suffix(['-', '\u{2212}']).is_some_and(|rest| !rest.ends_with(char::is_alphanumeric));
+    // Digits and the separators followed by a digit, up to the first other character ("1,280円" => "1,280")
+    let chars: Vec<char> = value[start..].chars().collect();
+    let mut run = String::new();
+    for (idx, c) in chars.iter().enumerate() {
+        if c.is_ascii_digit() || (SEPARATORS.contains(c) && chars.get(idx + 1).is_some_and(char::is_ascii_digit)) {
+            run.push(*c);
+        } else {
+            break;
+        }
+    }
+    let decimal_separator = decimal_separator.or_else(|| {
+        let last = run.rfind(['.', ','])?;
+        let separator = run[last..].chars().next()?;
+        let repeated = run.matches(separator).count() > 1;
+        let other = if separator == '.' { ',' } else { '.' };
+        let thousands = !run.contains(other) && (repeated || (run.len() - last - 1 == 3 && !run[..last].trim_start_matches('0').is_empty()));
+        (!thousands).then_some(separator)
+    });
+    let mut normalized = String::from(if negative { "-" } else { "" });
+    let mut is_float = false;
+    for c in run.chars() {
+        if c.is_ascii_digit() {
+            normalized.push(c);
+        } else if Some(c) == decimal_separator && !is_float {
+            normalized.push('.');
+            is_float = true;
+        } else if Some(c) == decimal_separator {
+            break; // A second decimal separator ends the number
+        }
+    }
+    if is_float {
+        normalized.parse::<f64>().ok().and_then(serde_json::Number::from_f64)
+    } else {
+        normalized.parse::<i64>().map(serde_json::Number::from)
+            .or_else(|_| normalized.parse::<u64>().map(serde_json::Number::from))
+            .ok()
+            .or_else(|| normalized.parse::<f64>().ok().and_then(serde_json::Number::from_f64))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+// Case of the keys built from attribute names
+pub enum ShadowJsonKeyCase {
+    #[default]
+    KebabCase, // As in the attribute name : "data-price-amount" => "price-amount"
+    CamelCase, // As in the DOM dataset : "data-price-amount" => "priceAmount"
+}
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct ShadowJsonData {
     /*
         Target json key path, where values extracted from this node will be stored.
@@ -21,24 +223,97 @@ pub struct ShadowJsonData {
         Otherwise, new elements will be inserted as keys of an object
         If multiple keys are separated with dots, nested objects will be generated.
         Path can be omitted in children (after being specified at least once), in which case the parent's current path will be used.
+        A path starting with "/" is absolute (from the root of the data), and each leading "../" starts one object higher (arrays are skipped). ".." alone targets the parent object itself
         Examples :
             "first.second" => target json : {"first": {"second": { ...(parsed values as keys) }}}
             "first.second." => target json : {"first": {"second": [ (parsed values as separate elements of array) ]}}
+            "/brands." => target json : {"brands": [ ... ]} at the root, wherever the node is nested
     */
     pub path: Option<String>,
     /*
         A map where key represents the name of the value, and value indicates how the data should be extracted from the current node
     */
-    pub values: Option<IndexMap<String, ShadowJsonValueSource>>
+    pub values: Option<IndexMap<String, ShadowJsonValue>>,
+    /*
+        Selector of the labels associated with the checkbox/radio inputs of this node, relative to the parent node (same as `s`).
+        When set, `Value` sources of checkbox/radio inputs produce an array of {"value", "label", "checked"} objects covering all inputs, checked or not.
+        Labels are associated through their `for` attribute matching the input `id`, or by wrapping the input
+        Example : "label"
+    */
+    pub label_selector: Option<String>,
+    /*
+        For array paths ("items."), store each matched element in an object keyed by the extracted value instead of appending it to an array.
+        Uses the same sources as values, e.g. {"source": "Attribute", "name": "data-sku"}
+        Items whose key is already present are resolved according to key_collision, which defaults to the collision_policy option
+    */
+    pub key_from: Option<ShadowJsonValueSource>,
+    /*
+        For array paths ("items."), key of the values identifying an item. When an element closes, its item is compared with the items collected before,
+        and a duplicate is resolved according to key_collision (defaults to merge) : merged into the first one, dropped (keep_first), replacing it (keep_last) or dropped with an error
+        Example : "sku"
+    */
+    pub dedupe_by: Option<String>,
+    pub key_collision: Option<ShadowDataCollisionPolicy>,
+    /*
+        Sorts the keys of the collected objects before the data is injected, so that the emitted json does not depend on the order of the document.
+        Applies to the object at path, or to the items of an array path, and to all the objects they contain
+    */
+    pub sort_keys: Option<bool>,
+    /*
+        For array paths ("items."), orders the items by one of their values before the data is injected, optionally followed by "asc" (default) or "desc".
+        Numbers and strings holding a number ("$1,280.50", read as by the parse_number transform) are compared as numbers, items without the value come last. Keyed collections (key_from) are ordered the same way
+        Examples : "price", "price desc"
+    */
+    pub sort_by: Option<String>,
+    pub required: Option<bool>, // Reports a ShadowError::MissingRequired at the end of the document if the selector never matched
+}
+
+/// Kinds of the string fields of a definition, to transform some of them only with ShadowJson::transform_fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShadowJsonField {
+    Selector, // s and data.label_selector
+    Html, // wrap, the injected fragments (append, prepend, insert_before, insert_after, region.html) and the val of set_html content edits
+    Value, // val of the other edit operations and inline rules, edit.style values, string inline vars, data.values defaults, enabled_if and the region markers
+    Pattern, // Regexes : match of the edit operations and inline rules, data.values extract
+    Url, // from, to and base of rewrite_urls
+    DataPath, // data.path, data.dedupe_by, data.sort_by, repeat_for, aggregate expressions and the path of copy_from_data operations
+    Name, // Attribute and key names : edit.attrs, edit.style properties, data.values keys, value source names, rewrite_urls attrs, inline vars names, region.key, aggregate keys
+}
+
+/// How the selector of a node is combined with the selector of its parent node
+#[derive(Default, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowJsonCombinator {
+    #[default]
+    Descendant, // "parent child" : any element inside the parent element
+    Child, // "parent > child" : direct children of the parent element
+    #[serde(rename = "self")]
+    SameElement, // "parent.child" : refines the parent element itself (compound selector). The selector must start with a class, id, attribute or pseudo-class
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct ShadowJson {
-    pub s: String, // selector of an element
+    #[serde(default)]
+    pub s: String, // selector of an element. Starting with "+" or "~", it selects the elements right after / after an element of the parent node
+    pub combinator: Option<ShadowJsonCombinator>, // How s is combined with the parent node selector : "descendant" (default), "child" or "self"
+    pub enabled_if: Option<String>, // Condition on the flags set with ShadowApi::set_flags, the node and its sub nodes being ignored when false, e.g. "ab_test=B && locale!=ja"
+    pub absolute: Option<bool>, // Select from the document root instead of inside the parent element, while collecting the data into the data of the last element matched by the parent node
     pub hide: Option<bool>, // Whether this element should be hidden or not
     pub delete: Option<bool>, // Whether this element should be deleted or not
+    pub wrap: Option<String>, // Opening HTML tag (or tag name) to surround this element with, e.g. "<div class=\"wrapper\">". The closing tag is generated from it. Other forms and void elements are reported and skipped
+    pub unwrap: Option<bool>, // Whether this element's own tags should be removed, keeping its children in place
+    pub empty: Option<bool>, // Whether this element's children should be removed, keeping the element and its attributes
+    pub form: Option<bool>, // Serialize the fields of the matched <form> elements into the data of this node as {name: value}, as a browser would submit them
+    pub limit: Option<usize>, // Apply this node (operations, data collection and sub nodes) only to the first N matched elements
+    pub skip: Option<usize>, // Ignore the first N matched elements. Combined with limit, the node applies to the N elements following the skipped ones
+    pub nth: Option<String>, // Apply this node only to the elements at the given position among the matches under the same parent element : "1", "odd", "even" or "an+b" (e.g. "3n+1")
+    pub stop_after: Option<bool>, // With the as_json option, stop reading the input once the first element this node applies to has closed (its contents and sub nodes being processed)
 
     pub edit: Option<ShadowJsonEdit>,
+    pub rewrite_urls: Option<ShadowJsonRewriteUrls>, // Rewrites the URLs of this element and all its descendants from one origin to another
+    pub inline: Option<ShadowJsonInline>, // Rewrites the whole contents of the matched <style> and <script> elements
 
     //  Indicates how to extract the data out of the current node. Applies AFTER attribute/content edit
     pub data: Option<ShadowJsonData>,
@@ -48,86 +323,614 @@ pub struct ShadowJson {
     pub prepend: Option<Vec<String>>,  // Vec of HTML tags. If an item defines multiple tags, only the first one will be parsed. Appends a new child, before existing children
     pub insert_before: Option<Vec<String>>, // Vec of HTML tags. If an item defines multiple tags, only the first one will be parsed. Inserts a new sibling before this node
     pub insert_after: Option<Vec<String>>, // Vec of HTML tags. If an item defines multiple tags, only the first one will be parsed. Inserts a new sibling after this node
+    pub nonce: Option<bool>, // Whether the CSP nonce set with ShadowApi::set_csp_nonce is attached to the <script> tags of the injected fragments above
+    pub region: Option<ShadowJsonRegion>, // Operation on the contents found between two marker comments inside the matched elements
+    pub repeat_for: Option<String>, // Dot separated path (from the root) of a collected array : the append and prepend fragments are rendered once per item, {{item.field}} placeholders resolving within the item
+    pub aggregate: Option<IndexMap<String, String>>, // Values derived from the collected data before it is injected, by dot separated path (from the root), e.g. {"items_count": "count(items)", "min_price": "min(items.*.price)"}
 
     // Recursive structure
     pub sub: Option<Rc<Vec<Rc<RefCell<ShadowJson>>>>>, // For subselectors having the same struct
+
+    // Reuse
+    pub defs: Option<IndexMap<String, ShadowJson>>, // Named nodes of the root node, reused by the nodes referencing them with "use"
+    #[serde(rename = "use")]
+    pub use_def: Option<String>, // Name of a node of defs : its fields are used for the fields this node does not set. Resolved by parse_str
+    pub vars: Option<IndexMap<String, String>>, // Variables of the root node, replacing the ${name} references of the strings. Resolved by parse_str
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+impl ShadowJsonData {
+    /// Key and direction (true when descending) of sort_by
+    pub fn sort_order(&self) -> Option<(String, bool)> {
+        let sort_by = self.sort_by.as_deref()?.trim();
+        match sort_by.rsplit_once(' ') {
+            Some((key, order)) if order.eq_ignore_ascii_case("desc") => Some((key.trim_end().to_string(), true)),
+            Some((key, order)) if order.eq_ignore_ascii_case("asc") => Some((key.trim_end().to_string(), false)),
+            _ => Some((sort_by.to_string(), false)),
+        }
+    }
+}
+
+impl Clone for ShadowJson {
+    fn clone(&self) -> Self {
+        ShadowJson {
+            s: self.s.clone(),
+            combinator: self.combinator,
+            enabled_if: self.enabled_if.clone(),
+            absolute: self.absolute,
+            hide: self.hide,
+            delete: self.delete,
+            wrap: self.wrap.clone(),
+            unwrap: self.unwrap,
+            empty: self.empty,
+            form: self.form,
+            limit: self.limit,
+            skip: self.skip,
+            nth: self.nth.clone(),
+            stop_after: self.stop_after,
+            edit: self.edit.clone(),
+            rewrite_urls: self.rewrite_urls.clone(),
+            inline: self.inline.clone(),
+            data: self.data.clone(),
+            append: self.append.clone(),
+            prepend: self.prepend.clone(),
+            insert_before: self.insert_before.clone(),
+            insert_after: self.insert_after.clone(),
+            nonce: self.nonce,
+            region: self.region.clone(),
+            repeat_for: self.repeat_for.clone(),
+            aggregate: self.aggregate.clone(),
+            // Deep copy : the copy does not share its sub nodes with the original
+            sub: self.sub.as_ref().map(|sub| Rc::new(sub.iter().map(|node| Rc::new(RefCell::new(node.borrow().clone()))).collect())),
+            defs: self.defs.clone(),
+            use_def: self.use_def.clone(),
+            vars: self.vars.clone(),
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct ShadowJsonEdit {
-    pub attrs: Option<IndexMap<String, ShadowJsonEditOne>>,
+    pub attrs: Option<IndexMap<String, ShadowJsonEditOps>>,
     pub content: Option<ShadowJsonEditOne>,
+    pub style: Option<IndexMap<String, Option<String>>>, // CSS properties of the inline style to set (value) or remove (null), the other declarations being kept
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ShadowJsonRewriteUrls {
+    pub from: String, // Origin (optionally with a path prefix) to rewrite, e.g. "https://origin.example.com"
+    pub to: String, // Replacement origin (optionally with a path prefix), e.g. "https://www.example.com/shop"
+    pub base: Option<String>, // URL the relative URLs are resolved against, usually the page URL. Relative URLs are left untouched if unset
+    pub attrs: Option<Vec<String>>, // Attributes to rewrite. Defaults to href, src, srcset, action and style (CSS url() references)
+}
+
+/// Region delimited by two marker comments, e.g. `<!-- region:start -->` and `<!-- region:end -->` as found in CMS templates.
+/// The markers are matched by their trimmed text, inside the elements matched by the node, and are kept
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ShadowJsonRegion {
+    pub start: String, // Text of the comment opening the region, e.g. "region:start"
+    pub end: String, // Text of the comment closing the region
+    #[serde(default)]
+    pub op: ShadowJsonRegionOp,
+    pub html: Option<String>, // replace : fragment inserted in place of the contents
+    pub key: Option<String>, // extract : key of the text in the current data object, "region" by default
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ShadowJsonRegionOp {
+    #[default]
+    Delete, // Removes the contents between the markers
+    Replace, // Replaces the contents between the markers with html
+    Extract, // Collects the text between the markers into the data, the contents being kept
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ShadowJsonInline {
+    pub rules: Option<Vec<ShadowJsonEditOne>>, // match_replace operations applied in order to the whole buffered contents
+    pub rewrite_urls: Option<ShadowJsonRewriteUrls>, // Rewrites the CSS url() references of <style> contents
+    pub vars: Option<IndexMap<String, serde_json::Value>>, // JS variables declared at the start of <script> contents, e.g. {"locale": "ja"} => var locale = "ja";
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct ShadowJsonEditOne {
     pub op: String,
     pub val: Option<String>,
-    pub r#match: Option<String>
+    pub r#match: Option<String>,
+    pub limit: Option<usize>, // match_replace only : maximum amount of replaced matches, from the start of the value. All matches are replaced if unset or 0
+    pub path: Option<String>, // copy_from_data only : dot separated path (from the root) of the collected data copied into the attribute. val is used instead if nothing was collected there
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+// A single operation, or a list of operations applied sequentially on the same attribute
+pub enum ShadowJsonEditOps {
+    One(ShadowJsonEditOne),
+    Many(Vec<ShadowJsonEditOne>),
+}
+impl ShadowJsonEditOps {
+    pub fn ops(&self) -> &[ShadowJsonEditOne] {
+        match self {
+            ShadowJsonEditOps::One(op) => std::slice::from_ref(op),
+            ShadowJsonEditOps::Many(ops) => ops.as_slice(),
+        }
+    }
+    pub fn ops_mut(&mut self) -> &mut [ShadowJsonEditOne] {
+        match self {
+            ShadowJsonEditOps::One(op) => std::slice::from_mut(op),
+            ShadowJsonEditOps::Many(ops) => ops.as_mut_slice(),
+        }
+    }
 }
 impl ShadowJson {
     // Wrapper function to unformize deserialization and add global error handling
     pub fn parse_str(json: &str, errors: Rc<RefCell<Vec<String>>>) -> Self {
-        // New lines are not allowed in json multi-line string values => just remove all of them
-        let json_processed = json.replace("\n", "").replace("  ", " ");
-        let jd = &mut serde_json::Deserializer::from_str(json_processed.as_str());
-        let result: Result<ShadowJson, _> = serde_path_to_error::deserialize(jd);
-
-        match result {
+        match Self::try_parse_str(json) {
             Ok(parsed) => parsed,
             Err(err) => {
                 let mut errors_m = errors.borrow_mut();
-                errors_m.push(format!("Invalid json : {}", err.to_string()));
+                errors_m.push(err.to_string());
                 ShadowJson::default()
             }
         }
     }
 
-    // Useful for replacing values in parsed ShadowJson
+    /// Deserializes a definition, returning a ShadowError::InvalidJson with the path of the failing field and its line/column in the source
+    /// Raw line breaks and tabs inside string values (multi-line definitions) are kept, as if they had been escaped
+    pub fn try_parse_str(json: &str) -> Result<Self, ShadowError> {
+        let (json_processed, inserted) = Self::escape_raw_controls(json);
+        let jd = &mut serde_json::Deserializer::from_str(json_processed.as_str());
+        let mut parsed: Self = serde_path_to_error::deserialize(jd).map_err(|err| {
+            let path = err.path().to_string();
+            let inner = err.into_inner();
+            let (line, column) = if inner.line() == 0 {
+                (0, 0)
+            } else {
+                Self::source_position(json, &json_processed, &inserted, inner.line(), inner.column())
+            };
+            // The position is reported by the fields, serde_json appends it to its message otherwise
+            let msg = inner.to_string();
+            let msg = match msg.rfind(" at line ") {
+                Some(idx) => msg[..idx].to_string(),
+                None => msg,
+            };
+            ShadowError::InvalidJson { path, line, column, msg }
+        })?;
+        let defs = parsed.defs.take().unwrap_or_default();
+        parsed.resolve_uses(&defs, &mut Vec::new(), ".")?;
+        let vars = parsed.vars.take().unwrap_or_default();
+        parsed.resolve(&vars)?;
+        Ok(parsed)
+    }
+
+    /// Deserializes a definition, or an array of definitions as found in the rule files of the bindings and the CLI.
+    /// Errors in the items of an array are reported with a path starting with their index, e.g. "[1].sub[0].s", without position
+    pub fn try_parse_list(json: &str) -> Result<Vec<Self>, ShadowError> {
+        if !json.trim_start().starts_with('[') {
+            return Self::try_parse_str(json).map(|def| vec![def]);
+        }
+        let (json_processed, _) = Self::escape_raw_controls(json);
+        let defs: Vec<serde_json::Value> = serde_json::from_str(&json_processed)
+            .map_err(|err| ShadowError::InvalidJson { path: ".".to_string(), line: 0, column: 0, msg: err.to_string() })?;
+        defs.iter().enumerate().map(|(idx, def)| {
+            Self::try_parse_str(&def.to_string()).map_err(|err| match err {
+                ShadowError::InvalidJson { path, msg, .. } => {
+                    let path = if path == "." { format!("[{}]", idx) } else { format!("[{}].{}", idx, path) };
+                    ShadowError::InvalidJson { path, line: 0, column: 0, msg }
+                },
+                err => err,
+            })
+        }).collect()
+    }
+
+    /// Replaces the ${name} references of the strings of the node and its sub nodes (selectors, edit values, injected fragments...) with the values of vars.
+    /// Unknown references are kept, as ${name} also refers to the named groups of the match_replace regexes, but not in the selectors where they are an error
+    pub fn resolve(&mut self, vars: &IndexMap<String, String>) -> Result<(), ShadowError> {
+        self.transform_strings(&mut |value: &mut String| {
+            if value.contains("${") {
+                *value = Self::substitute(value, vars);
+            }
+        });
+        self.check_selector_vars(".")
+    }
+
+    fn substitute(value: &str, vars: &IndexMap<String, String>) -> String {
+        let mut result = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 2..start + end];
+            result.push_str(&rest[..start]);
+            match vars.get(name) {
+                Some(var) => result.push_str(var),
+                None => result.push_str(&rest[start..=start + end]),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    // Reports the first reference left in a selector by resolve
+    fn check_selector_vars(&self, path: &str) -> Result<(), ShadowError> {
+        if let Some(start) = self.s.find("${") {
+            let name = self.s[start + 2..].split('}').next().unwrap_or_default();
+            return Err(ShadowError::InvalidJson { path: path.to_string(), line: 0, column: 0, msg: format!("unknown variable '{}' in selector {}", name, self.s) });
+        }
+        if let Some(sub) = &self.sub {
+            for (idx, node) in sub.iter().enumerate() {
+                let sub_path = if path == "." { format!("sub[{}]", idx) } else { format!("{}.sub[{}]", path, idx) };
+                node.borrow().check_selector_vars(&sub_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the "use" references of the node and its sub nodes with the nodes of defs. using holds the names being resolved, to detect cycles
+    fn resolve_uses(&mut self, defs: &IndexMap<String, ShadowJson>, using: &mut Vec<String>, path: &str) -> Result<(), ShadowError> {
+        let invalid = |msg: String| ShadowError::InvalidJson { path: path.to_string(), line: 0, column: 0, msg };
+        if self.defs.is_some() {
+            return Err(invalid("defs are only read in the root node".to_string()));
+        }
+        if self.vars.is_some() && path != "." {
+            return Err(invalid("vars are only read in the root node".to_string()));
+        }
+        if let Some(name) = self.use_def.take() {
+            if using.contains(&name) {
+                return Err(invalid(format!("cycle in the definitions : {} > {}", using.join(" > "), name)));
+            }
+            let mut def = defs.get(&name).cloned().ok_or_else(|| invalid(format!("unknown definition '{}'", name)))?;
+            using.push(name);
+            def.resolve_uses(defs, using, path)?;
+            using.pop();
+            self.fill_from(def);
+        }
+        if let Some(sub) = &self.sub {
+            for (idx, node) in sub.iter().enumerate() {
+                let sub_path = if path == "." { format!("sub[{}]", idx) } else { format!("{}.sub[{}]", path, idx) };
+                node.borrow_mut().resolve_uses(defs, using, &sub_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Sets the fields of the node which are not set yet to the ones of def
+    fn fill_from(&mut self, def: ShadowJson) {
+        if self.s.is_empty() {
+            self.s = def.s;
+        }
+        self.combinator = self.combinator.or(def.combinator);
+        self.enabled_if = self.enabled_if.take().or(def.enabled_if);
+        self.absolute = self.absolute.or(def.absolute);
+        self.hide = self.hide.or(def.hide);
+        self.delete = self.delete.or(def.delete);
+        self.wrap = self.wrap.take().or(def.wrap);
+        self.unwrap = self.unwrap.or(def.unwrap);
+        self.empty = self.empty.or(def.empty);
+        self.form = self.form.or(def.form);
+        self.limit = self.limit.or(def.limit);
+        self.skip = self.skip.or(def.skip);
+        self.nth = self.nth.take().or(def.nth);
+        self.stop_after = self.stop_after.or(def.stop_after);
+        self.edit = self.edit.take().or(def.edit);
+        self.rewrite_urls = self.rewrite_urls.take().or(def.rewrite_urls);
+        self.inline = self.inline.take().or(def.inline);
+        self.data = self.data.take().or(def.data);
+        self.append = self.append.take().or(def.append);
+        self.prepend = self.prepend.take().or(def.prepend);
+        self.insert_before = self.insert_before.take().or(def.insert_before);
+        self.insert_after = self.insert_after.take().or(def.insert_after);
+        self.nonce = self.nonce.or(def.nonce);
+        self.region = self.region.take().or(def.region);
+        self.repeat_for = self.repeat_for.take().or(def.repeat_for);
+        self.aggregate = self.aggregate.take().or(def.aggregate);
+        self.sub = self.sub.take().or(def.sub);
+    }
+
+    // Escapes the control characters json does not allow inside strings. Returns the processed json and the offsets of the bytes inserted in it
+    fn escape_raw_controls(json: &str) -> (String, Vec<usize>) {
+        let mut processed = String::with_capacity(json.len());
+        let mut inserted = Vec::new();
+        let mut in_string = false;
+        let mut escaped = false;
+        for c in json.chars() {
+            if in_string && !escaped {
+                let replacement = match c {
+                    '\n' => Some("\\n"),
+                    '\r' => Some("\\r"),
+                    '\t' => Some("\\t"),
+                    _ => None,
+                };
+                if let Some(replacement) = replacement {
+                    inserted.push(processed.len() + 1);
+                    processed.push_str(replacement);
+                    continue;
+                }
+            }
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+            } else if c == '"' {
+                in_string = true;
+            }
+            processed.push(c);
+        }
+        (processed, inserted)
+    }
+
+    // Maps a 1-based line/column of the processed json back to the source
+    fn source_position(json: &str, processed: &str, inserted: &[usize], line: usize, column: usize) -> (usize, usize) {
+        if inserted.is_empty() {
+            return (line, column);
+        }
+        let line_start = processed.split_inclusive('\n').take(line - 1).map(str::len).sum::<usize>();
+        let offset = (line_start + column.saturating_sub(1)).min(processed.len());
+        let offset = offset - inserted.iter().filter(|idx| **idx <= offset).count();
+        let before = &json.as_bytes()[..offset.min(json.len())];
+        let line = before.iter().filter(|b| **b == b'\n').count() + 1;
+        let column = before.len() - before.iter().rposition(|b| *b == b'\n').map(|idx| idx + 1).unwrap_or(0) + 1;
+        (line, column)
+    }
+
+    /// JSON Schema (draft 2020-12) of the definitions accepted by parse_str, for editors and external validation
+    #[cfg(feature = "schemars")]
+    pub fn schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(ShadowJson)).unwrap_or_default()
+    }
+
+    /// Evaluates the enabled_if condition of the node against flags (true without condition).
+    /// The condition is a list of terms separated by "||", each being a list of tests separated by "&&" : "name=value", "name!=value", "name" (set) or "!name" (not set)
+    pub fn is_enabled(&self, flags: &HashMap<String, String>) -> Result<bool, ShadowError> {
+        let Some(condition) = &self.enabled_if else {
+            return Ok(true);
+        };
+        let test = |test: &str| -> Result<bool, ShadowError> {
+            let test = test.trim();
+            let (name, expected, negated) = if let Some((name, value)) = test.split_once("!=") {
+                (name.trim(), Some(value.trim()), true)
+            } else if let Some((name, value)) = test.split_once('=') {
+                (name.trim(), Some(value.trim()), false)
+            } else if let Some(name) = test.strip_prefix('!') {
+                (name.trim(), None, true)
+            } else {
+                (test, None, false)
+            };
+            if name.is_empty() || name.contains(['!', '=', ' ']) {
+                return Err(ShadowError::Other(format!("Invalid enabled_if '{}' : '{}' is not a test", condition, test)));
+            }
+            let matched = match expected {
+                Some(expected) => flags.get(name).is_some_and(|value| value == expected),
+                None => flags.contains_key(name),
+            };
+            Ok(matched != negated)
+        };
+        for term in condition.split("||") {
+            let mut enabled = true;
+            for condition_test in term.split("&&") {
+                enabled &= test(condition_test)?; // Every test is checked, so that invalid ones are always reported
+            }
+            if enabled {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Copy of the node and its sub nodes without the fields editing the document, see ShadowApi::parse_collect_only
+    pub fn collect_only(&self) -> ShadowJson {
+        let mut copy = self.clone();
+        copy.retain_concern(&|node| {
+            node.hide = None;
+            node.delete = None;
+            node.wrap = None;
+            node.unwrap = None;
+            node.empty = None;
+            node.edit = None;
+            node.rewrite_urls = None;
+            node.inline = None;
+            node.append = None;
+            node.prepend = None;
+            node.insert_before = None;
+            node.insert_after = None;
+            node.repeat_for = None;
+            node.region = node.region.take().filter(|region| region.op == ShadowJsonRegionOp::Extract);
+        });
+        copy
+    }
+
+    /// Copy of the node and its sub nodes without the fields collecting the data, see ShadowApi::parse_mutate_only
+    pub fn mutate_only(&self) -> ShadowJson {
+        let mut copy = self.clone();
+        copy.retain_concern(&|node| {
+            node.data = None;
+            node.form = None;
+            node.aggregate = None;
+            node.region = node.region.take().filter(|region| region.op != ShadowJsonRegionOp::Extract);
+        });
+        copy
+    }
+
+    // Applies strip to the node and its sub nodes, which are deep copies already
+    fn retain_concern(&mut self, strip: &dyn Fn(&mut ShadowJson)) {
+        strip(self);
+        for node in self.sub.iter().flat_map(|sub| sub.iter()) {
+            node.borrow_mut().retain_concern(strip);
+        }
+    }
+
+    /// Calls f on every string of the node and its sub nodes, e.g. to replace values in a parsed definition
     pub fn transform_strings(&mut self, f: &mut dyn for<'r> FnMut(&'r mut String)) {
-        f(&mut self.s);
+        self.visit_strings(&mut |_, value| f(value));
+    }
+
+    /// Calls f on the strings of the given kinds only, e.g. `&[ShadowJsonField::Html]` for the injected fragments
+    pub fn transform_fields(&mut self, fields: &[ShadowJsonField], f: &mut dyn for<'r> FnMut(&'r mut String)) {
+        self.visit_strings(&mut |field, value| {
+            if fields.contains(&field) {
+                f(value);
+            }
+        });
+    }
+
+    fn visit_strings(&mut self, f: &mut dyn FnMut(ShadowJsonField, &mut String)) {
+        use ShadowJsonField::*;
+        f(Selector, &mut self.s);
+        if let Some(enabled_if) = &mut self.enabled_if {
+            f(Value, enabled_if);
+        }
+
+        if let Some(wrap) = &mut self.wrap {
+            f(Html, wrap);
+        }
 
         if let Some(edit) = &mut self.edit {
             if let Some(attrs) = &mut edit.attrs {
-                attrs.iter_mut().for_each(|attr| {
-                    if let Some(val) = &mut attr.1.val {
-                        f(val);
-                    }
+                Self::visit_keys(attrs, f);
+                attrs.values_mut().for_each(|ops| {
+                    ops.ops_mut().iter_mut().for_each(|op| op.visit_strings(f, Value));
                 });
             }
             if let Some(content) = &mut edit.content {
-                if let Some(val) = &mut content.val {
-                    f(val);
-                }
+                let kind = if content.op == "set_html" { Html } else { Value };
+                content.visit_strings(f, kind);
+            }
+            if let Some(style) = &mut edit.style {
+                Self::visit_keys(style, f);
+                style.values_mut().flatten().for_each(|value| f(Value, value));
+            }
+        }
+
+        if let Some(rewrite_urls) = &mut self.rewrite_urls {
+            rewrite_urls.visit_strings(f);
+        }
+
+        if let Some(inline) = &mut self.inline {
+            if let Some(rules) = &mut inline.rules {
+                rules.iter_mut().for_each(|rule| rule.visit_strings(f, Value));
+            }
+            if let Some(rewrite_urls) = &mut inline.rewrite_urls {
+                rewrite_urls.visit_strings(f);
+            }
+            if let Some(vars) = &mut inline.vars {
+                Self::visit_keys(vars, f);
+                vars.values_mut().for_each(|value| {
+                    if let serde_json::Value::String(value) = value {
+                        f(Value, value);
+                    }
+                });
+            }
+        }
+
+        if let Some(data) = &mut self.data {
+            if let Some(path) = &mut data.path {
+                f(DataPath, path);
+            }
+            if let Some(values) = &mut data.values {
+                Self::visit_keys(values, f);
+                values.values_mut().for_each(|value| {
+                    value.source.visit_strings(f);
+                    if let Some(extract) = &mut value.extract {
+                        f(Pattern, &mut extract.r#match);
+                    }
+                    if let Some(default) = &mut value.default {
+                        f(Value, default);
+                    }
+                });
+            }
+            if let Some(label_selector) = &mut data.label_selector {
+                f(Selector, label_selector);
+            }
+            if let Some(key_from) = &mut data.key_from {
+                key_from.visit_strings(f);
+            }
+            if let Some(dedupe_by) = &mut data.dedupe_by {
+                f(DataPath, dedupe_by);
+            }
+            if let Some(sort_by) = &mut data.sort_by {
+                f(DataPath, sort_by);
             }
         }
 
-        if let Some(append) = &mut self.append {
-            append.iter_mut().for_each(|a| {
-                f(a);
-            });
+        for fragments in [&mut self.append, &mut self.prepend, &mut self.insert_before, &mut self.insert_after].into_iter().flatten() {
+            fragments.iter_mut().for_each(|fragment| f(Html, fragment));
         }
-        if let Some(prepend) = &mut self.prepend {
-            prepend.iter_mut().for_each(|a| {
-                f(a)
-            });
+        if let Some(aggregate) = &mut self.aggregate {
+            Self::visit_keys(aggregate, f);
+            aggregate.values_mut().for_each(|expression| f(DataPath, expression));
         }
-        if let Some(insert_before) = &mut self.insert_before {
-            insert_before.iter_mut().for_each(|a| {
-                f(a)
-            });
+        if let Some(repeat_for) = &mut self.repeat_for {
+            f(DataPath, repeat_for);
         }
-        if let Some(insert_after) = &mut self.insert_after {
-            insert_after.iter_mut().for_each(|a| {
-                f(a)
-            });
+
+        if let Some(region) = &mut self.region {
+            f(Value, &mut region.start);
+            f(Value, &mut region.end);
+            if let Some(html) = &mut region.html {
+                f(Html, html);
+            }
+            if let Some(key) = &mut region.key {
+                f(Name, key);
+            }
         }
 
         // Recursive replacement
         if let Some(sub) = &self.sub {
             sub.iter().for_each(|el| {
-                el.borrow_mut().transform_strings(f);
+                el.borrow_mut().visit_strings(f);
             })
         }
     }
-}
\ No newline at end of file
+
+    // Calls f on the keys of map (names), which are re-inserted in the same order
+    fn visit_keys<V>(map: &mut IndexMap<String, V>, f: &mut dyn FnMut(ShadowJsonField, &mut String)) {
+        *map = std::mem::take(map).into_iter().map(|(mut key, value)| {
+            f(ShadowJsonField::Name, &mut key);
+            (key, value)
+        }).collect();
+    }
+}
+impl ShadowJsonEditOne {
+    fn visit_strings(&mut self, f: &mut dyn FnMut(ShadowJsonField, &mut String), val_kind: ShadowJsonField) {
+        if let Some(val) = &mut self.val {
+            f(val_kind, val);
+        }
+        if let Some(pattern) = &mut self.r#match {
+            f(ShadowJsonField::Pattern, pattern);
+        }
+        if let Some(path) = &mut self.path {
+            f(ShadowJsonField::DataPath, path);
+        }
+    }
+}
+
+impl ShadowJsonRewriteUrls {
+    fn visit_strings(&mut self, f: &mut dyn FnMut(ShadowJsonField, &mut String)) {
+        f(ShadowJsonField::Url, &mut self.from);
+        f(ShadowJsonField::Url, &mut self.to);
+        if let Some(base) = &mut self.base {
+            f(ShadowJsonField::Url, base);
+        }
+        self.attrs.iter_mut().flatten().for_each(|attr| f(ShadowJsonField::Name, attr));
+    }
+}
+
+impl ShadowJsonValueSource {
+    fn visit_strings(&mut self, f: &mut dyn FnMut(ShadowJsonField, &mut String)) {
+        if let ShadowJsonValueSource::Attribute(name) | ShadowJsonValueSource::HasAttribute(name) = self {
+            f(ShadowJsonField::Name, name);
+        }
+    }
+}
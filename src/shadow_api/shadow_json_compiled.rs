@@ -0,0 +1,61 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use lol_html::Selector;
+use regex::Regex;
+use serde::Deserialize;
+
+use super::{ShadowApi, ShadowApiOptions, ShadowJson, ShadowError};
+
+/// Definitions compiled once (selectors parsed, regexes compiled), to be instantiated into a fresh ShadowApi per request
+/// Unlike ShadowJson, it is Send + Sync : it can be shared between threads, e.g. in a static or an Arc
+pub struct CompiledShadowJson {
+    defs: Vec<serde_json::Value>, // Definitions, as validated by compile. Deserialized again by each instance, as ShadowJson relies on Rc
+    pub(crate) selectors: HashMap<String, Selector>, // Full selectors built by parse, and their parsed form
+    pub(crate) regexes: HashMap<String, Regex>, // match_replace and extract expressions of the definitions, compiled
+}
+
+impl CompiledShadowJson {
+    /// Builds the handler plan of the definitions once, reporting invalid selectors and regexes to the errors
+    pub fn compile(json_def: &Rc<Vec<Rc<RefCell<ShadowJson>>>>, errors: Rc<RefCell<Vec<String>>>) -> Result<Self, ShadowError> {
+        let defs = json_def.iter()
+            .map(|def| serde_json::to_value(&*def.borrow()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ShadowError::Other(format!("[compile] definition cannot be serialized : {}", e)))?;
+
+        // A throwaway parse collects the selectors exactly as they are built for the handlers, along with the compiled regexes
+        let shadow_api = ShadowApi::new(None);
+        let cache = shadow_api.parse(Rc::clone(json_def), errors);
+        let mut cache_borrowed = cache.borrow_mut();
+        let selectors = std::mem::take(&mut cache_borrowed.selectors);
+        let regexes = std::mem::take(&mut cache_borrowed.regex_map);
+
+        Ok(Self { defs, selectors, regexes })
+    }
+
+    /// Same as compile, from the json of a single definition
+    pub fn compile_str(json: &str, errors: Rc<RefCell<Vec<String>>>) -> Result<Self, ShadowError> {
+        let json_def = ShadowJson::try_parse_str(json)?;
+        Self::compile(&Rc::new(vec![Rc::new(RefCell::new(json_def))]), errors)
+    }
+
+    /// Fresh definitions, for ShadowApi::parse_compiled
+    pub fn definitions(&self) -> Result<Rc<Vec<Rc<RefCell<ShadowJson>>>>, ShadowError> {
+        let defs = self.defs.iter()
+            .map(|def| ShadowJson::deserialize(def).map(|def| Rc::new(RefCell::new(def))))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ShadowError::Other(format!("[instantiate] invalid definition : {}", e)))?;
+        Ok(Rc::new(defs))
+    }
+
+    /// A ShadowApi ready to process a document. To call setters (formatter, callbacks...) first, use ShadowApi::new and parse_compiled instead
+    pub fn instantiate<'h>(&self, options: Option<ShadowApiOptions>, errors: Rc<RefCell<Vec<String>>>) -> ShadowApi<'h> {
+        let shadow_api = ShadowApi::new(options);
+        shadow_api.parse_compiled(self, errors);
+        shadow_api
+    }
+}
+
+// Shared across threads by design
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CompiledShadowJson>();
+};
@@ -0,0 +1,61 @@
+use std::io::Read;
+
+/// Serialized collected data, for the as_json mode : its length is known upfront (Content-Length), and it can be consumed as a reader or as an iterator of chunks
+#[derive(Debug, Clone, Default)]
+pub struct ShadowJsonOutput {
+    bytes: Vec<u8>,
+    pos: usize, // Amount of bytes already consumed
+    chunk_size: usize, // Maximum size of the chunks returned by the iterator
+}
+
+impl ShadowJsonOutput {
+    pub fn new(bytes: Vec<u8>, chunk_size: usize) -> Self {
+        Self { bytes, pos: 0, chunk_size: chunk_size.max(1) }
+    }
+
+    /// Total length of the serialized data, in bytes
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Content type to send along with the data
+    pub fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    /// Remaining (not yet consumed) serialized data
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[self.pos..]
+    }
+
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.bytes.drain(..self.pos);
+        self.bytes
+    }
+}
+
+impl Read for ShadowJsonOutput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n_bytes = self.as_bytes().read(buf)?;
+        self.pos += n_bytes;
+        Ok(n_bytes)
+    }
+}
+
+impl Iterator for ShadowJsonOutput {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.bytes.len() {
+            return None;
+        }
+        let end = (self.pos + self.chunk_size).min(self.bytes.len());
+        let chunk = self.bytes[self.pos..end].to_vec();
+        self.pos = end;
+        Some(chunk)
+    }
+}
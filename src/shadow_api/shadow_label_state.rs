@@ -0,0 +1,56 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use super::{ShadowData, ShadowDataCollisionPolicy};
+
+// Tracks the association between checkbox/radio inputs and their labels (see ShadowJsonData.label_selector)
+#[derive(Default)]
+pub(super) struct LabelState {
+    pub inputs_by_id: HashMap<String, Rc<RefCell<ShadowData>>>, // Collected inputs still waiting for a label pointing at their id
+    pub labels_by_for: HashMap<String, String>, // Texts of the labels found before the input they point at
+    pub open_label: Option<(Option<String>, String)>, // "for" attribute and text of the label currently streamed
+    pub wrapped_input: Option<Rc<RefCell<ShadowData>>>, // Input found inside the label currently streamed
+}
+
+impl LabelState {
+    pub fn add_input(&mut self, id: Option<&String>, input: Rc<RefCell<ShadowData>>) {
+        if let Some((None, _)) = &self.open_label {
+            self.wrapped_input = Some(Rc::clone(&input));
+        }
+        if let Some(id) = id {
+            match self.labels_by_for.remove(id) {
+                Some(text) => Self::set_label(&input, text),
+                None => {
+                    self.inputs_by_id.insert(id.clone(), input);
+                }
+            }
+        }
+    }
+
+    pub fn close_label(&mut self) {
+        let wrapped_input = self.wrapped_input.take();
+        if let Some((for_id, text)) = self.open_label.take() {
+            let text = text.trim().to_string();
+            match for_id {
+                Some(for_id) => match self.inputs_by_id.remove(&for_id) {
+                    Some(input) => Self::set_label(&input, text),
+                    None => {
+                        self.labels_by_for.insert(for_id, text);
+                    }
+                },
+                None => {
+                    if let Some(input) = wrapped_input {
+                        Self::set_label(&input, text);
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_label(input: &Rc<RefCell<ShadowData>>, text: String) {
+        let parent = Rc::downgrade(input);
+        let mut input_m = input.borrow_mut();
+        let id = input_m.id;
+        // Replaces the empty label of the object built by labelled_input
+        input_m.set("label", ShadowData::wrap(ShadowData::new_string(id, parent, text)), ShadowDataCollisionPolicy::KeepLast).unwrap_or(());
+    }
+}
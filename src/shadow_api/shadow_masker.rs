@@ -0,0 +1,83 @@
+use std::borrow::Cow;
+use regex::Regex;
+
+use super::{ShadowData, ShadowError};
+
+/// Built-in detectors of ShadowMasker, replaced with "[email]", "[card]" and "[phone]"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowMaskDetector {
+    Email,
+    CardNumber, // Runs of 13 to 19 digits, optionally grouped with spaces or dashes
+    Phone, // Numbers of 3 groups of digits separated with spaces, dots or dashes, with an optional country code ("+81 90 1234 5678", "(03) 1234-5678")
+}
+
+impl ShadowMaskDetector {
+    fn rule(self) -> (&'static str, &'static str) {
+        match self {
+            ShadowMaskDetector::Email => (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}", "[email]"),
+            ShadowMaskDetector::CardNumber => (r"\b(?:\d[ -]?){12,18}\d\b", "[card]"),
+            ShadowMaskDetector::Phone => (r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{1,4}\)[ .-]?|\b\d{1,4}[ .-])\d{2,4}[ .-]\d{3,4}\b", "[phone]"),
+        }
+    }
+}
+
+/// Opt-in masking of personal data (PII), applied to the collected data and/or the texts of the document (see ShadowApi::set_masker)
+/// The detectors run in order, the built-in ones first : card numbers are detected before phone numbers, which they could contain
+#[derive(Debug, Clone)]
+pub struct ShadowMasker {
+    pub(crate) rules: Vec<(String, Regex, String)>, // Name, regex and mask ($1, ${name} refer to the capture groups)
+    pub data: bool, // Mask the string values of the collected data. Default
+    pub text: bool, // Mask the texts of the document as well, outside of <script> and <style> elements
+}
+
+impl Default for ShadowMasker {
+    /// All the built-in detectors, masking the collected data only
+    fn default() -> Self {
+        Self::new(&[ShadowMaskDetector::Email, ShadowMaskDetector::CardNumber, ShadowMaskDetector::Phone])
+    }
+}
+
+impl ShadowMasker {
+    /// Masker of the collected data with the given built-in detectors
+    pub fn new(detectors: &[ShadowMaskDetector]) -> Self {
+        let rules = detectors.iter()
+            .map(|detector| {
+                let (pattern, mask) = detector.rule();
+                (format!("{:?}", detector), Regex::new(pattern).expect("valid built-in pattern"), mask.to_string())
+            })
+            .collect();
+        Self { rules, data: true, text: false }
+    }
+
+    /// Adds a custom detector, run after the previous ones
+    pub fn with_pattern(mut self, name: &str, pattern: &str, mask: &str) -> Result<Self, ShadowError> {
+        let regex = Regex::new(pattern).map_err(|err| ShadowError::Other(format!("[masker] invalid pattern '{}' ({}) : {}", name, pattern, err)))?;
+        self.rules.push((name.to_string(), regex, mask.to_string()));
+        Ok(self)
+    }
+
+    /// Names of the detectors, in the order they run
+    pub fn detectors(&self) -> Vec<&str> {
+        self.rules.iter().map(|(name, _, _)| name.as_str()).collect()
+    }
+
+    /// Masks the personal data found in the text
+    pub fn mask<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let mut result = Cow::Borrowed(text);
+        for (_, regex, mask) in &self.rules {
+            if let Cow::Owned(masked) = regex.replace_all(&result, mask.as_str()) {
+                result = Cow::Owned(masked);
+            }
+        }
+        result
+    }
+
+    /// Masks the string values of the data and of all its descendants. Keys are left as they are
+    pub fn mask_data(&self, data: &mut ShadowData) {
+        data.transform_strings(&|value: &mut String| {
+            if let Cow::Owned(masked) = self.mask(value) {
+                *value = masked;
+            }
+        });
+    }
+}
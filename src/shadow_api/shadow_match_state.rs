@@ -0,0 +1,31 @@
+use std::{cell::RefCell, rc::{Rc, Weak}};
+
+use super::{ShadowData, ShadowNth};
+
+// Matching state of a rule, used to restrict the elements it applies to (limit/skip/nth)
+// The states of the ancestor rules are checked by every handler, so that sub rules are not applied inside an element their parent skipped
+#[derive(Default, Debug)]
+pub(super) struct ShadowMatchState {
+    pub count: usize, // Amount of elements matched so far
+    pub suppressed: usize, // Amount of currently open elements the rule was not applied to
+    pub context: usize, // Match count of the parent rule when the current context (parent element) started
+    pub context_count: usize, // Amount of elements matched in the current context
+    pub siblings: Vec<(usize, usize, usize)>, // For the sub rules starting with a sibling combinator : parent serial, first and last positions of the elements matched, by open parent
+    pub entry: Weak<RefCell<ShadowData>>, // For the absolute sub rules : data of the last element matched
+}
+
+pub(super) type MatchScope = Vec<Rc<RefCell<ShadowMatchState>>>;
+
+// Restrictions of a rule on the elements it applies to
+#[derive(Default, Debug, Clone, Copy)]
+pub(super) struct MatchRestriction {
+    pub limit: Option<usize>,
+    pub skip: usize,
+    pub nth: Option<ShadowNth>,
+}
+
+impl MatchRestriction {
+    pub fn is_restricted(&self) -> bool {
+        self.limit.is_some() || self.skip > 0 || self.nth.is_some()
+    }
+}
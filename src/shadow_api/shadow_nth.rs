@@ -0,0 +1,39 @@
+// Position formula of the nth field, "an+b" as in CSS :nth-child. Positions start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ShadowNth {
+    pub a: i64,
+    pub b: i64,
+}
+
+impl ShadowNth {
+    // Accepts "odd", "even", a position ("3") or a formula ("3n+1", "-n+3", "2n")
+    pub fn parse(nth: &str) -> Option<Self> {
+        let nth = nth.split_whitespace().collect::<String>().to_lowercase();
+        match nth.as_str() {
+            "odd" => return Some(Self { a: 2, b: 1 }),
+            "even" => return Some(Self { a: 2, b: 0 }),
+            _ => {}
+        }
+        match nth.split_once('n') {
+            Some((a, b)) => {
+                let a = match a {
+                    "" | "+" => 1,
+                    "-" => -1,
+                    a => a.parse().ok()?,
+                };
+                let b = if b.is_empty() { 0 } else if b.starts_with(['+', '-']) { b.parse().ok()? } else { return None };
+                Some(Self { a, b })
+            },
+            None => nth.parse().ok().map(|b| Self { a: 0, b }),
+        }
+    }
+
+    pub fn matches(&self, position: usize) -> bool {
+        let diff = position as i64 - self.b;
+        if self.a == 0 {
+            diff == 0
+        } else {
+            diff % self.a == 0 && diff / self.a >= 0
+        }
+    }
+}
@@ -0,0 +1,6 @@
+// Position of the streamed contents relative to the marker comments of a region (see ShadowJsonRegion)
+#[derive(Default)]
+pub(super) struct RegionState {
+    pub inside: bool, // Between the start and end markers
+    pub text: String, // Text streamed inside the region, for the extract operation
+}
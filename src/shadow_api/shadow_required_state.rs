@@ -0,0 +1,27 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{ShadowData, ShadowError};
+
+// Data items opened by a node defining data.required or required values, checked once the document ends
+pub(super) struct RequiredState {
+    pub selector: String,
+    pub data: bool, // data.required : the selector must match
+    pub keys: Vec<String>, // Keys of the required values, each one must be non-empty in one of the items at least
+    pub items: Vec<Rc<RefCell<ShadowData>>>,
+}
+
+impl RequiredState {
+    pub fn missing(&self) -> Vec<ShadowError> {
+        if self.items.is_empty() && self.data {
+            // The required values are not reported on top of the selector
+            return vec![ShadowError::MissingRequired { selector: self.selector.clone(), key: None }];
+        }
+        self.keys.iter()
+            .filter(|key| !self.items.iter().any(|item| {
+                let item = item.borrow();
+                item.as_object().and_then(|object| object.get(*key)).is_some_and(|value| !value.borrow().is_empty())
+            }))
+            .map(|key| ShadowError::MissingRequired { selector: self.selector.clone(), key: Some(key.clone()) })
+            .collect()
+    }
+}
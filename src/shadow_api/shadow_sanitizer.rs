@@ -0,0 +1,96 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+
+/// Allowlist based sanitizer applied to the HTML fragments injected by the definition (append, prepend, insert_before, insert_after, wrap and set_html)
+/// Disallowed elements are removed (along with their contents for script-like elements, otherwise their contents are kept), as well as disallowed attributes, event handlers and URLs with a disallowed scheme
+#[derive(Debug, Clone)]
+pub struct ShadowSanitizer {
+    pub tags: HashSet<String>, // Allowed tag names (lowercase)
+    pub attributes: HashSet<String>, // Allowed attribute names (lowercase), on any allowed tag. Event handlers (on*) are never allowed
+    pub url_schemes: HashSet<String>, // Allowed schemes of the URL attributes (href, src, action...). Relative URLs are always allowed
+}
+
+// Elements whose contents are dropped along with them, as it is not meant to be rendered as text
+const DROP_CONTENTS: [&str; 9] = ["script", "style", "iframe", "object", "embed", "noscript", "template", "textarea", "select"];
+const URL_ATTRIBUTES: [&str; 6] = ["href", "src", "action", "formaction", "poster", "xlink:href"];
+
+impl Default for ShadowSanitizer {
+    fn default() -> Self {
+        let to_set = |items: &[&str]| items.iter().map(|item| item.to_string()).collect::<HashSet<String>>();
+        ShadowSanitizer {
+            tags: to_set(&[
+                "a", "abbr", "b", "blockquote", "br", "caption", "code", "dd", "del", "div", "dl", "dt", "em", "figcaption", "figure",
+                "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i", "img", "ins", "li", "mark", "ol", "p", "picture", "pre", "q", "s",
+                "section", "small", "source", "span", "strong", "sub", "sup", "table", "tbody", "td", "tfoot", "th", "thead", "time",
+                "tr", "u", "ul",
+            ]),
+            attributes: to_set(&[
+                "alt", "class", "colspan", "datetime", "height", "href", "id", "lang", "rel", "rowspan", "sizes", "src", "srcset",
+                "target", "title", "width",
+            ]),
+            url_schemes: to_set(&["http", "https", "mailto", "tel"]),
+        }
+    }
+}
+
+impl ShadowSanitizer {
+    // Sanitizes the html of a fragment, reporting the removed elements and attributes to errors. None if nothing was removed
+    pub(crate) fn sanitize_reported(&self, html: &str, errors: &RefCell<Vec<String>>, field: &str, selector: &str) -> Option<String> {
+        let (sanitized, violations) = self.sanitize(html);
+        if violations.is_empty() {
+            return None;
+        }
+        let mut errors_m = errors.borrow_mut();
+        for violation in violations {
+            errors_m.push(format!("Sanitizer removed {} from {} of selector {}", violation, field, selector));
+        }
+        Some(sanitized)
+    }
+
+    /// Returns the sanitized html, along with the list of the removed elements and attributes
+    pub fn sanitize(&self, html: &str) -> (String, Vec<String>) {
+        let violations: RefCell<Vec<String>> = RefCell::new(Vec::new());
+        let result = rewrite_str(html, RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("*", |el| {
+                    let tag = el.tag_name();
+                    if !self.tags.contains(&tag) {
+                        violations.borrow_mut().push(format!("<{}>", tag));
+                        if DROP_CONTENTS.contains(&tag.as_str()) {
+                            el.remove();
+                        } else {
+                            el.remove_and_keep_content();
+                        }
+                        return Ok(());
+                    }
+                    let attributes: Vec<(String, String)> = el.attributes().iter().map(|a| (a.name(), a.value())).collect();
+                    for (name, value) in attributes {
+                        let allowed = !name.starts_with("on")
+                            && self.attributes.contains(&name)
+                            && (!URL_ATTRIBUTES.contains(&name.as_str()) || self.is_allowed_url(&value));
+                        if !allowed {
+                            violations.borrow_mut().push(format!("<{} {}>", tag, name));
+                            el.remove_attribute(&name);
+                        }
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        });
+        match result {
+            Ok(sanitized) => (sanitized, violations.into_inner()),
+            Err(e) => (String::new(), vec![format!("unparsable fragment ({})", e)]), // Nothing is injected rather than unsanitized html
+        }
+    }
+
+    fn is_allowed_url(&self, url: &str) -> bool {
+        // Browsers ignore control characters and whitespace inside the scheme
+        let url: String = url.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control()).collect();
+        match url.split_once(':') {
+            Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => self.url_schemes.contains(&scheme.to_ascii_lowercase()),
+            _ => true, // Relative URL
+        }
+    }
+}
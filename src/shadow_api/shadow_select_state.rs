@@ -0,0 +1,176 @@
+use std::{cell::RefCell, rc::{Rc, Weak}};
+
+use indexmap::IndexMap;
+use lol_html::html_content::Element;
+use regex::Regex;
+
+use super::{ShadowApi, ShadowApiCache, ShadowApiStats, ShadowData, ShadowDataCollisionPolicy};
+use super::shadow_json::ShadowJsonValue;
+
+// Select element currently streamed by a node collecting its Value : the selected options are written to its data item (see select_content_handlers)
+#[derive(Default)]
+pub(super) struct SelectState {
+    pub target: Option<(Rc<RefCell<ShadowData>>, Vec<String>)>, // Data item of the select and keys of its Value sources
+    pub multiple: bool, // The values of a <select multiple> are collected into an array
+    pub open_option: Option<String>, // Text of the selected option currently streamed, used as its value when it has no value attribute
+}
+
+impl SelectState {
+    pub fn select(&mut self, value: String, selector_id: usize) -> usize {
+        let Some((data_item, keys)) = &self.target else {
+            return 0;
+        };
+        let mut data_m = data_item.borrow_mut();
+        // Only the values actually stored are counted : a multiple select key replaced by another value is no longer an array,
+        // and a key kept by the collision policy no longer holds the value initialized by the select
+        keys.iter().filter(|key| {
+            let item = ShadowData::wrap(ShadowData::new_string(Some(selector_id), Rc::downgrade(data_item), value.clone()));
+            match data_m.get(key).filter(|existing| existing.borrow().id == Some(selector_id)) {
+                Some(array) if self.multiple => array.borrow_mut().push(item).is_ok(),
+                Some(_) => data_m.set(key, item, ShadowDataCollisionPolicy::KeepLast).is_ok(), // Replaces the initial value
+                None => false,
+            }
+        }).count()
+    }
+
+    pub fn close_option(&mut self, selector_id: usize) -> usize {
+        match self.open_option.take() {
+            Some(text) => self.select(text.trim().to_string(), selector_id),
+            None => 0,
+        }
+    }
+}
+
+// Collects the Value of a form element into the key of its data item, and returns whether a value was collected.
+// The selected options of a select and the text of a textarea or contenteditable element are filled later by their content handlers,
+// the checked radios and checkboxes of a node defining label_selector are collected with their label (see ShadowApi::labelled_input)
+#[allow(clippy::too_many_arguments)]
+pub(super) fn collect_value(
+    el: &mut Element,
+    attrs: &IndexMap<String, String>,
+    data_item: &Rc<RefCell<ShadowData>>,
+    key: &str,
+    value: &ShadowJsonValue,
+    extract_regex: Option<&Regex>,
+    selector_id: usize,
+    labelled: bool,
+    cache: &Rc<RefCell<ShadowApiCache>>,
+    stats: &Rc<RefCell<ShadowApiStats>>,
+    policy: ShadowDataCollisionPolicy,
+    errors: &RefCell<Vec<String>>
+) -> bool {
+    let self_weak = Rc::downgrade(data_item);
+    let select_state = cache.borrow().select_states.get(&selector_id).cloned();
+    let value_from_text = cache.borrow().text_values.get(&selector_id).cloned().unwrap_or_default();
+    let contenteditable = attrs.get("contenteditable").is_some_and(|editable| editable != "false");
+    value_from_text.set(el.tag_name() == "textarea" || contenteditable);
+    let attr_value = attrs.get("value").map(String::as_str).unwrap_or_default();
+    let stored = match el.tag_name().as_str() {
+        // LOLHTML does not allow to add handlers for the children once the element is found : the selected options
+        // are collected by the handlers registered by select_content_handlers for the nodes whose selector ends with "select"
+        "select" if select_state.is_some() => {
+            let select_state = select_state.unwrap();
+            let multiple = attrs.contains_key("multiple");
+            let init = if multiple {
+                ShadowData::new_array(Some(selector_id), Weak::clone(&self_weak))
+            } else {
+                ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), String::new())
+            };
+            if let Err(err) = data_item.borrow_mut().set(key, ShadowData::wrap(init), policy) {
+                errors.borrow_mut().push(err.to_string());
+                return false;
+            }
+            let mut state = select_state.borrow_mut();
+            state.multiple = multiple;
+            match &mut state.target {
+                Some((target, keys)) if Rc::ptr_eq(target, data_item) => keys.push(key.to_string()),
+                target => {
+                    *target = Some((Rc::clone(data_item), vec![key.to_string()]));
+                    let end_state = Rc::clone(&select_state);
+                    let end_stats = Rc::clone(stats);
+                    if let Some(handlers) = el.end_tag_handlers() {
+                        handlers.push(Box::new(move |_end| {
+                            let mut state = end_state.borrow_mut();
+                            end_stats.borrow_mut().data_values += state.close_option(selector_id);
+                            state.target = None;
+                            Ok(())
+                        }));
+                    }
+                },
+            }
+            return false;
+        },
+        "input" => {
+            let Some(input_type) = attrs.get("type") else {
+                return false;
+            };
+            if labelled && (input_type == "radio" || input_type == "checkbox") {
+                match ShadowApi::labelled_input(data_item, key, selector_id, attrs, Rc::clone(cache), policy) {
+                    Ok(collected) => return collected,
+                    Err(err) => {
+                        errors.borrow_mut().push(err.to_string());
+                        return false;
+                    },
+                }
+            }
+            let mut new_data_m = data_item.borrow_mut();
+            // The inputs of a group write the same key : the value of another input of the node is not a collision
+            let own = new_data_m.get(key).filter(|existing| existing.borrow().id == Some(selector_id));
+            match input_type.as_str() {
+                "radio" => {
+                    if attrs.get("checked").is_some() {
+                        // For radio/checkbox, we only consider the box which is checked. Make sure def json contains all items
+                        let policy = if own.is_some() { ShadowDataCollisionPolicy::KeepLast } else { policy };
+                        new_data_m.set(key, ShadowData::wrap(
+                            value.to_data(Some(selector_id), Weak::clone(&self_weak), attr_value, extract_regex)
+                        ), policy).map(|_| true)
+                    } else if new_data_m.get(key).is_none() {
+                        // Init
+                        new_data_m.set(key, ShadowData::wrap(
+                            ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), "".to_string())
+                        ), policy).map(|_| false)
+                    } else {
+                        Ok(false)
+                    }
+                }
+                "checkbox" => {
+                    let mut stored = Ok(false);
+                    if !own.as_ref().is_some_and(|own| own.borrow().is_array()) {
+                        stored = new_data_m.set(key, ShadowData::wrap(
+                            ShadowData::new_array(Some(selector_id), Weak::clone(&self_weak))
+                        ), policy).map(|_| false);
+                    }
+                    if attrs.get("checked").is_some() {
+                        // For radio/checkbox, we only consider the box which is checked. Make sure def json contains all items
+                        // A value kept by the collision policy is left as it is
+                        let own_array = new_data_m.get(key).filter(|arr| arr.borrow().id == Some(selector_id) && arr.borrow().is_array());
+                        if let Some(arr) = own_array {
+                            stored = arr.borrow_mut().push(ShadowData::wrap(
+                                value.to_data(Some(selector_id), Weak::clone(&self_weak), attr_value, extract_regex)
+                            )).map(|_| true);
+                        }
+                    }
+                    stored
+                }
+                _ => new_data_m.set(key, ShadowData::wrap(
+                    value.to_data(Some(selector_id), Weak::clone(&self_weak), attr_value, extract_regex)
+                ), policy).map(|_| true),
+            }
+        },
+        "option" => data_item.borrow_mut().set(key, ShadowData::wrap(
+            value.to_data(Some(selector_id), Weak::clone(&self_weak), attr_value, extract_regex)
+        ), policy).map(|_| true),
+        // Filled with the text of the element by text_content_handler
+        _ if value_from_text.get() => data_item.borrow_mut().set(key, ShadowData::wrap(
+            ShadowData::new_string(Some(selector_id), Weak::clone(&self_weak), String::new())
+        ), policy).map(|_| true),
+        _ => {
+            errors.borrow_mut().push(format!("Unimplemented input: '{}' (TODO)", el.tag_name().as_str()));
+            return false;
+        }
+    };
+    stored.unwrap_or_else(|err| {
+        errors.borrow_mut().push(err.to_string());
+        false
+    })
+}
@@ -0,0 +1,50 @@
+use super::ShadowMatchState;
+
+// Sibling combinator starting the selector of a node ("+ p", "~ p"), which lol_html does not support : the node applies to the elements
+// following an element matched by its parent node, checked against the positions recorded by the SiblingTracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SiblingCombinator {
+    Next, // "+" : the element right after
+    Later, // "~" : any element after
+}
+
+impl SiblingCombinator {
+    pub fn parse(s: &str) -> Option<(Self, &str)> {
+        if let Some(rest) = s.strip_prefix('+') {
+            Some((Self::Next, rest.trim_start()))
+        } else {
+            s.strip_prefix('~').map(|rest| (Self::Later, rest.trim_start()))
+        }
+    }
+}
+
+// Position of each element among its siblings, maintained by a handler matching all the elements, registered before the others
+pub(super) struct SiblingTracker {
+    pub serial: usize, // Amount of elements opened so far
+    pub open: Vec<(usize, usize)>, // Serial and amount of child elements of the open elements, the document being at the bottom (serial 0)
+    pub current: (usize, usize), // Parent serial and position among its siblings of the element being handled
+}
+
+impl SiblingTracker {
+    // Records the element being handled as matched by a node having sibling sub nodes
+    pub fn record(&self, match_state: &mut ShadowMatchState) {
+        let (parent, position) = self.current;
+        let siblings = &mut match_state.siblings;
+        while siblings.last().is_some_and(|(serial, _, _)| !self.open.iter().any(|(open, _)| open == serial)) {
+            siblings.pop(); // Parent closed
+        }
+        match siblings.last_mut() {
+            Some(last) if last.0 == parent => last.2 = position,
+            _ => siblings.push((parent, position, position)),
+        }
+    }
+
+    // Whether the element being handled follows an element recorded in match_state, according to combinator
+    pub fn follows(&self, match_state: &ShadowMatchState, combinator: SiblingCombinator) -> bool {
+        let (parent, position) = self.current;
+        match_state.siblings.iter().rev().find(|(serial, _, _)| *serial == parent).is_some_and(|(_, first, last)| match combinator {
+            SiblingCombinator::Next => last + 1 == position,
+            SiblingCombinator::Later => *first < position,
+        })
+    }
+}
@@ -0,0 +1,45 @@
+use lol_html::html_content::{ContentType, TextChunk};
+
+// Text of the element being streamed, buffered by the text handler of a node until its last chunk
+#[derive(Default)]
+pub(super) struct TextBuffer {
+    pub text: String,
+    pub overflow: bool, // The text exceeded max_text_bytes : it was sent back as is, and only its first bytes are kept for the data
+}
+
+impl TextBuffer {
+    // Buffers a chunk of the text. With a content edit the chunk is removed, to be sent back once edited by the last chunk,
+    // otherwise it passes through as it is and is only buffered for the data
+    // Returns max_bytes when the text has just exceeded it : the buffered text is sent back as is and truncated to its first max_bytes, the next chunks pass through
+    pub fn push(&mut self, chunk: &mut TextChunk, max_bytes: Option<usize>, edits_content: bool) -> Option<usize> {
+        if self.overflow {
+            return None
+        }
+        self.text.push_str(chunk.as_str());
+        match max_bytes.filter(|max| self.text.len() > *max) {
+            Some(max) => {
+                if edits_content {
+                    chunk.replace(&self.text, ContentType::Text);
+                }
+                let mut end = max;
+                while !self.text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                self.text.truncate(end);
+                self.overflow = true;
+                Some(max)
+            }
+            None => {
+                if edits_content {
+                    chunk.remove();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.overflow = false;
+    }
+}
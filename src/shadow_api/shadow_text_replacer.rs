@@ -0,0 +1,51 @@
+use std::{borrow::Cow, str::FromStr};
+use lol_html::Selector;
+use regex::Regex;
+
+use super::ShadowError;
+
+/// Document wide find/replace applied to every text of the document (rebranding a product name, masking PII...), where per-node rules are impractical
+/// The regexes match the raw html text, entities not being decoded (`&` is matched as `&amp;`). The contents of <script> and <style> elements are left untouched,
+/// as well as the texts inside the elements matching one of the excluded selectors. Texts edited by a node (edit.content) keep the edit of the node
+#[derive(Debug, Clone)]
+pub struct ShadowTextReplacer {
+    pub(crate) rules: Vec<(Regex, String)>, // Regex and replacement, escaped for html ($1, ${name} refer to the capture groups)
+    pub(crate) exclude: Vec<(String, Selector)>,
+}
+
+impl ShadowTextReplacer {
+    /// Compiles the (regex, replacement) rules, applied in order to each text, and the selectors of the elements whose texts are left untouched
+    pub fn new(rules: &[(&str, &str)], exclude: &[&str]) -> Result<Self, ShadowError> {
+        let rules = rules.iter()
+            .map(|(pattern, replacement)| {
+                Regex::new(pattern)
+                    .map(|regex| (regex, Self::escape(replacement)))
+                    .map_err(|err| ShadowError::Other(format!("[text_replacer] invalid regex '{}' : {}", pattern, err)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let exclude = exclude.iter()
+            .map(|selector| {
+                Selector::from_str(selector)
+                    .map(|selector_obj| (selector.to_string(), selector_obj))
+                    .map_err(|err| ShadowError::Other(format!("[text_replacer] invalid selector '{}' : {}", selector, err)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules, exclude })
+    }
+
+    /// Applies the rules to the raw html text
+    pub fn replace<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let mut result = Cow::Borrowed(text);
+        for (regex, replacement) in &self.rules {
+            if let Cow::Owned(replaced) = regex.replace_all(&result, replacement.as_str()) {
+                result = Cow::Owned(replaced);
+            }
+        }
+        result
+    }
+
+    // The replacements are inserted as raw html text
+    fn escape(replacement: &str) -> String {
+        replacement.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::OnceLock;
+use regex::{Captures, Regex};
+use url::Url;
+
+use crate::ShadowError;
+
+use super::shadow_json::ShadowJsonRewriteUrls;
+
+/// Rewrites the URLs pointing to one origin (optionally with a path prefix) so that they point to another one.
+/// Relative URLs are resolved against the base URL when one is given, and left untouched otherwise
+#[derive(Debug, Clone)]
+pub struct ShadowUrlRewriter {
+    from: String, // Without trailing slash
+    to: String, // Without trailing slash
+    base: Option<Url>,
+}
+
+impl ShadowUrlRewriter {
+    pub fn new(from: &str, to: &str, base: Option<&str>) -> Result<Self, ShadowError> {
+        let from = Url::parse(from).map_err(|e| ShadowError::from(format!("Invalid rewrite_urls.from '{}' : {}", from, e)))?;
+        let to = Url::parse(to).map_err(|e| ShadowError::from(format!("Invalid rewrite_urls.to '{}' : {}", to, e)))?;
+        let base = match base {
+            Some(base) => Some(Url::parse(base).map_err(|e| ShadowError::from(format!("Invalid rewrite_urls.base '{}' : {}", base, e)))?),
+            None => None,
+        };
+        Ok(ShadowUrlRewriter {
+            from: from.as_str().trim_end_matches('/').to_string(),
+            to: to.as_str().trim_end_matches('/').to_string(),
+            base,
+        })
+    }
+
+    pub fn from_json(def: &ShadowJsonRewriteUrls) -> Result<Self, ShadowError> {
+        Self::new(&def.from, &def.to, def.base.as_deref())
+    }
+
+    /// Rewrites a single URL. Returns None if it does not point to the `from` origin
+    pub fn rewrite_url(&self, url: &str) -> Option<String> {
+        let url = url.trim();
+        if url.is_empty() || url.starts_with('#') {
+            return None;
+        }
+        let resolved = match Url::parse(url) {
+            Ok(absolute) => absolute,
+            Err(url::ParseError::RelativeUrlWithoutBase) => self.base.as_ref()?.join(url).ok()?,
+            Err(_) => return None,
+        };
+        let rest = resolved.as_str().strip_prefix(self.from.as_str())?;
+        if !rest.is_empty() && !rest.starts_with(['/', '?', '#']) {
+            return None; // Another host or path sharing the same prefix
+        }
+        Some(format!("{}{}", self.to, rest))
+    }
+
+    /// Rewrites every candidate of a `srcset` attribute, keeping their descriptors
+    pub fn rewrite_srcset(&self, srcset: &str) -> Option<String> {
+        let mut changed = false;
+        let candidates: Vec<String> = srcset.split(',')
+            .map(|candidate| {
+                let candidate = candidate.trim();
+                let (url, descriptor) = candidate.split_once(char::is_whitespace).unwrap_or((candidate, ""));
+                match self.rewrite_url(url) {
+                    Some(new_url) => {
+                        changed = true;
+                        if descriptor.is_empty() { new_url } else { format!("{} {}", new_url, descriptor.trim()) }
+                    },
+                    None => candidate.to_string(),
+                }
+            })
+            .collect();
+        changed.then(|| candidates.join(", "))
+    }
+
+    /// Rewrites the `url()` references of CSS code (style attributes or stylesheets)
+    pub fn rewrite_css(&self, css: &str) -> Option<String> {
+        static CSS_URL: OnceLock<Regex> = OnceLock::new();
+        let regex = CSS_URL.get_or_init(|| {
+            Regex::new(r#"url\(\s*(?:"([^"]*)"|'([^']*)'|([^)'"\s]*))\s*\)"#).unwrap() // Constant, known to be valid
+        });
+        let mut changed = false;
+        let rewritten = regex.replace_all(css, |caps: &Captures| {
+            let (url, quote) = match (caps.get(1), caps.get(2), caps.get(3)) {
+                (Some(url), _, _) => (url.as_str(), "\""),
+                (_, Some(url), _) => (url.as_str(), "'"),
+                (_, _, Some(url)) => (url.as_str(), ""),
+                _ => return caps[0].to_string(),
+            };
+            match self.rewrite_url(url) {
+                Some(new_url) => {
+                    changed = true;
+                    format!("url({}{}{})", quote, new_url, quote)
+                },
+                None => caps[0].to_string(),
+            }
+        });
+        changed.then(|| rewritten.into_owned())
+    }
+
+    /// Rewrites an attribute value according to the attribute name
+    pub fn rewrite_attribute(&self, name: &str, value: &str) -> Option<String> {
+        match name {
+            "srcset" => self.rewrite_srcset(value),
+            "style" => self.rewrite_css(value),
+            _ => self.rewrite_url(value),
+        }
+    }
+}
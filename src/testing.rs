@@ -0,0 +1,188 @@
+//! Helpers to validate definitions against sample documents : the output of a ShadowApi must not depend on where the chunk
+//! boundaries fall (a tag or a text split across two chunks), which is easy to miss when testing with whole documents only
+
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use crate::{CompiledShadowJson, ShadowApiOptions};
+
+/// Result of a document processed by the helpers of this module
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShadowTestRun {
+    pub output: Vec<u8>, // Rewritten html, or the encoded data in as_json mode
+    pub data: String, // Collected data, serialized as json
+    pub errors: Vec<String>, // Errors reported while processing
+}
+
+/// The same document processed with different chunk boundaries gave different results
+#[derive(Debug, Clone)]
+pub struct ChunkMismatch {
+    pub boundaries: Vec<usize>, // Offsets of the input where the chunks of the failing run start, to replay it with run_with_boundaries
+    pub expected: ShadowTestRun, // Result of the document processed as a single chunk
+    pub actual: ShadowTestRun,
+}
+
+impl fmt::Display for ChunkMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[ChunkMismatch] Chunk boundaries {:?} change the result", self.boundaries)?;
+        if self.expected.output != self.actual.output {
+            writeln!(f, "output expected : {}", String::from_utf8_lossy(&self.expected.output))?;
+            writeln!(f, "output actual   : {}", String::from_utf8_lossy(&self.actual.output))?;
+        }
+        if self.expected.data != self.actual.data {
+            writeln!(f, "data expected : {}", self.expected.data)?;
+            writeln!(f, "data actual   : {}", self.actual.data)?;
+        }
+        if self.expected.errors != self.actual.errors {
+            writeln!(f, "errors expected : {:?}", self.expected.errors)?;
+            writeln!(f, "errors actual   : {:?}", self.actual.errors)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ChunkMismatch {}
+
+/// Processes the html with the definitions, split into chunks starting at the given offsets (increasing, 0 being implied)
+pub fn run_with_boundaries(compiled: &CompiledShadowJson, options: Option<ShadowApiOptions>, html: &[u8], boundaries: &[usize]) -> ShadowTestRun {
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = Vec::new();
+    let shadow_api = compiled.instantiate(options, Rc::clone(&errors));
+    let mut starts = boundaries.iter().copied().filter(|&start| start > 0 && start < html.len()).collect::<Vec<_>>();
+    starts.dedup();
+    let mut chunks = std::iter::once(0).chain(starts.iter().copied())
+        .zip(starts.iter().copied().chain(std::iter::once(html.len())))
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| Ok(html[start..end].to_vec()));
+    shadow_api.process_html_iter(&mut output, &mut chunks, Rc::clone(&errors));
+    let data = shadow_api.shadow_data_cursor.borrow().root.borrow().to_string();
+    drop(shadow_api);
+    ShadowTestRun { output, data, errors: errors.take() }
+}
+
+/// Processes the html with the definitions, split into chunks of chunk_size bytes
+pub fn run_chunked(compiled: &CompiledShadowJson, options: Option<ShadowApiOptions>, html: &[u8], chunk_size: usize) -> ShadowTestRun {
+    let boundaries = (chunk_size.max(1)..html.len()).step_by(chunk_size.max(1)).collect::<Vec<_>>();
+    run_with_boundaries(compiled, options, html, &boundaries)
+}
+
+/// Processes the html at every chunk size, from 1 byte to the whole document, returning the result shared by all the runs,
+/// or the first run differing from the document processed as a single chunk. Runs in quadratic time : keep the samples small
+pub fn check_chunk_sizes(compiled: &CompiledShadowJson, options: Option<ShadowApiOptions>, html: &[u8]) -> Result<ShadowTestRun, Box<ChunkMismatch>> {
+    let expected = run_with_boundaries(compiled, options, html, &[]);
+    for chunk_size in 1..html.len() {
+        let actual = run_chunked(compiled, options, html, chunk_size);
+        if actual != expected {
+            let boundaries = (chunk_size..html.len()).step_by(chunk_size).collect();
+            return Err(Box::new(ChunkMismatch { boundaries, expected, actual }));
+        }
+    }
+    Ok(expected)
+}
+
+/// Processes the html runs times, split at pseudo-random boundaries derived from the seed (the same seed always gives the same boundaries),
+/// returning the result shared by all the runs, or the first run differing from the document processed as a single chunk
+pub fn check_random_boundaries(compiled: &CompiledShadowJson, options: Option<ShadowApiOptions>, html: &[u8], seed: u64, runs: usize) -> Result<ShadowTestRun, Box<ChunkMismatch>> {
+    let expected = run_with_boundaries(compiled, options, html, &[]);
+    let mut state = seed | 1; // xorshift requires a non zero state
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..runs {
+        let max_chunk = 1 + (next() % 64) as usize; // Both tiny and larger chunks
+        let mut boundaries = Vec::new();
+        let mut start = 0;
+        loop {
+            start += 1 + (next() as usize) % max_chunk;
+            if start >= html.len() {
+                break;
+            }
+            boundaries.push(start);
+        }
+        let actual = run_with_boundaries(compiled, options, html, &boundaries);
+        if actual != expected {
+            return Err(Box::new(ChunkMismatch { boundaries, expected, actual }));
+        }
+    }
+    Ok(expected)
+}
+
+/// Panics with the differing results if the chunk boundaries change the result of the html, see check_chunk_sizes
+pub fn assert_chunk_size_independent(compiled: &CompiledShadowJson, options: Option<ShadowApiOptions>, html: &[u8]) -> ShadowTestRun {
+    check_chunk_sizes(compiled, options, html).unwrap_or_else(|mismatch| panic!("{}", mismatch))
+}
+
+/// Golden test of a definition : processes html_in with the shadow_json definition and the default options, and panics with a line diff
+/// unless the output is expected_html (including the injected data) and the data is expected_data_json (compared as json values, its formatting is free).
+/// Invalid definitions and errors reported while processing fail the test as well
+pub fn assert_transform(html_in: &str, shadow_json: &str, expected_html: &str, expected_data_json: &str) {
+    assert_transform_with(None, html_in, shadow_json, expected_html, expected_data_json)
+}
+
+/// Same as assert_transform, with options. In as_json mode, expected_html is compared to the encoded data
+pub fn assert_transform_with(options: Option<ShadowApiOptions>, html_in: &str, shadow_json: &str, expected_html: &str, expected_data_json: &str) {
+    let compiled = CompiledShadowJson::compile_str(shadow_json, Rc::new(RefCell::new(Vec::new())))
+        .unwrap_or_else(|err| panic!("[assert_transform] Invalid definition : {}", err));
+    let run = run_with_boundaries(&compiled, options, html_in.as_bytes(), &[]);
+    let expected_data = serde_json::from_str::<serde_json::Value>(expected_data_json)
+        .unwrap_or_else(|err| panic!("[assert_transform] Invalid expected data : {}", err));
+    let data = serde_json::from_str::<serde_json::Value>(&run.data)
+        .unwrap_or_else(|err| panic!("[assert_transform] Invalid data : {}", err));
+    let html = String::from_utf8_lossy(&run.output);
+
+    let mut report = String::new();
+    if !run.errors.is_empty() {
+        report += &format!("errors :\n{}\n", run.errors.iter().map(|err| format!("  {}", err)).collect::<Vec<_>>().join("\n"));
+    }
+    if html != expected_html {
+        report += &format!("html (- expected, + actual) :\n{}", diff(expected_html, &html));
+    }
+    if data != expected_data {
+        let pretty = |value: &serde_json::Value| serde_json::to_string_pretty(value).unwrap_or_default();
+        report += &format!("data (- expected, + actual) :\n{}", diff(&pretty(&expected_data), &pretty(&data)));
+    }
+    if !report.is_empty() {
+        panic!("[assert_transform] Unexpected result\n{}", report);
+    }
+}
+
+// Line diff of expected and actual, from their longest common subsequence of lines. Differing lines are prefixed with "-" (expected) or "+" (actual),
+// followed by the position of their first differing character when a single line differs (single line documents)
+fn diff(expected: &str, actual: &str) -> String {
+    let (expected, actual) = (expected.lines().collect::<Vec<_>>(), actual.lines().collect::<Vec<_>>());
+    // common[i][j] : length of the longest common subsequence of expected[i..] and actual[j..]
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+        }
+    }
+    let mut lines = Vec::new();
+    let mut changed = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", expected[i]));
+            changed.push((true, expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", actual[j]));
+            changed.push((false, actual[j]));
+            j += 1;
+        }
+    }
+    if let [(true, removed), (false, added)] = changed[..] {
+        let position = removed.chars().zip(added.chars()).take_while(|(a, b)| a == b).count();
+        let skipped = position.saturating_sub(20);
+        let context = |line: &str| format!("{}{}", if skipped > 0 { "..." } else { "" }, line.chars().skip(skipped).take(60).collect::<String>());
+        lines.push(format!("first difference at character {} :", position));
+        lines.push(format!("- {}", context(removed)));
+        lines.push(format!("+ {}", context(added)));
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
@@ -1,11 +1,13 @@
 use std::io::BufWriter;
-use std::{rc::Rc, cell::RefCell};
-use shadow_api::{ShadowJson, ShadowApiReplacer, ShadowApiInit};
-use shadow_api::ShadowApi;
+use std::{rc::{Rc, Weak}, cell::RefCell};
+use shadow_api::{ShadowJson, ShadowJsonField, ShadowApiReplacer, ShadowApiInit, ShadowApiOptions, ShadowDataCollisionPolicy, ShadowDataUidMode, ShadowOutputEncoding, ShadowError, ShadowErrorPolicy, ShadowInjectionOverflow, CompiledShadowJson, ShadowApiCache, ShadowApiBuilder};
+use shadow_api::{ShadowApi, ShadowApiStream, ShadowData, ShadowPlanPurpose, ShadowSanitizer, ShadowTextReplacer, ShadowMasker, ShadowMaskDetector};
+use lol_html::html_content::{ContentType, DocumentEnd};
+use lol_html::{DocumentContentHandlers, ElementContentHandlers};
 
 thread_local! {
     // The object holding the closure needs to be static (global), as the closure is invoked after process function exists, as it done later in async manner
-    pub static REPLACER: RefCell<Option<ShadowApiReplacer<'static>>> = RefCell::new(None);
+    pub static REPLACER: RefCell<Option<ShadowApiReplacer<'static>>> = const { RefCell::new(None) };
 }
 
 
@@ -329,8 +331,8 @@ fn test() {
 }
 
 #[test]
-fn test_replacer<'a>() {
-    let html_source: &'a str = html_source(); "<html><head><title>Old title</title></head><body></body></html>";
+fn test_replacer() {
+    let html_source: &str = html_source();
 
     let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
     let json_def: Vec<Rc<RefCell<ShadowJson>>> = Vec::from([
@@ -382,4 +384,3428 @@ fn test_replacer<'a>() {
     }
     let processed_html_output = String::from_utf8(output).unwrap();
     assert_eq!(processed_html_output, expected_html_output);
-}
\ No newline at end of file
+}
+// Runs a single ShadowJson definition over html. Returns the processed html, the collected data and the errors
+fn process_one(html: &str, shadow_json: &str, options: Option<ShadowApiOptions>) -> (String, String, Vec<String>) {
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+
+    let mut output = BufWriter::new(Vec::new());
+    let mut shadow_api_o = ShadowApi::new(options);
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| {
+        format!("<script>{}</script>", data)
+    })));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    let data = shadow_api_o.shadow_data_cursor.borrow().root.borrow().to_string();
+    drop(shadow_api_o);
+
+    let html = String::from_utf8(output.into_inner().unwrap_or_default()).unwrap();
+    let errors = errors.borrow().clone();
+    (html, data, errors)
+}
+
+#[test]
+fn test_collision_policy() {
+    let html = r#"<html><body><div id="a" title="x"><span>inner</span></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "#a",
+        "data": {
+            "values": {
+                "info": {"source": "Attribute", "name": "title"}
+            }
+        },
+        "sub": [
+            {
+                "s": "span",
+                "data": {
+                    "path": "info",
+                    "values": {
+                        "name": {"source": "Contents"}
+                    }
+                }
+            }
+        ]
+    }
+    "##;
+    let options = |collision_policy| Some(ShadowApiOptions { collision_policy, ..Default::default() });
+
+    let (_, data, errors) = process_one(html, shadow_json, options(ShadowDataCollisionPolicy::Merge));
+    assert_eq!(data, r#"{"info":{"name":"inner"}}"#);
+    assert!(errors.is_empty());
+
+    let (_, data, errors) = process_one(html, shadow_json, options(ShadowDataCollisionPolicy::KeepFirst));
+    assert_eq!(data, r#"{"info":"x"}"#);
+    assert!(errors.is_empty());
+
+    let (_, data, errors) = process_one(html, shadow_json, options(ShadowDataCollisionPolicy::Error));
+    assert_eq!(data, r#"{"info":"x"}"#);
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn test_collision_policy_inputs() {
+    let html = r#"<html><head><meta name="a" content="1"><meta name="a" content="2"></head><body><form><i data-c="red"></i><input type="radio" name="c" value="blue"><input type="radio" name="c" value="green" checked><input type="checkbox" name="t" value="x" checked><input type="checkbox" name="t" value="y" checked></form></body></html>"#;
+    let shadow_json = |colliding: &str| format!(r#"
+    {{
+        "s": "form",
+        "sub": [
+            {}
+            {{ "s": "input[type=radio]", "data": {{ "values": {{ "color": {{"source": "Value"}} }} }} }},
+            {{ "s": "input[type=checkbox]", "data": {{ "values": {{ "tags": {{"source": "Value"}} }} }} }}
+        ]
+    }}
+    "#, colliding);
+    let colliding = r#"{ "s": "i", "data": { "values": { "color": {"source": "Attribute", "name": "data-c"}, "tags": {"source": "Attribute", "name": "data-c"} } } },"#;
+    let options = |collision_policy| Some(ShadowApiOptions { collision_policy, collect_meta: true, ..Default::default() });
+
+    // The inputs of a radio or checkbox group do not collide with each other
+    let (_, data, errors) = process_one(html, &shadow_json(""), options(ShadowDataCollisionPolicy::Error));
+    assert_eq!(errors.len(), 1, "{:?}", errors); // Only the second meta
+    assert_eq!(data, r#"{"head":{"meta":{"a":"1"}},"color":"green","tags":["x","y"]}"#);
+
+    let (_, data, errors) = process_one(html, &shadow_json(colliding), options(ShadowDataCollisionPolicy::Error));
+    assert_eq!(errors.len(), 4, "{:?}", errors); // The second meta, color, and tags once per checkbox
+    assert_eq!(data, r#"{"head":{"meta":{"a":"1"}},"color":"red","tags":"red"}"#);
+
+    let (_, data, errors) = process_one(html, &shadow_json(colliding), options(ShadowDataCollisionPolicy::KeepFirst));
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(data, r#"{"head":{"meta":{"a":"1"}},"color":"red","tags":"red"}"#);
+
+    let (_, data, errors) = process_one(html, &shadow_json(colliding), options(ShadowDataCollisionPolicy::Merge));
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(data, r#"{"head":{"meta":{"a":"2"}},"color":"green","tags":["x","y"]}"#);
+}
+
+#[test]
+fn test_merge_parents() {
+    let object = |parent| ShadowData::wrap(ShadowData::new_object(None, parent));
+    let root = object(Weak::new());
+    let existing = object(Rc::downgrade(&root));
+    root.borrow_mut().set("a", Rc::clone(&existing), ShadowDataCollisionPolicy::Merge).unwrap();
+    existing.borrow_mut().set("kept", ShadowData::wrap(ShadowData::new_string(None, Rc::downgrade(&existing), "k".to_string())), ShadowDataCollisionPolicy::Merge).unwrap();
+
+    let merged = object(Rc::downgrade(&root));
+    let added = ShadowData::wrap(ShadowData::new_string(None, Rc::downgrade(&merged), "b".to_string()));
+    merged.borrow_mut().set("b", Rc::clone(&added), ShadowDataCollisionPolicy::Merge).unwrap();
+    root.borrow_mut().set("a", merged, ShadowDataCollisionPolicy::Merge).unwrap();
+
+    // The value moved into the existing object is linked to it
+    assert_eq!(root.borrow().to_string(), r#"{"a":{"kept":"k","b":"b"}}"#);
+    assert!(Rc::ptr_eq(&added.borrow().parent.upgrade().unwrap(), &existing));
+}
+
+#[test]
+fn test_wrap_unwrap() {
+    let html = r#"<html><body><span class="w">Wrapped</span><div class="u"><b>Kept</b></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            {
+                "s": ".w",
+                "wrap": "<div class=\"wrapper\">",
+                "insert_before": ["<hr>"]
+            },
+            {
+                "s": ".u",
+                "unwrap": true
+            }
+        ]
+    }
+    "##;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(html, r#"<html><body><hr><div class="wrapper"><span class="w">Wrapped</span></div><b>Kept</b><script>{}</script></body></html>"#);
+}
+
+#[test]
+fn test_wrap_invalid() {
+    let html = r#"<html><body><p class="a">x</p></body></html>"#;
+    for (wrap, reason) in [
+        ("<div>text</div>", "expected a tag name or a single opening tag"),
+        ("<div><span>", "expected a tag name or a single opening tag"),
+        ("</div>", "expected a tag name or a single opening tag"),
+        ("div class", "expected a tag name or a single opening tag"),
+        ("<div/>", "a self-closing tag cannot wrap the element"),
+        ("<br>", "a void element cannot wrap the element"),
+        ("IMG", "a void element cannot wrap the element"),
+    ] {
+        let shadow_json = serde_json::json!({ "s": "p", "wrap": wrap, "edit": { "attrs": { "id": { "op": "upsert", "val": "e" } } } }).to_string();
+        let (output, _, errors) = process_one(html, &shadow_json, None);
+        assert_eq!(errors, vec![format!("Invalid wrap '{}' for selector p : {}", wrap, reason)]);
+        // The wrap is skipped, the rest of the node applies
+        assert_eq!(output, r#"<html><body><p class="a" id="e">x</p><script>{}</script></body></html>"#, "{}", wrap);
+    }
+
+    // A '>' within an attribute value does not end the opening tag
+    let (output, _, errors) = process_one(html, r#"{ "s": "p", "wrap": "<div title='a > b'>" }"#, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(output.contains(r#"<div title='a > b'><p class="a">x</p></div>"#), "{}", output);
+}
+
+#[test]
+fn test_set_html() {
+    let html = r#"<html><body><div class="component"><p>Old <b>body</b></p></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": ".component",
+        "edit": {
+            "content": {
+                "op": "set_html",
+                "val": "<ul><li>New</li></ul>"
+            }
+        },
+        "data": {
+            "values": {
+                "old": {"source": "Contents"}
+            }
+        }
+    }
+    "##;
+    let (html, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(html, r#"<html><body><div class="component"><ul><li>New</li></ul></div><script>{"old":"body"}</script></body></html>"#);
+    assert_eq!(data, r#"{"old":"body"}"#);
+}
+
+#[test]
+fn test_session() {
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![
+        Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json_1(), Rc::clone(&errors)))),
+        Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json_2(), Rc::clone(&errors)))),
+    ]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| {
+        format!("<script>var my_data = {};</script>", data)
+    })));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut session = shadow_api_o.finalize_session();
+    let mut output: Vec<u8> = Vec::new();
+    for chunk in html_source().as_bytes().chunks(13) {
+        output.extend(session.write(chunk).unwrap());
+    }
+    let end = session.end().unwrap();
+    output.extend(end.output);
+
+    assert!(errors.borrow().is_empty());
+    let processed_html = String::from_utf8(output).unwrap();
+    assert_eq!(processed_html, html_result().replace("AppendModified", "Append"));
+    assert!(end.data.starts_with(r#"{"top_link":{"url":"https://top.link","name":"New Top Link"}"#));
+}
+
+#[test]
+fn test_attribute_template() {
+    let html = r##"<html><head><link rel="canonical" href="https://example.com/item"></head><body><a class="share" href="#">Share</a></body></html>"##;
+    let shadow_json = r##"
+    {
+        "s": "html",
+        "sub": [
+            {
+                "s": "link[rel=canonical]",
+                "data": {
+                    "path": "page",
+                    "values": {
+                        "url": {"source": "Attribute", "name": "href"}
+                    }
+                }
+            },
+            {
+                "s": "a.share",
+                "edit": {
+                    "attrs": {
+                        "href": {"op": "upsert", "val": "https://share.example.com/?u={{ page.url }}&missing={{page.nothing}}"}
+                    }
+                }
+            }
+        ]
+    }
+    "##;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert!(html.contains(r#"<a class="share" href="https://share.example.com/?u=https://example.com/item&missing=">"#), "{}", html);
+}
+
+#[test]
+fn test_checkbox_labels() {
+    let html = r#"<html><body><form>
+        <label for="c1">Red</label><input type="checkbox" id="c1" name="color" value="r" checked>
+        <input type="checkbox" id="c2" name="color" value="g"><label for="c2">Green</label>
+        <label><input type="checkbox" name="color" value="b" checked> Blue </label>
+    </form></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "form",
+        "sub": [
+            {
+                "s": "input[name=color]",
+                "data": {
+                    "values": {
+                        "color": {"source": "Value"}
+                    },
+                    "label_selector": "label"
+                }
+            }
+        ]
+    }
+    "##;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(data, r#"{"color":[{"value":"r","label":"Red","checked":true},{"value":"g","label":"Green","checked":false},{"value":"b","label":"Blue","checked":true}]}"#);
+}
+
+#[test]
+fn test_fragment_template() {
+    let html = r#"<html><body><div class="product"><h1>Widget</h1></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": ".product",
+        "data": {
+            "path": "product"
+        },
+        "wrap": "section",
+        "prepend": ["<p>Before: {{product.name}}</p>"],
+        "append": ["<p>Static</p>", "<p>After: {{product.name}}</p>"],
+        "insert_after": ["<aside>{{product.name}}</aside>"],
+        "sub": [
+            {
+                "s": "h1",
+                "data": {
+                    "values": {
+                        "name": {"source": "Contents"}
+                    }
+                }
+            }
+        ]
+    }
+    "##;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(html, r#"<html><body><section><div class="product"><p>Before: </p><h1>Widget</h1><p>Static</p><p>After: Widget</p></div></section><aside>Widget</aside><script>{"product":{"name":"Widget"}}</script></body></html>"#);
+}
+
+#[test]
+fn test_fragment_template_escaping() {
+    let html = r#"<html><body><div title="&lt;img src=x onerror=alert(1)&gt;">A &amp; B</div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "div",
+        "data": {
+            "values": {
+                "t": {"source": "Attribute", "name": "title", "decode": ["html_entities"]},
+                "text": {"source": "Contents"}
+            }
+        },
+        "append": ["<span>{{t}}</span>", "<b title={{t}}>{{text}}</b>", "<i data-x='{{t}}'>x > y</i>"]
+    }
+    "##;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    // The value is escaped as text, or as an attribute value in a tag. The character references of raw values are kept
+    assert!(html.contains("<span>&lt;img src=x onerror=alert(1)&gt;</span>"), "{}", html);
+    assert!(html.contains("<b title=&lt;img&#32;src&#61;x&#32;onerror&#61;alert(1)&gt;>A &amp; B</b>"), "{}", html);
+    assert!(html.contains("<i data-x='&lt;img&#32;src&#61;x&#32;onerror&#61;alert(1)&gt;'>x > y</i>"), "{}", html);
+}
+
+#[test]
+fn test_limit_skip() {
+    let html = r#"<html><body><ul><li class="item"><b>A</b></li><li class="item"><b>B</b></li><li class="item"><b>C</b></li></ul><p>1</p><p>2</p><p>3</p></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "body",
+        "sub": [
+            {
+                "s": ".item",
+                "limit": 2,
+                "data": { "path": "items." },
+                "sub": [
+                    {
+                        "s": "b",
+                        "data": { "values": { "name": {"source": "Contents"} } }
+                    }
+                ]
+            },
+            {
+                "s": "p",
+                "skip": 1,
+                "limit": 1,
+                "delete": true
+            }
+        ]
+    }
+    "#;
+    let (html, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(data, r#"{"items":[{"name":"A"},{"name":"B"}]}"#);
+    assert!(html.contains("<p>1</p><p>3</p>"));
+}
+
+#[test]
+fn test_stats() {
+    let html = r#"<html><body><a href="/a">A</a><a href="/b">B</a><p class="gone">x</p></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "body",
+        "sub": [
+            {
+                "s": "a",
+                "data": {
+                    "path": "links.",
+                    "values": {
+                        "url": {"source": "Attribute", "name": "href"},
+                        "name": {"source": "Contents"}
+                    }
+                },
+                "edit": { "attrs": { "rel": { "op": "upsert", "val": "nofollow" } } }
+            },
+            {
+                "s": ".missing",
+                "delete": true
+            },
+            {
+                "s": "a",
+                "nth": "2"
+            }
+        ]
+    }
+    "#;
+    let run = |stats: bool| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+        let shadow_api_o = ShadowApi::new(Some(ShadowApiOptions { stats, ..Default::default() }));
+        shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+        let mut session = shadow_api_o.finalize_session();
+        let mut output: Vec<u8> = Vec::new();
+        for chunk in html.as_bytes().chunks(7) {
+            output.extend(session.write(chunk).unwrap());
+        }
+        output.extend(session.end().unwrap().output);
+        assert!(errors.borrow().is_empty());
+        let stats = shadow_api_o.take_stats();
+        assert_eq!(shadow_api_o.take_stats(), Default::default());
+        (stats, output.len())
+    };
+
+    let (stats, output_len) = run(true);
+    // By rule index : the rules sharing a selector are counted apart
+    let count = |rule: usize| stats.matches.get(&rule).map(|rule_matches| (rule_matches.selector.as_str(), rule_matches.count));
+    assert_eq!(count(0), Some(("body", 1)));
+    assert_eq!(count(1), Some(("body a", 2)));
+    assert_eq!(count(3), Some(("body a", 2)));
+    assert_eq!(stats.unmatched().collect::<Vec<&str>>(), vec!["body .missing"]);
+    assert_eq!(stats.edits, 2);
+    assert_eq!(stats.data_values, 4);
+    assert_eq!(stats.bytes_in, html.len());
+    assert_eq!(stats.bytes_out, output_len);
+
+    // Without the option, the matches are not counted
+    let (stats, _) = run(false);
+    assert!(stats.matches.is_empty());
+    assert_eq!(stats.edits, 2);
+    assert_eq!(stats.data_values, 4);
+}
+
+#[test]
+fn test_replace_into() {
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![
+        Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json_1(), Rc::clone(&errors)))),
+        Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json_2(), Rc::clone(&errors)))),
+    ]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| {
+        format!("<script>var my_data = {};</script>", data)
+    })));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut replacer = shadow_api_o.finalize_replacer();
+    let mut output: Vec<u8> = Vec::new();
+    let mut reused: Vec<u8> = Vec::with_capacity(64);
+    for chunk in html_source().as_bytes().chunks(13) {
+        reused.clear();
+        let written = replacer.replace_into(chunk, &mut reused).unwrap();
+        output.extend_from_slice(written);
+    }
+    // Output already present in the caller's buffer is kept
+    let mut appended = b"prefix".to_vec();
+    assert_eq!(replacer.replace_into(b"", &mut appended).unwrap(), b"");
+    assert_eq!(appended, b"prefix");
+
+    let buffer = Rc::clone(&replacer.buffer);
+    let write_idx = Rc::clone(&replacer.write_idx);
+    replacer.finish().unwrap();
+    output.extend_from_slice(&buffer.borrow()[..*write_idx.borrow()]);
+
+    assert!(errors.borrow().is_empty());
+    assert_eq!(String::from_utf8(output).unwrap(), html_result().replace("AppendModified", "Append"));
+}
+
+#[test]
+fn test_document_end() {
+    let html = r#"<html><body><a href="/a">A</a>"#;
+    let shadow_json = r#"
+    {
+        "s": "a",
+        "data": {
+            "values": { "url": {"source": "Attribute", "name": "href"} }
+        }
+    }
+    "#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| {
+        format!("<script>{}</script>", data)
+    })));
+    shadow_api_o.set_on_document_end(Rc::new(Box::new(|end: &mut DocumentEnd, data: &ShadowData| {
+        end.append(&format!("<!-- {} -->", data), ContentType::Html);
+    })));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut session = shadow_api_o.finalize_session();
+    let mut output: Vec<u8> = Vec::new();
+    for chunk in html.as_bytes().chunks(7) {
+        output.extend(session.write(chunk).unwrap());
+    }
+    output.extend(session.end().unwrap().output);
+    assert!(errors.borrow().is_empty());
+    // The body never closes : the data is injected at the end of the document, followed by the callback output
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><body><a href="/a">A</a><script>{"url":"/a"}</script><!-- {"url":"/a"} -->"#
+    );
+}
+
+#[test]
+fn test_fallback_injection() {
+    let html = r#"<div><a href="/a">A</a></div>"#;
+    let shadow_json = r#"
+    {
+        "s": "a",
+        "data": {
+            "values": { "url": {"source": "Attribute", "name": "href"} }
+        }
+    }
+    "#;
+    // No body : nothing is injected by default
+    let (output, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(output, html);
+
+    let options = Some(ShadowApiOptions { fallback_injection: true, ..Default::default() });
+    let (output, _, _) = process_one(html, shadow_json, options);
+    assert_eq!(output, r#"<div><a href="/a">A</a></div><script>{"url":"/a"}</script>"#);
+
+    let options = Some(ShadowApiOptions { fallback_injection: true, fallback_close_body: true, ..Default::default() });
+    let (output, _, _) = process_one(html, shadow_json, options);
+    assert_eq!(output, r#"<div><a href="/a">A</a></div><script>{"url":"/a"}</script></body></html>"#);
+
+    // A body closed normally is not affected
+    let html = r#"<html><body><a href="/a">A</a></body></html>"#;
+    let (output, _, _) = process_one(html, shadow_json, options);
+    assert_eq!(output, r#"<html><body><a href="/a">A</a><script>{"url":"/a"}</script></body></html>"#);
+}
+
+#[test]
+fn test_data_serialization() {
+    let root = ShadowData::wrap(ShadowData::new_object(None, Weak::new()));
+    {
+        let parent = Rc::downgrade(&root);
+        let mut root_m = root.borrow_mut();
+        root_m.set("quote", ShadowData::wrap(ShadowData::new_string(None, Weak::clone(&parent), r#"Say "hi" \o/"#.to_string())), ShadowDataCollisionPolicy::Merge).unwrap();
+        root_m.set("line\"key", ShadowData::wrap(ShadowData::new_string(None, Weak::clone(&parent), "a\nb\tc".to_string())), ShadowDataCollisionPolicy::Merge).unwrap();
+        root_m.set("flag", ShadowData::wrap(ShadowData::new_bool(None, Weak::clone(&parent), true)), ShadowDataCollisionPolicy::Merge).unwrap();
+        root_m.set("list", ShadowData::wrap(ShadowData::new_array(None, Weak::clone(&parent))), ShadowDataCollisionPolicy::Merge).unwrap();
+    }
+    let expected = r#"{"quote":"Say \"hi\" \\o/","line\"key":"a\nb\tc","flag":true,"list":[]}"#;
+    assert_eq!(root.borrow().to_string(), expected);
+
+    let mut written: Vec<u8> = Vec::new();
+    root.borrow().to_json_writer(&mut written).unwrap();
+    assert_eq!(String::from_utf8(written).unwrap(), expected);
+    let parsed: serde_json::Value = serde_json::from_str(expected).unwrap();
+    assert_eq!(parsed["quote"], r#"Say "hi" \o/"#);
+
+    // Control characters are escaped, other characters (emoji, line separators) are kept as they are and survive a round trip
+    let unicode = "caf\u{e9} \u{1F600} \u{2028}\u{2029} \u{1}\u{1f}";
+    let value = ShadowData::wrap(ShadowData::new_string(None, Weak::new(), unicode.to_string()));
+    assert_eq!(value.borrow().to_string(), "\"caf\u{e9} \u{1F600} \u{2028}\u{2029} \\u0001\\u001f\"");
+    assert_eq!(serde_json::from_str::<String>(&value.borrow().to_string()).unwrap(), unicode);
+    assert_eq!(value.borrow().visualize(0), value.borrow().to_string());
+    assert!(root.borrow().visualize(0).contains(r#"quote: "Say \"hi\" \\o/""#), "{}", root.borrow().visualize(0));
+
+    // Collected the same way from a page
+    let html = "<html><body><p title='Say \"hi\" \\o/ \u{1F600}\u{2028}'>p</p></body></html>";
+    let shadow_json = r##"{ "s": "p", "data": { "values": { "title": { "source": "Attribute", "name": "title" } } } }"##;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let parsed: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(parsed["title"], "Say \"hi\" \\o/ \u{1F600}\u{2028}");
+}
+
+#[test]
+fn test_seed_data() {
+    let html = r#"<html><body><div class="user"><span class="name">Clark</span></div></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": ".user",
+        "data": { "path": "user" },
+        "sub": [
+            {
+                "s": ".name",
+                "data": { "values": { "name": {"source": "Contents"} } }
+            }
+        ]
+    }
+    "#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.seed_data("", &serde_json::json!({"geo": {"country": "JP"}, "user": {"id": 42, "unknown": null}})).unwrap();
+    shadow_api_o.seed_data("ab.bucket", "B").unwrap();
+    shadow_api_o.seed_data("ab.weights", &serde_json::json!([0.5, null, 2])).unwrap();
+    assert!(shadow_api_o.seed_data("", "not an object").is_err());
+    assert!(shadow_api_o.seed_data("ab.bucket.sub", "B").is_err());
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut session = shadow_api_o.finalize_session();
+    session.write(html.as_bytes()).unwrap();
+    let end = session.end().unwrap();
+    assert!(errors.borrow().is_empty());
+    // Numbers are kept, null values leave their key out but are kept as array items
+    assert_eq!(end.data, r#"{"geo":{"country":"JP"},"user":{"id":42,"name":"Clark"},"ab":{"bucket":"B","weights":[0.5,null,2]}}"#);
+
+    let data = ShadowData::from_json(None, Weak::new(), &serde_json::json!({"n": -1.25, "z": null, "a": [1, null, "x", true]}));
+    assert_eq!(data.borrow().to_string(), r#"{"a":[1,null,"x",true],"n":-1.25,"z":null}"#);
+}
+
+#[test]
+fn test_data_transformer() {
+    let html = r#"<html><body><i>a</i><i>b</i><i>a</i></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "i",
+        "data": {
+            "path": "items.",
+            "values": { "v": {"source": "Contents"} }
+        }
+    }
+    "#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| {
+        format!("<script>{}</script>", data)
+    })));
+    shadow_api_o.set_data_transformer(Rc::new(Box::new(|data: &mut ShadowData| {
+        // Deduplicates the items and adds their count
+        let items = data.get("items").unwrap();
+        let count = {
+            let mut items_m = items.borrow_mut();
+            let items_vec = items_m.as_array_mut().unwrap();
+            let mut seen: Vec<String> = Vec::new();
+            items_vec.retain(|item| {
+                let v = item.borrow().to_string();
+                let keep = !seen.contains(&v);
+                seen.push(v);
+                keep
+            });
+            items_vec.len()
+        };
+        data.set("count", ShadowData::wrap(ShadowData::new_string(None, Weak::new(), count.to_string())), ShadowDataCollisionPolicy::Merge).unwrap();
+    })));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut session = shadow_api_o.finalize_session();
+    let mut output = session.write(html.as_bytes()).unwrap();
+    output.extend(session.end().unwrap().output);
+    assert!(errors.borrow().is_empty());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><body><i>a</i><i>b</i><i>a</i><script>{"items":[{"v":"a"},{"v":"b"}],"count":"2"}</script></body></html>"#
+    );
+}
+
+#[test]
+fn test_collect_meta() {
+    let html = r#"<html><head><title> Daily Planet </title><meta charset="utf-8"><meta name="description" content="News"><meta property="og:title" content="Planet"><link rel="canonical" href="https://planet.test/"></head><body><svg><title>Icon</title></svg></body></html>"#;
+    let shadow_json = r#"{ "s": "body" }"#;
+    let options = Some(ShadowApiOptions { collect_meta: true, ..Default::default() });
+    let (_, data, errors) = process_one(html, shadow_json, options);
+    assert!(errors.is_empty());
+    assert_eq!(data, r#"{"head":{"title":"Daily Planet","meta":{"description":"News"},"property":{"og:title":"Planet"},"canonical":"https://planet.test/"}}"#);
+
+    let (_, data, _) = process_one(html, shadow_json, None);
+    assert_eq!(data, "{}");
+}
+
+#[test]
+fn test_nth() {
+    let html = r#"<html><body><ul><li>1</li><li>2</li><li>3</li></ul><ul><li>4</li><li>5</li></ul></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "ul",
+        "sub": [
+            {
+                "s": "li",
+                "nth": "odd",
+                "data": { "path": "odd.", "values": { "v": {"source": "Contents"} } }
+            },
+            {
+                "s": "li",
+                "nth": "2",
+                "edit": { "attrs": { "class": { "op": "upsert", "val": "second" } } }
+            },
+            {
+                "s": "li",
+                "nth": "-n+1",
+                "edit": { "attrs": { "id": { "op": "upsert", "val": "first" } } }
+            }
+        ]
+    }
+    "#;
+    let (html, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(data, r#"{"odd":[{"v":"1"},{"v":"3"},{"v":"4"}]}"#);
+    assert!(html.contains(r#"<li id="first">1</li><li class="second">2</li><li>3</li></ul><ul><li id="first">4</li><li class="second">5</li>"#));
+
+    let (_, _, errors) = process_one("<p></p>", r#"{ "s": "p", "nth": "2x" }"#, None);
+    assert_eq!(errors, vec!["Invalid nth '2x' for selector p".to_string()]);
+    // The node is skipped rather than applied to every match
+    let (html, _, errors) = process_one("<body><p>1</p><p>2</p><p>3</p></body>", r#"{ "s": "p", "nth": "first", "delete": true }"#, None);
+    assert_eq!(errors, vec!["Invalid nth 'first' for selector p".to_string()]);
+    assert_eq!(html, "<body><p>1</p><p>2</p><p>3</p><script>{}</script></body>");
+}
+
+#[test]
+fn test_combinator() {
+    let html = r#"<html><body><div class="menu"><a href="/1" class="active">1</a><p><a href="/2">2</a></p></div></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": ".menu",
+        "data": { "path": "menu" },
+        "sub": [
+            {
+                "s": "a",
+                "combinator": "child",
+                "data": { "path": "direct.", "values": { "url": {"source": "Attribute", "name": "href"} } },
+                "sub": [
+                    {
+                        "s": ".active",
+                        "combinator": "self",
+                        "data": { "values": { "active": {"source": "Contents"} } }
+                    }
+                ]
+            },
+            {
+                "s": "a",
+                "edit": { "attrs": { "rel": { "op": "upsert", "val": "x" } } }
+            }
+        ]
+    }
+    "#;
+    let (html, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert_eq!(data, r#"{"menu":{"direct":[{"url":"/1","active":"1"}]}}"#);
+    assert!(html.contains(r#"<a href="/1" class="active" rel="x">1</a><p><a href="/2" rel="x">2</a>"#));
+
+    let (_, _, errors) = process_one("<p></p>", r#"{ "s": "body", "sub": [{ "s": "p", "combinator": "self" }] }"#, None);
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn test_escaping_paths() {
+    let html = r#"<html><body><div class="p"><i>X</i><b>A</b><em>1</em></div><div class="p"><i>Y</i><b>B</b><em>2</em><input type="text" name="q" value="v"><s>Z</s></div></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": ".p",
+        "data": { "path": "products." },
+        "sub": [
+            {
+                "s": "i",
+                "data": { "path": "/brands.", "values": { "brand": {"source": "Contents"} } }
+            },
+            {
+                "s": "b",
+                "data": { "values": { "name": {"source": "Contents"} } }
+            },
+            {
+                "s": "em",
+                "data": { "path": "..", "values": { "last": {"source": "Contents"} } }
+            },
+            {
+                "s": "input",
+                "data": { "path": "../form", "values": { "q": {"source": "Value"} } }
+            },
+            {
+                "s": "s",
+                "data": { "values": { "extra": {"source": "Contents"} } }
+            }
+        ]
+    }
+    "#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        data,
+        r#"{"products":[{"name":"A"},{"name":"B","extra":"Z"}],"brands":[{"brand":"X"},{"brand":"Y"}],"last":"2","form":{"q":"v"}}"#
+    );
+
+    let (_, _, errors) = process_one(html, r#"{ "s": "b", "data": { "path": "../x" } }"#, None);
+    assert_eq!(errors, vec!["[ShadowError] [path] '../x' escapes above the root".to_string(); 2]); // Once per b element
+}
+
+#[test]
+fn test_key_from() {
+    let html = r#"<html><body><div class="p" data-sku="A1"><b>Cape</b></div><div class="p" data-sku="B2"><b>Boots</b></div><div class="p" data-sku="A1"><b>Cape (mobile)</b><i>red</i></div><ul><li>x<s>1</s></li><li>y<s>2</s></li></ul></body></html>"#;
+    let shadow_json = r#"
+    { "s": "body", "sub": [
+        {
+            "s": ".p",
+            "data": {
+                "path": "products.",
+                "key_from": {"source": "Attribute", "name": "data-sku"},
+                "key_collision": "keep_first"
+            },
+            "sub": [
+                { "s": "b", "data": { "values": { "name": {"source": "Contents"} } } },
+                { "s": "i", "data": { "values": { "color": {"source": "Contents"} } } }
+            ]
+        },
+        {
+            "s": "li",
+            "data": {
+                "path": "by_text.",
+                "key_from": {"source": "Contents"}
+            },
+            "sub": [
+                { "s": "s", "data": { "values": { "n": {"source": "Contents"} } } }
+            ]
+        }
+    ] }
+    "#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(data, r#"{"products":{"A1":{"name":"Cape"},"B2":{"name":"Boots"}},"by_text":{"x":{"n":"1"},"y":{"n":"2"}}}"#);
+}
+
+#[test]
+fn test_dedupe_by() {
+    let html = r#"<html><body><div class="p" data-sku="A1"><b>Cape</b></div><div class="p" data-sku="B2"><b>Boots</b></div><div class="p" data-sku="A1"><i>red</i></div></body></html>"#;
+    let shadow_json = |policy: &str| format!(r#"
+    {{
+        "s": ".p",
+        "data": {{
+            "path": "products.",
+            "values": {{ "sku": {{"source": "Attribute", "name": "data-sku"}} }},
+            "dedupe_by": "sku"{}
+        }},
+        "sub": [
+            {{ "s": "b", "data": {{ "values": {{ "name": {{"source": "Contents"}} }} }} }},
+            {{ "s": "i", "data": {{ "values": {{ "color": {{"source": "Contents"}} }} }} }}
+        ]
+    }}
+    "#, policy);
+    let (_, data, errors) = process_one(html, &shadow_json(""), None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(data, r#"{"products":[{"sku":"A1","name":"Cape","color":"red"},{"sku":"B2","name":"Boots"}]}"#);
+
+    let (_, data, _) = process_one(html, &shadow_json(r#", "key_collision": "keep_first""#), None);
+    assert_eq!(data, r#"{"products":[{"sku":"A1","name":"Cape"},{"sku":"B2","name":"Boots"}]}"#);
+
+    let (_, data, _) = process_one(html, &shadow_json(r#", "key_collision": "keep_last""#), None);
+    assert_eq!(data, r#"{"products":[{"sku":"A1","color":"red"},{"sku":"B2","name":"Boots"}]}"#);
+
+    let (_, data, errors) = process_one(html, &shadow_json(r#", "key_collision": "error""#), None);
+    assert_eq!(data, r#"{"products":[{"sku":"A1","name":"Cape"},{"sku":"B2","name":"Boots"}]}"#);
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn test_sort_keys_and_sort_by() {
+    let html = r#"<html><body><h1 data-z="1" data-a="2">Shop</h1><div class="p" data-price="$1,280"><b>Cape</b></div><div class="p"><b>Hat</b></div><div class="p" data-price="$95.50"><b>Boots</b></div><div class="p" data-price="$300"><b>Belt</b></div></body></html>"#;
+    let shadow_json = |sort_by: &str| format!(r#"
+    {{
+        "s": "body",
+        "sub": [
+            {{ "s": "h1", "data": {{ "path": "shop", "sort_keys": true, "values": {{ "z": {{"source": "Attribute", "name": "data-z"}}, "a": {{"source": "Attribute", "name": "data-a"}} }} }} }},
+            {{
+                "s": ".p",
+                "data": {{ "path": "products.", "sort_keys": true, "sort_by": "{}", "values": {{ "price": {{"source": "Attribute", "name": "data-price"}} }} }},
+                "sub": [ {{ "s": "b", "data": {{ "values": {{ "name": {{"source": "Contents"}} }} }} }} ]
+            }}
+        ]
+    }}
+    "#, sort_by);
+    let (html_out, data, errors) = process_one(html, &shadow_json("price"), None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let expected = r#"{"shop":{"a":"2","z":"1"},"products":[{"name":"Boots","price":"$95.50"},{"name":"Belt","price":"$300"},{"name":"Cape","price":"$1,280"},{"name":"Hat"}]}"#;
+    assert_eq!(data, expected);
+    assert!(html_out.contains(&format!("<script>{}</script>", expected)), "{}", html_out);
+
+    let (_, data, _) = process_one(html, &shadow_json("price desc"), None);
+    assert_eq!(data, r#"{"shop":{"a":"2","z":"1"},"products":[{"name":"Cape","price":"$1,280"},{"name":"Belt","price":"$300"},{"name":"Boots","price":"$95.50"},{"name":"Hat"}]}"#);
+
+    let (_, data, _) = process_one(html, &shadow_json("name desc"), None);
+    assert_eq!(data, r#"{"shop":{"a":"2","z":"1"},"products":[{"name":"Hat"},{"name":"Cape","price":"$1,280"},{"name":"Boots","price":"$95.50"},{"name":"Belt","price":"$300"}]}"#);
+}
+
+#[test]
+fn test_aggregate() {
+    let html = r#"<html><body><div class="p" data-price="$1,280"><b>Cape</b></div><div class="p"><b>Hat</b></div><div class="p" data-price="$95.50"><b>Boots</b></div></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "body",
+        "aggregate": {
+            "items_count": "count(products)",
+            "stats.priced": "count(products.*.price)",
+            "stats.min_price": "min(products.*.price)",
+            "stats.max_price": "max(products.*.price)",
+            "stats.total": "sum(products.*.price)",
+            "first_name": "first(products.0.name)",
+            "none": "min(products.*.name)"
+        },
+        "sub": [{
+            "s": ".p",
+            "data": { "path": "products.", "sort_by": "price", "values": { "price": {"source": "Attribute", "name": "data-price"} } },
+            "sub": [ { "s": "b", "data": { "values": { "name": {"source": "Contents"} } } } ]
+        }]
+    }
+    "#;
+    let (html_out, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let expected = r#"{"products":[{"price":"$95.50","name":"Boots"},{"price":"$1,280","name":"Cape"},{"name":"Hat"}],"items_count":3,"stats":{"priced":2,"min_price":95.5,"max_price":1280,"total":1375.5},"first_name":"Boots"}"#;
+    assert_eq!(data, expected);
+    assert!(html_out.contains(&format!("<script>{}</script>", expected)), "{}", html_out);
+
+    let (_, _, errors) = process_one(html, r#"{ "s": "body", "aggregate": { "x": "avg(products)", "y": "count" } }"#, None);
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+    assert!(errors[0].contains("unknown function 'avg'"), "{:?}", errors);
+}
+
+#[test]
+fn test_match_replace_sequence() {
+    let html = r#"<html><body><img src="http://old.example.com/img/a.png?v=1"><p class="t">a-b-c</p></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "body",
+        "sub": [
+            {
+                "s": "img",
+                "edit": { "attrs": { "src": [
+                    { "op": "match_replace", "match": "^http:", "val": "https:" },
+                    { "op": "match_replace", "match": "//old\\.example\\.com(?P<path>/[^?]*)", "val": "//cdn.example.com${path}" },
+                    { "op": "match_replace", "match": "\\?.*$", "val": "" }
+                ] } }
+            },
+            {
+                "s": ".t",
+                "edit": { "content": { "op": "match_replace", "match": "-", "val": "+", "limit": 1 } }
+            }
+        ]
+    }
+    "#;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert!(html.contains(r#"<img src="https://cdn.example.com/img/a.png">"#));
+    assert!(html.contains(r#"<p class="t">a+b-c</p>"#));
+}
+
+#[test]
+fn test_rewrite_urls() {
+    let html = r#"<html><body><a href="/shop/item?id=1">1</a><a href="https://other.com/x">2</a><img src="img/a.png" srcset="https://origin.com/a.png 1x, https://origin.com/a@2x.png 2x"><div style="background: url('/bg.png')"></div><form action="https://origin.com.evil.com/post"></form></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "body",
+        "rewrite_urls": { "from": "https://origin.com", "to": "https://www.example.com/site", "base": "https://origin.com/shop/" }
+    }
+    "#;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty());
+    assert!(html.contains(r#"<a href="https://www.example.com/site/shop/item?id=1">1</a><a href="https://other.com/x">2</a>"#));
+    assert!(html.contains(r#"<img src="https://www.example.com/site/shop/img/a.png" srcset="https://www.example.com/site/a.png 1x, https://www.example.com/site/a@2x.png 2x">"#));
+    assert!(html.contains(r#"<div style="background: url('https://www.example.com/site/bg.png')"></div>"#));
+    assert!(html.contains(r#"<form action="https://origin.com.evil.com/post"></form>"#));
+}
+
+#[test]
+fn test_inline() {
+    let html = r#"<html><head><style>.a { background: url("/bg.png"); }</style><script>var api = "http://api.origin.com"; if (a < b) {}</script><script type="application/ld+json">{"name":"x"}</script></head><body><p>http://</p></body></html>"#;
+    let shadow_json = r#"
+    {
+        "s": "html",
+        "sub": [
+            {
+                "s": "style, script",
+                "inline": {
+                    "rules": [ { "op": "match_replace", "match": "http://api\\.origin\\.com", "val": "https://api.example.com" } ],
+                    "rewrite_urls": { "from": "https://origin.com", "to": "https://cdn.example.com", "base": "https://origin.com/" },
+                    "vars": { "locale": "ja", "tag": "</script>" }
+                }
+            }
+        ]
+    }
+    "#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_inline_rewriter(Rc::new(Box::new(|tag: &str, contents: &mut String| {
+        if tag == "script" {
+            contents.push_str("/*rewritten*/");
+        }
+    })));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut session = shadow_api_o.finalize_session();
+    let mut output = Vec::new();
+    for chunk in html.as_bytes().chunks(7) {
+        output.extend(session.write(chunk).unwrap());
+    }
+    output.extend(session.end().unwrap().output);
+    assert!(errors.borrow().is_empty());
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(r#"<style>.a { background: url("https://cdn.example.com/bg.png"); }</style>"#));
+    assert!(output.contains("<script>var locale = \"ja\";\nvar tag = \"<\\/script>\";\nvar api = \"https://api.example.com\"; if (a < b) {}/*rewritten*/</script>"));
+    assert!(output.contains(r#"<script type="application/ld+json">{"name":"x"}/*rewritten*/</script>"#));
+    assert!(output.contains("<p>http://</p>"));
+}
+
+#[test]
+fn test_csp_nonce() {
+    let html = r#"<html><body><div id="a"></div><div id="b"></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            { "s": "#a", "nonce": true, "append": ["<script>a()</script>", "<script nonce=\"own\">b()</script>"] },
+            { "s": "#b", "append": ["<SCRIPT src=\"/c.js\"></SCRIPT>"] }
+        ]
+    }
+    "##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_csp_nonce_provider(Rc::new(Box::new(|| "r4nd\"".to_string())));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut session = shadow_api_o.finalize_session();
+    let mut output = session.write(html.as_bytes()).unwrap();
+    output.extend(session.end().unwrap().output);
+    assert!(errors.borrow().is_empty());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><body><div id="a"><script nonce="r4nd&quot;">a()</script><script nonce="own">b()</script></div><div id="b"><SCRIPT src="/c.js"></SCRIPT></div><script nonce="r4nd&quot;">var shadow_api_data = {};</script></body></html>"#
+    );
+}
+
+#[test]
+fn test_sanitizer() {
+    let html = r#"<html><body><div id="a"></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "#a",
+        "wrap": "<section onclick=\"x()\">",
+        "append": ["<p class=\"c\" onmouseover=\"x()\">Hi <script>x()</script><a href=\" javascript:x()\">a</a><blink>b</blink></p>"]
+    }
+    "##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_sanitizer(ShadowSanitizer::default());
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|_data: String| String::new())));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    let mut session = shadow_api_o.finalize_session();
+    let mut output = session.write(html.as_bytes()).unwrap();
+    output.extend(session.end().unwrap().output);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><body><section><div id="a"><p class="c">Hi <a>a</a>b</p></div></section></body></html>"#
+    );
+    assert_eq!(errors.borrow().clone(), vec![
+        "Sanitizer removed <p onmouseover> from append of selector #a",
+        "Sanitizer removed <script> from append of selector #a",
+        "Sanitizer removed <a href> from append of selector #a",
+        "Sanitizer removed <blink> from append of selector #a",
+        "Sanitizer removed <section onclick> from wrap of selector #a",
+    ]);
+}
+
+#[test]
+fn test_sanitizer_interpolated() {
+    let html = r#"<html><body><div id="b" data-u="javascript:alert(1)" data-v="https://example.com/">B</div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "#b",
+        "data": { "values": { "u": {"source": "Attribute", "name": "data-u"}, "v": {"source": "Attribute", "name": "data-v"} } },
+        "append": ["<a href=\"{{u}}\">u</a><a href=\"{{v}}\">v</a>"],
+        "prepend": ["<i onclick=\"x()\">i</i>"]
+    }
+    "##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_sanitizer(ShadowSanitizer::default());
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|_data: String| String::new())));
+    shadow_api_o.parse(Rc::clone(&json_def), Rc::clone(&errors));
+    // The definition given to parse is left as it is
+    assert_eq!(json_def[0].borrow().prepend, Some(vec![r#"<i onclick="x()">i</i>"#.to_string()]));
+
+    let mut session = shadow_api_o.finalize_session();
+    let mut output = session.write(html.as_bytes()).unwrap();
+    output.extend(session.end().unwrap().output);
+    // The fragments are sanitized again once the collected values are interpolated
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><body><div id="b" data-u="javascript:alert(1)" data-v="https://example.com/"><i>i</i>B<a>u</a><a href="https://example.com/">v</a></div></body></html>"#
+    );
+    assert_eq!(errors.borrow().clone(), vec![
+        "Sanitizer removed <i onclick> from prepend of selector #b",
+        "Sanitizer removed <a href> from append of selector #b",
+    ]);
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn test_process_async() {
+    let html = r#"<html><body><h1>Title</h1></body></html>"#;
+    let shadow_json = r#"{ "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } }"#;
+    let run = |as_json: bool, from_stream: bool| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+        let shadow_api_o = ShadowApi::new(Some(ShadowApiOptions { as_json, ..Default::default() }));
+        shadow_api_o.parse(json_def, Rc::clone(&errors));
+        let mut output: Vec<u8> = Vec::new();
+        futures::executor::block_on(async {
+            if from_stream {
+                let chunks = html.as_bytes().chunks(7).map(Ok::<_, std::io::Error>);
+                shadow_api_o.process_stream(futures::stream::iter(chunks), &mut output, Rc::clone(&errors)).await;
+            } else {
+                let mut reader = futures::io::Cursor::new(html.as_bytes());
+                shadow_api_o.process_html_async(&mut reader, &mut output, Rc::clone(&errors)).await;
+            }
+        });
+        assert!(errors.borrow().is_empty());
+        String::from_utf8(output).unwrap()
+    };
+    let expected_html = r#"<html><body><h1>Title</h1><script>var shadow_api_data = {"title":"Title"};</script></body></html>"#;
+    assert_eq!(run(false, false), expected_html);
+    assert_eq!(run(false, true), expected_html);
+    assert_eq!(run(true, false), r#"{"title":"Title"}"#);
+    assert_eq!(run(true, true), r#"{"title":"Title"}"#);
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn test_rewriter_async() {
+    use futures::AsyncWriteExt;
+
+    let html = r#"<html><body><h1>Title</h1></body></html>"#;
+    let shadow_json = r#"{ "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } }"#;
+    let run = |as_json: bool| {
+        let mut output: Vec<u8> = Vec::new(); // The writer must outlive the ShadowApi
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+        let shadow_api_o = ShadowApi::new(Some(ShadowApiOptions { as_json, ..Default::default() }));
+        shadow_api_o.parse(json_def, Rc::clone(&errors));
+        futures::executor::block_on(async {
+            let mut rewriter = shadow_api_o.finalize_rewriter_async(&mut output);
+            for chunk in html.as_bytes().chunks(7) {
+                // write_all would never complete if no input was reported as consumed (as_json)
+                rewriter.write_all(chunk).await.unwrap();
+            }
+            rewriter.close().await.unwrap();
+        });
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty());
+        String::from_utf8(output).unwrap()
+    };
+    assert_eq!(run(false), r#"<html><body><h1>Title</h1><script>var shadow_api_data = {"title":"Title"};</script></body></html>"#);
+    assert_eq!(run(true), "");
+}
+
+#[cfg(feature = "http-body")]
+#[test]
+fn test_http_body() {
+    use std::{pin::Pin, task::{Context, Poll, Waker}, collections::VecDeque};
+    use http_body::{Body, Frame};
+
+    // Ready body made of several data frames followed by trailers
+    struct ChunkedBody(VecDeque<Frame<bytes::Bytes>>);
+    impl Body for ChunkedBody {
+        type Data = bytes::Bytes;
+        type Error = std::io::Error;
+        fn poll_frame(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    let html = r#"<html><body><h1>Title</h1></body></html>"#;
+    let shadow_json = r#"{ "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } }"#;
+    let mut frames: VecDeque<Frame<bytes::Bytes>> = html.as_bytes().chunks(7).map(|c| Frame::data(bytes::Bytes::copy_from_slice(c))).collect();
+    let mut trailers = http::HeaderMap::new();
+    trailers.insert("x-checksum", http::HeaderValue::from_static("1"));
+    frames.push_back(Frame::trailers(trailers));
+
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    let mut body = Box::pin(shadow_api_o.finalize_body(ChunkedBody(frames)));
+
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut output = Vec::new();
+    let mut received_trailers = None;
+    while let Poll::Ready(Some(frame)) = body.as_mut().poll_frame(&mut cx) {
+        match frame.unwrap().into_data() {
+            Ok(data) => output.extend_from_slice(&data),
+            Err(frame) => received_trailers = frame.into_trailers().ok(),
+        }
+    }
+    assert!(body.is_end_stream());
+    assert!(errors.borrow().is_empty());
+    assert_eq!(String::from_utf8(output).unwrap(), r#"<html><body><h1>Title</h1><script>var shadow_api_data = {"title":"Title"};</script></body></html>"#);
+    assert_eq!(received_trailers.unwrap().get("x-checksum").unwrap(), "1");
+}
+
+#[cfg(feature = "worker")]
+#[test]
+fn test_worker_stream() {
+    use futures::StreamExt;
+
+    let html = r#"<html><body><h1>Title</h1></body></html>"#;
+    let shadow_json = r#"{ "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } }"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    let upstream = futures::stream::iter(html.as_bytes().chunks(7).map(|c| Ok(c.to_vec())).collect::<Vec<_>>());
+    let chunks: Vec<Vec<u8>> = futures::executor::block_on(shadow_api_o.finalize_worker_stream(upstream).map(Result::unwrap).collect());
+    assert!(errors.borrow().is_empty());
+    assert_eq!(String::from_utf8(chunks.concat()).unwrap(), r#"<html><body><h1>Title</h1><script>var shadow_api_data = {"title":"Title"};</script></body></html>"#);
+}
+
+#[test]
+fn test_json_output() {
+    let html = r#"<html><body><h1 id="title">Hello</h1><p>World</p></body></html>"#;
+    let shadow_json = r##"{ "s": "#title", "data": { "values": { "title": {"source": "Contents"} } } }"##;
+    let options = Some(ShadowApiOptions { as_json: true, ..Default::default() });
+    let (output, data, errors) = process_one(html, shadow_json, options);
+    assert!(errors.is_empty(), "{:?}", errors);
+    // The data is written once the document has ended, in place of the html
+    assert_eq!(output, data);
+    assert!(output.contains(r#""title":"Hello""#), "{}", output);
+
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut sink = Vec::new();
+    let shadow_api_o = ShadowApi::new(options);
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut sink, &mut bytes, Rc::clone(&errors));
+    let json_output = shadow_api_o.json_output().unwrap();
+    drop(shadow_api_o);
+    assert_eq!(String::from_utf8(sink).unwrap(), data);
+
+    assert_eq!(json_output.len(), data.len());
+    assert_eq!(json_output.content_type(), "application/json");
+    let chunks: Vec<Vec<u8>> = json_output.clone().collect();
+    assert_eq!(chunks.concat(), data.as_bytes());
+    let mut read = String::new();
+    std::io::Read::read_to_string(&mut json_output.clone(), &mut read).unwrap();
+    assert_eq!(read, data);
+}
+
+#[test]
+fn test_stream_items() {
+    let html = r#"<html><body><h1>List</h1><ul><li class="item"><i>x</i><i>y</i><a href="/a">A</a></li><li class="item"><a href="/b">B</a></li></ul></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            { "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } },
+            {
+                "s": ".item",
+                "data": { "path": "items." },
+                "sub": [
+                    { "s": "a", "data": { "values": { "href": {"source": "Attribute", "name": "href"} } } },
+                    { "s": "i", "data": { "path": "tags.", "values": { "tag": {"source": "Contents"} } } }
+                ]
+            }
+        ]
+    }
+    "##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let lines: Rc<RefCell<Vec<u8>>> = Rc::new(RefCell::new(Vec::new()));
+    let sink_lines = Rc::clone(&lines);
+
+    let mut output = Vec::new();
+    let mut shadow_api_o = ShadowApi::new(Some(ShadowApiOptions { stream_items: true, ..Default::default() }));
+    shadow_api_o.set_item_sink(Rc::new(Box::new(move |line: &[u8]| {
+        sink_lines.borrow_mut().extend_from_slice(line);
+    })));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    let data = shadow_api_o.shadow_data_cursor.borrow().root.borrow().to_string();
+    drop(shadow_api_o);
+
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    // Nested array items are part of their streamed ancestor item
+    assert_eq!(
+        String::from_utf8(lines.take()).unwrap(),
+        "{\"tags\":[{\"tag\":\"x\"},{\"tag\":\"y\"}],\"href\":\"/a\"}\n{\"href\":\"/b\"}\n"
+    );
+    assert_eq!(data, r#"{"title":"List","items":[]}"#);
+
+    // Without sink, the option is reported and the items are kept
+    let (_, data, errors) = process_one(html, shadow_json, Some(ShadowApiOptions { stream_items: true, ..Default::default() }));
+    assert_eq!(errors.len(), 1);
+    assert!(data.contains(r#""href":"/b""#));
+}
+
+#[test]
+fn test_output_encoding() {
+    let html = r#"<html><body><h1>Title</h1><ul><li>a</li><li>b</li></ul></body></html>"#;
+    let shadow_json = r#"{ "s": "body", "sub": [
+        { "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } },
+        { "s": "li", "data": { "path": "items.", "values": { "name": {"source": "Contents"} } } }
+    ] }"#;
+    let run = |output_encoding: ShadowOutputEncoding| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApi::new(Some(ShadowApiOptions { as_json: true, output_encoding, ..Default::default() }));
+        shadow_api_o.parse(json_def, Rc::clone(&errors));
+        let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+        shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+        drop(shadow_api_o);
+        let errors = errors.borrow().clone();
+        (output, errors)
+    };
+    let expected = r#"{"title":"Title","items":[{"name":"a"},{"name":"b"}]}"#;
+    let (output, errors) = run(ShadowOutputEncoding::Json);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    assert_eq!(ShadowOutputEncoding::Cbor.content_type(), "application/cbor");
+
+    let (output, errors) = run(ShadowOutputEncoding::MsgPack);
+    #[cfg(feature = "msgpack")]
+    {
+        assert!(errors.is_empty(), "{:?}", errors);
+        let value: serde_json::Value = rmp_serde::from_slice(&output).unwrap();
+        assert_eq!(value, serde_json::from_str::<serde_json::Value>(expected).unwrap());
+    }
+    #[cfg(not(feature = "msgpack"))]
+    {
+        // The encoding is not available : nothing is written and the error is reported
+        assert!(output.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
+    let (output, errors) = run(ShadowOutputEncoding::Cbor);
+    #[cfg(feature = "cbor")]
+    {
+        assert!(errors.is_empty(), "{:?}", errors);
+        let value: serde_json::Value = ciborium::from_reader(output.as_slice()).unwrap();
+        assert_eq!(value, serde_json::from_str::<serde_json::Value>(expected).unwrap());
+    }
+    #[cfg(not(feature = "cbor"))]
+    {
+        assert!(output.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+}
+
+#[test]
+fn test_parse_str() {
+    // Line breaks and double spaces inside string values are kept
+    let shadow_json = "{\n  \"s\": \"h1\",\n  \"append\": [\"<pre>a  b\nc</pre>\"]\n}";
+    let parsed = ShadowJson::try_parse_str(shadow_json).unwrap();
+    assert_eq!(parsed.append.unwrap(), vec!["<pre>a  b\nc</pre>".to_string()]);
+
+    let (html, _, errors) = process_one("<html><body><h1>Title</h1></body></html>", shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(html.contains("<h1>Title<pre>a  b\nc</pre></h1>"), "{}", html);
+
+    // Errors locate the failing field in the source, after a multi-line value
+    let shadow_json = "{\n  \"s\": \"h1\",\n  \"append\": [\"a\nb\"],\n  \"sub\": [{\"s\": \"a\", \"delete\": \"yes\"}]\n}";
+    match ShadowJson::try_parse_str(shadow_json) {
+        Err(ShadowError::InvalidJson { path, line, column, msg }) => {
+            assert_eq!(path, "sub[0].delete");
+            assert_eq!((line, column), (5, 36)); // End of the invalid value
+            assert!(msg.starts_with("invalid type"), "{}", msg);
+        },
+        other => panic!("Unexpected result : {:?}", other.map(|_| ())),
+    }
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    ShadowJson::parse_str("{\"s\": ", Rc::clone(&errors));
+    assert_eq!(errors.borrow().len(), 1);
+    assert!(errors.borrow()[0].starts_with("[ShadowError] Invalid json at 's' (line 1, column 6)"), "{}", errors.borrow()[0]);
+}
+
+#[cfg(feature = "schemars")]
+#[test]
+fn test_schema() {
+    let schema = ShadowJson::schema();
+    assert!(schema.get("required").is_none()); // "s" may come from a node of defs
+    assert_eq!(schema["properties"]["sub"]["items"]["$ref"], "#"); // Recursive nodes
+    let defs = schema["$defs"].as_object().unwrap();
+    assert!(defs.contains_key("ShadowJsonData") && defs.contains_key("ShadowJsonEdit"));
+    // Single operation or list of operations per attribute
+    assert_eq!(defs["ShadowJsonEditOps"]["anyOf"].as_array().unwrap().len(), 2);
+    assert_eq!(defs["ShadowDataCollisionPolicy"]["enum"], serde_json::json!(["error", "keep_first", "keep_last", "merge"]));
+}
+
+// The hand-written schema of the repository describes the fields of the generated one
+#[cfg(feature = "schemars")]
+#[test]
+fn test_schema_file() {
+    let generated = serde_json::to_value(ShadowJson::schema()).unwrap();
+    let file: serde_json::Value = serde_json::from_str(include_str!("../shadow_json_schema.json")).unwrap();
+    let keys = |schema: &serde_json::Value| schema["properties"].as_object().unwrap().keys().cloned().collect::<std::collections::BTreeSet<String>>();
+    assert_eq!(keys(&file), keys(&generated));
+    for def in ["ShadowJsonData", "ShadowJsonEdit", "ShadowJsonEditOne", "ShadowJsonRewriteUrls"] {
+        assert_eq!(keys(&file["$defs"][def]), keys(&generated["$defs"][def]), "{}", def);
+    }
+    // The fields of the value sources are generated by variant
+    let value = &generated["$defs"]["ShadowJsonValue"];
+    let mut value_keys = keys(value);
+    value["oneOf"].as_array().unwrap().iter().for_each(|variant| value_keys.extend(keys(variant)));
+    assert_eq!(keys(&file["$defs"]["ShadowJsonValue"]), value_keys);
+    let file_value = &file["$defs"]["ShadowJsonValue"]["properties"];
+    assert_eq!(file_value["transforms"]["items"]["enum"], generated["$defs"]["ShadowJsonTransform"]["enum"]);
+    assert_eq!(file_value["decode"]["items"]["enum"], generated["$defs"]["ShadowJsonDecoder"]["enum"]);
+}
+
+#[test]
+fn test_compiled_shadow_json() {
+    let shadow_json = r#"{ "s": "body", "sub": [
+        { "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } },
+        { "s": "a", "edit": { "attrs": { "href": {"op": "match_replace", "match": "^/(?P<page>\\w+)$", "val": "/shop/$page"} } } }
+    ] }"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let compiled = std::sync::Arc::new(CompiledShadowJson::compile_str(shadow_json, Rc::clone(&errors)).unwrap());
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+
+    // Compiled once, instantiated per request on any thread
+    let outputs: Vec<(String, Vec<String>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = ["first", "second"].iter().map(|title| {
+            let compiled = std::sync::Arc::clone(&compiled);
+            scope.spawn(move || {
+                let html = format!(r#"<html><body><h1>{}</h1><a href="/cart">Cart</a></body></html>"#, title);
+                let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+                let mut output = Vec::new();
+                let shadow_api_o = compiled.instantiate(None, Rc::clone(&errors));
+                let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+                shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+                drop(shadow_api_o);
+                let errors = errors.borrow().clone();
+                (String::from_utf8(output).unwrap(), errors)
+            })
+        }).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    for ((html, errors), title) in outputs.iter().zip(["first", "second"]) {
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(html, &format!(r#"<html><body><h1>{}</h1><a href="/shop/cart">Cart</a><script>var shadow_api_data = {{"title":"{}"}};</script></body></html>"#, title, title));
+    }
+
+    // Invalid selectors and regexes are reported when compiling
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    CompiledShadowJson::compile_str(r#"{ "s": "body", "sub": [{ "s": "h1[" }, { "s": "a", "edit": { "content": {"op": "match_replace", "match": "(", "val": ""} } }] }"#, Rc::clone(&errors)).unwrap();
+    assert_eq!(errors.borrow().len(), 2, "{:?}", errors.borrow());
+}
+
+#[test]
+fn test_regex_parse_errors() {
+    let shadow_json = r#"{ "s": "body", "sub": [
+        { "s": "p", "edit": { "attrs": { "class": [
+            {"op": "match_replace", "match": "[", "val": "x"},
+            {"op": "match_replace", "match": "^a", "val": "b"}
+        ] } } }
+    ] }"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    // Reported while parsing, before any element matched
+    assert_eq!(errors.borrow().len(), 1);
+    assert!(errors.borrow()[0].starts_with("Invalid regex: ["), "{:?}", errors.borrow());
+    drop(shadow_api_o);
+
+    // The valid operations still apply, and the invalid one is not reported again for each element
+    let (html, _, errors) = process_one(r#"<html><body><p class="aa"></p><p class="ab"></p></body></html>"#, shadow_json, None);
+    assert_eq!(errors.len(), 1);
+    assert!(html.contains(r#"<p class="ba"></p><p class="bb"></p>"#), "{}", html);
+}
+
+#[test]
+fn test_parse_cache() {
+    #[derive(Debug, PartialEq)]
+    struct DocumentEnds(usize);
+
+    let html = r#"<html><body><a href="/a">A</a><a href="/b">B</a></body></html>"#;
+    let shadow_json = r#"{ "s": "a", "edit": { "attrs": { "href": [
+        {"op": "match_replace", "match": "^/", "val": "/shop/"},
+        {"op": "match_replace", "match": "^/", "val": "/en/"}
+    ] } } }"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let cache_cell: Rc<RefCell<Option<Rc<RefCell<ShadowApiCache>>>>> = Rc::new(RefCell::new(None));
+    let end_cache_cell = Rc::clone(&cache_cell);
+
+    let mut output = Vec::new();
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_on_document_end(Rc::new(Box::new(move |_end: &mut DocumentEnd, _data: &ShadowData| {
+        // User state kept in the cache, by type
+        if let Some(cache) = end_cache_cell.borrow().as_ref() {
+            if let Some(ends) = cache.borrow_mut().get_mut::<DocumentEnds>() {
+                ends.0 += 1;
+            }
+        }
+    })));
+    let cache = shadow_api_o.parse(json_def, Rc::clone(&errors));
+    assert_eq!(cache.borrow().regex_count(), 1); // Compiled once for both operations
+    assert_eq!(cache.borrow_mut().insert(DocumentEnds(0)), None);
+    *cache_cell.borrow_mut() = Some(Rc::clone(&cache));
+
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    assert!(String::from_utf8(output).unwrap().contains(r#"<a href="/en/shop/a">A</a><a href="/en/shop/b">B</a>"#));
+    assert_eq!(cache.borrow().get::<DocumentEnds>(), Some(&DocumentEnds(1)));
+    assert_eq!(cache.borrow_mut().remove::<DocumentEnds>(), Some(DocumentEnds(1)));
+    assert!(cache.borrow().get::<DocumentEnds>().is_none());
+    *cache_cell.borrow_mut() = None; // Breaks the reference cycle
+}
+
+#[test]
+fn test_builder() {
+    let html = r#"<html><body><h1>Title</h1><p class="x">One</p><p>Two</p></body></html>"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let shadow_json = ShadowJson::parse_str(r#"{ "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } }"#, Rc::clone(&errors));
+    let paragraphs = Rc::new(RefCell::new(0));
+    let paragraphs_counter = Rc::clone(&paragraphs);
+
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .options(ShadowApiOptions::default())
+        .chunk_size(16)
+        .formatter(|data: String| format!("<script>{}</script>", data))
+        .definition(shadow_json)
+        .errors(Rc::clone(&errors))
+        .data_transformer(|data: &mut ShadowData| {
+            data.set("built", ShadowData::wrap(ShadowData::new_bool(None, Weak::new(), true)), ShadowDataCollisionPolicy::Merge).unwrap();
+        })
+        .element_handler("p", ElementContentHandlers::default().element(move |el| {
+            *paragraphs_counter.borrow_mut() += 1;
+            el.set_attribute("data-seen", "1")?;
+            Ok(())
+        }))
+        .element_handler("p[", ElementContentHandlers::default())
+        .document_handler(DocumentContentHandlers::default().end(|end| {
+            end.append("<!-- end -->", ContentType::Html);
+            Ok(())
+        }))
+        .build();
+    assert_eq!(errors.borrow().len(), 1, "{:?}", errors.borrow());
+    assert!(errors.borrow()[0].contains("p["));
+    errors.borrow_mut().clear();
+
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    assert_eq!(*paragraphs.borrow(), 2);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><body><h1>Title</h1><p class="x" data-seen="1">One</p><p data-seen="1">Two</p><script>{"title":"Title","built":true}</script></body></html><!-- end -->"#
+    );
+}
+
+#[test]
+fn test_replacer_finish_into() {
+    let html = r#"<html><body><h1>Title</h1></body></html>"#;
+    let shadow_json = r#"{ "s": "h1", "data": { "values": { "title": {"source": "Contents"} } } }"#;
+
+    for as_json in [false, true] {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(ShadowApiOptions { as_json, ..Default::default() })
+            .formatter(|data: String| format!("<script>{}</script>", data))
+            .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+            .errors(Rc::clone(&errors))
+            .document_handler(DocumentContentHandlers::default().end(|end| {
+                end.append("<!-- end -->", ContentType::Html);
+                Ok(())
+            }))
+            .build();
+        let mut replacer = shadow_api_o.finalize_replacer();
+        let mut output = Vec::new();
+        for chunk in html.as_bytes().chunks(7) {
+            replacer.replace_into(chunk, &mut output).unwrap();
+        }
+        let html_len = output.len();
+        replacer.finish_into(&mut output).unwrap();
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+
+        let output = String::from_utf8(output).unwrap();
+        if as_json {
+            // The output emitted at the end of the document is flushed, then the data appended
+            assert_eq!(&output[html_len..], r#"<!-- end -->{"title":"Title"}"#);
+        } else {
+            assert_eq!(&output[html_len..], "<!-- end -->");
+            assert_eq!(output, r#"<html><body><h1>Title</h1><script>{"title":"Title"}</script></body></html><!-- end -->"#);
+        }
+    }
+}
+
+#[test]
+fn test_on_data() {
+    let html = r#"<html><body><div id="product" data-id="p42"><h1>Shoes</h1>
+<ul><li data-sku="a">A</li><li data-sku="b">B</li></ul></div></body></html>"#;
+    let shadow_json = r##"{
+        "s": "#product",
+        "data": { "path": "product", "values": { "id": {"source": "Attribute", "name": "data-id"} } },
+        "sub": [
+            { "s": "h1", "data": { "values": { "name": {"source": "Contents"} } } },
+            { "s": "li", "data": { "path": "variants.", "values": { "sku": {"source": "Attribute", "name": "data-sku"} } } }
+        ]
+    }"##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let events: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let (id_events, sku_events, all_events) = (Rc::clone(&events), Rc::clone(&events), Rc::clone(&events));
+    let html_seen = Rc::new(RefCell::new(0usize));
+    let html_seen_id = Rc::clone(&html_seen);
+    let html_seen_counter = Rc::clone(&html_seen);
+
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+        .errors(Rc::clone(&errors))
+        .on_data("product.id", move |path: &str, value: &ShadowData| {
+            // Fired while the document is still streaming
+            assert!(*html_seen_id.borrow() < html.len());
+            id_events.borrow_mut().push(format!("{}={}", path, value));
+        })
+        .on_data("product.variants.*.sku", move |path: &str, value: &ShadowData| {
+            sku_events.borrow_mut().push(format!("{}={}", path, value));
+        })
+        .on_data("**.name", move |path: &str, value: &ShadowData| {
+            all_events.borrow_mut().push(format!("{}={}", path, value));
+        })
+        .build();
+    let mut bytes = html.as_bytes().chunks(7).map(|c| {
+        *html_seen_counter.borrow_mut() += c.len();
+        Ok(c.to_vec())
+    });
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    assert_eq!(*events.borrow(), vec![
+        r#"product.id="p42""#.to_string(),
+        r#"product.name="Shoes""#.to_string(),
+        r#"product.variants.0.sku="a""#.to_string(),
+        r#"product.variants.1.sku="b""#.to_string(),
+    ]);
+}
+
+#[test]
+fn test_stop_after() {
+    let mut html = String::from(r#"<html><head><title>Shop</title></head><body><div class="product" data-id="1"><h2>One</h2></div>"#);
+    for id in 2..200 {
+        html.push_str(&format!(r#"<div class="product" data-id="{}"><h2>Other</h2></div>"#, id));
+    }
+    html.push_str("</body></html>");
+    let shadow_json = r#"[
+        { "s": "title", "data": { "values": { "title": {"source": "Contents"} } } },
+        { "s": ".product", "limit": 1, "stop_after": true, "data": { "path": "product", "values": { "id": {"source": "Attribute", "name": "data-id"} } },
+            "sub": [ { "s": "h2", "data": { "values": { "name": {"source": "Contents"} } } } ] }
+    ]"#;
+    let run = |as_json: bool, stop_condition: bool| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let definitions: Vec<serde_json::Value> = serde_json::from_str(shadow_json).unwrap();
+        let mut output = Vec::new();
+        let mut builder = ShadowApiBuilder::new()
+            .options(ShadowApiOptions { as_json, ..Default::default() })
+            .errors(Rc::clone(&errors));
+        if stop_condition {
+            // Same node without stop_after, the predicate stops as soon as the title is known
+            builder = builder
+                .definition(ShadowJson::parse_str(&definitions[0].to_string(), Rc::clone(&errors)))
+                .stop_condition(|data: &ShadowData| data.get("title").is_some());
+        } else {
+            for definition in definitions {
+                builder = builder.definition(ShadowJson::parse_str(&definition.to_string(), Rc::clone(&errors)));
+            }
+        }
+        let shadow_api_o = builder.build();
+        let consumed = Rc::new(RefCell::new(0usize));
+        let consumed_c = Rc::clone(&consumed);
+        let mut bytes = html.as_bytes().chunks(64).map(|c| {
+            *consumed_c.borrow_mut() += c.len();
+            Ok(c.to_vec())
+        });
+        shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        let consumed = *consumed.borrow();
+        (String::from_utf8(output).unwrap(), consumed)
+    };
+
+    let (output, consumed) = run(true, false);
+    assert!(consumed < 300, "{} bytes read", consumed);
+    let data: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(data, serde_json::json!({"title": "Shop", "product": {"id": "1", "name": "One"}}));
+
+    let (output, consumed) = run(true, true);
+    assert!(consumed < 100, "{} bytes read", consumed);
+    assert_eq!(output, r#"{"title":"Shop"}"#);
+
+    // The html output is never truncated
+    let (output, consumed) = run(false, false);
+    assert_eq!(consumed, html.len());
+    assert!(output.ends_with("</body></html>"));
+}
+
+#[test]
+fn test_sentinel() {
+    let enabled = r#"<html><head><meta name="shadow-enabled"></head><body><h1>Title</h1></body></html>"#;
+    let disabled = r#"<html><head></head><body><h1>Title</h1></body></html>"#;
+    let late = format!(r#"<html><head>{}<meta name="shadow-enabled"></head><body><h1>Title</h1></body></html>"#, "<!-- padding -->".repeat(10));
+    let shadow_json = r#"{ "s": "h1", "data": { "values": { "title": {"source": "Contents"} } }, "edit": { "content": {"op": "upsert", "val": "New"} } }"#;
+    // Runs process_html_iter (7 bytes chunks) or process_html, and the async variants with the futures feature
+    let run = |html: &str, as_json: bool, from_reader: bool| {
+        let mut output = Vec::new();
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(ShadowApiOptions { as_json, ..Default::default() })
+            .formatter(|data: String| format!("<script>{}</script>", data))
+            .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+            .errors(Rc::clone(&errors))
+            .sentinel("meta[name=shadow-enabled]", 100)
+            .build();
+        if from_reader {
+            shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+        } else {
+            let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+            shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+        }
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        String::from_utf8(output).unwrap()
+    };
+    for from_reader in [false, true] {
+        assert_eq!(
+            run(enabled, false, from_reader),
+            r#"<html><head><meta name="shadow-enabled"></head><body><h1>New</h1><script>{"title":"New"}</script></body></html>"#
+        );
+        assert_eq!(run(disabled, false, from_reader), disabled);
+        assert_eq!(run(&late, false, from_reader), late); // Beyond the scanned bytes
+        assert_eq!(run(enabled, true, from_reader), r#"{"title":"New"}"#);
+        assert_eq!(run(disabled, true, from_reader), "{}");
+    }
+
+    #[cfg(feature = "futures")]
+    {
+        let run_async = |html: &str, as_json: bool, from_stream: bool| {
+            let mut output: Vec<u8> = Vec::new();
+            let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+            let shadow_api_o = ShadowApiBuilder::new()
+                .options(ShadowApiOptions { as_json, ..Default::default() })
+                .formatter(|data: String| format!("<script>{}</script>", data))
+                .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+                .errors(Rc::clone(&errors))
+                .sentinel("meta[name=shadow-enabled]", 100)
+                .build();
+            futures::executor::block_on(async {
+                if from_stream {
+                    let chunks = html.as_bytes().chunks(7).map(Ok::<_, std::io::Error>);
+                    shadow_api_o.process_stream(futures::stream::iter(chunks), &mut output, Rc::clone(&errors)).await;
+                } else {
+                    let mut reader = futures::io::Cursor::new(html.as_bytes());
+                    shadow_api_o.process_html_async(&mut reader, &mut output, Rc::clone(&errors)).await;
+                }
+            });
+            assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+            String::from_utf8(output).unwrap()
+        };
+        for from_stream in [false, true] {
+            assert!(run_async(enabled, false, from_stream).contains(r#"<h1>New</h1><script>{"title":"New"}</script>"#));
+            assert_eq!(run_async(disabled, false, from_stream), disabled);
+            assert_eq!(run_async(&late, false, from_stream), late);
+            assert_eq!(run_async(disabled, true, from_stream), "{}");
+        }
+    }
+}
+
+#[test]
+fn test_copy_from_data() {
+    let html = r#"<html><head><link rel="canonical" href="https://shop.example/p/1"><meta property="og:url" content="/p/1"><meta property="og:site_name" content="Shop"></head><body></body></html>"#;
+    let shadow_json = r#"[
+        { "s": "meta[property='og:url']", "edit": { "attrs": { "content": {"op": "copy_from_data", "path": "head.canonical"} } } },
+        { "s": "meta[property='og:site_name']", "edit": { "attrs": {
+            "content": {"op": "copy_from_data", "path": "head.missing"},
+            "data-fallback": {"op": "copy_from_data", "path": "head.missing", "val": "none"},
+            "data-title": {"op": "copy_from_data"}
+        } } }
+    ]"#;
+    let definitions: Vec<serde_json::Value> = serde_json::from_str(shadow_json).unwrap();
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .options(ShadowApiOptions { collect_meta: true, ..Default::default() })
+        .definitions(definitions.iter().map(|def| Rc::new(RefCell::new(ShadowJson::parse_str(&def.to_string(), Rc::clone(&errors))))))
+        .errors(Rc::clone(&errors))
+        .formatter(|_data: String| String::new())
+        .build();
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+
+    assert_eq!(errors.borrow().clone(), vec!["copy_from_data requires path (edit.attrs.data-title)"]);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><head><link rel="canonical" href="https://shop.example/p/1"><meta property="og:url" content="https://shop.example/p/1"><meta property="og:site_name" content="Shop" data-fallback="none"></head><body></body></html>"#
+    );
+}
+
+
+#[test]
+fn test_two_pass() {
+    let html = r#"<html><body><nav><a href="/cart">Cart</a></nav><main><span class="count">3</span></main></body></html>"#;
+    let shadow_json = r#"[
+        { "s": "nav a", "edit": { "attrs": { "data-count": {"op": "copy_from_data", "path": "cart_count", "val": "0"} } }, "append": ["<b>{{cart_count}}</b>"] },
+        { "s": "span.count", "data": { "values": { "cart_count": {"source": "Contents"} } } }
+    ]"#;
+    let definitions: Vec<serde_json::Value> = serde_json::from_str(shadow_json).unwrap();
+    let run = |two_pass: bool, from_reader: bool| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(ShadowApiOptions { two_pass, stats: true, ..Default::default() })
+            .definitions(definitions.iter().map(|def| Rc::new(RefCell::new(ShadowJson::parse_str(&def.to_string(), Rc::clone(&errors))))))
+            .errors(Rc::clone(&errors))
+            .formatter(|data: String| format!("<script>{}</script>", data))
+            .build();
+        if from_reader {
+            shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+        } else {
+            let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+            shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+        }
+        let stats = shadow_api_o.take_stats();
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        (String::from_utf8(output).unwrap(), stats)
+    };
+
+    // Single pass : the data is not collected yet when the nav is rewritten
+    assert_eq!(
+        run(false, false).0,
+        r#"<html><body><nav><a href="/cart" data-count="0">Cart<b></b></a></nav><main><span class="count">3</span></main><script>{"cart_count":"3"}</script></body></html>"#
+    );
+    for from_reader in [false, true] {
+        let (output, stats) = run(true, from_reader);
+        assert_eq!(
+            output,
+            r#"<html><body><nav><a href="/cart" data-count="3">Cart<b>3</b></a></nav><main><span class="count">3</span></main><script>{"cart_count":"3"}</script></body></html>"#
+        );
+        // Counted once, as for a single pass
+        assert_eq!(stats.data_values, 1);
+        assert_eq!(stats.edits, 2);
+        assert_eq!(stats.bytes_in, html.len());
+        assert_eq!(stats.matches.values().find(|rule| rule.selector == "nav a").map(|rule| rule.count), Some(1));
+    }
+
+    #[cfg(feature = "futures")]
+    for from_stream in [false, true] {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output: Vec<u8> = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(ShadowApiOptions { two_pass: true, ..Default::default() })
+            .definitions(definitions.iter().map(|def| Rc::new(RefCell::new(ShadowJson::parse_str(&def.to_string(), Rc::clone(&errors))))))
+            .errors(Rc::clone(&errors))
+            .formatter(|_data: String| String::new())
+            .build();
+        futures::executor::block_on(async {
+            if from_stream {
+                let chunks = html.as_bytes().chunks(7).map(Ok::<_, std::io::Error>);
+                shadow_api_o.process_stream(futures::stream::iter(chunks), &mut output, Rc::clone(&errors)).await;
+            } else {
+                let mut reader = futures::io::Cursor::new(html.as_bytes());
+                shadow_api_o.process_html_async(&mut reader, &mut output, Rc::clone(&errors)).await;
+            }
+        });
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"<html><body><nav><a href="/cart" data-count="3">Cart<b>3</b></a></nav><main><span class="count">3</span></main></body></html>"#
+        );
+    }
+}
+
+
+#[test]
+fn test_empty() {
+    let html = r#"<html><body><div id="promo" class="box"><p>Sale</p><img src="a.png"><span class="price">10</span></div><br></body></html>"#;
+    let shadow_json = r##"{ "s": "#promo", "empty": true, "append": ["<i>none</i>"], "sub": [
+        { "s": ".price", "data": { "values": { "price": {"source": "Contents"} } } }
+    ] }"##;
+    let (html, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(html, r#"<html><body><div id="promo" class="box"><i>none</i></div><br><script>{"price":"10"}</script></body></html>"#);
+    assert_eq!(data, r#"{"price":"10"}"#);
+
+    // Void elements have no children to remove
+    let (html, _, errors) = process_one("<body><br></body>", r#"{ "s": "br", "empty": true }"#, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(html, "<body><br><script>{}</script></body>");
+}
+
+
+#[test]
+fn test_class_ops() {
+    let html = r#"<body><div class="card  theme-light card" id="a"></div><p id="b"></p><span class="on big"></span></body>"#;
+    let shadow_json = r##"{ "s": "body", "sub": [
+        { "s": "#a", "edit": { "attrs": { "class": [
+            {"op": "replace_class", "match": "theme-light", "val": "theme-dark"},
+            {"op": "add_class", "val": "card shadow"},
+            {"op": "remove_class", "val": "missing"}
+        ] } } },
+        { "s": "#b", "edit": { "attrs": { "class": {"op": "add_class", "val": "new"} } } },
+        { "s": "span", "edit": { "attrs": {
+            "class": {"op": "toggle_class", "val": "on off"},
+            "data-x": {"op": "remove_class", "val": "on"}
+        } } }
+    ] }"##;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        html,
+        r#"<body><div class="card theme-dark shadow" id="a"></div><p id="b" class="new"></p><span class="big off"></span><script>{}</script></body>"#
+    );
+
+    let (_, _, errors) = process_one("<body><p></p></body>", r#"{ "s": "p", "edit": { "attrs": { "class": {"op": "replace_class", "val": "a"} } } }"#, None);
+    assert_eq!(errors, vec!["replace_class requires match (edit.attrs.class)"]);
+}
+
+
+#[test]
+fn test_edit_style() {
+    let html = r#"<body><div id="a" style="COLOR:red;background: url('a;b.png') ; margin:0;"></div><div id="b" style="display:block"></div><p style="color: red"></p></body>"#;
+    let shadow_json = r##"{ "s": "body", "sub": [
+        { "s": "#a", "edit": { "style": { "color": "blue", "margin": null, "--gap": "4px" } } },
+        { "s": "#b", "hide": true, "edit": { "style": { "display": "flex" } } },
+        { "s": "p", "edit": { "style": { "color": null } } }
+    ] }"##;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        html,
+        r#"<body><div id="a" style="color: blue; background: url('a;b.png'); --gap: 4px"></div><div id="b" style="display: none"></div><p></p><script>{}</script></body>"#
+    );
+}
+
+
+#[test]
+fn test_data_attributes() {
+    let html = r#"<body><div class="card" data-sku="A1" data-price-amount="10" data-x-2="y" data-="empty" id="c1"></div></body>"#;
+    let shadow_json = r#"{ "s": ".card", "data": { "values": {
+        "raw": {"source": "DataAttributes"},
+        "dataset": {"source": "DataAttributes", "name": "camel_case"}
+    } } }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        data,
+        r#"{"raw":{"sku":"A1","price-amount":"10","x-2":"y"},"dataset":{"sku":"A1","priceAmount":"10","x-2":"y"}}"#
+    );
+}
+
+
+#[test]
+fn test_has_attribute() {
+    let html = r#"<body><button class="buy" disabled data-in-stock="">Buy</button><button class="buy">Buy</button></body>"#;
+    let shadow_json = r#"{ "s": ".buy", "data": { "path": "buttons.", "values": {
+        "disabled": {"source": "HasAttribute", "name": "disabled"},
+        "in_stock": {"source": "HasAttribute", "name": "data-in-stock"}
+    } } }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        data,
+        r#"{"buttons":[{"disabled":true,"in_stock":true},{"disabled":false,"in_stock":false}]}"#
+    );
+}
+
+
+#[test]
+fn test_select_value() {
+    let html = r#"<body><form>
+        <select name="size"><option value="s">S</option><option value="m" selected>M</option></select>
+        <select name="colors" multiple><option value="red" selected>Red</option><option value="blue">Blue</option><optgroup><option selected> Green </option></optgroup></select>
+        <select name="none"><option value="a">A</option></select>
+    </form></body>"#;
+    let shadow_json = r#"{ "s": "form", "data": { "path": "form" }, "sub": [
+        { "s": "select[name=size]", "data": { "values": { "size": {"source": "Value"} } } },
+        { "s": "select[multiple]", "data": { "values": { "colors": {"source": "Value"} } } },
+        { "s": "select[name=none]", "data": { "values": { "none": {"source": "Value"} } } }
+    ] }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(data, r#"{"form":{"size":"m","colors":["red","Green"],"none":""}}"#);
+}
+
+
+#[test]
+fn test_text_value() {
+    let html = r#"<body><form><textarea name="comment">Hello, bye</textarea><div class="editor" contenteditable="true">Rich <b>text</b>!</div><div class="editor" contenteditable="false">Fixed</div></form></body>"#;
+    let shadow_json = r#"{ "s": "form", "data": { "path": "form" }, "sub": [
+        { "s": "textarea", "data": { "values": { "comment": {"source": "Value"} } } },
+        { "s": ".editor", "data": { "path": "editors.", "values": { "text": {"source": "Value"} } } }
+    ] }"#;
+    let (html_out, data, errors) = process_one(html, shadow_json, None);
+    assert_eq!(errors, vec!["Unimplemented input: 'div' (TODO)"]);
+    assert_eq!(data, r#"{"form":{"comment":"Hello, bye","editors":[{"text":"Rich text!"},{}]}}"#);
+    assert!(html_out.starts_with(html.trim_end_matches("</body>")), "{}", html_out);
+}
+
+
+#[test]
+fn test_form() {
+    let html = r#"<body><form id="order" action="/order">
+        <input type="text" name="name" value="Ann">
+        <input type="email" name="email">
+        <input type="hidden" name="token" value="t1">
+        <input type="text" name="locked" value="x" disabled>
+        <input type="text" value="no name">
+        <input type="checkbox" name="tags" value="a" checked>
+        <input type="checkbox" name="tags" value="b">
+        <input type="checkbox" name="tags" value="c" checked>
+        <input type="checkbox" name="newsletter" checked>
+        <input type="radio" name="ship" value="std">
+        <input type="radio" name="ship" value="express" checked>
+        <select name="size"><option value="s">S</option><option value="m">M</option></select>
+        <select name="colors" multiple><option selected>Red</option><option value="g">Green</option><option value="b" selected>Blue</option></select>
+        <textarea name="note">Ring twice</textarea>
+        <input type="submit" name="go" value="Send">
+    </form></body>"#;
+    let shadow_json = r##"{ "s": "#order", "form": true, "data": { "path": "order" } }"##;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        data,
+        r#"{"order":{"name":"Ann","email":"","token":"t1","tags":["a","c"],"newsletter":"on","ship":"express","size":"s","colors":["Red","b"],"note":"Ring twice"}}"#
+    );
+}
+
+
+#[test]
+fn test_value_transforms() {
+    let html = r#"<body><div class="item" data-desc="<b>Big</b> &amp; bold">
+        Hello
+            World
+    </div></body>"#;
+    let shadow_json = r#"{ "s": ".item", "data": { "values": {
+        "raw": {"source": "Contents"},
+        "text": {"source": "Contents", "transforms": ["collapse_whitespace", "lowercase"]},
+        "trimmed": {"source": "Contents", "transforms": ["trim"]},
+        "desc": {"source": "Attribute", "name": "data-desc", "transforms": ["strip_tags"]}
+    } } }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data["raw"], "\n        Hello\n            World\n    ");
+    assert_eq!(data["text"], "hello world");
+    assert_eq!(data["trimmed"], "Hello\n            World");
+    assert_eq!(data["desc"], "Big &amp; bold");
+}
+
+#[test]
+fn test_parse_number() {
+    let html = r#"<body><ul>
+        <li class="p" data-price="¥1,280">Sale : -$12.50</li>
+        <li class="p" data-price="1.280,50 €">0.125 kg</li>
+        <li class="p" data-price="1,280,000">12,5 %</li>
+        <li class="p" data-price="N/A">SKU-42</li>
+    </ul></body>"#;
+    let shadow_json = r#"{ "s": ".p", "data": { "path": "items.", "values": {
+        "price": {"source": "Attribute", "name": "data-price", "transforms": ["parse_number"]},
+        "text": {"source": "Contents", "transforms": ["parse_number"]}
+    } } }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data["items"], serde_json::json!([
+        {"price": 1280, "text": -12.5},
+        {"price": 1280.5, "text": 0.125},
+        {"price": 1280000, "text": 12.5},
+        {"price": "N/A", "text": 42}
+    ]));
+
+    // An explicit decimal separator settles the ambiguous cases
+    let html = r#"<body><span data-price="1,280">x</span></body>"#;
+    let shadow_json = r#"{ "s": "span", "data": { "values": {
+        "price": {"source": "Attribute", "name": "data-price", "transforms": ["trim", "parse_number"], "decimal_separator": ","}
+    } } }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(data, r#"{"price":1.28}"#);
+}
+
+#[test]
+fn test_value_extract() {
+    let html = r#"<body>
+        <p class="p" data-ref="item-0042">SKU: AB-12 (in stock)</p>
+        <p class="p" data-ref="none">Price : 1,280 yen</p>
+    </body>"#;
+    let shadow_json = r#"{ "s": ".p", "data": { "path": "items.", "values": {
+        "sku": {"source": "Contents", "extract": {"match": "SKU: ([A-Z]+)-(\\d+)", "group": 2}},
+        "id": {"source": "Attribute", "name": "data-ref", "extract": {"match": "item-(\\d+)"}, "transforms": ["parse_number"]},
+        "price": {"source": "Contents", "extract": {"match": "\\d[\\d,]*"}, "transforms": ["parse_number"]}
+    } } }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data["items"], serde_json::json!([
+        {"sku": "12", "id": 42, "price": 12},
+        {"sku": null, "id": null, "price": 1280}
+    ]));
+
+    let (_, _, errors) = process_one(html, r#"{ "s": ".p", "data": { "values": { "sku": {"source": "Contents", "extract": {"match": "("}} } } }"#, None);
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("Invalid regex: ("), "{:?}", errors);
+}
+
+#[test]
+fn test_value_decode() {
+    let html = r#"<body><a class="l" href="/search?q=caf%C3%A9&amp;tag=a%2Bb+c">Fish &amp; Chips &eacute;t&#233; &#x1F600; &#150; &notanentity; &amp</a></body>"#;
+    let shadow_json = r#"{ "s": ".l", "data": { "path": "link", "values": {
+        "raw": {"source": "Attribute", "name": "href"},
+        "href": {"source": "Attribute", "name": "href", "decode": ["html_entities", "url"]},
+        "q": {"source": "Attribute", "name": "href", "decode": ["html_entities", "url"], "extract": {"match": "q=([^&]*)"}},
+        "text": {"source": "Contents", "decode": ["html_entities"]}
+    } } }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data["link"], serde_json::json!({
+        "raw": "/search?q=caf%C3%A9&amp;tag=a%2Bb+c",
+        "href": "/search?q=café&tag=a+b+c",
+        "q": "café",
+        "text": "Fish & Chips été 😀 \u{2013} &notanentity; &amp"
+    }));
+}
+
+#[test]
+fn test_parse_json_transform() {
+    // {"id":7,"tags":["a","b"],"price":12.5,"gift":null}
+    let html = r#"<body><div class="w" data-props='{"id":7,"tags":["a","b"],"price":12.5,"gift":null}' data-state="eyJpZCI6NywidGFncyI6WyJhIiwiYiJdLCJwcmljZSI6MTIuNSwiZ2lmdCI6bnVsbH0" data-bad="{oops"></div></body>"#;
+    let shadow_json = r#"{ "s": ".w", "data": { "path": "widget", "values": {
+        "props": {"source": "Attribute", "name": "data-props", "transforms": ["parse_json"]},
+        "state": {"source": "Attribute", "name": "data-state", "decode": ["base64"], "transforms": ["parse_json"]},
+        "bad": {"source": "Attribute", "name": "data-bad", "transforms": ["parse_json"]}
+    } } }"#;
+    let (html_out, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let props = r#"{"id":7,"tags":["a","b"],"price":12.5,"gift":null}"#;
+    assert_eq!(data, format!(r#"{{"widget":{{"props":{},"state":{},"bad":"{{oops"}}}}"#, props, props));
+    assert!(html_out.contains(&data), "{}", html_out);
+}
+
+#[test]
+fn test_value_default() {
+    let html = r#"<body>
+        <p class="p" data-price="1,280">Shirt</p>
+        <p class="p"></p>
+        <p class="p" data-price="">  </p>
+        <input class="i" type="text">
+    </body>"#;
+    let shadow_json = r#"{ "s": "body", "sub": [
+        { "s": ".p", "data": { "path": "items.", "values": {
+            "name": {"source": "Contents", "transforms": ["trim"], "default": "unknown"},
+            "price": {"source": "Attribute", "name": "data-price", "transforms": ["parse_number"], "default": "0"},
+            "code": {"source": "Attribute", "name": "data-code"}
+        } } },
+        { "s": ".i", "data": { "values": { "input": {"source": "Value", "default": "none"} } } }
+    ] }"#;
+    let (_, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data["items"], serde_json::json!([
+        {"price": 1280, "name": "Shirt"},
+        {"price": 0, "name": "unknown"},
+        {"price": 0, "name": "unknown"}
+    ]));
+    assert_eq!(data["input"], "none");
+}
+
+#[test]
+fn test_required() {
+    let html = r#"<html><body>
+        <div class="product" data-id="1"><h2>Shoes</h2><span class="price"></span></div>
+        <div class="product"><h2></h2></div>
+    </body></html>"#;
+    let shadow_json = r#"{ "s": "body", "sub": [
+        { "s": ".product", "data": { "path": "items.", "values": {
+            "id": {"source": "Attribute", "name": "data-id", "required": true},
+            "name": {"source": "Contents", "required": true}
+        } }, "sub": [
+            { "s": ".price", "data": { "values": { "price": {"source": "Contents", "required": true} } } }
+        ] },
+        { "s": ".rating", "data": { "path": "rating", "required": true, "values": { "stars": {"source": "Contents", "required": true} } } },
+        { "s": ".optional", "data": { "values": { "note": {"source": "Contents"} } } }
+    ] }"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let missing = Rc::new(RefCell::new(Vec::<(String, Option<String>)>::new()));
+    let missing_c = Rc::clone(&missing);
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+        .errors(Rc::clone(&errors))
+        .on_missing_required(move |errors: &[ShadowError]| {
+            missing_c.borrow_mut().extend(errors.iter().filter_map(|err| match err {
+                ShadowError::MissingRequired { selector, key } => Some((selector.clone(), key.clone())),
+                _ => None,
+            }));
+        })
+        .build();
+    shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+    drop(shadow_api_o);
+
+    // The name of the second product is empty, but the first one has a name. The rating selector never matched
+    assert_eq!(*errors.borrow(), vec![
+        "[ShadowError] Required value 'price' of selector body .product .price was not collected".to_string(),
+        "[ShadowError] Required selector body .rating never matched".to_string(),
+    ]);
+    assert_eq!(*missing.borrow(), vec![
+        ("body .product .price".to_string(), Some("price".to_string())),
+        ("body .rating".to_string(), None),
+    ]);
+}
+
+#[test]
+fn test_trace() {
+    let html = r#"<body><ul><li data-sku="a">A</li><li data-sku="b">B</li></ul><a href="/x">x</a></body>"#;
+    let shadow_json = r#"{ "s": "body", "sub": [
+        { "s": "li", "data": { "path": "products.", "values": { "sku": {"source": "Attribute", "name": "data-sku"} } } },
+        { "s": "a", "edit": { "attrs": { "href": {"op": "upsert", "val": "/y"} } }, "append": ["<i></i>"] }
+    ] }"#;
+    let options = ShadowApiOptions { trace: true, ..ShadowApiOptions::default() };
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .options(options)
+        .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+        .errors(Rc::clone(&errors))
+        .build();
+    shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+
+    let trace = serde_json::to_value(shadow_api_o.take_trace()).unwrap();
+    assert_eq!(trace, serde_json::json!([
+        {"event": "matched", "selector": "body", "tag": "body"},
+        {"event": "matched", "selector": "body li", "tag": "li"},
+        {"event": "data_entered", "selector": "body li", "path": "products.0"},
+        {"event": "collected", "selector": "body li", "path": "products.0.sku"},
+        {"event": "data_left", "selector": "body li", "path": "products"},
+        {"event": "matched", "selector": "body li", "tag": "li"},
+        {"event": "data_entered", "selector": "body li", "path": "products.1"},
+        {"event": "collected", "selector": "body li", "path": "products.1.sku"},
+        {"event": "data_left", "selector": "body li", "path": "products"},
+        {"event": "matched", "selector": "body a", "tag": "a"},
+        {"event": "edited", "selector": "body a", "ops": ["append", "edit.attrs.href:upsert"]}
+    ]));
+    assert!(shadow_api_o.take_trace().is_empty());
+    drop(shadow_api_o);
+    // The output is rewritten as usual
+    assert!(String::from_utf8(output).unwrap().contains(r#"<a href="/y">x<i></i></a>"#));
+}
+
+#[test]
+fn test_cursor_inspection() {
+    let html = r#"<body><ul><li data-sku="a/1"><span>A</span></li><li data-sku="b"><span>B</span></li></ul></body>"#;
+    let shadow_json = r#"{ "s": "ul", "data": { "path": "catalog" }, "sub": [
+        { "s": "li", "data": { "path": "by_sku.", "key_from": {"source": "Attribute", "name": "data-sku"}, "values": { "sku": {"source": "Attribute", "name": "data-sku"} } } }
+    ] }"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]);
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApi::new(Some(ShadowApiOptions { as_json: true, ..ShadowApiOptions::default() }));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+
+    // Inspected from a handler running after the ones of the definition
+    let cursor = Rc::clone(&shadow_api_o.shadow_data_cursor);
+    let states = Rc::new(RefCell::new(Vec::new()));
+    let states_c = Rc::clone(&states);
+    shadow_api_o.ech.borrow_mut().push(lol_html::element!("li span", move |_el| {
+        let cursor = cursor.borrow();
+        states_c.borrow_mut().push((cursor.current_path(), cursor.depth(), cursor.snapshot()));
+        Ok(())
+    }));
+    shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+
+    let states = states.borrow();
+    // The keyed item is designated by its key, and "/" is escaped
+    assert_eq!(states[0].0.as_deref(), Some("/catalog/by_sku/a~11"));
+    assert_eq!(states[0].1, 3);
+    assert_eq!(states[1].0.as_deref(), Some("/catalog/by_sku/b"));
+    // Snapshots are not affected by the rest of the processing. Keyed items are inserted as their element closes
+    assert_eq!(states[0].2, serde_json::json!({"catalog": {"by_sku": {}}}));
+    assert_eq!(states[1].2, serde_json::json!({"catalog": {"by_sku": {"a/1": {"sku": "a/1"}}}}));
+
+    let cursor = shadow_api_o.shadow_data_cursor.borrow();
+    assert_eq!(cursor.current_path().as_deref(), Some(""));
+    assert_eq!(cursor.depth(), 0);
+}
+
+#[test]
+fn test_deep_clone() {
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let def = ShadowJson::parse_str(r#"{ "s": "ul", "sub": [{ "s": "li", "data": { "path": "items.", "values": { "name": {"source": "Contents"} } } }] }"#, Rc::clone(&errors));
+    let copy = def.clone();
+    copy.sub.as_ref().unwrap()[0].borrow_mut().s = "li.other".to_string();
+    assert_eq!(def.sub.as_ref().unwrap()[0].borrow().s, "li");
+
+    // The same definition reused by two instances
+    for _ in 0..2 {
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(ShadowApiOptions { as_json: true, ..ShadowApiOptions::default() })
+            .definition(def.clone())
+            .errors(Rc::clone(&errors))
+            .build();
+        shadow_api_o.process_html(&mut output, &mut "<body><ul><li>A</li></ul></body>".as_bytes(), Rc::clone(&errors));
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        assert_eq!(String::from_utf8(output).unwrap(), r#"{"items":[{"name":"A"}]}"#);
+    }
+
+    let data = ShadowData::from_json(None, Weak::new(), &serde_json::json!({"items": [{"name": "A"}], "count": "1"}));
+    let copy = ShadowData::deep_clone(&data);
+    let item = copy.borrow().get("items").unwrap().borrow().as_array().unwrap()[0].clone();
+    item.borrow_mut().set("name", ShadowData::wrap(ShadowData::new_string(None, Rc::downgrade(&item), "B".to_string())), ShadowDataCollisionPolicy::Merge).unwrap();
+    assert_eq!(data.borrow().to_string(), r#"{"count":"1","items":[{"name":"A"}]}"#);
+    assert_eq!(copy.borrow().to_string(), r#"{"count":"1","items":[{"name":"B"}]}"#);
+    // Each copied node points at its copied parent
+    let items = copy.borrow().get("items").unwrap();
+    assert!(Rc::ptr_eq(&items.borrow().parent.upgrade().unwrap(), &copy));
+    assert!(Rc::ptr_eq(&item.borrow().parent.upgrade().unwrap(), &items));
+
+    // Clone links the children of the copy once wrapped
+    let copy = ShadowData::wrap(data.borrow().clone());
+    let items = copy.borrow().get("items").unwrap();
+    assert!(Rc::ptr_eq(&items.borrow().parent.upgrade().unwrap(), &copy));
+    assert_eq!(copy.borrow().to_string(), data.borrow().to_string());
+}
+
+#[test]
+fn test_data_path() {
+    let root = ShadowData::from_json(None, Weak::new(), &serde_json::json!({"products": [{"sku": "A1"}, {"sku": "B2"}], "a/b": {"c~d": "x"}}));
+    assert_eq!(root.borrow().get_path("products.1.sku").unwrap().borrow().to_string(), r#""B2""#);
+    assert_eq!(root.borrow().get_path("/products/0/sku").unwrap().borrow().to_string(), r#""A1""#);
+    assert_eq!(root.borrow().get_path("/a~1b/c~0d").unwrap().borrow().to_string(), r#""x""#);
+    assert!(root.borrow().get_path("products.2.sku").is_none());
+    assert!(root.borrow().get_path("products.0.sku.x").is_none());
+    assert!(root.borrow().get_path("").is_none());
+
+    // Intermediate objects are created, the value is linked to its container
+    ShadowData::set_path(&root, "meta.page.title", ShadowData::wrap(ShadowData::new_string(None, Weak::new(), "Home".to_string()))).unwrap();
+    let page = root.borrow().get_path("meta.page").unwrap();
+    assert!(Rc::ptr_eq(&root.borrow().get_path("meta.page.title").unwrap().borrow().parent.upgrade().unwrap(), &page));
+    ShadowData::set_path(&root, "/products/0/sku", ShadowData::wrap(ShadowData::new_string(None, Weak::new(), "C3".to_string()))).unwrap();
+    ShadowData::set_path(&root, "products.-", ShadowData::wrap(ShadowData::new_object(None, Weak::new()))).unwrap();
+    ShadowData::set_path(&root, "products.2.sku", ShadowData::wrap(ShadowData::new_bool(None, Weak::new(), true))).unwrap();
+    assert!(matches!(ShadowData::set_path(&root, "products.5", ShadowData::wrap(ShadowData::new_null(None, Weak::new()))), Err(ShadowError::Data(_))));
+    assert!(matches!(ShadowData::set_path(&root, "products.0.sku.x", ShadowData::wrap(ShadowData::new_null(None, Weak::new()))), Err(ShadowError::Data(_))));
+    assert!(ShadowData::set_path(&root, "", ShadowData::wrap(ShadowData::new_null(None, Weak::new()))).is_err());
+
+    // Walking back up from a value set by set_path reaches the root
+    let mut current = root.borrow().get_path("meta.page.title").unwrap();
+    let mut ancestors = 0;
+    loop {
+        let parent = current.borrow().parent.upgrade();
+        match parent {
+            Some(parent) => current = parent,
+            None => break,
+        }
+        ancestors += 1;
+    }
+    assert_eq!(ancestors, 3);
+    assert!(Rc::ptr_eq(&current, &root));
+    let sku = root.borrow().get_path("products.2.sku").unwrap();
+    assert!(Rc::ptr_eq(&sku.borrow().parent.upgrade().unwrap().borrow().parent.upgrade().unwrap(), &root.borrow().get_path("products").unwrap()));
+
+    let mut data = root.borrow_mut();
+    assert_eq!(data.remove_path("products.1").unwrap().borrow().to_string(), r#"{"sku":"B2"}"#);
+    assert_eq!(data.remove_path("/a~1b").unwrap().borrow().to_string(), r#"{"c~d":"x"}"#);
+    assert!(data.remove_path("products.5").is_none());
+    assert_eq!(data.to_string(), r#"{"products":[{"sku":"C3"},{"sku":true}],"meta":{"page":{"title":"Home"}}}"#);
+}
+
+#[test]
+fn test_debug_uids() {
+    let html = "<body><ul><li>A</li><li>B</li></ul></body>";
+    let def = r#"{ "s": "li", "data": { "path": "items.", "values": { "name": {"source": "Contents"} } } }"#;
+    let visualize = |options: ShadowApiOptions| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(options)
+            .definition(ShadowJson::parse_str(def, Rc::clone(&errors)))
+            .errors(Rc::clone(&errors))
+            .build();
+        shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        let visualized = shadow_api_o.shadow_data_cursor.borrow().visualize();
+        visualized
+    };
+
+    // Uids are left empty by default
+    assert_eq!(visualize(ShadowApiOptions::default()), "{\n  items: [\n    {\n      name: \"A\"\n    },\n    {\n      name: \"B\"\n    }\n  ]\n}");
+
+    // With debug_uids, each instance produces the same uids
+    let options = ShadowApiOptions { debug_uids: true, ..ShadowApiOptions::default() };
+    let first = visualize(options);
+    assert_eq!(first, "#0 ^ - {\n  items: #1 ^ 0 [\n    #2 ^ 1 {\n      name: #3 ^ 2 \"A\"\n    },\n    #4 ^ 1 {\n      name: #5 ^ 4 \"B\"\n    }\n  ]\n}");
+    assert_eq!(first, visualize(options));
+    assert!(ShadowData::new_null(None, Weak::new()).uid.is_empty()); // The mode of the thread is left as is
+
+    ShadowData::set_uid_mode(ShadowDataUidMode::Seeded(10));
+    let data = ShadowData::wrap(ShadowData::new_array(Some(3), Weak::new()));
+    assert_eq!(first, visualize(options)); // Neither disturbed by the thread counter nor advancing it
+    let item = ShadowData::wrap(ShadowData::new_bool(Some(4), Rc::downgrade(&data), true));
+    data.borrow_mut().push(item).unwrap();
+    assert_eq!(data.borrow().visualize(0), "#10 ^ - [\n  #11 ^ 10 true\n]");
+    ShadowData::set_uid_mode(ShadowDataUidMode::Disabled);
+    assert!(ShadowData::new_null(None, Weak::new()).uid.is_empty());
+}
+
+#[test]
+fn test_data_wrong_subtype() {
+    let array = ShadowData::wrap(ShadowData::new_array(None, Weak::new()));
+    let string = ShadowData::wrap(ShadowData::new_string(None, Weak::new(), "a".to_string()));
+    let value = || ShadowData::wrap(ShadowData::new_bool(None, Weak::new(), true));
+
+    assert!(array.borrow().get("key").is_none());
+    assert!(string.borrow().get("key").is_none());
+    assert!(matches!(array.borrow_mut().set("key", value(), ShadowDataCollisionPolicy::Merge), Err(ShadowError::Data(_))));
+    assert!(matches!(string.borrow_mut().set("key", value(), ShadowDataCollisionPolicy::KeepLast), Err(ShadowError::Data(_))));
+    assert!(matches!(string.borrow_mut().push(value()), Err(ShadowError::Data(_))));
+    assert!(ShadowData::force_object(Rc::clone(&string)).is_err());
+
+    array.borrow_mut().push(value()).unwrap();
+    assert!(ShadowData::force_object(Rc::clone(&array)).unwrap().is_some());
+    assert_eq!(array.borrow().to_string(), "[true,{}]");
+    assert_eq!(string.borrow().to_string(), r#""a""#);
+}
+
+#[test]
+fn test_error_policy() {
+    // The second item has no id : its end tag handler fails
+    let html = r#"<html><body><ul><li id="a">A</li><li>B</li><li id="c">C</li></ul><p>end</p></body></html>"#;
+    let shadow_json = r#"
+    { "s": "body", "sub": [
+        { "s": "li", "data": { "path": "items.", "key_from": {"source": "Attribute", "name": "id"}, "values": { "name": {"source": "Contents"} } } },
+        { "s": "p", "append": ["!"] }
+    ] }
+    "#;
+    let options = |error_policy| Some(ShadowApiOptions { error_policy, ..ShadowApiOptions::default() });
+
+    let (html_out, data, errors) = process_one(html, shadow_json, options(ShadowErrorPolicy::Collect));
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(data, r#"{"items":{"a":{"name":"A"},"c":{"name":"C"}}}"#);
+    assert!(html_out.ends_with(r#"<p>end!</p><script>{"items":{"a":{"name":"A"},"c":{"name":"C"}}}</script></body></html>"#), "{}", html_out);
+
+    // The rest of the document is passed through, without the data
+    let (html_out, data, errors) = process_one(html, shadow_json, options(ShadowErrorPolicy::FailOpen));
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].starts_with("[fail_open]"), "{}", errors[0]);
+    assert_eq!(data, r#"{"items":{"a":{"name":"A"}}}"#);
+    assert!(html_out.ends_with(r#"<li id="c">C</li></ul><p>end</p></body></html>"#), "{}", html_out);
+
+    // Nothing is written after the failing element
+    let (html_out, _, errors) = process_one(html, shadow_json, options(ShadowErrorPolicy::FailClosed));
+    assert!(!errors.is_empty());
+    assert!(!html_out.contains("end"), "{}", html_out);
+
+    // Panics of the data formatter are caught where the data is injected
+    for error_policy in [ShadowErrorPolicy::Collect, ShadowErrorPolicy::FailOpen] {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(ShadowApiOptions { error_policy, ..ShadowApiOptions::default() })
+            .formatter(|_: String| -> String { panic!("formatter failure") })
+            .definition(ShadowJson::parse_str(r#"{ "s": "p", "append": ["!"] }"#, Rc::clone(&errors)))
+            .errors(Rc::clone(&errors))
+            .build();
+        shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+        assert_eq!(shadow_api_o.has_failed(), error_policy == ShadowErrorPolicy::FailOpen);
+        drop(shadow_api_o);
+        assert_eq!(errors.borrow().len(), 1);
+        assert!(errors.borrow()[0].contains("formatter failure"), "{:?}", errors.borrow());
+        assert!(String::from_utf8(output).unwrap().ends_with("<p>end!</p></body></html>"));
+    }
+}
+
+#[test]
+fn test_size_limits() {
+    let html = r#"<html><body><p class="t">Héllo wörld, a long text</p><ul><li title="a">1</li><li title="b">2</li><li title="c">3</li></ul></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            {
+                "s": "p.t",
+                "edit": {"content": {"op": "upsert", "val": "replaced"}},
+                "data": {"values": {"text": {"source": "Contents"}}}
+            },
+            {
+                "s": "li",
+                "data": {"path": "items.", "values": {"title": {"source": "Attribute", "name": "title"}}}
+            }
+        ]
+    }
+    "##;
+
+    // Past max_text_bytes, the text is passed through and its data truncated at a char boundary
+    let options = ShadowApiOptions { max_text_bytes: Some(3), max_array_len: Some(2), ..Default::default() };
+    let (output, data, errors) = process_one(html, shadow_json, Some(options));
+    assert!(output.contains(r#"<p class="t">Héllo wörld, a long text</p>"#));
+    assert!(output.contains(r#"<li title="c">3</li>"#));
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data, serde_json::json!({"text": "Hé", "items": [{"title": "a"}, {"title": "b"}]}));
+    assert_eq!(errors, vec![
+        "[max_text_bytes] A text of 'p.t' exceeds 3 bytes : its data is truncated and its content edits are skipped".to_string(),
+        "[max_array_len] 'items' is limited to 2 items : the next items are dropped".to_string(),
+    ]);
+
+    // Under the limits, nothing changes
+    let options = ShadowApiOptions { max_text_bytes: Some(100), max_array_len: Some(3), ..Default::default() };
+    let (output, _, errors) = process_one(html, shadow_json, Some(options));
+    assert!(output.contains(r#"<p class="t">replaced</p>"#));
+    assert!(errors.is_empty());
+
+    // Past max_data_bytes, the values which do not fit are dropped : "text" (14 bytes) and the first title (8 bytes) fit in 25 bytes
+    let options = ShadowApiOptions { max_data_bytes: Some(25), ..Default::default() };
+    let (_, data, errors) = process_one(html, shadow_json, Some(options));
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data, serde_json::json!({"text": "replaced", "items": [{"title": "a"}, {}, {}]}));
+    assert_eq!(errors, vec!["[max_data_bytes] The data exceeds 25 bytes : the values which do not fit are dropped".to_string()]);
+}
+
+#[test]
+fn test_memory_settings() {
+    let html = format!(r#"<html><body><div id="a" title="{}">x</div></body></html>"#, "t".repeat(4096));
+    let shadow_json = r##"{"s": "#a", "data": {"values": {"text": {"source": "Contents"}}}}"##;
+
+    // The tag is split over many chunks : its buffering by the rewriter exceeds the limit
+    let options = ShadowApiOptions { max_allowed_memory_usage: Some(2048), preallocated_parsing_buffer_size: Some(0), ..Default::default() };
+    let (_, _, errors) = process_one(&html, shadow_json, Some(options));
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("memory limit"), "{}", errors[0]);
+
+    let options = ShadowApiOptions { max_allowed_memory_usage: Some(65536), ..Default::default() };
+    let (_, data, errors) = process_one(&html, shadow_json, Some(options));
+    assert!(errors.is_empty());
+    assert_eq!(data, r#"{"text":"x"}"#);
+}
+
+#[test]
+fn test_sibling_combinators() {
+    let html = r#"<html><body><div><h2>A</h2><p>1</p><p>2</p><section><h2>B</h2></section><p>3</p></div><h2>C</h2><span></span><p>4</p></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            {
+                "s": "h2",
+                "sub": [
+                    {"s": "+ p", "wrap": "b"},
+                    {"s": "~ p", "data": {"path": "later.", "values": {"text": {"source": "Contents"}}}}
+                ]
+            }
+        ]
+    }
+    "##;
+    let (output, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(output.contains("<h2>A</h2><b><p>1</p></b><p>2</p>"));
+    assert!(output.contains("<h2>B</h2></section><p>3</p>"));
+    assert!(output.contains("<span></span><p>4</p>"));
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data, serde_json::json!({"later": [{"text": "1"}, {"text": "2"}, {"text": "3"}, {"text": "4"}]}));
+
+    // A sibling combinator needs a parent node to follow
+    let (_, _, errors) = process_one(html, r##"{"s": "+ p"}"##, None);
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("Selector + p is invalid"));
+}
+
+#[test]
+fn test_absolute_node() {
+    let html = r#"<html><head><title>Shop</title></head><body><div id="cfg" data-lang="ja"><span>v2</span></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "head",
+        "sub": [
+            {
+                "s": "title",
+                "data": {"path": "page", "values": {"title": {"source": "Contents"}}},
+                "sub": [
+                    {
+                        "s": "#cfg",
+                        "absolute": true,
+                        "edit": {"attrs": {"class": {"op": "upsert", "val": "loaded"}}},
+                        "data": {"values": {"lang": {"source": "Attribute", "name": "data-lang"}}},
+                        "sub": [{"s": "span", "data": {"path": "meta", "values": {"version": {"source": "Contents"}}}}]
+                    }
+                ]
+            }
+        ]
+    }
+    "##;
+    let (output, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(output.contains(r#"<div id="cfg" data-lang="ja" class="loaded"><span>v2</span></div>"#));
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data, serde_json::json!({"page": {"title": "Shop", "lang": "ja", "meta": {"version": "v2"}}}));
+}
+
+#[test]
+fn test_definition_uses() {
+    let html = r#"<html><body><div class="main"><div class="card"><h3>A</h3><b>1</b></div></div><aside><div class="card"><h3>B</h3><b>2</b></div></aside></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "defs": {
+            "card": {
+                "s": ".card",
+                "sub": [{"use": "title"}, {"s": "b", "data": {"values": {"price": {"source": "Contents"}}}}]
+            },
+            "title": {"s": "h3", "data": {"values": {"title": {"source": "Contents"}}}}
+        },
+        "sub": [
+            {"s": ".main", "sub": [{"use": "card", "data": {"path": "main."}}]},
+            {"s": "aside", "sub": [{"use": "card", "data": {"path": "aside."}, "hide": true}]}
+        ]
+    }
+    "##;
+    let (output, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(output.contains(r#"<div class="main"><div class="card"><h3>"#));
+    assert!(output.contains(r#"<aside><div class="card" style="display: none"><h3>"#));
+    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data, serde_json::json!({
+        "main": [{"title": "A", "price": "1"}],
+        "aside": [{"title": "B", "price": "2"}]
+    }));
+
+    // Unknown names and cycles are reported with the path of the node
+    let err = ShadowJson::try_parse_str(r#"{"s": "body", "sub": [{"use": "missing"}]}"#).unwrap_err();
+    assert_eq!(err.to_string(), ShadowError::InvalidJson { path: "sub[0]".to_string(), line: 0, column: 0, msg: "unknown definition 'missing'".to_string() }.to_string());
+    let err = ShadowJson::try_parse_str(r#"{"s": "body", "defs": {"a": {"s": "a", "sub": [{"use": "b"}]}, "b": {"s": "b", "sub": [{"use": "a"}]}}, "sub": [{"use": "a"}]}"#).unwrap_err();
+    assert!(err.to_string().contains("cycle in the definitions : a > b > a"), "{}", err);
+}
+
+#[test]
+fn test_definition_vars() {
+    let html = r#"<html><body><div id="price" class="amount">1,280 JPY</div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "vars": {"price_id": "price", "currency": "EUR"},
+        "sub": [
+            {
+                "s": "#${price_id}",
+                "edit": {"content": {"op": "match_replace", "match": "(?P<amount>[0-9,]+) JPY", "val": "${amount} ${currency}"}},
+                "append": ["<small>${currency}</small>"]
+            }
+        ]
+    }
+    "##;
+    let (output, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    // ${amount} is left to the regex as no variable has that name
+    assert!(output.contains(r#"<div id="price" class="amount">1,280 EUR<small>EUR</small></div>"#), "{}", output);
+
+    let err = ShadowJson::try_parse_str(r##"{"s": "body", "sub": [{"s": "#${missing}"}]}"##).unwrap_err();
+    assert!(err.to_string().contains("unknown variable 'missing' in selector #${missing}"), "{}", err);
+}
+
+#[test]
+fn test_transform_fields() {
+    let shadow_json = r##"
+    {
+        "s": "#shop",
+        "append": ["<p>shop</p>"],
+        "edit": {"attrs": {"data-shop": {"op": "match_replace", "match": "shop", "val": "shop"}}},
+        "data": {"path": "shop.", "values": {"shop": {"source": "Attribute", "name": "data-shop"}}},
+        "sub": [{"s": ".shop", "insert_after": ["<b>shop</b>"]}]
+    }
+    "##;
+    let count = |json: &ShadowJson| serde_json::to_string(json).unwrap().matches("store").count();
+
+    // Only the injected fragments
+    let mut json = ShadowJson::try_parse_str(shadow_json).unwrap();
+    json.transform_fields(&[ShadowJsonField::Html], &mut |s: &mut String| *s = s.replace("shop", "store"));
+    assert_eq!(json.append, Some(vec!["<p>store</p>".to_string()]));
+    assert_eq!(json.s, "#shop");
+    assert_eq!(count(&json), 2);
+
+    // Every string, with a closure capturing its environment
+    let mut visited = Vec::new();
+    let mut json = ShadowJson::try_parse_str(shadow_json).unwrap();
+    json.transform_strings(&mut |s: &mut String| {
+        visited.push(s.clone());
+        *s = s.replace("shop", "store");
+    });
+    assert_eq!(visited.len(), 10);
+    assert_eq!(count(&json), 10);
+    assert_eq!(json.data.as_ref().unwrap().path.as_deref(), Some("store."));
+}
+
+#[test]
+fn test_enabled_if() {
+    let html = r#"<html><body><div id="a">A</div><div id="b">B</div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            {"s": "#a", "enabled_if": "ab_test=B && locale!=en", "append": ["<i>variant B</i>"]},
+            {"s": "#a", "enabled_if": "!ab_test || ab_test=A", "append": ["<i>variant A</i>"]},
+            {"s": "#b", "enabled_if": "preview", "hide": true, "sub": [{"s": "*", "delete": true}]}
+        ]
+    }
+    "##;
+    let run = |flags: &[(&str, &str)]| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .flags(flags.iter().copied())
+            .definition(ShadowJson::try_parse_str(shadow_json).unwrap())
+            .errors(Rc::clone(&errors))
+            .build();
+        shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        String::from_utf8(output).unwrap()
+    };
+    assert!(run(&[]).contains(r#"<div id="a">A<i>variant A</i></div><div id="b">B</div>"#));
+    assert!(run(&[("ab_test", "B"), ("locale", "ja")]).contains(r#"<div id="a">A<i>variant B</i></div>"#));
+    assert!(run(&[("ab_test", "B"), ("locale", "en")]).contains(r#"<div id="a">A</div>"#));
+    assert!(run(&[("preview", "1")]).contains(r#"<div id="b" style="display: none">B</div>"#));
+
+    let (_, _, errors) = process_one(html, r##"{"s": "#a", "enabled_if": "a=1 &&", "hide": true}"##, None);
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("Invalid enabled_if 'a=1 &&' : '' is not a test"), "{}", errors[0]);
+}
+
+#[test]
+fn test_parse_modes() {
+    let html = r#"<html><body><div id="a" title="x">A</div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            {"s": "#a", "hide": true, "edit": {"attrs": {"title": {"op": "upsert", "val": "y"}}}, "data": {"values": {"title": {"source": "Attribute", "name": "title"}}}}
+        ]
+    }
+    "##;
+    let run = |collect: bool| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::try_parse_str(shadow_json).unwrap()))]);
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApi::new(None);
+        if collect {
+            shadow_api_o.parse_collect_only(Rc::clone(&json_def), Rc::clone(&errors));
+        } else {
+            shadow_api_o.parse_mutate_only(Rc::clone(&json_def), Rc::clone(&errors));
+        }
+        shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+        let data = shadow_api_o.shadow_data_cursor.borrow().root.borrow().to_string();
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        // The definitions given are left untouched
+        assert!(json_def[0].borrow().sub.as_ref().unwrap()[0].borrow().data.is_some());
+        (String::from_utf8(output).unwrap(), data)
+    };
+
+    // The data of the original document, which is written unchanged
+    let (output, data) = run(true);
+    assert_eq!(output, html);
+    assert_eq!(data, r#"{"title":"x"}"#);
+
+    // Edited, without data nor injection
+    let (output, data) = run(false);
+    assert_eq!(output, r#"<html><body><div id="a" title="y" style="display: none">A</div></body></html>"#);
+    assert_eq!(data, "{}");
+}
+
+#[test]
+fn test_merged_handlers() {
+    let html = r#"<html><body><div class="a">x</div><div class="b">y</div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            {"s": ".a", "append": ["<i>1</i>"]},
+            {"s": ".a", "data": {"values": {"text": {"source": "Contents"}}}},
+            {"s": ".b", "hide": true},
+            {"s": ".a", "prepend": ["<b>2</b>"]}
+        ]
+    }
+    "##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::try_parse_str(shadow_json).unwrap()))]);
+    let mut output = Vec::new();
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| format!("<script>{}</script>", data))));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    // The first two ".a" nodes share an entry, the last one runs after the ".b" element handler and keeps its own
+    assert_eq!(shadow_api_o.selector_count(), 4);
+    shadow_api_o.process_html(&mut output, &mut html.as_bytes(), Rc::clone(&errors));
+    drop(shadow_api_o);
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"<html><body><div class="a"><b>2</b>x<i>1</i></div><div class="b" style="display: none">y</div><script>{"text":"x"}</script></body></html>"#
+    );
+}
+
+#[test]
+fn test_plan() {
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "data": {"path": "page", "values": {"lang": {"source": "Attribute", "name": "lang"}}},
+        "sub": [
+            {"s": "h1", "data": {"values": {"title": {"source": "Contents"}}}},
+            {"s": "a", "rewrite_urls": {"from": "https://origin.example.com", "to": "https://www.example.com"}}
+        ]
+    }
+    "##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::try_parse_str(shadow_json).unwrap()))]);
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    let plan: Vec<(String, Option<String>, ShadowPlanPurpose, bool, bool, bool)> = shadow_api_o.plan().into_iter()
+        .map(|entry| (entry.selector, entry.node, entry.purpose, entry.element, entry.text, entry.end_tag))
+        .collect();
+    let node = |path: &str| Some(path.to_string());
+    assert_eq!(plan, vec![
+        ("body".to_string(), node("[0]"), ShadowPlanPurpose::Node, true, false, true),
+        ("body h1".to_string(), node("[0].sub[0]"), ShadowPlanPurpose::Node, false, true, false),
+        ("body a".to_string(), node("[0].sub[1]"), ShadowPlanPurpose::RewriteUrls, true, false, false),
+        ("body a *".to_string(), node("[0].sub[1]"), ShadowPlanPurpose::RewriteUrls, true, false, false),
+        ("body".to_string(), None, ShadowPlanPurpose::Inject, true, false, true),
+    ]);
+}
+
+#[test]
+fn test_reset_for_next_document() {
+    let pages = [
+        r#"<html><body><h1>Page 1</h1><p class="first">z</p><ul><li data-sku="a">A</li><li data-sku="b">B</li></ul></body></html>"#,
+        r#"<html><body><h1>Page 2</h1><p class="first">x</p><p>y</p><ul><li data-sku="c">C</li></ul></body></html>"#,
+    ];
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            {"s": "h1", "data": {"values": {"title": {"source": "Contents"}}}},
+            {"s": "ul", "data": {"path": "catalog"}, "sub": [
+                {"s": "li", "data": {"path": "items.", "values": {"sku": {"source": "Attribute", "name": "data-sku"}}}}
+            ]},
+            {"s": "p", "limit": 1, "data": {"path": "notes.", "values": {"class": {"source": "Attribute", "name": "class"}}}}
+        ]
+    }
+    "##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::try_parse_str(shadow_json).unwrap()))]);
+    let mut outputs = vec![Vec::new(), Vec::new()];
+    let mut shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| format!("<script>{}</script>", data))));
+    shadow_api_o.parse(json_def, Rc::clone(&errors));
+    for (idx, output) in outputs.iter_mut().enumerate() {
+        if idx > 0 {
+            shadow_api_o.reset_for_next_document();
+        }
+        shadow_api_o.process_html(output, &mut pages[idx].as_bytes(), Rc::clone(&errors));
+    }
+    let data = shadow_api_o.shadow_data_cursor.borrow().root.borrow().to_string();
+    drop(shadow_api_o);
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    // The items of the second page are appended, the plain values are replaced. The limit applies to each document
+    assert_eq!(data, r#"{"title":"Page 2","notes":[{"class":"first"},{"class":"first"}],"catalog":{"items":[{"sku":"a"},{"sku":"b"},{"sku":"c"}]}}"#);
+    // Each document gets the data collected so far
+    let second = String::from_utf8(outputs.pop().unwrap()).unwrap();
+    assert!(second.ends_with(&format!("<script>{}</script></body></html>", data)), "{}", second);
+}
+
+#[test]
+fn test_stream() {
+    let html = r#"<html><body><div id="a" title="x">A</div><div id="b">B</div></body></html>"#;
+    let shadow_json = r##"{"s": "#a", "append": ["<i>!</i>"], "data": {"values": {"title": {"source": "Attribute", "name": "title"}}}}"##;
+    let stream = |shadow_api_o: &ShadowApi<'static>| -> (ShadowApiStream<'static>, Vec<u8>) {
+        let mut stream = shadow_api_o.finalize_stream();
+        let mut output = Vec::new();
+        for (idx, chunk) in html.as_bytes().chunks(7).enumerate() {
+            stream.push(chunk).unwrap();
+            if idx % 3 == 0 {
+                // Pulled whenever the runtime is ready, the output being kept meanwhile
+                stream.pull(&mut output);
+            }
+        }
+        stream.finish().unwrap();
+        assert!(stream.is_finished());
+        stream.pull(&mut output);
+        (stream, output)
+    };
+    let new_api = |options: Option<ShadowApiOptions>| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let json_def = Rc::new(vec![Rc::new(RefCell::new(ShadowJson::try_parse_str(shadow_json).unwrap()))]);
+        let mut shadow_api_o = ShadowApi::new(options);
+        shadow_api_o.set_data_formatter(Rc::new(Box::new(|data: String| format!("<script>{}</script>", data))));
+        shadow_api_o.parse(json_def, Rc::clone(&errors));
+        shadow_api_o
+    };
+
+    let (expected, _, _) = process_one(html, shadow_json, None);
+    let (mut finished, output) = stream(&new_api(None));
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    assert_eq!(finished.pull(&mut Vec::new()), 0);
+    assert!(finished.push(b"<p>").is_err());
+    assert!(finished.finish().is_err());
+
+    // Only the data in as_json mode
+    let (_, output) = stream(&new_api(Some(ShadowApiOptions { as_json: true, ..Default::default() })));
+    assert_eq!(String::from_utf8(output).unwrap(), r#"{"title":"x"}"#);
+}
+
+#[test]
+fn test_try_process_html() {
+    // Writes at most 3 bytes at a time, and is interrupted on every other call (or not ready, as a non-blocking writer, with would_block)
+    struct SlowWriter { output: Vec<u8>, ready: bool, would_block: bool }
+    impl std::io::Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(if self.would_block { std::io::ErrorKind::WouldBlock } else { std::io::ErrorKind::Interrupted }.into());
+            }
+            let n_bytes = buf.len().min(3);
+            self.output.extend_from_slice(&buf[..n_bytes]);
+            Ok(n_bytes)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    // Interrupted once, then reads 7 bytes at a time, and fails after fail_after bytes
+    struct FlakyReader<'a> { input: &'a [u8], interrupted: bool, fail_after: usize }
+    impl std::io::Read for FlakyReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            if self.fail_after == 0 {
+                return Err(std::io::Error::other("connection reset"));
+            }
+            let n_bytes = self.input.len().min(buf.len()).min(7).min(self.fail_after);
+            buf[..n_bytes].copy_from_slice(&self.input[..n_bytes]);
+            self.input = &self.input[n_bytes..];
+            self.fail_after -= n_bytes;
+            Ok(n_bytes)
+        }
+    }
+    let html = r#"<html><body><h1 title="x">Title</h1></body></html>"#;
+    let shadow_json = r#"{"s": "h1", "append": ["<i>!</i>"]}"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+
+    let mut writer = SlowWriter { output: Vec::new(), ready: false, would_block: false };
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.parse(Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]), Rc::clone(&errors));
+    let mut reader = FlakyReader { input: html.as_bytes(), interrupted: false, fail_after: usize::MAX };
+    let report = shadow_api_o.try_process_html(&mut writer, &mut reader, Rc::clone(&errors)).unwrap();
+    drop(shadow_api_o);
+    let expected = r#"<html><body><h1 title="x">Title<i>!</i></h1><script>var shadow_api_data = {};</script></body></html>"#;
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    assert_eq!(String::from_utf8(writer.output).unwrap(), expected);
+    assert_eq!((report.bytes_read, report.bytes_written), (html.len(), expected.len()));
+
+    // A non-blocking writer which is not ready stops the processing instead of being retried in a loop
+    let mut writer = SlowWriter { output: Vec::new(), ready: false, would_block: true };
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.parse(Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]), Rc::clone(&errors));
+    let mut reader = FlakyReader { input: html.as_bytes(), interrupted: false, fail_after: usize::MAX };
+    let result = shadow_api_o.try_process_html(&mut writer, &mut reader, Rc::clone(&errors));
+    assert!(matches!(result, Err(ShadowError::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock));
+
+    // A failing read stops the processing
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApi::new(None);
+    shadow_api_o.parse(Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]), Rc::clone(&errors));
+    let mut reader = FlakyReader { input: html.as_bytes(), interrupted: false, fail_after: 14 };
+    shadow_api_o.process_html(&mut output, &mut reader, Rc::clone(&errors));
+    drop(shadow_api_o);
+    assert_eq!(*errors.borrow(), vec!["[process_html] [ShadowError] connection reset".to_string()]);
+}
+
+#[test]
+fn test_try_process_html_iter() {
+    let html = r#"<html><body><ul><li>a</li><li>b</li><li>c</li></ul></body></html>"#;
+    let shadow_json = r#"{"s": "li", "data": {"path": "items.", "values": {"name": {"source": "Contents"}}}}"#;
+    let options = ShadowApiOptions { as_json: true, max_array_len: Some(2), ..Default::default() };
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+
+    // Limits reached while processing are non-fatal : they are reported as warnings
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApi::new(Some(options));
+    shadow_api_o.parse(Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]), Rc::clone(&errors));
+    let mut chunks = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    let report = shadow_api_o.try_process_html_iter(&mut output, &mut chunks, Rc::clone(&errors)).unwrap();
+    drop(shadow_api_o);
+    let expected = r#"{"items":[{"name":"a"},{"name":"b"}]}"#;
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    assert_eq!(report.bytes_read, html.len());
+    assert_eq!(report.bytes_written, expected.len());
+    assert_eq!(report.warnings, vec!["[max_array_len] 'items' is limited to 2 items : the next items are dropped".to_string()]);
+    assert_eq!(report.warnings, *errors.borrow());
+
+    // An invalid chunk stops the processing
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApi::new(Some(options));
+    shadow_api_o.parse(Rc::new(vec![Rc::new(RefCell::new(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))))]), Rc::clone(&errors));
+    let mut chunks = vec![Ok(html.as_bytes()[..20].to_vec()), Err(std::io::Error::other("truncated body"))].into_iter();
+    let result = shadow_api_o.try_process_html_iter(&mut output, &mut chunks, Rc::clone(&errors));
+    drop(shadow_api_o);
+    assert!(matches!(result, Err(ShadowError::Io(err)) if err.to_string() == "truncated body"));
+    assert!(output.is_empty());
+}
+
+#[test]
+fn test_chunk_boundaries() {
+    let html = br#"<html><body><h1>Hello world</h1><p title="a &amp; b">First text node</p><a href="/x">X</a><a href="/y">Y</a><!-- c --></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            { "s": "h1", "edit": { "content": { "op": "match_replace", "match": "(\\S+) (\\S+)", "val": "$2 $1" } }, "data": { "values": { "title": { "source": "Contents" } } } },
+            { "s": "p", "prepend": ["<b>"], "data": { "values": { "text": { "source": "Contents" }, "tip": { "source": "Attribute", "name": "title" } } } },
+            { "s": "a", "data": { "path": "links.", "values": { "href": { "source": "Attribute", "name": "href" } } } }
+        ]
+    }
+    "##;
+    let compiled = CompiledShadowJson::compile_str(shadow_json, Rc::new(RefCell::new(Vec::new()))).unwrap();
+
+    let run = shadow_api::testing::assert_chunk_size_independent(&compiled, None, html);
+    assert!(run.errors.is_empty(), "{:?}", run.errors);
+    assert!(String::from_utf8(run.output).unwrap().contains("<h1>world Hello</h1><p title=\"a &amp; b\"><b>First"));
+    assert_eq!(run.data, r#"{"title":"world Hello","tip":"a &amp; b","text":"First text node","links":[{"href":"/x"},{"href":"/y"}]}"#);
+
+    let options = ShadowApiOptions { as_json: true, ..Default::default() };
+    let run = shadow_api::testing::check_random_boundaries(&compiled, Some(options), html, 42, 16).unwrap();
+    assert_eq!(run.output, run.data.as_bytes());
+
+    // Boundaries replay a run
+    let replayed = shadow_api::testing::run_with_boundaries(&compiled, Some(options), html, &[1, 5, 40, 41]);
+    assert_eq!(replayed, run);
+}
+
+#[test]
+fn test_assert_transform() {
+    let shadow_json = r#"{"s": "h1", "append": ["<i>!</i>"], "data": {"values": {"title": {"source": "Contents"}}}}"#;
+    shadow_api::testing::assert_transform(
+        "<html><body><h1>Hi</h1></body></html>",
+        shadow_json,
+        r#"<html><body><h1>Hi<i>!</i></h1><script>var shadow_api_data = {"title":"Hi"};</script></body></html>"#,
+        r#"{ "title": "Hi" }"#,
+    );
+
+    // A mismatch panics with a diff
+    let result = std::panic::catch_unwind(|| shadow_api::testing::assert_transform(
+        "<html><body><h1>Hi</h1></body></html>",
+        shadow_json,
+        r#"<html><body><h1>Hi<b>!</b></h1><script>var shadow_api_data = {"title":"Hi"};</script></body></html>"#,
+        r#"{ "title": "Hello" }"#,
+    ));
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    assert_eq!(*message, [
+        "[assert_transform] Unexpected result",
+        "html (- expected, + actual) :",
+        r#"- <html><body><h1>Hi<b>!</b></h1><script>var shadow_api_data = {"title":"Hi"};</script></body></html>"#,
+        r#"+ <html><body><h1>Hi<i>!</i></h1><script>var shadow_api_data = {"title":"Hi"};</script></body></html>"#,
+        "first difference at character 19 :",
+        "- <html><body><h1>Hi<b>!</b></h1><script>var shadow_api_data =",
+        "+ <html><body><h1>Hi<i>!</i></h1><script>var shadow_api_data =",
+        "data (- expected, + actual) :",
+        "  {",
+        "-   \"title\": \"Hello\"",
+        "+   \"title\": \"Hi\"",
+        "  }",
+        "first difference at character 13 :",
+        "-   \"title\": \"Hello\"",
+        "+   \"title\": \"Hi\"",
+        "",
+    ].join("\n"));
+}
+
+#[test]
+fn test_parse_list() {
+    let defs = ShadowJson::try_parse_list(r#"{"s": "h1"}"#).unwrap();
+    assert_eq!(defs.iter().map(|def| def.s.as_str()).collect::<Vec<_>>(), vec!["h1"]);
+    let defs = ShadowJson::try_parse_list(r#" [{"s": "h1"}, {"s": "h2", "sub": [{"s": "a"}]}]"#).unwrap();
+    assert_eq!(defs.iter().map(|def| def.s.as_str()).collect::<Vec<_>>(), vec!["h1", "h2"]);
+
+    match ShadowJson::try_parse_list(r#"[{"s": "h1"}, {"s": "h2", "sub": [{"s": 1}]}]"#) {
+        Err(ShadowError::InvalidJson { path, .. }) => assert_eq!(path, "[1].sub[0].s"),
+        other => panic!("unexpected {:?}", other.map(|defs| defs.len())),
+    }
+}
+
+#[test]
+fn test_repeat_for() {
+    let html = r#"<html><body><nav></nav><ul><li><a href="/a">A</a></li><li><a href="/b">B &amp; C</a></li></ul><footer></footer></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            { "s": "nav", "repeat_for": "links", "append": ["<a href=\"{{item.href}}\">{{item.text}}</a>"] },
+            { "s": "li", "data": { "path": "links." }, "sub": [
+                { "s": "a", "data": { "values": { "href": { "source": "Attribute", "name": "href" }, "text": { "source": "Contents" } } } }
+            ] },
+            { "s": "footer", "repeat_for": "links", "prepend": ["<i>{{title}}:{{item.text}}</i>"], "append": ["<b>{{item}}</b>"] }
+        ]
+    }
+    "##;
+    let (html, _, errors) = process_one(html, shadow_json, Some(ShadowApiOptions { two_pass: true, ..Default::default() }));
+    assert!(errors.is_empty(), "{:?}", errors);
+    // With two_pass, the nav is rebuilt from the items collected further down the page
+    assert!(html.contains(r#"<nav><a href="/a">A</a><a href="/b">B &amp; C</a></nav>"#), "{}", html);
+    assert!(html.contains(r#"<footer><i>:A</i><i>:B &amp; C</i><b>{"href":"/a","text":"A"}</b><b>{"href":"/b","text":"B &amp; C"}</b></footer>"#), "{}", html);
+
+    // In a single pass, only the items collected before the element are rendered
+    let (html, _, _) = process_one(r#"<html><body><nav></nav><ul><li><a href="/a">A</a></li></ul><footer></footer></body></html>"#, shadow_json, None);
+    assert!(html.contains("<nav></nav>"), "{}", html);
+    assert!(html.contains(r#"<footer><i>:A</i><b>{"href":"/a","text":"A"}</b></footer>"#), "{}", html);
+}
+
+#[test]
+fn test_repeat_for_escaping() {
+    let html = r#"<html><body><ul><li title="&quot; onmouseover=&quot;alert(1)">&lt;script&gt;alert(1)&lt;/script&gt;</li></ul><footer></footer></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            { "s": "li", "data": { "path": "items.", "values": {
+                "title": { "source": "Attribute", "name": "title", "decode": ["html_entities"] },
+                "text": { "source": "Contents", "decode": ["html_entities"] }
+            } } },
+            { "s": "footer", "repeat_for": "items", "append": ["<p title=\"{{item.title}}\">{{item.text}}</p>"] }
+        ]
+    }
+    "##;
+    let (html, _, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    // The item values are escaped for their context, as the other placeholders of the fragments
+    assert!(html.contains("<footer><p title=\"&quot;&#32;onmouseover&#61;&quot;alert(1)\">&lt;script&gt;alert(1)&lt;/script&gt;</p></footer>"), "{}", html);
+}
+
+#[test]
+fn test_region() {
+    let html = r#"<html><body><div id="a">keep<!-- ad:start --><p>ad <b>text</b></p>more<!-- other --><!-- ad:end -->end</div><div id="b">x<!-- ad:start -->old<!-- ad:end -->y</div><div id="c"><!-- ad:start -->Hello <i>world</i><!-- ad:end --></div></body></html>"#;
+    let shadow_json = r##"
+    {
+        "s": "body",
+        "sub": [
+            { "s": "#a", "region": { "start": "ad:start", "end": "ad:end" } },
+            { "s": "#b", "region": { "start": "ad:start", "end": "ad:end", "op": "replace", "html": "<em>new</em>" } },
+            { "s": "#c", "region": { "start": " ad:start ", "end": "ad:end", "op": "extract", "key": "teaser" } }
+        ]
+    }
+    "##;
+    let (html, data, errors) = process_one(html, shadow_json, None);
+    assert!(errors.is_empty(), "{:?}", errors);
+    // The markers are kept, the contents in between are removed or replaced
+    assert!(html.contains(r#"<div id="a">keep<!-- ad:start --><!-- ad:end -->end</div>"#), "{}", html);
+    assert!(html.contains(r#"<div id="b">x<!-- ad:start --><em>new</em><!-- ad:end -->y</div>"#), "{}", html);
+    // Extracted regions are left untouched, their text is collected
+    assert!(html.contains(r#"<div id="c"><!-- ad:start -->Hello <i>world</i><!-- ad:end --></div>"#), "{}", html);
+    assert_eq!(data, r#"{"teaser":"Hello world"}"#);
+}
+
+#[test]
+fn test_text_replacer() {
+    let html = r#"<html><head><title>Acme shop</title><script>var brand = "Acme";</script></head><body><h1>Acme &amp; co</h1><p>Call 090-1234-5678 or write to Acme</p><pre class="raw">Acme</pre><p id="edited">Acme</p></body></html>"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let shadow_json = ShadowJson::parse_str(r##"{ "s": "body", "sub": [
+        { "s": "h1", "data": { "values": { "title": { "source": "Contents" } } } },
+        { "s": "#edited", "edit": { "content": { "op": "upsert", "val": "Edited Acme" } } }
+    ] }"##, Rc::clone(&errors));
+    let text_replacer = ShadowTextReplacer::new(&[("Acme", "Nova <Inc>"), (r"(\d{3})-\d{4}-(\d{4})", "$1-****-$2")], &["pre.raw"]).unwrap();
+
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .definition(shadow_json)
+        .errors(Rc::clone(&errors))
+        .text_replacer(text_replacer)
+        .build();
+    let mut bytes = html.as_bytes().chunks(5).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    let output = String::from_utf8(output).unwrap();
+    // Texts split over several chunks are replaced as a whole, the replacements being escaped
+    assert!(output.contains("<title>Nova &lt;Inc&gt; shop</title>"), "{}", output);
+    assert!(output.contains("<h1>Nova &lt;Inc&gt; &amp; co</h1>"), "{}", output);
+    assert!(output.contains("<p>Call 090-****-5678 or write to Nova &lt;Inc&gt;</p>"), "{}", output);
+    // Scripts, excluded elements and the texts edited by the nodes are left untouched
+    assert!(output.contains(r#"<script>var brand = "Acme";</script>"#), "{}", output);
+    assert!(output.contains(r#"<pre class="raw">Acme</pre>"#), "{}", output);
+    assert!(output.contains(r#"<p id="edited">Edited Acme</p>"#), "{}", output);
+    // The collected data is not affected
+    assert!(output.contains(r#""title":"Acme \u0026amp; co""#), "{}", output);
+
+    assert!(ShadowTextReplacer::new(&[("(", "")], &[]).is_err());
+    assert!(ShadowTextReplacer::new(&[], &["p["]).is_err());
+}
+
+#[test]
+fn test_masker() {
+    let html = r#"<html><head><meta name="author" content="jane@example.com"></head><body><div class="user"><span class="mail">jane.doe@example.co.jp</span><span class="tel">Tel +81 90-1234-5678</span><span class="card">4111 1111 1111 1111</span><span class="id">ID-778899</span></div><p>Contact jane@example.com</p></body></html>"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let shadow_json = ShadowJson::parse_str(r##"{ "s": "div.user", "data": { "path": "user" }, "sub": [
+        { "s": ".mail", "data": { "values": { "mail": { "source": "Contents" } } } },
+        { "s": ".tel", "data": { "values": { "tel": { "source": "Contents" } } } },
+        { "s": ".card", "data": { "values": { "card": { "source": "Contents" } } } },
+        { "s": ".id", "data": { "values": { "id": { "source": "Contents" } } } }
+    ] }"##, Rc::clone(&errors));
+    let masker = ShadowMasker::default().with_pattern("member_id", r"ID-\d+", "ID-***").unwrap();
+    assert_eq!(masker.detectors(), vec!["Email", "CardNumber", "Phone", "member_id"]);
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let seen_c = Rc::clone(&seen);
+
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .options(ShadowApiOptions { collect_meta: true, ..Default::default() })
+        .definition(shadow_json)
+        .errors(Rc::clone(&errors))
+        .masker(masker)
+        .on_data("user.*", move |path, value| seen_c.borrow_mut().push(format!("{}={}", path, value)))
+        .build();
+    let mut bytes = html.as_bytes().chunks(9).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    let output = String::from_utf8(output).unwrap();
+    // The collected data is masked, the document is not
+    assert!(output.contains(r#""user":{"mail":"[email]","tel":"Tel [phone]","card":"[card]","id":"ID-***"}"#), "{}", output);
+    assert!(output.contains(r#""author":"[email]""#), "{}", output);
+    assert!(output.contains("<p>Contact jane@example.com</p>"), "{}", output);
+    // The data listeners only see the masked values
+    assert_eq!(seen.borrow().len(), 4);
+    assert!(seen.borrow().iter().all(|value| !value.contains("example")), "{:?}", seen.borrow());
+
+    // With the text flag, the texts of the document are masked as well
+    let mut masker = ShadowMasker::new(&[ShadowMaskDetector::Email]);
+    masker.text = true;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new().errors(Rc::clone(&errors)).masker(masker).build();
+    let mut bytes = html.as_bytes().chunks(9).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("<p>Contact [email]</p>"), "{}", output);
+    assert!(output.contains(r#"<span class="tel">Tel +81 90-1234-5678</span>"#), "{}", output);
+    assert!(output.contains(r#"content="jane@example.com""#), "{}", output); // Attributes are not texts
+
+    assert!(ShadowMasker::default().with_pattern("broken", "(", "").is_err());
+}
+
+#[test]
+fn test_attribute_injection() {
+    let html = r#"<html><head><title>Shop</title></head><body><div class="product" data-sku="A-1"><h1>Chair</h1><span class="price">120</span></div></body></html>"#;
+    let shadow_json = r##"{ "s": "div.product", "data": { "path": "product", "values": { "sku": { "source": "Attribute", "name": "data-sku" } } }, "sub": [
+        { "s": "h1", "data": { "values": { "name": { "source": "Contents" } } } }
+    ] }"##;
+    let run = |options: ShadowApiOptions| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(options)
+            .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+            .errors(Rc::clone(&errors))
+            .attribute_injection("body", &[("product-sku", "product.sku"), ("data-product", "product"), ("missing", "product.price")])
+            .build();
+        let mut bytes = html.as_bytes().chunks(11).map(|c| Ok(c.to_vec()));
+        shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        String::from_utf8(output).unwrap()
+    };
+    // The data of the body is collected after its start tag : only the two_pass option provides it
+    let output = run(ShadowApiOptions::default());
+    assert!(output.contains("<body>"), "{}", output);
+    assert!(!output.contains("<script>"), "{}", output);
+    let output = run(ShadowApiOptions { two_pass: true, ..Default::default() });
+    assert!(output.contains(r#"<body data-product-sku="A-1" data-product="{&quot;sku&quot;:&quot;A-1&quot;,&quot;name&quot;:&quot;Chair&quot;}">"#), "{}", output);
+    assert!(!output.contains("<script>"), "{}", output);
+
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    ShadowApiBuilder::new().errors(Rc::clone(&errors)).attribute_injection("body[", &[]).build();
+    assert!(errors.borrow()[0].contains("[attribute_injection]"), "{:?}", errors.borrow());
+}
+
+#[test]
+fn test_on_complete() {
+    let html = r#"<html><body><h1>Chair</h1><p>Made of wood</p></body></html>"#;
+    let two_pass = ShadowApiOptions { two_pass: true, ..Default::default() };
+    let as_json = ShadowApiOptions { as_json: true, ..Default::default() };
+    for options in [ShadowApiOptions::default(), two_pass, as_json] {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let header = Rc::new(RefCell::new(Vec::new()));
+        let header_c = Rc::clone(&header);
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(options)
+            .definition(ShadowJson::parse_str(r#"{ "s": "h1", "data": { "values": { "title": { "source": "Contents" } } } }"#, Rc::clone(&errors)))
+            .errors(Rc::clone(&errors))
+            .on_complete(move |data: &ShadowData| {
+                // e.g. X-Product-Title
+                let title = data.get("title").map(|title| title.borrow().to_string()).unwrap_or_default();
+                header_c.borrow_mut().push(title);
+            })
+            .build();
+        let mut bytes = html.as_bytes().chunks(8).map(|c| Ok(c.to_vec()));
+        shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+        drop(shadow_api_o);
+        assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+        // Invoked once, by the rewriting pass with two_pass
+        assert_eq!(*header.borrow(), vec![r#""Chair""#.to_string()]);
+    }
+}
+
+#[test]
+fn test_max_injected_bytes() {
+    let html = r#"<html><body><h1>Chairs</h1><aside>Related items</aside><ul><li>Oak</li><li>Pine</li><li>Birch</li><li>Maple</li></ul><ol><li>Red</li><li>Blue</li></ol></body></html>"#;
+    let shadow_json = r##"{ "s": "body", "sub": [
+        { "s": "h1", "data": { "values": { "title": { "source": "Contents" } } } },
+        { "s": "ul > li", "data": { "path": "woods.", "values": { "name": { "source": "Contents" } } } },
+        { "s": "aside", "data": { "values": { "related": { "source": "Contents" } } } },
+        { "s": "ol > li", "data": { "path": "colors.", "values": { "name": { "source": "Contents" } } } }
+    ] }"##;
+    let run = |options: ShadowApiOptions, low_priority_paths: &[&str]| {
+        let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut output = Vec::new();
+        let shadow_api_o = ShadowApiBuilder::new()
+            .options(options)
+            .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+            .errors(Rc::clone(&errors))
+            .low_priority_paths(low_priority_paths.iter().copied())
+            .build();
+        let mut bytes = html.as_bytes().chunks(16).map(|c| Ok(c.to_vec()));
+        shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+        let data = shadow_api_o.shadow_data_cursor.borrow().root.borrow().to_string();
+        drop(shadow_api_o);
+        let output = String::from_utf8(output).unwrap();
+        let injected = output.split("<script>var shadow_api_data = ").nth(1).map(|tail| tail.split(";</script>").next().unwrap().to_string());
+        (output, injected, data, errors.take())
+    };
+    let full = r#"{"title":"Chairs","related":"Related items","woods":[{"name":"Oak"},{"name":"Pine"},{"name":"Birch"},{"name":"Maple"}],"colors":[{"name":"Red"},{"name":"Blue"}]}"#;
+
+    // Within the budget, the data is injected as it is
+    let (_, injected, _, errors) = run(ShadowApiOptions { max_injected_bytes: Some(1000), ..Default::default() }, &[]);
+    assert_eq!(injected.as_deref(), Some(full));
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    // The longest arrays lose their last items first, the collected data is untouched
+    let (_, injected, data, errors) = run(ShadowApiOptions { max_injected_bytes: Some(120), ..Default::default() }, &[]);
+    assert_eq!(injected.as_deref(), Some(r#"{"title":"Chairs","related":"Related items","woods":[{"name":"Oak"},{"name":"Pine"}],"colors":[{"name":"Red"}]}"#));
+    assert_eq!(data, full);
+    assert!(errors[0].starts_with("[max_injected_bytes]"), "{:?}", errors);
+
+    let drop_paths = ShadowApiOptions { max_injected_bytes: Some(120), injection_overflow: ShadowInjectionOverflow::DropPaths, ..Default::default() };
+    let (_, injected, _, _) = run(drop_paths, &["related", "colors", "woods"]);
+    assert_eq!(injected.as_deref(), Some(r#"{"title":"Chairs","woods":[{"name":"Oak"},{"name":"Pine"},{"name":"Birch"},{"name":"Maple"}]}"#));
+    // Still too large once all the paths are dropped
+    let (output, injected, _, errors) = run(drop_paths, &["related"]);
+    assert_eq!(injected, None);
+    assert!(output.contains("<!-- [max_injected_bytes] The data (161 bytes) exceeds 120 bytes : it was not injected --></body>"), "{}", output);
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+
+    let (output, injected, _, _) = run(ShadowApiOptions { max_injected_bytes: Some(120), injection_overflow: ShadowInjectionOverflow::Skip, ..Default::default() }, &[]);
+    assert_eq!(injected, None);
+    assert!(output.contains("<!-- [max_injected_bytes]"), "{}", output);
+}
+
+#[test]
+fn test_escape_json_for_script() {
+    let html = "<html><body><p title=\"a</script><script>alert(1)</script> <!-- b & c \u{2028}\">p</p></body></html>";
+    let shadow_json = r##"{ "s": "p", "data": { "values": { "title": { "source": "Attribute", "name": "title" } } } }"##;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new().definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors))).errors(Rc::clone(&errors)).build();
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    let data = shadow_api_o.shadow_data_cursor.borrow().root.borrow().to_string();
+    drop(shadow_api_o);
+    assert!(errors.borrow().is_empty(), "{:?}", errors.borrow());
+    let html = String::from_utf8(output).unwrap();
+    // The page holds a single closing script tag, the one of the injected data
+    let injected = html.split("<script>var shadow_api_data = ").nth(1).unwrap();
+    assert!(data.contains("</script>"), "{}", data);
+    assert_eq!(injected.matches("</script>").count(), 1, "{}", html);
+    assert!(!injected.contains("<!--") && !injected.contains('\u{2028}'), "{}", html);
+    // The escapes leave the value unchanged
+    let json = injected.split(";</script>").next().unwrap();
+    assert_eq!(serde_json::from_str::<serde_json::Value>(json).unwrap(), serde_json::from_str::<serde_json::Value>(&data).unwrap());
+    assert_eq!(ShadowApi::escape_json_for_script(r#"{"a":"</script>&"}"#), r#"{"a":"\u003c/script\u003e\u0026"}"#);
+}
+
+#[test]
+fn test_ld_json_key() {
+    let html = r#"<html><head>
+        <script type="application/ld+json">{"@context": "https://schema.org", "@type": "Product", "name": "Chair", "offers": {"price": 120.5, "priceCurrency": "EUR"}}</script>
+        <script type="application/ld+json">[{"@type": "BreadcrumbList", "itemListElement": []}, {"@type": "Organization", "name": "Shop"}]</script>
+        <script type="application/ld+json">{"@type": "Broken",}</script>
+        <script>var notStructured = {"@type": "Ignored"};</script>
+    </head><body><h1>Chair</h1></body></html>"#;
+    let shadow_json = r#"{ "s": "h1", "data": { "path": "structured", "values": { "title": {"source": "Contents"} } } }"#;
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut output = Vec::new();
+    let shadow_api_o = ShadowApiBuilder::new()
+        .options(ShadowApiOptions { as_json: true, ..Default::default() })
+        .definition(ShadowJson::parse_str(shadow_json, Rc::clone(&errors)))
+        .errors(Rc::clone(&errors))
+        .ld_json_key("structured.ld")
+        .build();
+    let mut bytes = html.as_bytes().chunks(7).map(|c| Ok(c.to_vec()));
+    shadow_api_o.process_html_iter(&mut output, &mut bytes, Rc::clone(&errors));
+    drop(shadow_api_o);
+    assert_eq!(errors.borrow().len(), 1, "{:?}", errors.borrow());
+    assert!(errors.borrow()[0].starts_with("[ld_json]"), "{:?}", errors.borrow());
+    assert_eq!(String::from_utf8(output).unwrap(), concat!(
+        r#"{"structured":{"ld":[{"@context":"https://schema.org","@type":"Product","name":"Chair","offers":{"price":120.5,"priceCurrency":"EUR"}},"#,
+        r#"{"@type":"BreadcrumbList","itemListElement":[]},{"@type":"Organization","name":"Shop"}],"title":"Chair"}}"#
+    ));
+
+    let errors: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    ShadowApiBuilder::new().errors(Rc::clone(&errors)).ld_json_key("structured..ld").build();
+    assert!(errors.borrow()[0].contains("[ld_json]"), "{:?}", errors.borrow());
+}